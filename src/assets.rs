@@ -0,0 +1,243 @@
+//! Static files of the site (`[assets]`): the stylesheets, scripts, fonts and
+//! images in `assets.dir`, copied to `assets.out_dir` before a directory build
+//! renders its pages. With `assets.fingerprint`, CSS and JS files are also
+//! written as `name-<hash>.ext` and the template's references to them are
+//! pointed at those copies, so hosts can cache them for good and a changed
+//! file gets a new URL without being renamed by hand.
+
+use crate::config;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Fingerprinted files of this build by URL path, without the leading `/`.
+    static ref FINGERPRINTS: Mutex<BTreeMap<String, Fingerprint>> = Mutex::new(BTreeMap::new());
+    /// Copies written by an earlier build, which are not fingerprinted again.
+    static ref HASHED_COPY: Regex = Regex::new(r"-[0-9a-f]{12}\.(?:css|js|mjs)$").unwrap();
+}
+
+#[derive(Debug, Clone)]
+struct Fingerprint {
+    /// The file in `assets.dir`, which pages referring to it depend on.
+    source: PathBuf,
+    /// URL path of the hashed copy, without the leading `/`.
+    hashed: String,
+}
+
+/// Copies `assets.dir` to `assets.out_dir` and writes the fingerprinted
+/// copies, remembering them for [`fingerprint_references`].
+pub fn publish(config: &config::Config) -> Result<(), String> {
+    let assets = &config.assets;
+    let Some(dir) = assets.dir.as_deref() else {
+        return Ok(());
+    };
+    let out_dir = assets.out_dir.as_deref().unwrap_or(dir);
+    let published = publish_dir(Path::new(dir), Path::new(out_dir), assets.fingerprint)?;
+    let base = url_path(Path::new(out_dir));
+    let mut fingerprints = FINGERPRINTS
+        .lock()
+        .map_err(|_| "asset fingerprints lock poisoned".to_string())?;
+    for (relative, mut fingerprint) in published {
+        fingerprint.hashed = join_url(&base, &fingerprint.hashed);
+        fingerprints.insert(join_url(&base, &relative), fingerprint);
+    }
+    Ok(())
+}
+
+/// The files with fingerprinted copies, whose hashes pages embed.
+pub fn fingerprinted_sources() -> Vec<PathBuf> {
+    FINGERPRINTS
+        .lock()
+        .map(|fingerprints| {
+            fingerprints
+                .values()
+                .map(|fingerprint| fingerprint.source.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// `template` with its `href="..."` and `src="..."` references to
+/// fingerprinted files pointed at the hashed copies.
+pub fn fingerprint_references(template: &str, root_url: Option<&str>) -> String {
+    match FINGERPRINTS.lock() {
+        Ok(fingerprints) if !fingerprints.is_empty() => {
+            rewrite_references(template, root_url, &fingerprints)
+        }
+        _ => template.to_string(),
+    }
+}
+
+/// Copies every file below `dir` to the same place below `out_dir` and,
+/// with `fingerprint`, writes hashed copies of the CSS and JS files beside
+/// them. Returns the hashed files by path relative to `out_dir`.
+fn publish_dir(
+    dir: &Path,
+    out_dir: &Path,
+    fingerprint: bool,
+) -> Result<BTreeMap<String, Fingerprint>, String> {
+    let mut published = BTreeMap::new();
+    let mut stack = vec![dir.to_path_buf()];
+    while let Some(current) = stack.pop() {
+        let entries = fs::read_dir(&current)
+            .map_err(|e| format!("Failed to read assets in {}: {}", current.display(), e))?;
+        for entry in entries.flatten() {
+            let source = entry.path();
+            if source.is_dir() {
+                stack.push(source);
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if HASHED_COPY.is_match(&name) {
+                continue;
+            }
+            let relative = source.strip_prefix(dir).unwrap_or(&source).to_path_buf();
+            let target = out_dir.join(&relative);
+            let contents = fs::read(&source)
+                .map_err(|e| format!("Failed to read {}: {}", source.display(), e))?;
+            if target != source {
+                write_if_changed(&target, &contents)?;
+            }
+            let extension = source.extension().and_then(|ext| ext.to_str());
+            if !fingerprint || !matches!(extension, Some("css" | "js" | "mjs")) {
+                continue;
+            }
+            let hash = blake3::hash(&contents).to_hex();
+            let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+            let hashed = relative.with_file_name(format!(
+                "{}-{}.{}",
+                stem,
+                &hash[..12],
+                extension.unwrap_or_default()
+            ));
+            write_if_changed(&out_dir.join(&hashed), &contents)?;
+            published.insert(
+                url_path(&relative),
+                Fingerprint {
+                    source,
+                    hashed: url_path(&hashed),
+                },
+            );
+        }
+    }
+    Ok(published)
+}
+
+/// Writes `contents` to `path` unless it already holds them, so an unchanged
+/// file keeps its modification time.
+fn write_if_changed(path: &Path, contents: &[u8]) -> Result<(), String> {
+    if fs::read(path).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    fs::write(path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+fn rewrite_references(
+    template: &str,
+    root_url: Option<&str>,
+    fingerprints: &BTreeMap<String, Fingerprint>,
+) -> String {
+    lazy_static! {
+        static ref REFERENCE: Regex = Regex::new(r#"(\s(?:href|src)=")([^"]*)""#).unwrap();
+    }
+    let root = root_url
+        .filter(|root| *root != "/")
+        .map(|root| root.trim_end_matches('/'));
+    REFERENCE
+        .replace_all(template, |caps: &regex::Captures| {
+            let reference = &caps[2];
+            let end = reference.find(['?', '#']).unwrap_or(reference.len());
+            let (path, suffix) = reference.split_at(end);
+            // Keeps whatever leads up to the path from the site root
+            let rooted = root
+                .and_then(|root| path.strip_prefix(root))
+                .filter(|rest| rest.starts_with('/'))
+                .unwrap_or(path);
+            let relative = rooted.trim_start_matches('/');
+            match fingerprints.get(relative) {
+                Some(fingerprint) => format!(
+                    "{}{}{}{}\"",
+                    &caps[1],
+                    &path[..path.len() - relative.len()],
+                    fingerprint.hashed,
+                    suffix
+                ),
+                None => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// `/`-separated path of a relative filesystem path.
+fn url_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(segment) => Some(segment.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn join_url(base: &str, path: &str) -> String {
+    if base.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}/{}", base, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copies_assets_and_points_references_at_hashed_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let theme = dir.path().join("theme");
+        fs::create_dir_all(theme.join("js")).unwrap();
+        fs::write(theme.join("site.css"), "body { margin: 0 }").unwrap();
+        fs::write(theme.join("js").join("app.js"), "console.log(1);").unwrap();
+        fs::write(theme.join("logo.svg"), "<svg/>").unwrap();
+        let out = dir.path().join("site").join("static");
+
+        let published = publish_dir(&theme, &out, true).unwrap();
+        assert_eq!(fs::read_to_string(out.join("logo.svg")).unwrap(), "<svg/>");
+        let app = &published["js/app.js"];
+        assert_eq!(app.source, theme.join("js").join("app.js"));
+        assert!(app.hashed.starts_with("js/app-") && app.hashed.ends_with(".js"));
+        assert!(out.join(&app.hashed).is_file());
+        // Copies from the last build are not fingerprinted again
+        assert_eq!(publish_dir(&out, &out, true).unwrap().len(), 2);
+
+        let fingerprints: BTreeMap<String, Fingerprint> = published
+            .into_iter()
+            .map(|(relative, mut fingerprint)| {
+                fingerprint.hashed = join_url("static", &fingerprint.hashed);
+                (join_url("static", &relative), fingerprint)
+            })
+            .collect();
+        let template = concat!(
+            "<link href=\"https://example.com/static/site.css\">",
+            "<script src=\"/static/js/app.js?v=2\"></script>",
+            "<img src=\"static/logo.svg\"><a href=\"https://other.org/static/site.css\">"
+        );
+        let css = &fingerprints["static/site.css"].hashed;
+        let js = &fingerprints["static/js/app.js"].hashed;
+        assert_eq!(
+            rewrite_references(template, Some("https://example.com/"), &fingerprints),
+            format!(
+                "<link href=\"https://example.com/{}\"><script src=\"/{}?v=2\"></script>\
+                 <img src=\"static/logo.svg\"><a href=\"https://other.org/static/site.css\">",
+                css, js
+            )
+        );
+    }
+}
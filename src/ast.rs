@@ -1,34 +1,230 @@
-#[derive(Debug, Default)]
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Article {
     pub header: Option<ArticleHeader>,
     pub body: Vec<Block>,
 }
 
-#[derive(Debug)]
+impl Article {
+    /// Every block in document order, including the ones inside list items
+    /// and details blocks.
+    pub fn blocks(&self) -> Vec<&Block> {
+        fn push<'a>(blocks: &'a [Block], out: &mut Vec<&'a Block>) {
+            for block in blocks {
+                out.push(block);
+                match block {
+                    Block::UnorderedList(items) | Block::OrderedList(items) => {
+                        for item in items {
+                            push(&item.blocks, out);
+                        }
+                    }
+                    Block::Details { blocks, .. } => push(blocks, out),
+                    _ => {}
+                }
+            }
+        }
+        let mut out = Vec::new();
+        push(&self.body, &mut out);
+        out
+    }
+
+    /// The TeX of every equation in document order, paired with whether it is
+    /// inline math rather than a display equation.
+    pub fn equations(&self) -> Vec<(&str, bool)> {
+        let mut out = Vec::new();
+        for block in self.blocks() {
+            match block {
+                Block::DisplayMath { content, .. } => out.push((content.as_str(), false)),
+                Block::BlockQuote(text)
+                | Block::Paragraph(text)
+                | Block::BigButton { text, .. }
+                | Block::Embed { text, .. }
+                | Block::Details { summary: text, .. } => push_math(text, &mut out),
+                Block::ImageFigure { text, credit, .. } => {
+                    push_math(text, &mut out);
+                    if let Some(credit) = credit {
+                        push_math(credit, &mut out);
+                    }
+                }
+                Block::CodeBlock {
+                    caption: Some(caption),
+                    ..
+                } => push_math(caption, &mut out),
+                Block::Table {
+                    header,
+                    rows,
+                    caption,
+                    ..
+                } => {
+                    for cell in header.iter().chain(rows.iter().flatten()) {
+                        push_math(&cell.content, &mut out);
+                    }
+                    if let Some(caption) = caption {
+                        push_math(caption, &mut out);
+                    }
+                }
+                Block::UnorderedList(items) | Block::OrderedList(items) => {
+                    for item in items {
+                        push_math(&item.text, &mut out);
+                    }
+                }
+                _ => {}
+            }
+        }
+        out
+    }
+
+    /// `p-<hash>` anchors for the top-level paragraphs, one entry per body
+    /// block. The hash covers only the paragraph's words, so an anchor survives
+    /// rewrapping and edits elsewhere in the article; repeated paragraphs get
+    /// `-2`, `-3` and so on.
+    pub fn paragraph_ids(&self) -> Vec<Option<String>> {
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        self.body
+            .iter()
+            .map(|block| {
+                let Block::Paragraph(inlines) = block else {
+                    return None;
+                };
+                let mut text = String::new();
+                push_words(inlines, &mut text);
+                let words = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                if words.is_empty() {
+                    return None;
+                }
+                let id = format!("p-{}", &blake3::hash(words.as_bytes()).to_hex()[..8]);
+                let count = seen.entry(id.clone()).or_default();
+                *count += 1;
+                Some(match *count {
+                    1 => id,
+                    n => format!("{}-{}", id, n),
+                })
+            })
+            .collect()
+    }
+}
+
+fn push_math<'a>(inlines: &'a [InlineElement], out: &mut Vec<(&'a str, bool)>) {
+    for inline in inlines {
+        match inline {
+            InlineElement::InlineMath(tex) => out.push((tex.as_str(), true)),
+            InlineElement::Link { text, .. }
+            | InlineElement::Lang { text, .. }
+            | InlineElement::Emphasis(text)
+            | InlineElement::Strong(text) => push_math(text, out),
+            _ => {}
+        }
+    }
+}
+
+fn push_words(inlines: &[InlineElement], out: &mut String) {
+    for inline in inlines {
+        match inline {
+            InlineElement::Text(text)
+            | InlineElement::Code(text)
+            | InlineElement::InlineMath(text)
+            | InlineElement::Reference(text)
+            | InlineElement::ReferenceAnchor { content: text, .. } => out.push_str(text),
+            InlineElement::Link { text, .. }
+            | InlineElement::Lang { text, .. }
+            | InlineElement::Emphasis(text)
+            | InlineElement::Strong(text) => push_words(text, out),
+        }
+        // Keeps neighbouring words apart; spacing is collapsed before hashing
+        out.push(' ');
+    }
+}
+
+/// Whether a code block's `lang` is Graphviz (`dot` or `graphviz`), drawn as
+/// a figure numbered along with images instead of shown as code.
+pub fn is_graphviz(language: Option<&str>) -> bool {
+    language.is_some_and(|language| {
+        language.eq_ignore_ascii_case("dot") || language.eq_ignore_ascii_case("graphviz")
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ArticleHeader {
     pub title: String,
     pub date: Option<String>,
+    /// Custom `x-name: value` header fields, keyed by lowercased `x-name`.
+    pub vars: BTreeMap<String, String>,
+    /// `pinned: true`: listed above the other posts on its collection index.
+    #[serde(default)]
+    pub pinned: bool,
+    /// `featured: true`: marked on indexes and exposed to templates as `{{featured}}`.
+    #[serde(default)]
+    pub featured: bool,
+    /// `template: name`: the `[html.templates]` entry the page is rendered with.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Other `key: value` header fields, keyed by lowercased key, filled in as
+    /// `{{meta.key}}` in templates.
+    #[serde(default)]
+    pub meta: BTreeMap<String, String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 #[allow(clippy::enum_variant_names)]
 pub enum Block {
-    Raw(String),
+    Raw {
+        /// Backend that emits the content: the tag of `??? latex`, or `html` for a bare `???`.
+        format: String,
+        content: String,
+    },
     CodeBlock {
         language: Option<String>,
         code: String,
+        line_numbers: Option<bool>,
+        highlight_lines: Vec<usize>,
+        filename: Option<String>,
+        caption: Option<Vec<InlineElement>>,
+        id: Option<String>,
+        id_number: usize,
     },
     SectionHeader {
         level: usize,
         id: String,
         text: String,
+        /// `id` was written as `# Heading {#id}` rather than made from the
+        /// text; it then replaces the section number as the heading's anchor.
+        #[serde(default)]
+        custom_id: bool,
     },
     BlockQuote(Vec<InlineElement>),
+    /// `+++ summary` ... `+++`: blocks collapsed below a summary line until
+    /// the reader opens them.
+    Details {
+        summary: Vec<InlineElement>,
+        blocks: Vec<Block>,
+    },
     ImageFigure {
         url: String,
         id: Option<String>,
         id_number: usize,
         alt: String,
+        /// Alt text written as `-`: the image is decorative and gets an empty `alt`.
+        decorative: bool,
+        text: Vec<InlineElement>,
+        /// Optional `:: credit` attribution shown below the caption.
+        credit: Option<Vec<InlineElement>>,
+        /// `crop=` hint for thumbnails and social cards cut from the image.
+        #[serde(default)]
+        crop: Option<Crop>,
+        /// `resize=off` hint: the figure links the image as written, without
+        /// variants, `srcset` or EXIF details.
+        #[serde(default)]
+        no_resize: bool,
+    },
+    /// `embed youtube VIDEO_ID : caption`: a video player, numbered with the figures.
+    Embed {
+        provider: EmbedProvider,
+        video: String,
+        id: Option<String>,
+        id_number: usize,
         text: Vec<InlineElement>,
     },
     DisplayMath {
@@ -38,26 +234,208 @@ pub enum Block {
     },
     Table {
         id_number: usize,
-        header: Vec<Vec<InlineElement>>,    // list of header cells
-        rows: Vec<Vec<Vec<InlineElement>>>, // list of rows, each row is list of cells
-        caption: Vec<InlineElement>,
+        header: Vec<TableCell>,    // list of header cells
+        rows: Vec<Vec<TableCell>>, // list of rows, each row is list of cells
+        /// Only captioned tables are numbered figures.
+        caption: Option<Vec<InlineElement>>,
     },
     BigButton {
         text: Vec<InlineElement>,
         url: String,
     },
+    /// `include path.dllu`, as written. A parser made with
+    /// `Parser::with_includes` replaces it with the blocks of that file.
+    Include {
+        path: String,
+    },
     UnorderedList(Vec<ListItem>),
     OrderedList(Vec<ListItem>),
     Paragraph(Vec<InlineElement>),
 }
 
-#[derive(Debug)]
+/// Video sites an `embed` block can show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbedProvider {
+    YouTube,
+    Vimeo,
+}
+
+impl EmbedProvider {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "youtube" => Some(Self::YouTube),
+            "vimeo" => Some(Self::Vimeo),
+            _ => None,
+        }
+    }
+
+    /// The spelling `embed` blocks use.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::YouTube => "youtube",
+            Self::Vimeo => "vimeo",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::YouTube => "YouTube",
+            Self::Vimeo => "Vimeo",
+        }
+    }
+
+    /// Whether `video` has the shape of one of this site's video ids, which
+    /// also keeps it safe to put in a URL path unescaped.
+    pub fn is_video_id(self, video: &str) -> bool {
+        match self {
+            Self::YouTube => {
+                !video.is_empty()
+                    && video
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            }
+            Self::Vimeo => !video.is_empty() && video.chars().all(|c| c.is_ascii_digit()),
+        }
+    }
+
+    /// The video's page on the site, for readers without frames or scripts.
+    pub fn watch_url(self, video: &str) -> String {
+        match self {
+            Self::YouTube => format!("https://www.youtube.com/watch?v={}", video),
+            Self::Vimeo => format!("https://vimeo.com/{}", video),
+        }
+    }
+
+    /// The embeddable player in its privacy-enhanced form: YouTube's cookieless
+    /// domain, or Vimeo with tracking turned off.
+    pub fn player_url(self, video: &str) -> String {
+        match self {
+            Self::YouTube => format!("https://www.youtube-nocookie.com/embed/{}", video),
+            Self::Vimeo => format!("https://player.vimeo.com/video/{}?dnt=1", video),
+        }
+    }
+}
+
+const FOCUS_NAMES: [(&str, (f64, f64)); 9] = [
+    ("center", (0.5, 0.5)),
+    ("top", (0.5, 0.0)),
+    ("bottom", (0.5, 1.0)),
+    ("left", (0.0, 0.5)),
+    ("right", (1.0, 0.5)),
+    ("top-left", (0.0, 0.0)),
+    ("top-right", (1.0, 0.0)),
+    ("bottom-left", (0.0, 1.0)),
+    ("bottom-right", (1.0, 1.0)),
+];
+
+/// Aspect ratio and focal point of a cropped image variant, written
+/// `16:9@center`, `1:1@top`, `4:3@0.3,0.6` or just one of the two halves.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Crop {
+    /// Width over height.
+    pub aspect: Option<f64>,
+    /// Point kept in frame, as fractions of the width and height from the top left.
+    pub focus: Option<(f64, f64)>,
+}
+
+impl Crop {
+    pub fn parse(spec: &str) -> Option<Self> {
+        let spec = spec.trim();
+        let (aspect, focus) = spec.split_once('@').unwrap_or((spec, ""));
+        let aspect = match aspect.split_once(':') {
+            Some((w, h)) => {
+                let ratio = w.trim().parse::<f64>().ok()? / h.trim().parse::<f64>().ok()?;
+                if !(ratio.is_finite() && ratio > 0.0) {
+                    return None;
+                }
+                Some(ratio)
+            }
+            None if aspect.is_empty() => None,
+            None => return None,
+        };
+        let named = FOCUS_NAMES.iter().find(|(name, _)| *name == focus);
+        let focus = match (focus, named) {
+            ("", _) => None,
+            (_, Some((_, point))) => Some(*point),
+            (point, None) => {
+                let (x, y) = point.split_once(',')?;
+                let (x, y) = (x.trim().parse::<f64>().ok()?, y.trim().parse::<f64>().ok()?);
+                if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+                    return None;
+                }
+                Some((x, y))
+            }
+        };
+        (aspect.is_some() || focus.is_some()).then_some(Self { aspect, focus })
+    }
+
+    /// This crop with the blanks filled in from `fallback`.
+    pub fn or(self, fallback: Option<Crop>) -> Self {
+        let fallback = fallback.unwrap_or_default();
+        Self {
+            aspect: self.aspect.or(fallback.aspect),
+            focus: self.focus.or(fallback.focus),
+        }
+    }
+}
+
+/// The `crop=` spelling [`Crop::parse`] reads back, with whole-number ratios
+/// like `16:9` where possible.
+impl fmt::Display for Crop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(aspect) = self.aspect {
+            let whole = (1..=32u32).find(|height| {
+                let width = aspect * *height as f64;
+                (width - width.round()).abs() < 1e-9
+            });
+            match whole {
+                Some(height) => write!(f, "{}:{}", (aspect * height as f64).round(), height)?,
+                None => write!(f, "{}:1", aspect)?,
+            }
+        }
+        if let Some(focus) = self.focus {
+            match FOCUS_NAMES.iter().find(|(_, point)| *point == focus) {
+                Some((name, _)) => write!(f, "@{}", name)?,
+                None => write!(f, "@{},{}", focus.0, focus.1)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ListItem {
     pub level: usize,
     pub text: Vec<InlineElement>,
+    /// `* [ ] item` / `* [x] item`: a task list item and whether it is done.
+    #[serde(default)]
+    pub checked: Option<bool>,
+    /// Paragraphs and other blocks indented below the item after a blank line.
+    #[serde(default)]
+    pub blocks: Vec<Block>,
 }
 
-#[derive(Debug)]
+/// A table cell. A cell spanning several columns or rows stands for all of
+/// them, and the cells it covers are left out of their rows.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TableCell {
+    pub content: Vec<InlineElement>,
+    pub colspan: usize,
+    pub rowspan: usize,
+}
+
+impl TableCell {
+    pub fn new(content: Vec<InlineElement>) -> Self {
+        Self {
+            content,
+            colspan: 1,
+            rowspan: 1,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum InlineElement {
     Text(String),
     Code(String),
@@ -68,6 +446,11 @@ pub enum InlineElement {
     },
     Emphasis(Vec<InlineElement>),
     Strong(Vec<InlineElement>),
+    /// `{fr|bonjour}`: text in another language, tagged with its BCP 47 code.
+    Lang {
+        lang: String,
+        text: Vec<InlineElement>,
+    },
     #[allow(dead_code)]
     Reference(String),
     #[allow(dead_code)]
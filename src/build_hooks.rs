@@ -0,0 +1,45 @@
+//! `[build]` pre/post commands run around a site build.
+
+use std::path::Path;
+use std::process::Command;
+
+/// Runs each command in `dir`, in order, stopping at the first failure. Commands
+/// are split like a shell would but not run through one.
+pub fn run(stage: &str, commands: &[String], dir: &Path) -> Result<(), String> {
+    for command in commands {
+        let parts = shell_words::split(command)
+            .map_err(|e| format!("invalid {} `{}`: {}", stage, command, e))?;
+        let Some((program, args)) = parts.split_first() else {
+            continue;
+        };
+        eprintln!("[build] {}: {}", stage, command);
+        let status = Command::new(program)
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .map_err(|e| format!("failed to run {} `{}`: {}", stage, command, e))?;
+        if !status.success() {
+            return Err(format!("{} `{}` failed: {}", stage, command, status));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_at_the_first_failing_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let commands = vec![
+            "touch first".to_string(),
+            "false".to_string(),
+            "touch second".to_string(),
+        ];
+        let err = run("pre_command", &commands, dir.path()).unwrap_err();
+        assert!(err.starts_with("pre_command `false` failed"));
+        assert!(dir.path().join("first").exists());
+        assert!(!dir.path().join("second").exists());
+    }
+}
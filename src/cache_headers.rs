@@ -0,0 +1,272 @@
+//! `Cache-Control` rules for hosts (`[headers]`), written to the site root after
+//! a directory build so hosting config follows the generator's file naming:
+//! content-hashed files (image variants and crops, GIF videos, social cards,
+//! the hashed stylesheet and fingerprinted assets) never change and are cached for good, while pages
+//! are revalidated.
+//!
+//! Netlify and Cloudflare Pages `_headers` files only match literal paths, so
+//! they list every page and cached image; the Apache and nginx snippets match
+//! the same files by pattern.
+
+use crate::config::{Config, HeadersConfig, HeadersFormat};
+use regex::Regex;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// First line of every generated file; a file without it is never overwritten.
+const MARKER: &str = "# Generated by dllup-rs from [headers]; edits are overwritten.";
+
+lazy_static! {
+    /// `-<hash>.<ext>` ending shared by every content-hashed file the build writes.
+    static ref FINGERPRINTED: Regex = Regex::new(FINGERPRINT_PATTERN).unwrap();
+}
+const FINGERPRINT_PATTERN: &str = r"-[0-9a-f]{8,16}\.[A-Za-z0-9]+$";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Caching {
+    Page,
+    Immutable,
+    Asset,
+}
+
+/// Writes the files `config.headers.formats` asks for into `site_root`, given
+/// the HTML files the build produced there.
+pub fn write(site_root: &Path, config: &Config, pages: &[PathBuf]) -> Result<(), String> {
+    let headers = &config.headers;
+    if headers.formats.is_empty() {
+        return Ok(());
+    }
+    let asset_dirs = asset_dirs(config);
+    for format in &headers.formats {
+        let (file_name, contents) = match format {
+            HeadersFormat::Netlify => (
+                "_headers",
+                netlify(headers, &literal_rules(site_root, pages, &asset_dirs)),
+            ),
+            HeadersFormat::Apache => (".htaccess", apache(headers, &asset_dirs)),
+            HeadersFormat::Nginx => ("cache-headers.nginx.conf", nginx(headers, &asset_dirs)),
+        };
+        let path = site_root.join(file_name);
+        if let Ok(existing) = fs::read_to_string(&path) {
+            if !existing.starts_with(MARKER) {
+                crate::diagnostics::warn(format!(
+                    "[headers] not overwriting {}, which was not generated by dllup-rs",
+                    path.display()
+                ));
+                continue;
+            }
+        }
+        fs::write(&path, contents)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Directories holding content-hashed files, as (filesystem path, URL path):
+/// the image cache, the stylesheet's directory and fingerprinted assets.
+fn asset_dirs(config: &Config) -> Vec<(PathBuf, String)> {
+    let mut dirs = vec![PathBuf::from(&config.images.cache_dir)];
+    let css = config.html.css_href.trim();
+    if !css.is_empty() && !css.contains("://") && !css.starts_with("//") {
+        let css = Path::new(css.trim_start_matches('/'));
+        dirs.push(css.parent().map(Path::to_path_buf).unwrap_or_default());
+    }
+    let assets = &config.assets;
+    if let Some(dir) = assets.out_dir.as_ref().or(assets.dir.as_ref()) {
+        if assets.fingerprint {
+            dirs.push(PathBuf::from(dir.trim_start_matches('/')));
+        }
+    }
+    // A stylesheet at the top level leaves no directory to scope the rules to
+    let mut asset_dirs: Vec<(PathBuf, String)> = dirs
+        .into_iter()
+        .map(|dir| {
+            let url = url_path(&dir);
+            (dir, url)
+        })
+        .filter(|(_, url)| !url.is_empty())
+        .collect();
+    asset_dirs.dedup_by(|a, b| a.1 == b.1);
+    asset_dirs
+}
+
+/// `/`-separated URL path of a relative filesystem path, with a leading `/`.
+fn url_path(path: &Path) -> String {
+    let mut url = String::new();
+    for component in path.components() {
+        if let Component::Normal(segment) = component {
+            url.push('/');
+            url.push_str(&segment.to_string_lossy());
+        }
+    }
+    url
+}
+
+/// Every page (and the directory URL of each `index.html`) and every file in
+/// the asset directories, sorted by URL.
+fn literal_rules(
+    site_root: &Path,
+    pages: &[PathBuf],
+    asset_dirs: &[(PathBuf, String)],
+) -> Vec<(String, Caching)> {
+    let mut rules = Vec::new();
+    for page in pages {
+        let relative = page.strip_prefix(site_root).unwrap_or(page);
+        let url = url_path(relative);
+        if let Some(dir) = url.strip_suffix("index.html") {
+            rules.push((dir.to_string(), Caching::Page));
+        }
+        rules.push((url, Caching::Page));
+    }
+    for (dir, dir_url) in asset_dirs {
+        let mut stack = vec![dir.clone()];
+        while let Some(current) = stack.pop() {
+            let Ok(entries) = fs::read_dir(&current) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    stack.push(path);
+                    continue;
+                }
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let relative = path.strip_prefix(dir).unwrap_or(&path);
+                let url = format!("{}{}", dir_url, url_path(relative));
+                if FINGERPRINTED.is_match(&name) {
+                    rules.push((url, Caching::Immutable));
+                } else if path.starts_with(&asset_dirs[0].0) {
+                    // Cached originals keep their names, so they may be replaced
+                    rules.push((url, Caching::Asset));
+                }
+            }
+        }
+    }
+    rules.sort_by(|a, b| a.0.cmp(&b.0));
+    rules.dedup_by(|a, b| a.0 == b.0);
+    rules
+}
+
+fn cache_control(headers: &HeadersConfig, caching: Caching) -> &str {
+    match caching {
+        Caching::Page => &headers.pages,
+        Caching::Immutable => &headers.immutable,
+        Caching::Asset => &headers.assets,
+    }
+}
+
+fn netlify(headers: &HeadersConfig, rules: &[(String, Caching)]) -> String {
+    let mut out = format!("{}\n", MARKER);
+    for (url, caching) in rules {
+        out.push_str(&format!(
+            "{}\n  Cache-Control: {}\n",
+            url,
+            cache_control(headers, *caching)
+        ));
+    }
+    out
+}
+
+/// Regex for the URLs of content-hashed files in any of the asset directories.
+fn fingerprinted_urls(asset_dirs: &[(PathBuf, String)]) -> String {
+    let dirs: Vec<String> = asset_dirs
+        .iter()
+        .map(|(_, url)| regex::escape(url))
+        .collect();
+    format!("^({})/(.+/)?[^/]+{}", dirs.join("|"), FINGERPRINT_PATTERN)
+}
+
+fn apache(headers: &HeadersConfig, asset_dirs: &[(PathBuf, String)]) -> String {
+    let rule = |condition: &str, value: &str| {
+        format!(
+            "  <If \"{}\">\n    Header set Cache-Control \"{}\"\n  </If>\n",
+            condition, value
+        )
+    };
+    let mut out = format!("{}\n<IfModule mod_headers.c>\n", MARKER);
+    out.push_str(&rule("%{REQUEST_URI} =~ m#(\\.html|/)$#", &headers.pages));
+    if let Some((_, cache_url)) = asset_dirs.first() {
+        out.push_str(&rule(
+            &format!("%{{REQUEST_URI}} =~ m#^{}/#", regex::escape(cache_url)),
+            &headers.assets,
+        ));
+    }
+    // Later matches override earlier ones
+    out.push_str(&rule(
+        &format!("%{{REQUEST_URI}} =~ m#{}#", fingerprinted_urls(asset_dirs)),
+        &headers.immutable,
+    ));
+    out.push_str("</IfModule>\n");
+    out
+}
+
+fn nginx(headers: &HeadersConfig, asset_dirs: &[(PathBuf, String)]) -> String {
+    let rule = |pattern: &str, value: &str| {
+        format!(
+            "location ~ \"{}\" {{\n    add_header Cache-Control \"{}\" always;\n}}\n",
+            pattern, value
+        )
+    };
+    let mut out = format!("{}\n# Include inside the site's `server` block.\n", MARKER);
+    // nginx uses the first matching regex location
+    out.push_str(&rule(&fingerprinted_urls(asset_dirs), &headers.immutable));
+    if let Some((_, cache_url)) = asset_dirs.first() {
+        let pattern = format!("^{}/", regex::escape(cache_url));
+        out.push_str(&rule(&pattern, &headers.assets));
+    }
+    out.push_str(&rule("(\\.html|/)$", &headers.pages));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn lists_pages_and_hashed_assets() {
+        let tmp = tempdir().unwrap();
+        let site = tmp.path().join("site");
+        fs::create_dir_all(site.join("blog")).unwrap();
+        let cache = tmp.path().join("img");
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(cache.join("kite.jpg"), b"").unwrap();
+        fs::write(cache.join("kite-800-0123abcd.jpg"), b"").unwrap();
+        let mut config = Config::default();
+        config.images.cache_dir = cache.to_string_lossy().into_owned();
+        config.html.css_href = String::new();
+        config.headers.formats = vec![HeadersFormat::Netlify, HeadersFormat::Nginx];
+
+        let pages = [site.join("blog/index.html"), site.join("about.html")];
+        write(&site, &config, &pages).unwrap();
+        let netlify = fs::read_to_string(site.join("_headers")).unwrap();
+        let cache_url = url_path(&cache);
+        let expected = format!(
+            "{}\n/about.html\n  Cache-Control: {}\n/blog/\n  Cache-Control: {}\n\
+             /blog/index.html\n  Cache-Control: {}\n\
+             {}/kite-800-0123abcd.jpg\n  Cache-Control: {}\n\
+             {}/kite.jpg\n  Cache-Control: {}\n",
+            MARKER,
+            config.headers.pages,
+            config.headers.pages,
+            config.headers.pages,
+            cache_url,
+            config.headers.immutable,
+            cache_url,
+            config.headers.assets,
+        );
+        assert_eq!(netlify, expected);
+        let nginx = fs::read_to_string(site.join("cache-headers.nginx.conf")).unwrap();
+        let pattern = Regex::new(&fingerprinted_urls(&asset_dirs(&config))).unwrap();
+        assert!(pattern.is_match(&format!("{}/kite-800-0123abcd.jpg", cache_url)));
+        assert!(!pattern.is_match(&format!("{}/kite.jpg", cache_url)));
+        assert!(nginx.contains("immutable"));
+
+        // Hand-written files are left alone
+        fs::write(site.join("_headers"), "/*\n  X-Frame-Options: DENY\n").unwrap();
+        write(&site, &config, &pages).unwrap();
+        assert!(fs::read_to_string(site.join("_headers"))
+            .unwrap()
+            .starts_with("/*"));
+    }
+}
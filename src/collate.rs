@@ -0,0 +1,85 @@
+//! Light Unicode collation for generated indexes and sitemaps, so accented and
+//! non-ASCII titles sort next to their base letters instead of after `z`.
+
+use std::cmp::Ordering;
+
+/// Orders by base letters first (ignoring case and accents), then by accents,
+/// then by case, so `Émile` sorts between `Eddy` and `Ezra`.
+pub fn compare(a: &str, b: &str) -> Ordering {
+    primary_key(a)
+        .cmp(&primary_key(b))
+        .then_with(|| a.to_lowercase().cmp(&b.to_lowercase()))
+        .then_with(|| a.cmp(b))
+}
+
+fn primary_key(s: &str) -> String {
+    let mut key = String::with_capacity(s.len());
+    for ch in s.chars().flat_map(char::to_lowercase) {
+        if is_combining_mark(ch) {
+            continue;
+        }
+        match fold(ch) {
+            Some(base) => key.push_str(base),
+            None => key.push(ch),
+        }
+    }
+    key
+}
+
+fn is_combining_mark(ch: char) -> bool {
+    matches!(ch, '\u{0300}'..='\u{036f}' | '\u{1ab0}'..='\u{1aff}' | '\u{20d0}'..='\u{20ff}')
+}
+
+/// Base letters of lowercase Latin letters with diacritics and ligatures.
+fn fold(ch: char) -> Option<&'static str> {
+    let base = match ch {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' | 'ǎ' | 'ȧ' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ď' | 'đ' | 'ð' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' | 'ẽ' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' | 'ǐ' => "i",
+        'ĵ' => "j",
+        'ķ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' | 'ǒ' => "o",
+        'œ' => "oe",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' | 'ș' => "s",
+        'ß' => "ss",
+        'ţ' | 'ť' | 'ŧ' | 'ț' => "t",
+        'þ' => "th",
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' | 'ǔ' => "u",
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => return None,
+    };
+    Some(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accented_titles_sort_with_their_base_letters() {
+        let mut titles = vec!["Zebra", "Émile", "apple", "Ezra", "Eddy", "Ångström"];
+        titles.sort_by(|a, b| compare(a, b));
+        assert_eq!(
+            titles,
+            vec!["Ångström", "apple", "Eddy", "Émile", "Ezra", "Zebra"]
+        );
+    }
+
+    #[test]
+    fn ties_break_on_accents_then_case() {
+        assert_eq!(compare("resume", "résumé"), Ordering::Less);
+        assert_eq!(compare("Resume", "resume"), Ordering::Less);
+        assert_eq!(compare("Stra\u{df}e", "strasse"), Ordering::Greater);
+        assert_eq!(compare("e\u{301}te\u{301}", "ete"), Ordering::Greater);
+    }
+}
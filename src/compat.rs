@@ -0,0 +1,224 @@
+//! `dllup-rs compat`: lists the features of built pages and the stylesheet
+//! that older browsers lack, with what happens without them and how the
+//! template covers them (`compat.fallbacks`), to help decide which polyfills
+//! the template needs. It reads the HTML of the last build rather than
+//! rendering the pages again.
+
+use crate::config;
+use crate::walk;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+struct Feature {
+    /// Key in `compat.fallbacks`.
+    name: &'static str,
+    /// Browser versions that first supported it.
+    since: &'static str,
+    /// What a browser without it shows.
+    without: &'static str,
+    /// Markers of the feature in page HTML.
+    html: &'static [&'static str],
+    /// Markers of the feature in the stylesheet.
+    css: &'static [&'static str],
+}
+
+const FEATURES: &[Feature] = &[
+    Feature {
+        name: "avif",
+        since: "Chrome 85, Firefox 93, Safari 16.4",
+        without: "the next <source> format or the <img> fallback is loaded",
+        html: &["type=\"image/avif\""],
+        css: &[],
+    },
+    Feature {
+        name: "webp",
+        since: "Chrome 32, Firefox 65, Safari 14",
+        without: "the next <source> format or the <img> fallback is loaded",
+        html: &["type=\"image/webp\""],
+        css: &[],
+    },
+    Feature {
+        name: "srcset",
+        since: "Chrome 38, Firefox 38, Safari 9",
+        without: "the src image is loaded at its fallback size",
+        html: &[" srcset=\""],
+        css: &[],
+    },
+    Feature {
+        name: "lazy_loading",
+        since: "Chrome 77, Firefox 75, Safari 15.4",
+        without: "images and embeds load with the page",
+        html: &["loading=\"lazy\""],
+        css: &[],
+    },
+    Feature {
+        name: "details",
+        since: "Chrome 12, Firefox 49, Safari 6, Edge 79",
+        without: "<details> contents are always shown and the summary does not toggle",
+        html: &["<details"],
+        css: &[],
+    },
+    Feature {
+        name: "module_scripts",
+        since: "Chrome 61, Firefox 60, Safari 11",
+        without: "the script does not run, so client-side Mermaid diagrams stay as source",
+        html: &["<script type=\"module\""],
+        css: &[],
+    },
+    Feature {
+        name: "clipboard",
+        since: "Chrome 66, Firefox 63, Safari 13.1",
+        without: "the Copy button on code blocks does nothing",
+        html: &["navigator.clipboard"],
+        css: &[],
+    },
+    Feature {
+        name: "aspect_ratio",
+        since: "Chrome 88, Firefox 89, Safari 15",
+        without:
+            "boxes take their content's height, so embeds and grid thumbnails lose their shape",
+        html: &["aspect-ratio"],
+        css: &["aspect-ratio"],
+    },
+    Feature {
+        name: "css_grid",
+        since: "Chrome 57, Firefox 52, Safari 10.1",
+        without: "grid items are stacked in a single column",
+        html: &["display:grid", "display: grid"],
+        css: &["display:grid", "display: grid"],
+    },
+    Feature {
+        name: "css_variables",
+        since: "Chrome 49, Firefox 31, Safari 9.1, no Internet Explorer",
+        without: "colors and sizes given through var(--...) fall back to the browser defaults",
+        html: &["var(--"],
+        css: &["var(--"],
+    },
+];
+
+/// Entry point for `dllup-rs compat <directory> [config.toml]`.
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.is_empty() || args.len() > 2 {
+        return Err("Usage: dllup-rs compat <directory> [config.toml]".into());
+    }
+    let dir = Path::new(&args[0]);
+    let explicit_config = match args.get(1) {
+        Some(path) => Some(config::Config::load(Path::new(path))?),
+        None => None,
+    };
+    let config = crate::site_config(dir, explicit_config.as_ref())?;
+    let fallbacks = &config.compat.fallbacks;
+    for name in fallbacks.keys() {
+        if !FEATURES.iter().any(|feature| feature.name == name.as_str()) {
+            crate::diagnostics::warn(format!(
+                "[compat] unknown feature {:?} in compat.fallbacks",
+                name
+            ));
+        }
+    }
+    let reported = |feature: &&Feature| {
+        fallbacks
+            .get(feature.name)
+            .is_none_or(|fallback| fallback != "ignore")
+    };
+
+    let mut pages_using: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut unbuilt = 0;
+    for file in walk::collect_dllu_files(dir, &config.build)? {
+        let page = file.with_extension("html");
+        let Ok(html) = fs::read_to_string(&page) else {
+            unbuilt += 1;
+            continue;
+        };
+        let used: Vec<&Feature> = page_features(&html).into_iter().filter(reported).collect();
+        if used.is_empty() {
+            continue;
+        }
+        let names: Vec<&str> = used.iter().map(|feature| feature.name).collect();
+        println!(
+            "{}: {}",
+            page.strip_prefix(dir).unwrap_or(&page).display(),
+            names.join(", ")
+        );
+        for name in names {
+            *pages_using.entry(name).or_default() += 1;
+        }
+    }
+
+    let css_href = config.html.css_href.trim();
+    let mut stylesheet_features = Vec::new();
+    if !css_href.contains("://") && !css_href.starts_with("//") {
+        let path = dir.join(css_href.trim_start_matches('/'));
+        if let Ok(css) = fs::read_to_string(&path) {
+            stylesheet_features = stylesheet_features_of(&css)
+                .into_iter()
+                .filter(reported)
+                .map(|feature| feature.name)
+                .collect();
+            if !stylesheet_features.is_empty() {
+                println!("{}: {}", css_href, stylesheet_features.join(", "));
+            }
+        }
+    }
+
+    println!();
+    for feature in FEATURES.iter().filter(reported) {
+        let pages = pages_using.get(feature.name).copied().unwrap_or(0);
+        let in_stylesheet = stylesheet_features.contains(&feature.name);
+        if pages == 0 && !in_stylesheet {
+            continue;
+        }
+        let used_by = match (pages, in_stylesheet) {
+            (0, _) => "the stylesheet".to_string(),
+            (pages, false) => format!("{} page(s)", pages),
+            (pages, true) => format!("{} page(s) and the stylesheet", pages),
+        };
+        println!(
+            "{} (used by {}; since {})",
+            feature.name, used_by, feature.since
+        );
+        println!("  without it: {}", feature.without);
+        match fallbacks.get(feature.name) {
+            Some(fallback) => println!("  fallback: {}", fallback),
+            None => println!("  fallback: none configured"),
+        }
+    }
+    if unbuilt > 0 {
+        crate::diagnostics::warn(format!(
+            "[compat] {} page(s) have no HTML yet and were skipped; build the site first",
+            unbuilt
+        ));
+    }
+    Ok(())
+}
+
+fn page_features(html: &str) -> Vec<&'static Feature> {
+    FEATURES
+        .iter()
+        .filter(|feature| feature.html.iter().any(|marker| html.contains(marker)))
+        .collect()
+}
+
+fn stylesheet_features_of(css: &str) -> Vec<&'static Feature> {
+    FEATURES
+        .iter()
+        .filter(|feature| feature.css.iter().any(|marker| css.contains(marker)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_features_by_their_markup() {
+        let html = "<picture><source type=\"image/avif\" srcset=\"a.avif 480w\"><img src=\"a.jpg\" loading=\"lazy\"></picture><details><summary>EXIF data</summary></details>";
+        let names: Vec<&str> = page_features(html).iter().map(|f| f.name).collect();
+        assert_eq!(names, vec!["avif", "srcset", "lazy_loading", "details"]);
+
+        let css = ".embed { aspect-ratio: 16 / 9; color: var(--dark); }";
+        let names: Vec<&str> = stylesheet_features_of(css).iter().map(|f| f.name).collect();
+        assert_eq!(names, vec!["aspect_ratio", "css_variables"]);
+    }
+}
@@ -1,39 +1,309 @@
+use crate::ast::Crop;
+use crate::downloads::FetchPolicy;
+use crate::remote_files;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct Config {
     pub timings: bool,
+    /// Fail the build when it printed any warning, as `--strict` does.
+    pub strict: bool,
     pub root_url: Option<String>,
+    pub data_dir: String,
     pub math: MathConfig,
     pub html: HtmlConfig,
     pub images: ImagesConfig,
+    pub cards: CardsConfig,
+    pub assets: AssetsConfig,
+    pub headers: HeadersConfig,
     pub feed: FeedConfig,
+    pub newsletter: NewsletterConfig,
+    pub collections: Vec<CollectionConfig>,
+    pub lint: LintConfig,
+    pub build: BuildConfig,
+    pub fmt: FmtConfig,
+    pub compat: CompatConfig,
+    /// Commands that receive each parsed article as JSON on stdin and print
+    /// the transformed article on stdout, applied in order before rendering.
+    pub plugins: Vec<String>,
+    /// Values for `{{name}}` placeholders in page text, links and templates.
+    pub vars: BTreeMap<String, String>,
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timings: false,
+            strict: false,
+            root_url: None,
+            data_dir: "data".into(),
+            math: MathConfig::default(),
+            html: HtmlConfig::default(),
+            images: ImagesConfig::default(),
+            cards: CardsConfig::default(),
+            assets: AssetsConfig::default(),
+            headers: HeadersConfig::default(),
+            feed: FeedConfig::default(),
+            newsletter: NewsletterConfig::default(),
+            collections: Vec::new(),
+            lint: LintConfig::default(),
+            build: BuildConfig::default(),
+            fmt: FmtConfig::default(),
+            compat: CompatConfig::default(),
+            plugins: Vec::new(),
+            vars: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct MathConfig {
+    pub engine: MathEngineKind,
     pub prefer_persistent: bool,
     pub command: Option<String>,
+    /// Persistent KaTeX processes shared across pages; defaults to the CPU count.
+    pub workers: Option<usize>,
+    /// KaTeX contrib modules (e.g. `mhchem`) loaded by the persistent engine.
+    pub katex_extensions: Vec<String>,
+    /// KaTeX `macros`, e.g. `"\\R" = "\\mathbb{R}"`, passed to the persistent and command engines.
+    pub macros: BTreeMap<String, String>,
+    /// Seconds an engine may spend on one equation before it is killed and
+    /// the next engine is tried; 0 disables the limit.
+    pub timeout_secs: u64,
+}
+
+impl Default for MathConfig {
+    fn default() -> Self {
+        Self {
+            engine: MathEngineKind::default(),
+            prefer_persistent: false,
+            command: None,
+            workers: None,
+            katex_extensions: Vec::new(),
+            macros: BTreeMap::new(),
+            timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MathEngineKind {
+    /// `command` if set, else the persistent KaTeX helper, else `npx katex`.
+    #[default]
+    Auto,
+    /// Only the persistent Node.js KaTeX helper.
+    Persistent,
+    /// Only `command` (defaults to `npx katex`).
+    Command,
+    /// KaTeX run in-process by an embedded JavaScript engine (`native-math` feature).
+    Native,
+    /// `typst` (or `command`), rendering formulas written in Typst math syntax to SVG.
+    Typst,
+    /// No engine; math is emitted as escaped TeX.
+    None,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct HtmlConfig {
+    /// A path, or a URL downloaded into `remote_cache_dir` (as are `.toml` highlight themes).
     pub template_path: String,
+    /// Other page templates by name, chosen by a page's `template:` header
+    /// line or for the pages below their `dirs`.
+    pub templates: BTreeMap<String, PageTemplate>,
     pub css_href: String,
     pub blog_dir: Option<String>,
+    /// How the blog's index, post pages and feed are laid out.
+    pub blog_mode: BlogMode,
+    pub code_line_numbers: bool,
+    pub microformats: bool,
+    pub highlight_theme: String,
+    pub highlight_theme_dark: Option<String>,
+    pub copy_button: bool,
+    pub soft_wrap: SoftWrap,
+    /// What `:shortcode:` emoji names in text become.
+    pub emoji: Emoji,
+    /// Image URL for `emoji = "svg"`; `{code}` is replaced with the emoji's
+    /// code points in lowercase hex joined by `-`, as Twemoji names its files.
+    pub emoji_svg_url: String,
+    pub data_permalinks: bool,
+    /// Give top-level paragraphs `id="p-<hash of their text>"` anchors for deep links.
+    pub paragraph_ids: bool,
+    /// Render `embed` blocks as a link that loads the player only when clicked.
+    pub embed_facade: bool,
+    /// Use the plain text of a figure's caption as its alt text when it has none.
+    pub alt_from_caption: bool,
+    /// Show a figure's alt text as its caption when it has none.
+    pub caption_from_alt: bool,
+    /// Link figure images to their full-size file for click-to-zoom galleries.
+    pub lightbox: Lightbox,
+    /// How `~~~ lang mermaid` code blocks are shown.
+    pub mermaid: Mermaid,
+    /// Module URL of the Mermaid library loaded for `mermaid = "client"`.
+    pub mermaid_script: String,
+    /// Reads a `~~~ lang dot` graph on stdin and prints it as SVG; empty shows the source.
+    pub graphviz_command: String,
+    pub toc_json: TocJson,
+    /// Show each post's first figure as a thumbnail on blog and collection indexes.
+    pub index_thumbnails: bool,
+    /// Show each post's reading time on blog and collection indexes.
+    pub index_reading_time: bool,
+    /// Reading speed behind `{{readingtime}}` and `index_reading_time`.
+    pub words_per_minute: usize,
+    /// Posts sharing `x-tags` listed by `{{related}}` on collection posts; 0 lists none.
+    pub related_posts: usize,
+    /// Write a page per `x-tags` tag, and a list of them, beside each collection index.
+    pub tag_pages: bool,
+    /// Split date-sorted indexes into per-year sections.
+    pub index_group_by_year: bool,
+    /// With `index_group_by_year`, years after the newest N are collapsed.
+    pub index_open_years: Option<usize>,
+    /// Date posts without a header date by their first figure's EXIF `DateTimeOriginal`.
+    pub index_date_from_exif: bool,
+    /// Where templates and highlight themes given by URL are downloaded to.
+    pub remote_cache_dir: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PageTemplate {
+    /// A path or URL, as `html.template_path`.
+    pub path: String,
+    /// Directories, relative to the site root, whose pages use the template
+    /// unless a page names another.
+    #[serde(default)]
+    pub dirs: Vec<String>,
+}
+
+/// How a line break inside a paragraph is rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SoftWrap {
+    /// Keep the break, which browsers show as a space.
+    #[default]
+    Space,
+    /// Drop the break when it falls between two Chinese or Japanese characters.
+    Cjk,
+}
+
+/// What `html.emoji` turns `:shortcode:` names into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Emoji {
+    /// Leave them as written, colons and all.
+    #[default]
+    Off,
+    /// The Unicode emoji.
+    Unicode,
+    /// An `<img>` from `emoji_svg_url`, so emoji look the same everywhere.
+    Svg,
+}
+
+/// How `images.svg_cleanup` rewrites published SVGs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SvgCleanup {
+    /// Publish SVGs byte for byte.
+    #[default]
+    Off,
+    /// Drop editor metadata, comments, unused ids and whitespace between elements.
+    Minify,
+    /// Minify and also drop scripts, event handlers and `javascript:` URLs.
+    Sanitize,
+}
+
+/// How the posts of the blog or a collection are presented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BlogMode {
+    /// A list of titles, with the full posts in the feed.
+    #[default]
+    Posts,
+    /// A photo stream: a thumbnail grid, open EXIF panels, newer/older links
+    /// between posts and feed items led by each post's first photo.
+    Photos,
+}
+
+/// What `html.lightbox` adds to figures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Lightbox {
+    #[default]
+    None,
+    /// `<a data-lightbox>` links around figure images, for a lightbox library.
+    Markup,
+    /// The links plus a small built-in viewer in `{{scripts}}`.
+    Script,
+}
+
+/// How `html.mermaid` shows Mermaid diagrams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Mermaid {
+    /// An ordinary code block.
+    Off,
+    /// `<pre class="mermaid">` drawn in the browser by `mermaid_script`.
+    #[default]
+    Client,
+    /// An inline SVG drawn at build time by `mmdc`, or the client version
+    /// when that fails.
+    Server,
+}
+
+/// Where the headings of a page are published as JSON for client-side scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TocJson {
+    #[default]
+    None,
+    /// A `<script type="application/json" id="toc-data">` in `{{scripts}}`.
+    Embed,
+    /// A `<page>.toc.json` file next to the page's HTML.
+    Sidecar,
 }
 
 impl Default for HtmlConfig {
     fn default() -> Self {
         Self {
             template_path: "static/template.html".into(),
+            templates: BTreeMap::new(),
             css_href: "static/styles.css".into(),
             blog_dir: Some("blog".into()),
+            blog_mode: BlogMode::Posts,
+            code_line_numbers: false,
+            microformats: false,
+            highlight_theme: "onedarker".into(),
+            highlight_theme_dark: None,
+            copy_button: false,
+            soft_wrap: SoftWrap::Space,
+            emoji: Emoji::Off,
+            emoji_svg_url:
+                "https://cdn.jsdelivr.net/gh/jdecked/twemoji@15.1.0/assets/svg/{code}.svg".into(),
+            data_permalinks: false,
+            paragraph_ids: false,
+            embed_facade: false,
+            alt_from_caption: false,
+            caption_from_alt: false,
+            lightbox: Lightbox::None,
+            mermaid: Mermaid::Client,
+            mermaid_script: "https://cdn.jsdelivr.net/npm/mermaid@11/dist/mermaid.esm.min.mjs"
+                .into(),
+            graphviz_command: "dot -Tsvg".into(),
+            toc_json: TocJson::None,
+            index_thumbnails: false,
+            index_reading_time: false,
+            words_per_minute: 200,
+            related_posts: 0,
+            tag_pages: false,
+            index_group_by_year: false,
+            index_open_years: None,
+            index_date_from_exif: false,
+            remote_cache_dir: ".dllup-remote".into(),
         }
     }
 }
@@ -46,10 +316,150 @@ pub struct ImagesConfig {
     pub img_root_url: Option<String>,
     pub sizes: Vec<u32>,
     pub display_sizes: Vec<u32>,
+    /// Pixel densities (1x, 2x, ...) of each figure's display width that get their
+    /// own variant and srcset entry, so high-DPI screens load a sharp one.
+    pub densities: Vec<u32>,
+    /// Extra encodings of every resized raster (`avif`, `webp`, `jpeg`, `png`), offered
+    /// in this order as `<picture>` sources ahead of the original format.
+    pub formats: Vec<String>,
+    /// Show a tiny blurred copy of each raster image while the full one loads.
+    pub placeholders: bool,
+    /// Paint each raster image's average color behind it while it loads.
+    pub dominant_color: bool,
+    /// Re-encode generated PNG/JPEG variants with oxipng/mozjpeg (`optimize` feature).
+    pub optimize: bool,
+    /// EXIF tag groups left out of the metadata copied into generated variants.
+    pub strip_exif: Vec<ExifStrip>,
+    /// Convert GIFs to looping WebM/MP4 with ffmpeg, keeping the GIF as a fallback.
+    pub gif_to_video: bool,
+    /// Give figure images `loading="lazy" decoding="async"`.
+    pub lazy_loading: bool,
+    /// Figures at the top of each page that load eagerly despite `lazy_loading`.
+    pub eager_figures: usize,
+    /// Upper bound on images resized at once, whatever `build.image_jobs` says.
+    pub max_concurrent_resizes: Option<usize>,
+    /// Megabytes of source images resized per build; images past it link their
+    /// originals until a later build gets to them.
+    pub resize_budget_mb: Option<u64>,
+    /// SVG figures up to this many bytes are put in the page as sanitized
+    /// markup instead of being linked.
+    pub inline_svg_max_bytes: Option<u64>,
+    /// Rewrite SVGs before they are copied to the cache.
+    pub svg_cleanup: SvgCleanup,
     pub meta_size: Option<u32>,
+    /// Aspect ratio and focal point (`"16:9@center"`) of the cropped thumbnails
+    /// shown on indexes; a figure's `crop=` hint overrides either half.
+    pub index_crop: Option<String>,
+    /// The same for the cropped social card image (`og:image`).
+    pub meta_crop: Option<String>,
     pub jpeg_quality: u8,
     pub layout_width: u32,
     pub remote_fetch_timeout_secs: u64,
+    /// Further attempts at a remote image after a timeout, dropped connection or
+    /// 408/429/5xx response.
+    pub remote_fetch_retries: u32,
+    /// Wait before the first retry, doubled before each one after it.
+    pub remote_fetch_backoff_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExifStrip {
+    /// Location, altitude and the rest of the GPS block.
+    Gps,
+    /// Camera body and lens serial numbers, and the camera owner's name.
+    Serial,
+    /// Every group above.
+    Privacy,
+}
+
+impl ImagesConfig {
+    pub fn strips_exif(&self, group: ExifStrip) -> bool {
+        self.strip_exif
+            .iter()
+            .any(|&strip| strip == group || strip == ExifStrip::Privacy)
+    }
+
+    /// Crop of index thumbnails cut from a figure with the given `crop=` hint;
+    /// `None` when neither sets an aspect ratio.
+    pub fn index_thumbnail_crop(&self, figure: Option<Crop>) -> Option<Crop> {
+        merge_crop(figure, self.index_crop.as_deref())
+    }
+
+    /// Crop of the social card image cut from a figure, like [`Self::index_thumbnail_crop`].
+    pub fn meta_image_crop(&self, figure: Option<Crop>) -> Option<Crop> {
+        merge_crop(figure, self.meta_crop.as_deref())
+    }
+}
+
+fn merge_crop(figure: Option<Crop>, configured: Option<&str>) -> Option<Crop> {
+    let crop = figure
+        .unwrap_or_default()
+        .or(configured.and_then(Crop::parse));
+    crop.aspect.is_some().then_some(crop)
+}
+
+/// Generated `og:image` cards for pages without a figure fit for one.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CardsConfig {
+    pub enabled: bool,
+    /// TrueType or OpenType font for the title and site name; required when enabled.
+    pub font: Option<String>,
+    /// Image cropped to fill the card behind the text, instead of `background_color`.
+    pub background: Option<String>,
+    pub background_color: String,
+    pub text_color: String,
+    /// Shown along the bottom of title cards; defaults to `feed.channel_title`.
+    pub site_name: Option<String>,
+}
+
+impl Default for CardsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            font: None,
+            background: None,
+            background_color: "#1d1f21".into(),
+            text_color: "#ffffff".into(),
+            site_name: None,
+        }
+    }
+}
+
+/// `Cache-Control` rules written for the host after a directory build.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HeadersConfig {
+    pub formats: Vec<HeadersFormat>,
+    /// Pages and their directory URLs.
+    pub pages: String,
+    /// Files named with a content hash.
+    pub immutable: String,
+    /// Other files in the image cache, such as cached originals.
+    pub assets: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadersFormat {
+    /// `_headers`, read by Netlify and Cloudflare Pages.
+    Netlify,
+    /// `.htaccess`, for Apache with `mod_headers`.
+    Apache,
+    /// `cache-headers.nginx.conf`, to include in an nginx `server` block.
+    Nginx,
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        Self {
+            formats: Vec::new(),
+            pages: "public, max-age=0, must-revalidate".into(),
+            immutable: "public, max-age=31536000, immutable".into(),
+            assets: "public, max-age=86400".into(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -62,6 +472,193 @@ pub struct FeedConfig {
     pub description: Option<String>,
     pub link: Option<String>,
     pub limit: Option<usize>,
+    /// How equations appear in `content:encoded`.
+    pub math: FeedMath,
+    /// Image URL for `math = "image"`; `{tex}` is replaced with the percent-encoded TeX.
+    pub math_image_url: String,
+}
+
+/// Form of the equations in feed content; KaTeX HTML needs the site's stylesheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedMath {
+    /// The HTML of the page, as the math engine rendered it.
+    #[default]
+    Html,
+    /// The TeX source.
+    Tex,
+    /// The MathML that KaTeX renders alongside its HTML.
+    Mathml,
+    /// Images from `math_image_url`.
+    Image,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CollectionConfig {
+    pub dir: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub sort: CollectionSort,
+    pub mode: BlogMode,
+    pub template_path: Option<String>,
+    pub feed: bool,
+    pub feed_path: String,
+    pub feed_limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CollectionSort {
+    /// Newest first; entries without a parseable date go last.
+    Date,
+    /// Alphabetical by title.
+    Title,
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        Self {
+            dir: String::new(),
+            title: None,
+            description: None,
+            sort: CollectionSort::Date,
+            mode: BlogMode::Posts,
+            template_path: None,
+            feed: true,
+            feed_path: "rss.xml".into(),
+            feed_limit: None,
+        }
+    }
+}
+
+impl CollectionConfig {
+    /// Feed settings for this collection, in the shape used for the blog feed,
+    /// with the site feed's math settings.
+    pub fn feed_config(&self, site_feed: &FeedConfig) -> FeedConfig {
+        FeedConfig {
+            enabled: self.feed,
+            output_path: self.feed_path.clone(),
+            channel_title: self.title.clone(),
+            title: None,
+            description: self.description.clone(),
+            link: None,
+            limit: self.feed_limit,
+            math: site_feed.math,
+            math_image_url: site_feed.math_image_url.clone(),
+        }
+    }
+}
+
+/// Settings for directory builds (not used by `newsletter`/`pandoc`).
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct BuildConfig {
+    /// Run from the site root before any page is rendered; a failure aborts the build.
+    pub pre_commands: Vec<String>,
+    /// Run once pages and image variants are written; a failure fails the build.
+    pub post_commands: Vec<String>,
+    /// Skip pages whose recorded dependencies are unchanged since the last build.
+    pub incremental: bool,
+    /// Keep rendered blocks in `images.cache_dir` so a changed page only renders
+    /// the blocks that changed.
+    pub fragment_cache: bool,
+    /// Threads rendering pages (`--jobs` overrides); one per CPU when unset.
+    pub jobs: Option<usize>,
+    /// Threads resizing images in the background; defaults to `jobs`.
+    pub image_jobs: Option<usize>,
+    /// Remote images downloaded at once across all pages; 4 when unset.
+    pub fetch_jobs: Option<usize>,
+    /// Gitignore-style patterns, relative to the site root, of paths that hold no pages.
+    pub ignore: Vec<String>,
+    /// Look for pages in directories whose names start with `.`.
+    pub include_hidden: bool,
+    /// Directory levels below the site root searched for pages; 32 when unset.
+    pub max_depth: Option<usize>,
+    /// Files and directories examined while looking for pages; 200000 when unset.
+    pub max_files: Option<usize>,
+}
+
+/// Static files published by directory builds.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct AssetsConfig {
+    /// Stylesheets, scripts, fonts and other files of the site; off when unset.
+    pub dir: Option<String>,
+    /// Where they are copied to, a path that is also their URL path; they are
+    /// served from `dir` when unset.
+    pub out_dir: Option<String>,
+    /// Also write CSS and JS files as `name-<hash>.ext` and point the template's
+    /// references at those copies.
+    pub fingerprint: bool,
+}
+
+/// Settings for `dllup-rs fmt`.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct FmtConfig {
+    /// Column paragraphs, list items and quotes are wrapped at; 0 keeps each on one line.
+    pub width: usize,
+}
+
+/// Settings for `dllup-rs compat`.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct CompatConfig {
+    /// How the template covers a feature for older browsers, by feature name;
+    /// `"ignore"` leaves the feature out of the report.
+    pub fallbacks: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct LintConfig {
+    /// Figures whose alt text is empty or a placeholder such as "image" or the file name.
+    pub alt_text: LintLevel,
+    /// `(#name)` and `\eqref{name}` references with nothing to point at.
+    pub references: LintLevel,
+    /// Relative and site-rooted links to files that don't exist.
+    pub links: LintLevel,
+    /// Links whose `#fragment` matches no element of the built page they lead to.
+    pub fragments: LintLevel,
+    /// Collection posts left out of their index for a missing title or date.
+    pub skipped_posts: LintLevel,
+    /// Command `dllup-rs lint --prose` pipes page text to, which prints the
+    /// words it doesn't know one per line; `hunspell -l` when unset.
+    pub prose_command: Option<String>,
+    /// Hunspell dictionary of the default prose command; `en_US` when unset.
+    pub prose_dictionary: Option<String>,
+    /// Words the prose check accepts, such as names, in any case.
+    pub prose_words: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LintLevel {
+    Off,
+    /// Print a warning and keep building.
+    #[default]
+    Warn,
+    /// Print the findings and fail the build.
+    Error,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NewsletterConfig {
+    pub image_width: u32,
+    /// Image URL for equations, with `{tex}` replaced by the percent-encoded TeX;
+    /// unset leaves them as TeX.
+    pub math_image_url: Option<String>,
+}
+
+impl Default for NewsletterConfig {
+    fn default() -> Self {
+        Self {
+            image_width: 600,
+            math_image_url: None,
+        }
+    }
 }
 
 impl Default for FeedConfig {
@@ -74,6 +671,8 @@ impl Default for FeedConfig {
             description: None,
             link: None,
             limit: None,
+            math: FeedMath::Html,
+            math_image_url: "https://latex.codecogs.com/svg.image?{tex}".into(),
         }
     }
 }
@@ -86,10 +685,27 @@ impl Default for ImagesConfig {
             img_root_url: None,
             sizes: vec![480, 800, 1200],
             display_sizes: Vec::new(),
+            densities: vec![1, 2],
+            formats: Vec::new(),
+            placeholders: false,
+            dominant_color: false,
+            optimize: false,
+            strip_exif: Vec::new(),
+            gif_to_video: false,
+            lazy_loading: true,
+            eager_figures: 0,
+            max_concurrent_resizes: None,
+            resize_budget_mb: None,
+            inline_svg_max_bytes: None,
+            svg_cleanup: SvgCleanup::Off,
             meta_size: None,
+            index_crop: None,
+            meta_crop: None,
             jpeg_quality: 85,
             layout_width: 1200,
             remote_fetch_timeout_secs: 10,
+            remote_fetch_retries: 3,
+            remote_fetch_backoff_ms: 500,
         }
     }
 }
@@ -101,9 +717,171 @@ impl Config {
         let mut config: Config = toml::from_str(&contents)
             .map_err(|e| format!("failed to parse config {}: {}", display(path), e))?;
         config.normalize();
+        config
+            .check_urls()
+            .map_err(|e| format!("{} (in {})", e, display(path)))?;
+        config
+            .localize_remote_files()
+            .map_err(|e| format!("{} (in {})", e, display(path)))?;
+        for crop in [&config.images.index_crop, &config.images.meta_crop]
+            .into_iter()
+            .flatten()
+        {
+            if Crop::parse(crop).is_none() {
+                return Err(format!(
+                    "invalid crop {:?} in {}: expected e.g. \"16:9@center\" or \"1:1@0.3,0.5\"",
+                    crop,
+                    display(path)
+                ));
+            }
+        }
+        let cards = &config.cards;
+        for (key, color) in [
+            ("background_color", &cards.background_color),
+            ("text_color", &cards.text_color),
+        ] {
+            if crate::image_processor::parse_hex_color(color).is_none() {
+                return Err(format!(
+                    "invalid cards.{} {:?} in {}: expected e.g. \"#1d1f21\"",
+                    key,
+                    color,
+                    display(path)
+                ));
+            }
+        }
+        if let Some(name) = config.vars.keys().find(|name| !valid_var_name(name)) {
+            return Err(format!(
+                "invalid vars.{} in {}: names use letters, digits, `-` and `_`, and cannot start with `x-`, be `data` or be a template placeholder such as `title`",
+                name,
+                display(path)
+            ));
+        }
+        if cards.enabled && cards.font.is_none() {
+            return Err(format!(
+                "cards.enabled needs cards.font, the font for card titles (in {})",
+                display(path)
+            ));
+        }
         Ok(config)
     }
 
+    /// Checks `root_url`, `images.img_root_url` and `feed.link` before they are
+    /// spread over permalinks, feeds, the sitemap and meta tags.
+    fn check_urls(&self) -> Result<(), String> {
+        if let Some(root) = &self.root_url {
+            check_site_url("root_url", root)?;
+        }
+        if let Some(img_root) = &self.images.img_root_url {
+            check_site_url("images.img_root_url", img_root)?;
+        }
+        if let Some(link) = &self.feed.link {
+            if link != "/" && url_origin(link).is_none() {
+                return Err(format!(
+                    "invalid feed.link {:?}: expected an absolute URL such as \"https://example.com/blog\" or \"/\"",
+                    link
+                ));
+            }
+        }
+
+        let Some((scheme, host)) = self.root_url.as_deref().and_then(url_origin) else {
+            return Ok(());
+        };
+        if let Some(img_root) = &self.images.img_root_url {
+            let img_scheme = url_origin(img_root).map(|(scheme, _)| scheme);
+            if scheme == "https" && img_scheme.as_deref() == Some("http") {
+                return Err(format!(
+                    "images.img_root_url {:?} is http while root_url is https; browsers block or flag the images as mixed content",
+                    img_root
+                ));
+            }
+        }
+        if let Some(link) = self.feed.link.as_deref().filter(|link| *link != "/") {
+            if url_origin(link) != Some((scheme.clone(), host.clone())) {
+                return Err(format!(
+                    "feed.link {:?} is not on the site at {}://{}; feed readers resolve item links against it",
+                    link, scheme, host
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Points templates and highlight themes given by URL at local copies.
+    fn localize_remote_files(&mut self) -> Result<(), String> {
+        let cache_dir = PathBuf::from(&self.html.remote_cache_dir);
+        let policy = FetchPolicy::new(&self.images);
+        let localize = |reference: &mut String| -> Result<(), String> {
+            *reference = remote_files::localize(reference, &cache_dir, policy)?;
+            Ok(())
+        };
+        localize(&mut self.html.template_path)?;
+        localize(&mut self.html.highlight_theme)?;
+        if let Some(theme) = &mut self.html.highlight_theme_dark {
+            localize(theme)?;
+        }
+        for template in self.html.templates.values_mut() {
+            localize(&mut template.path)?;
+        }
+        for collection in &mut self.collections {
+            if let Some(template_path) = &mut collection.template_path {
+                localize(template_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The template of a page: the `html.templates` entry its `template:`
+    /// header line names, else the one with the deepest of `dirs` holding
+    /// `page_dir` (relative to the site root), else `html.template_path`.
+    pub fn page_template(&self, name: Option<&str>, page_dir: &Path) -> Result<&str, String> {
+        if let Some(name) = name {
+            return match self.html.templates.get(name) {
+                Some(template) => Ok(&template.path),
+                None => Err(format!(
+                    "unknown template `{}`; html.templates has {}",
+                    name,
+                    if self.html.templates.is_empty() {
+                        "none".to_string()
+                    } else {
+                        self.html
+                            .templates
+                            .keys()
+                            .map(|name| format!("`{}`", name))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    }
+                )),
+            };
+        }
+        let by_dir = self
+            .html
+            .templates
+            .values()
+            .flat_map(|template| {
+                template
+                    .dirs
+                    .iter()
+                    .map(move |dir| (Path::new(dir), template))
+            })
+            .filter(|(dir, _)| page_dir.starts_with(dir))
+            .max_by_key(|(dir, _)| dir.components().count());
+        Ok(match by_dir {
+            Some((_, template)) => &template.path,
+            None => &self.html.template_path,
+        })
+    }
+
+    /// Replaces `root_url` (`--base-url`), trimmed like a configured one; an
+    /// empty URL leaves links root-relative.
+    pub fn set_root_url(&mut self, url: &str) {
+        let url = url.trim();
+        self.root_url = match url {
+            "" => None,
+            "/" => Some(url.into()),
+            _ => Some(url.trim_end_matches('/').into()),
+        };
+    }
+
     fn normalize(&mut self) {
         if let Some(root) = &mut self.root_url {
             if root != "/" {
@@ -121,8 +899,38 @@ impl Config {
                 }
             }
         }
+        if self.data_dir.trim().is_empty() {
+            self.data_dir = "data".into();
+        }
+        let theme = self.html.highlight_theme.trim();
+        self.html.highlight_theme = if theme.is_empty() {
+            "onedarker".into()
+        } else {
+            theme.to_string()
+        };
+        self.html.highlight_theme_dark = self.html.highlight_theme_dark.as_ref().and_then(|t| {
+            let trimmed = t.trim();
+            if trimmed.is_empty() {
+                None
+            } else {
+                Some(trimmed.to_string())
+            }
+        });
         self.feed.normalize();
         self.images.normalize();
+        for collection in &mut self.collections {
+            collection.dir = collection.dir.trim().trim_matches('/').to_string();
+            let mut feed = collection.feed_config(&self.feed);
+            feed.normalize();
+            collection.feed_path = feed.output_path;
+            collection.title = feed.channel_title;
+            collection.description = feed.description;
+            collection.feed_limit = feed.limit;
+        }
+        self.collections.retain(|c| !c.dir.is_empty());
+        if self.newsletter.image_width == 0 {
+            self.newsletter.image_width = 600;
+        }
     }
 }
 
@@ -149,6 +957,9 @@ impl ImagesConfig {
                 self.display_sizes = self.sizes.clone();
             }
         }
+        self.densities.retain(|v| *v > 0);
+        self.densities.sort_unstable();
+        self.densities.dedup();
         if self.layout_width == 0 {
             self.layout_width = 1200;
         }
@@ -161,6 +972,8 @@ impl ImagesConfig {
                 None
             }
         });
+        self.max_concurrent_resizes = self.max_concurrent_resizes.filter(|&max| max > 0);
+        self.resize_budget_mb = self.resize_budget_mb.filter(|&mb| mb > 0);
         self.jpeg_quality = self.jpeg_quality.clamp(10, 100);
         if self.remote_fetch_timeout_secs == 0 {
             self.remote_fetch_timeout_secs = 10;
@@ -231,6 +1044,78 @@ impl FeedConfig {
     }
 }
 
+/// Whether `name` can be a `[vars]` key: `x-` names are header variables,
+/// `data` holds data files and the template's own placeholders come first.
+fn valid_var_name(name: &str) -> bool {
+    const TEMPLATE_PLACEHOLDERS: &[&str] = &[
+        "body",
+        "css",
+        "data",
+        "date",
+        "featured",
+        "images",
+        "index",
+        "meta",
+        "metas",
+        "posts",
+        "preload",
+        "readingtime",
+        "related",
+        "scripts",
+        "tableofcontents",
+        "tags",
+        "this",
+        "title",
+        "wordcount",
+    ];
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !name.to_ascii_lowercase().starts_with("x-")
+        && !TEMPLATE_PLACEHOLDERS.contains(&name)
+}
+
+/// Checks that `url`, set as `key`, is `/` or an absolute http(s) or file URL
+/// that paths can be appended to.
+pub fn check_site_url(key: &str, url: &str) -> Result<(), String> {
+    if url == "/" {
+        return Ok(());
+    }
+    if url_origin(url).is_none() {
+        return Err(format!(
+            "invalid {} {:?}: expected an absolute URL such as \"https://example.com\" or \"/\"",
+            key, url
+        ));
+    }
+    if url.contains(['?', '#']) {
+        return Err(format!(
+            "invalid {} {:?}: paths are appended to it, so it cannot have a query or fragment",
+            key, url
+        ));
+    }
+    Ok(())
+}
+
+/// The lowercased scheme and host (with any port) of an absolute http(s) URL,
+/// or of a file URL, whose host is usually empty as in `file:///home/site`.
+pub fn url_origin(url: &str) -> Option<(String, String)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let scheme = scheme.to_ascii_lowercase();
+    if scheme != "http" && scheme != "https" && scheme != "file" {
+        return None;
+    }
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let valid = |c: char| c.is_ascii_alphanumeric() || "-.:[]".contains(c);
+    if (host.is_empty() && scheme != "file") || host.starts_with(':') || !host.chars().all(valid) {
+        return None;
+    }
+    if url.chars().any(char::is_whitespace) {
+        return None;
+    }
+    Some((scheme, host.to_ascii_lowercase()))
+}
+
 fn display(path: &Path) -> String {
     path.to_string_lossy().into_owned()
 }
@@ -239,3 +1124,85 @@ pub fn default_config_path(input_path: &Path) -> PathBuf {
     let dir = input_path.parent().unwrap_or_else(|| Path::new("."));
     dir.join("dllup.toml")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_site_urls() {
+        assert!(check_site_url("root_url", "https://example.com/blog").is_ok());
+        assert!(check_site_url("root_url", "/").is_ok());
+        assert!(check_site_url("root_url", "example.com").is_err());
+        assert!(check_site_url("root_url", "https://example.com/?ref=feed").is_err());
+        assert!(check_site_url("root_url", "ftp://example.com").is_err());
+        assert!(check_site_url("root_url", "https:///blog").is_err());
+        // Local previews, as in the example dllup.toml
+        assert!(check_site_url("root_url", "file:///home/dllu/proj/dllup-rs").is_ok());
+        assert!(check_site_url("root_url", "file://localhost/srv/site").is_ok());
+        assert_eq!(
+            url_origin("file:///home/dllu/proj/dllup-rs"),
+            Some(("file".into(), String::new()))
+        );
+
+        let mut config = Config {
+            root_url: Some("https://example.com".into()),
+            ..Config::default()
+        };
+        config.feed.link = Some("https://EXAMPLE.com/blog".into());
+        assert!(config.check_urls().is_ok());
+        config.feed.link = Some("https://www.example.com/blog".into());
+        assert!(config.check_urls().is_err());
+        config.feed.link = None;
+        config.images.img_root_url = Some("http://cdn.example.net".into());
+        assert!(config.check_urls().is_err());
+    }
+
+    #[test]
+    fn base_url_replaces_root_url_like_a_configured_one() {
+        let mut config = Config {
+            root_url: Some("https://example.com/".into()),
+            ..Config::default()
+        };
+        config.normalize();
+        assert_eq!(config.root_url.as_deref(), Some("https://example.com"));
+
+        config.set_root_url("  https://example.com/previews/main//  ");
+        assert_eq!(
+            config.root_url.as_deref(),
+            Some("https://example.com/previews/main")
+        );
+        // `/` stays a root, while an empty URL leaves links root-relative
+        config.set_root_url(" / ");
+        assert_eq!(config.root_url.as_deref(), Some("/"));
+        config.set_root_url("");
+        assert_eq!(config.root_url, None);
+        config.set_root_url("   ");
+        assert_eq!(config.root_url, None);
+    }
+
+    #[test]
+    fn pages_use_the_template_they_name_or_their_directory_has() {
+        let config: Config = toml::from_str(
+            r#"
+            [html.templates.wide]
+            path = "static/wide.html"
+            dirs = ["photos"]
+            [html.templates.album]
+            path = "static/album.html"
+            dirs = ["photos/albums/"]
+            "#,
+        )
+        .unwrap();
+        let template = |name, dir| config.page_template(name, Path::new(dir));
+        assert_eq!(template(None, ""), Ok("static/template.html"));
+        assert_eq!(template(None, "photos/kites"), Ok("static/wide.html"));
+        assert_eq!(
+            template(None, "photos/albums/2024"),
+            Ok("static/album.html")
+        );
+        assert_eq!(template(None, "photoshop"), Ok("static/template.html"));
+        assert_eq!(template(Some("wide"), "blog"), Ok("static/wide.html"));
+        assert!(template(Some("narrow"), "blog").is_err());
+    }
+}
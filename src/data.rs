@@ -0,0 +1,161 @@
+use crate::config;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    static ref DATA_CACHE: Mutex<HashMap<PathBuf, Arc<Value>>> = Mutex::new(HashMap::new());
+}
+
+/// Loads every `*.toml` / `*.json` / `*.yaml` file in the configured data directory into
+/// one object keyed by file stem, e.g. `data/talks.toml` becomes `talks`.
+/// The directory is resolved against the site root (or the page's directory
+/// when rendering a single file) and is read once per build.
+pub fn load_site_data(
+    input_path: &Path,
+    site_root: Option<&Path>,
+    config: &config::Config,
+) -> Result<Arc<Value>, String> {
+    let dir = data_dir(input_path, site_root, config);
+
+    let mut cache = DATA_CACHE.lock().expect("data cache mutex poisoned");
+    if let Some(data) = cache.get(&dir) {
+        return Ok(Arc::clone(data));
+    }
+    let data = Arc::new(read_data_dir(&dir)?);
+    cache.insert(dir, Arc::clone(&data));
+    Ok(data)
+}
+
+/// The data directory [`load_site_data`] reads for `input_path`.
+pub fn data_dir(input_path: &Path, site_root: Option<&Path>, config: &config::Config) -> PathBuf {
+    site_root
+        .or_else(|| input_path.parent())
+        .unwrap_or_else(|| Path::new("."))
+        .join(&config.data_dir)
+}
+
+fn read_data_dir(dir: &Path) -> Result<Value, String> {
+    let mut out = Map::new();
+    if !dir.is_dir() {
+        return Ok(Value::Object(out));
+    }
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read data directory {}: {}", dir.display(), e))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        let stem = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) => stem.to_string(),
+            None => continue,
+        };
+        let contents = || {
+            fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read data file {}: {}", path.display(), e))
+        };
+        let value: Value = match ext.as_deref() {
+            Some("toml") => toml::from_str(&contents()?)
+                .map_err(|e| format!("Failed to parse data file {}: {}", path.display(), e))?,
+            Some("json") => serde_json::from_str(&contents()?)
+                .map_err(|e| format!("Failed to parse data file {}: {}", path.display(), e))?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&contents()?)
+                .map_err(|e| format!("Failed to parse data file {}: {}", path.display(), e))?,
+            _ => continue,
+        };
+        if out.insert(stem, value).is_some() {
+            return Err(format!(
+                "Data file {} clashes with another file of the same name",
+                path.display()
+            ));
+        }
+    }
+    Ok(Value::Object(out))
+}
+
+/// Looks up a dotted path such as `talks.0.title`; numeric segments index
+/// arrays. Only scalar values have a textual form.
+pub fn lookup(data: &Value, path: &str) -> Option<String> {
+    value(data, path).and_then(text)
+}
+
+/// The value at a dotted path, as [`lookup`] but also for tables and arrays.
+pub fn value<'a>(data: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut current = data;
+    for segment in path.split('.') {
+        current = match current {
+            Value::Object(map) => map.get(segment)?,
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+/// The textual form of a string, number or boolean.
+pub fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_toml_and_json_files_by_stem() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("talks.toml"),
+            "[[talk]]\ntitle = \"Lidar\"\nyear = 2019\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("site.json"), "{\"owner\": \"dllu\"}").unwrap();
+
+        let data = read_data_dir(dir.path()).unwrap();
+        assert_eq!(
+            lookup(&data, "talks.talk.0.title").as_deref(),
+            Some("Lidar")
+        );
+        assert_eq!(lookup(&data, "talks.talk.0.year").as_deref(), Some("2019"));
+        assert_eq!(lookup(&data, "site.owner").as_deref(), Some("dllu"));
+        assert_eq!(lookup(&data, "talks.talk"), None);
+        assert_eq!(lookup(&data, "missing.key"), None);
+    }
+
+    #[test]
+    fn reads_yaml_files_by_stem() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("papers.yaml"),
+            "- title: Lidar SLAM\n  year: 2019\n  open: true\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("links.yml"), "github: dllu\n").unwrap();
+
+        let data = read_data_dir(dir.path()).unwrap();
+        assert_eq!(
+            lookup(&data, "papers.0.title").as_deref(),
+            Some("Lidar SLAM")
+        );
+        assert_eq!(lookup(&data, "papers.0.year").as_deref(), Some("2019"));
+        assert_eq!(lookup(&data, "papers.0.open").as_deref(), Some("true"));
+        assert_eq!(lookup(&data, "links.github").as_deref(), Some("dllu"));
+
+        fs::write(dir.path().join("links.yaml"), "github: dllu\n").unwrap();
+        assert!(read_data_dir(dir.path()).unwrap_err().contains("clashes"));
+    }
+}
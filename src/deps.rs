@@ -0,0 +1,191 @@
+//! Per-page dependency graph for incremental directory builds (`build.incremental`).
+//!
+//! Each page records a fingerprint of its config and a content hash of every file
+//! it read (source, template, data directory, local figure images). The graph is
+//! saved in the site root after a build, and the next build skips pages whose
+//! recorded hashes still match, so a template edit rebuilds every page while an
+//! image tweak rebuilds only the pages showing it.
+
+use crate::ast::{Article, Block};
+use crate::config::{BuildConfig, Config, FmtConfig, HeadersConfig};
+use crate::html_renderer::PageImage;
+use crate::image_processor;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File in the site root that holds the graph between builds.
+pub const GRAPH_FILE: &str = ".dllup-deps.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DepGraph {
+    pages: BTreeMap<PathBuf, PageDeps>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageDeps {
+    config: String,
+    files: BTreeMap<PathBuf, String>,
+    /// Pages that list other pages (collection indexes) are always rebuilt.
+    always: bool,
+    /// Images the page showed, kept for the sitemap of builds that skip it.
+    #[serde(default)]
+    images: Vec<PageImage>,
+}
+
+impl DepGraph {
+    /// Reads the graph saved by the previous build; a missing or unreadable
+    /// file yields an empty graph, which rebuilds everything.
+    pub fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(path, json).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn insert(&mut self, page: PathBuf, deps: PageDeps) {
+        self.pages.insert(page, deps);
+    }
+
+    /// The recorded dependencies of `page` if none of them changed since they
+    /// were recorded under `config`.
+    pub fn unchanged(&self, page: &Path, config: &Config) -> Option<&PageDeps> {
+        let deps = self.pages.get(page)?;
+        let fresh = !deps.always
+            && deps.config == config_fingerprint(config)
+            && deps
+                .files
+                .iter()
+                .all(|(path, hash)| fingerprint(path) == *hash);
+        fresh.then_some(deps)
+    }
+}
+
+impl PageDeps {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config_fingerprint(config),
+            files: BTreeMap::new(),
+            always: false,
+            images: Vec::new(),
+        }
+    }
+
+    /// Records a file or directory; one that does not exist yet is recorded
+    /// too, so creating it later triggers a rebuild.
+    pub fn add(&mut self, path: &Path) {
+        let hash = fingerprint(path);
+        self.files.insert(path.to_path_buf(), hash);
+    }
+
+    /// Records the local images shown as figures in `article`.
+    pub fn add_images(&mut self, article: &Article, asset_root: &Path) {
+        for block in article.blocks() {
+            if let Block::ImageFigure { url, .. } = block {
+                if !image_processor::is_remote(url) {
+                    self.add(&image_processor::local_source_path(url, asset_root));
+                }
+            }
+        }
+    }
+
+    pub fn always_rebuild(&mut self) {
+        self.always = true;
+    }
+
+    pub fn set_images(&mut self, images: Vec<PageImage>) {
+        self.images = images;
+    }
+
+    pub fn images(&self) -> &[PageImage] {
+        &self.images
+    }
+}
+
+/// Hash of everything in the config that can affect a page's output.
+fn config_fingerprint(config: &Config) -> String {
+    let mut config = config.clone();
+    config.build = BuildConfig::default();
+    config.fmt = FmtConfig::default();
+    config.headers = HeadersConfig::default();
+    blake3::hash(format!("{:?}", config).as_bytes())
+        .to_hex()
+        .to_string()
+}
+
+/// Content hash of a file, or of the names and contents of a directory's files;
+/// empty when the path does not exist.
+fn fingerprint(path: &Path) -> String {
+    if path.is_dir() {
+        let Ok(entries) = fs::read_dir(path) else {
+            return String::new();
+        };
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        files.sort();
+        let mut hasher = blake3::Hasher::new();
+        for file in files {
+            hasher.update(file.as_os_str().as_encoded_bytes());
+            hasher.update(&[0]);
+            hasher.update(fingerprint(&file).as_bytes());
+        }
+        return hasher.finalize().to_hex().to_string();
+    }
+    match fs::read(path) {
+        Ok(bytes) => blake3::hash(&bytes).to_hex().to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn pages_are_stale_once_a_dependency_changes() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("index.dllu");
+        let image = dir.path().join("cat.png");
+        fs::write(&source, "Title\n").unwrap();
+        fs::write(&image, b"v1").unwrap();
+        let config = Config::default();
+
+        let mut deps = PageDeps::new(&config);
+        deps.add(&source);
+        deps.add(&image);
+        let mut graph = DepGraph::default();
+        graph.insert(source.clone(), deps);
+        let graph_path = dir.path().join(GRAPH_FILE);
+        graph.save(&graph_path).unwrap();
+        let graph = DepGraph::load(&graph_path);
+        assert!(graph.unchanged(&source, &config).is_some());
+
+        fs::write(&image, b"v2").unwrap();
+        assert!(graph.unchanged(&source, &config).is_none());
+    }
+
+    #[test]
+    fn config_changes_invalidate_pages_but_build_settings_do_not() {
+        let source = PathBuf::from("index.dllu");
+        let config = Config::default();
+        let mut graph = DepGraph::default();
+        graph.insert(source.clone(), PageDeps::new(&config));
+
+        let mut hooks = config.clone();
+        hooks.build.post_commands = vec!["true".into()];
+        assert!(graph.unchanged(&source, &hooks).is_some());
+
+        let mut moved = config.clone();
+        moved.root_url = Some("https://example.com".into());
+        assert!(graph.unchanged(&source, &moved).is_none());
+    }
+}
@@ -0,0 +1,133 @@
+//! Problems found while building a page, printed for people on stderr or as
+//! JSON lines on stdout (`--message-format json`) for editors and CI.
+
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+/// Warnings printed so far, which fail a `--strict` build.
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    pub file: String,
+    /// 1-based line of the source the problem was found on, when known.
+    pub line: Option<usize>,
+    pub severity: Severity,
+    /// Which check produced it, e.g. `broken-link`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(file: &Path, severity: Severity, code: &'static str, message: String) -> Self {
+        Self {
+            file: file.display().to_string(),
+            line: None,
+            severity,
+            code,
+            message,
+        }
+    }
+
+    /// Points the diagnostic at the first line of `source` containing `text`.
+    pub fn at(mut self, source: &str, text: &str) -> Self {
+        self.line = line_of(source, text);
+        self
+    }
+}
+
+/// Switches [`emit`] to JSON lines; set once from the command line.
+pub fn use_json_output(json: bool) {
+    JSON_OUTPUT.store(json, Ordering::SeqCst);
+}
+
+pub fn emit(diagnostic: &Diagnostic) {
+    if diagnostic.severity == Severity::Warning {
+        count_warnings(1);
+    }
+    if JSON_OUTPUT.load(Ordering::SeqCst) {
+        if let Ok(line) = serde_json::to_string(diagnostic) {
+            println!("{}", line);
+        }
+        return;
+    }
+    let severity = match diagnostic.severity {
+        Severity::Warning => "warning",
+        Severity::Error => "error",
+    };
+    match diagnostic.line {
+        Some(line) => eprintln!(
+            "{}: {}:{}: {}",
+            severity, diagnostic.file, line, diagnostic.message
+        ),
+        None => eprintln!("{}: {}: {}", severity, diagnostic.file, diagnostic.message),
+    }
+}
+
+/// Prints a warning that isn't tied to a line of a page, such as a setting
+/// this build can't honour or an equation left as TeX, and counts it like
+/// the ones passed to [`emit`].
+pub fn warn(message: impl std::fmt::Display) {
+    count_warnings(1);
+    eprintln!("{}", message);
+}
+
+fn count_warnings(count: usize) {
+    WARNINGS.fetch_add(count, Ordering::SeqCst);
+}
+
+/// Warnings of the build so far.
+pub fn warning_count() -> usize {
+    WARNINGS.load(Ordering::SeqCst)
+}
+
+/// Warns about each figure image the renderer could not process
+/// ([`HtmlRenderer::image_errors`](crate::html_renderer::HtmlRenderer::image_errors)).
+pub fn emit_image_errors(file: &Path, source: &str, errors: &[(String, String)]) {
+    for (reference, error) in errors {
+        let message = format!("image {}: {}", reference, error);
+        emit(&Diagnostic::new(file, Severity::Warning, "image", message).at(source, reference));
+    }
+}
+
+fn line_of(source: &str, text: &str) -> Option<usize> {
+    if text.is_empty() {
+        return None;
+    }
+    source
+        .lines()
+        .position(|line| line.contains(text))
+        .map(|index| index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_as_one_json_object() {
+        let diagnostic = Diagnostic::new(
+            Path::new("blog/post/index.dllu"),
+            Severity::Warning,
+            "broken-link",
+            "link to missing.html does not resolve".into(),
+        )
+        .at(
+            "Title\n\n===\n\nSee [here](missing.html).\n",
+            "missing.html",
+        );
+        assert_eq!(
+            serde_json::to_string(&diagnostic).unwrap(),
+            r#"{"file":"blog/post/index.dllu","line":5,"severity":"warning","code":"broken-link","message":"link to missing.html does not resolve"}"#
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! Rendered output kept on disk as `<hash>.<extension>` files, keyed by a
+//! hash of its input and the settings that change it. The math, fragment and
+//! Graphviz caches in `images.cache_dir` are each one of these; an entry is
+//! never rewritten with different contents, so a changed setting or input
+//! only leaves the old entry unused.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct DiskCache {
+    dir: PathBuf,
+    extension: &'static str,
+    /// Debug form of the settings that affect the output, hashed into every key.
+    settings: String,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, extension: &'static str, settings: String) -> Self {
+        Self {
+            dir,
+            extension,
+            settings,
+        }
+    }
+
+    pub fn settings(&self) -> &str {
+        &self.settings
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.settings.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(key.as_bytes());
+        self.dir.join(format!(
+            "{}.{}",
+            &hasher.finalize().to_hex()[..32],
+            self.extension
+        ))
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        fs::read_to_string(self.path(key)).ok()
+    }
+
+    /// Stores an entry; the cache is only a speedup, so failures are ignored.
+    pub fn put(&self, key: &str, contents: &str) {
+        let path = self.path(key);
+        let partial = partial_path(&path);
+        if fs::create_dir_all(&self.dir).is_ok() && fs::write(&partial, contents).is_ok() {
+            // Renamed into place so a concurrent build never reads half a file
+            if fs::rename(&partial, &path).is_err() {
+                let _ = fs::remove_file(&partial);
+            }
+        }
+    }
+}
+
+/// A name beside `path` to write it under before renaming it into place,
+/// unique to this process and write, so threads or builds writing the same
+/// file at once never mix their bytes.
+fn partial_path(path: &Path) -> PathBuf {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    let write = WRITES.fetch_add(1, Ordering::Relaxed);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}-{}.part", std::process::id(), write));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_keyed_by_settings_and_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::new(dir.path().join("math"), "html", "katex".into());
+        assert_eq!(cache.get("x^2"), None);
+        cache.put("x^2", "<span>x²</span>");
+        assert_eq!(cache.get("x^2").as_deref(), Some("<span>x²</span>"));
+        assert_eq!(cache.get("x^3"), None);
+
+        let other = DiskCache::new(dir.path().join("math"), "html", "typst".into());
+        assert_eq!(other.get("x^2"), None);
+
+        // Only the finished entry is left behind
+        let names: Vec<String> = fs::read_dir(dir.path().join("math"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names.len(), 1);
+        assert!(names[0].ends_with(".html"), "{:?}", names);
+    }
+}
@@ -0,0 +1,305 @@
+//! Downloads of remote images and theme files: at most `build.fetch_jobs` at
+//! once across every page being rendered, transient failures retried with
+//! exponential backoff, and the images that still failed listed once the build
+//! is done.
+//!
+//! Cached downloads are revalidated with the `ETag` / `Last-Modified` the origin
+//! sent, once per URL per build, so a changed origin file is picked up without
+//! downloading unchanged ones again.
+
+use crate::config::ImagesConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Concurrent downloads when `build.fetch_jobs` is unset.
+const DEFAULT_FETCH_JOBS: usize = 4;
+
+/// Downloads allowed at once; 0 means [`DEFAULT_FETCH_JOBS`].
+static FETCH_JOBS: AtomicUsize = AtomicUsize::new(0);
+
+/// `--refresh-remote`: download cached images again even without validators.
+static REFRESH: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref ACTIVE: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+    /// URL -> last error of each download that failed for good.
+    static ref FAILURES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+    /// URLs whose cached copy was already checked against the origin this build.
+    static ref CHECKED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// How a download is attempted (`images.remote_fetch_*`).
+#[derive(Debug, Clone, Copy)]
+pub struct FetchPolicy {
+    pub timeout: Duration,
+    /// Further attempts after the first fails with a transient error.
+    pub retries: u32,
+    /// Wait before the first retry, doubled before each one after it.
+    pub backoff: Duration,
+}
+
+impl FetchPolicy {
+    pub fn new(images: &ImagesConfig) -> Self {
+        Self {
+            timeout: Duration::from_secs(images.remote_fetch_timeout_secs),
+            retries: images.remote_fetch_retries,
+            backoff: Duration::from_millis(images.remote_fetch_backoff_ms),
+        }
+    }
+}
+
+/// What the origin said identifies a downloaded file's version.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Validators stored for the cached file at `path`, if any.
+    pub fn load(path: &Path) -> Option<Self> {
+        let bytes = fs::read(validators_path(path)).ok()?;
+        serde_json::from_slice::<Self>(&bytes)
+            .ok()
+            .filter(|validators| !validators.is_empty())
+    }
+
+    /// Stores the validators of the cached file at `path`, or removes stale
+    /// ones when the origin sent none.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let stored = validators_path(path);
+        if self.is_empty() {
+            return match fs::remove_file(stored) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        fs::write(stored, serde_json::to_vec(self)?)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+pub enum Fetched {
+    Modified(Vec<u8>, Validators),
+    /// The origin confirmed the cached copy is current (HTTP 304).
+    NotModified,
+}
+
+fn validators_path(path: &Path) -> std::path::PathBuf {
+    path.with_extension("http")
+}
+
+/// Caps concurrent downloads (`build.fetch_jobs`); call before any image is processed.
+pub fn set_fetch_jobs(jobs: usize) {
+    FETCH_JOBS.store(jobs, Ordering::SeqCst);
+}
+
+/// Makes cached images be downloaded again (`--refresh-remote`).
+pub fn set_refresh(refresh: bool) {
+    REFRESH.store(refresh, Ordering::SeqCst);
+}
+
+pub fn refreshing() -> bool {
+    REFRESH.load(Ordering::SeqCst)
+}
+
+/// Whether the cached copy of `url` should be checked against the origin: only
+/// the first time it is used in a build, and only if the origin sent validators
+/// or `--refresh-remote` was given.
+pub fn should_revalidate(url: &str, has_validators: bool) -> bool {
+    (has_validators || refreshing()) && CHECKED.lock().unwrap().insert(url.to_string())
+}
+
+/// Lists `url` among the images that could not be fetched in [`report_failures`].
+pub fn record_failure(url: &str, error: &str) {
+    FAILURES
+        .lock()
+        .unwrap()
+        .insert(url.to_string(), error.to_string());
+}
+
+/// Downloads `url`, waiting for a free slot first and retrying timeouts,
+/// dropped connections and 408/429/5xx responses. With `cached` validators the
+/// request is conditional and may come back [`Fetched::NotModified`].
+pub fn fetch(
+    url: &str,
+    policy: FetchPolicy,
+    cached: Option<&Validators>,
+) -> Result<Fetched, String> {
+    // A fresh download is as current as a revalidated one for the rest of the build
+    CHECKED.lock().unwrap().insert(url.to_string());
+    let agent = ureq::AgentBuilder::new().timeout(policy.timeout).build();
+    let mut attempt = 0;
+    loop {
+        // The slot is freed while waiting to retry
+        let result = {
+            let _slot = Slot::acquire();
+            get(&agent, url, cached)
+        };
+        let error = match result {
+            Ok(fetched) => return Ok(fetched),
+            Err(error) => error,
+        };
+        if !error.transient || attempt >= policy.retries {
+            return Err(error.message);
+        }
+        let delay = backoff_delay(policy.backoff, attempt);
+        attempt += 1;
+        eprintln!(
+            "fetching {} failed ({}); retry {}/{} in {:?}",
+            url, error.message, attempt, policy.retries, delay
+        );
+        thread::sleep(delay);
+    }
+}
+
+/// Lists the downloads that failed during the build, if any.
+pub fn report_failures() {
+    let Ok(failures) = FAILURES.lock() else {
+        return;
+    };
+    if failures.is_empty() {
+        return;
+    }
+    eprintln!(
+        "{} remote image(s) could not be fetched and were linked as is:",
+        failures.len()
+    );
+    for (url, error) in failures.iter() {
+        crate::diagnostics::warn(format!("  {}: {}", url, error));
+    }
+}
+
+struct FetchError {
+    message: String,
+    transient: bool,
+}
+
+fn get(agent: &ureq::Agent, url: &str, cached: Option<&Validators>) -> Result<Fetched, FetchError> {
+    let mut request = agent.get(url);
+    if let Some(cached) = cached {
+        if let Some(etag) = &cached.etag {
+            request = request.set("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            request = request.set("If-Modified-Since", last_modified);
+        }
+    }
+    let response = request.call().map_err(|error| {
+        let transient = match &error {
+            ureq::Error::Status(status, _) => is_transient_status(*status),
+            ureq::Error::Transport(transport) => matches!(
+                transport.kind(),
+                ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+            ),
+        };
+        // ureq's message starts with the URL, which the caller already names
+        let message = error.to_string();
+        let message = message
+            .strip_prefix(&format!("{}: ", url))
+            .unwrap_or(&message)
+            .to_string();
+        FetchError { message, transient }
+    })?;
+    if response.status() == 304 {
+        return Ok(Fetched::NotModified);
+    }
+    let validators = Validators {
+        etag: response.header("ETag").map(str::to_string),
+        last_modified: response.header("Last-Modified").map(str::to_string),
+    };
+    let mut bytes = Vec::new();
+    // A body cut off midway is as transient as a dropped connection
+    response
+        .into_reader()
+        .read_to_end(&mut bytes)
+        .map_err(|error| FetchError {
+            message: error.to_string(),
+            transient: true,
+        })?;
+    Ok(Fetched::Modified(bytes, validators))
+}
+
+fn is_transient_status(status: u16) -> bool {
+    matches!(status, 408 | 429 | 500..=599)
+}
+
+fn backoff_delay(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(1 << attempt.min(16))
+}
+
+/// One of the `build.fetch_jobs` download slots, released on drop.
+struct Slot;
+
+impl Slot {
+    fn acquire() -> Self {
+        let limit = match FETCH_JOBS.load(Ordering::SeqCst) {
+            0 => DEFAULT_FETCH_JOBS,
+            jobs => jobs,
+        };
+        let (active, freed) = &*ACTIVE;
+        let mut active = active.lock().unwrap();
+        while *active >= limit {
+            active = freed.wait(active).unwrap();
+        }
+        *active += 1;
+        Slot
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let (active, freed) = &*ACTIVE;
+        *active.lock().unwrap() -= 1;
+        freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_only_transient_statuses() {
+        assert!(is_transient_status(503));
+        assert!(is_transient_status(429));
+        assert!(!is_transient_status(404));
+        assert!(!is_transient_status(403));
+    }
+
+    #[test]
+    fn validators_are_stored_next_to_the_cached_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cached = dir.path().join("cat.jpg");
+        assert_eq!(Validators::load(&cached), None);
+
+        let validators = Validators {
+            etag: Some("\"abc\"".into()),
+            last_modified: None,
+        };
+        validators.save(&cached).unwrap();
+        assert_eq!(Validators::load(&cached), Some(validators));
+
+        Validators::default().save(&cached).unwrap();
+        assert!(!dir.path().join("cat.http").exists());
+    }
+
+    #[test]
+    fn backoff_doubles_each_attempt() {
+        let base = Duration::from_millis(500);
+        assert_eq!(backoff_delay(base, 0), Duration::from_millis(500));
+        assert_eq!(backoff_delay(base, 2), Duration::from_secs(2));
+    }
+}
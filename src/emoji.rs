@@ -0,0 +1,191 @@
+//! `:shortcode:` emoji names in text, as GitHub and chat apps write them,
+//! turned into emoji when `html.emoji` is on. Names outside the table are left
+//! alone, so colons in times, ratios and code-like text are kept.
+
+use crate::config::Emoji;
+use crate::html_renderer::escape_html;
+use std::borrow::Cow;
+
+/// Shortcodes and their emoji, sorted by name for binary search.
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("airplane", "✈️"),
+    ("alarm_clock", "⏰"),
+    ("angry", "😠"),
+    ("apple", "🍎"),
+    ("art", "🎨"),
+    ("beer", "🍺"),
+    ("bike", "🚲"),
+    ("bird", "🐦"),
+    ("blush", "😊"),
+    ("book", "📖"),
+    ("books", "📚"),
+    ("bug", "🐛"),
+    ("bulb", "💡"),
+    ("cake", "🍰"),
+    ("camera", "📷"),
+    ("car", "🚗"),
+    ("cat", "🐱"),
+    ("chart_with_upwards_trend", "📈"),
+    ("clap", "👏"),
+    ("coffee", "☕"),
+    ("computer", "💻"),
+    ("confused", "😕"),
+    ("construction", "🚧"),
+    ("cry", "😢"),
+    ("dog", "🐶"),
+    ("email", "📧"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("flushed", "😳"),
+    ("gear", "⚙️"),
+    ("gift", "🎁"),
+    ("grin", "😁"),
+    ("grinning", "😀"),
+    ("hammer", "🔨"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("heavy_check_mark", "✔️"),
+    ("hourglass", "⌛"),
+    ("house", "🏠"),
+    ("hugs", "🤗"),
+    ("information_source", "ℹ️"),
+    ("joy", "😂"),
+    ("key", "🔑"),
+    ("laughing", "😆"),
+    ("link", "🔗"),
+    ("lock", "🔒"),
+    ("mag", "🔍"),
+    ("memo", "📝"),
+    ("moon", "🌙"),
+    ("muscle", "💪"),
+    ("notes", "🎶"),
+    ("ok_hand", "👌"),
+    ("partying_face", "🥳"),
+    ("pencil2", "✏️"),
+    ("pizza", "🍕"),
+    ("point_right", "👉"),
+    ("pray", "🙏"),
+    ("question", "❓"),
+    ("rainbow", "🌈"),
+    ("raised_hands", "🙌"),
+    ("recycle", "♻️"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("rotating_light", "🚨"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("shrug", "🤷"),
+    ("skull", "💀"),
+    ("sleeping", "😴"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("smirk", "😏"),
+    ("snowflake", "❄️"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("star_struck", "🤩"),
+    ("stuck_out_tongue", "😛"),
+    ("sunglasses", "😎"),
+    ("sunny", "☀️"),
+    ("sweat_smile", "😅"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("trophy", "🏆"),
+    ("umbrella", "☔"),
+    ("unamused", "😒"),
+    ("warning", "⚠️"),
+    ("wave", "👋"),
+    ("white_check_mark", "✅"),
+    ("wink", "😉"),
+    ("worried", "😟"),
+    ("x", "❌"),
+    ("yum", "😋"),
+    ("zap", "⚡"),
+    ("zzz", "💤"),
+];
+
+/// `html` with every known `:shortcode:` replaced as `mode` asks; `html` is
+/// already escaped text, which shortcodes never contain markup of.
+pub fn replace<'a>(html: &'a str, mode: Emoji, svg_url: &str) -> Cow<'a, str> {
+    if mode == Emoji::Off || !html.contains(':') {
+        return Cow::Borrowed(html);
+    }
+    let mut out = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find(':') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')))
+            .unwrap_or(after.len());
+        let emoji = after[name_len..]
+            .starts_with(':')
+            .then(|| lookup(&after[..name_len]))
+            .flatten();
+        match emoji {
+            Some(emoji) => {
+                out.push_str(&emoji_html(emoji, mode, svg_url));
+                rest = &after[name_len + 1..];
+            }
+            None => {
+                // The closing colon may open the next shortcode
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+fn lookup(name: &str) -> Option<&'static str> {
+    SHORTCODES
+        .binary_search_by_key(&name, |&(name, _)| name)
+        .ok()
+        .map(|index| SHORTCODES[index].1)
+}
+
+fn emoji_html(emoji: &str, mode: Emoji, svg_url: &str) -> String {
+    if mode != Emoji::Svg {
+        return emoji.to_string();
+    }
+    // Twemoji file names leave out the emoji presentation selector
+    let code: Vec<String> = emoji
+        .chars()
+        .filter(|&c| c != '\u{fe0f}')
+        .map(|c| format!("{:x}", c as u32))
+        .collect();
+    let src = svg_url.replace("{code}", &code.join("-"));
+    format!(
+        "<img class=\"emoji\" src=\"{}\" alt=\"{}\" draggable=\"false\">",
+        escape_html(&src),
+        emoji
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_known_shortcodes_only() {
+        assert!(SHORTCODES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        let text = "Shipped :rocket: at 12:30:45 :not_an_emoji::+1:";
+        assert_eq!(replace(text, Emoji::Off, ""), text);
+        assert_eq!(
+            replace(text, Emoji::Unicode, ""),
+            "Shipped 🚀 at 12:30:45 :not_an_emoji:👍"
+        );
+        assert_eq!(
+            replace(":heart:", Emoji::Svg, "/emoji/{code}.svg"),
+            "<img class=\"emoji\" src=\"/emoji/2764.svg\" alt=\"❤️\" draggable=\"false\">"
+        );
+    }
+}
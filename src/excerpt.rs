@@ -0,0 +1,232 @@
+//! Metadata about a dllu source for indexers and deployment scripts: title,
+//! date, tags, summary, plain text, word count and first image, read from the
+//! parsed article rather than scraped from rendered HTML.
+//!
+//! Available as [`excerpt`] from the `dllup_rs` library and as JSON from
+//! `dllup-rs excerpt <file>`. Plugins are not applied; the excerpt describes
+//! the source as written.
+
+use crate::ast::{Article, Block, InlineElement};
+use crate::parser::Parser;
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Excerpt {
+    pub title: Option<String>,
+    /// The header's date line as written.
+    pub date: Option<String>,
+    /// Comma-separated `x-tags` header field.
+    pub tags: Vec<String>,
+    /// First non-empty paragraph, the same summary indexes and feeds show.
+    pub summary: Option<String>,
+    /// Prose of the body (paragraphs, headings, lists, quotes, tables and
+    /// captions) with whitespace collapsed; code, math and raw blocks are left out.
+    pub text: String,
+    pub word_count: usize,
+    pub image: Option<ExcerptImage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExcerptImage {
+    /// URL as written in the source, relative to the source file unless absolute.
+    pub url: String,
+    /// Empty for decorative images.
+    pub alt: String,
+}
+
+/// Parses `source` and describes it.
+pub fn excerpt(source: &str) -> Excerpt {
+    let mut parser = Parser::default();
+    parser.parse(source);
+    article_excerpt(&parser.article)
+}
+
+/// Describes an already parsed article.
+pub fn article_excerpt(article: &Article) -> Excerpt {
+    let header = article.header.as_ref();
+    let text = body_text(article);
+    let image = article.blocks().into_iter().find_map(|block| match block {
+        Block::ImageFigure { url, alt, .. } => Some(ExcerptImage {
+            url: url.clone(),
+            alt: alt.clone(),
+        }),
+        _ => None,
+    });
+    Excerpt {
+        title: header
+            .map(|h| h.title.trim().to_string())
+            .filter(|title| !title.is_empty()),
+        date: header
+            .and_then(|h| h.date.as_deref())
+            .map(str::trim)
+            .filter(|date| !date.is_empty())
+            .map(str::to_string),
+        tags: tags(article),
+        summary: summary(&article.body),
+        word_count: text.split_whitespace().count(),
+        text,
+        image,
+    }
+}
+
+/// The comma-separated `x-tags` header field, as [`Excerpt::tags`].
+pub fn tags(article: &Article) -> Vec<String> {
+    article
+        .header
+        .as_ref()
+        .and_then(|h| h.vars.get("x-tags"))
+        .map(|tags| {
+            tags.split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Words of the article's prose, as [`Excerpt::word_count`] counts them.
+pub fn word_count(article: &Article) -> usize {
+    body_text(article).split_whitespace().count()
+}
+
+fn body_text(article: &Article) -> String {
+    let mut parts = Vec::new();
+    for block in article.blocks() {
+        push_block_text(block, &mut parts);
+    }
+    collapse_whitespace(&parts.join(" "))
+}
+
+/// The first paragraph with any text, as plain text on one line.
+pub fn summary(blocks: &[Block]) -> Option<String> {
+    blocks.iter().find_map(|block| match block {
+        Block::Paragraph(inlines) => {
+            Some(collapse_whitespace(&plain_text(inlines))).filter(|text| !text.is_empty())
+        }
+        _ => None,
+    })
+}
+
+/// The text of `inlines` without markup; link text is kept and URLs dropped.
+pub fn plain_text(inlines: &[InlineElement]) -> String {
+    let mut out = String::new();
+    for inline in inlines {
+        match inline {
+            InlineElement::Text(t) => out.push_str(t),
+            InlineElement::Code(c) | InlineElement::InlineMath(c) => out.push_str(c),
+            InlineElement::Link { text, .. } | InlineElement::Lang { text, .. } => {
+                out.push_str(&plain_text(text))
+            }
+            InlineElement::Emphasis(inner) | InlineElement::Strong(inner) => {
+                out.push_str(&plain_text(inner))
+            }
+            InlineElement::Reference(s) => out.push_str(s),
+            InlineElement::ReferenceAnchor { content, invisible } => {
+                // A caption's `[#name]` label is not shown
+                if !invisible {
+                    out.push_str(content)
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Runs of whitespace as single spaces, trimmed at both ends.
+pub fn collapse_whitespace(input: &str) -> String {
+    let mut result = String::new();
+    let mut last_was_space = false;
+    for ch in input.chars() {
+        if ch.is_whitespace() {
+            if !last_was_space && !result.is_empty() {
+                result.push(' ');
+            }
+            last_was_space = true;
+        } else {
+            result.push(ch);
+            last_was_space = false;
+        }
+    }
+    if last_was_space {
+        result.pop();
+    }
+    result
+}
+
+fn push_block_text(block: &Block, parts: &mut Vec<String>) {
+    match block {
+        Block::Paragraph(inlines) | Block::BlockQuote(inlines) => parts.push(plain_text(inlines)),
+        Block::BigButton { text, .. }
+        | Block::Embed { text, .. }
+        | Block::Details { summary: text, .. } => parts.push(plain_text(text)),
+        Block::SectionHeader { text, .. } => parts.push(text.clone()),
+        Block::UnorderedList(items) | Block::OrderedList(items) => {
+            parts.extend(items.iter().map(|item| plain_text(&item.text)))
+        }
+        Block::Table {
+            header,
+            rows,
+            caption,
+            ..
+        } => {
+            parts.extend(header.iter().map(|cell| plain_text(&cell.content)));
+            for row in rows {
+                parts.extend(row.iter().map(|cell| plain_text(&cell.content)));
+            }
+            if let Some(caption) = caption {
+                parts.push(plain_text(caption));
+            }
+        }
+        Block::ImageFigure { text, credit, .. } => {
+            parts.push(plain_text(text));
+            parts.extend(credit.as_deref().map(plain_text));
+        }
+        Block::CodeBlock {
+            caption: Some(caption),
+            ..
+        } => parts.push(plain_text(caption)),
+        Block::CodeBlock { .. }
+        | Block::DisplayMath { .. }
+        | Block::Raw { .. }
+        | Block::Include { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_a_post() {
+        let source = "Launch notes\n2024-05-06\nx-tags: release, rust ,\n\n===\n\n\
+                      pic shot.png A screenshot : The new _editor_\n\n\
+                      We shipped [it](https://example.com) today.\n\n\
+                      ~~~\nfn main() {}\n~~~\n\n* one\n* two\n";
+        let excerpt = excerpt(source);
+        assert_eq!(excerpt.title.as_deref(), Some("Launch notes"));
+        assert_eq!(excerpt.date.as_deref(), Some("2024-05-06"));
+        assert_eq!(excerpt.tags, ["release", "rust"]);
+        assert_eq!(excerpt.summary.as_deref(), Some("We shipped it today."));
+        assert_eq!(excerpt.text, "The new editor We shipped it today. one two");
+        assert_eq!(excerpt.word_count, 9);
+        let image = excerpt.image.expect("expected image");
+        assert_eq!(
+            (image.url.as_str(), image.alt.as_str()),
+            ("shot.png", "A screenshot")
+        );
+    }
+
+    #[test]
+    fn counts_only_prose_words() {
+        let source = "Counting\n\n===\n\n# Two words\n\n\
+                      Three more words.\n\n\
+                      $ a + b = c\n\n\
+                      ~~~\nlet not_counted = 1;\n~~~\n\n\
+                      | one | two |\n";
+        let mut parser = Parser::default();
+        parser.parse(source);
+        assert_eq!(word_count(&parser.article), 7);
+        assert_eq!(excerpt(source).word_count, 7);
+    }
+}
@@ -0,0 +1,137 @@
+//! Math in feed content, chosen with `feed.math`. KaTeX HTML needs the site's
+//! stylesheet and fonts, which feed readers don't load, so each equation can be
+//! replaced with its TeX, the MathML KaTeX renders next to the HTML, or an image
+//! from `feed.math_image_url`. Output of other engines is kept as it is.
+
+use crate::config::FeedMath;
+use crate::html_renderer::{escape_html, unescape_html};
+use crate::newsletter::percent_encode;
+use regex::{Captures, Regex};
+
+/// `html` with its equations in the form `mode` asks for.
+pub fn convert(html: &str, mode: FeedMath, image_url: &str) -> String {
+    lazy_static! {
+        static ref KATEX: Regex =
+            Regex::new(r#"<span class="katex-display">|<span class="katex">"#).unwrap();
+        static ref MATHML: Regex = Regex::new(r"(?s)<math[ >].*?</math>").unwrap();
+        static ref TEX: Regex =
+            Regex::new(r#"(?s)<annotation encoding="application/x-tex">(.*?)</annotation>"#)
+                .unwrap();
+        static ref FALLBACK: Regex = Regex::new(
+            r#"(?s)<span class="math-inline">(.*?)</span>|<div class="math-display">(.*?)</div>"#
+        )
+        .unwrap();
+    }
+    if mode == FeedMath::Html {
+        return html.to_string();
+    }
+    let mut out = String::new();
+    let mut rest = html;
+    while let Some(start) = KATEX.find(rest) {
+        let end = start.start() + span_len(&rest[start.start()..]);
+        let katex = &rest[start.start()..end];
+        out.push_str(&rest[..start.start()]);
+        let inline = start.as_str() == "<span class=\"katex\">";
+        let mathml = MATHML.find(katex).map(|m| m.as_str());
+        let tex = TEX.captures(katex).and_then(|caps| caps.get(1));
+        match (mode, mathml, tex) {
+            (FeedMath::Mathml, Some(mathml), _) => out.push_str(mathml),
+            (FeedMath::Tex, _, Some(tex)) => out.push_str(&tex_element(tex.as_str(), inline)),
+            (FeedMath::Image, _, Some(tex)) => {
+                out.push_str(&image_element(tex.as_str(), inline, image_url))
+            }
+            _ => out.push_str(katex),
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+
+    // Equations no engine rendered are already TeX
+    if mode != FeedMath::Image {
+        return out;
+    }
+    FALLBACK
+        .replace_all(&out, |caps: &Captures| match caps.get(1) {
+            Some(tex) => image_element(tex.as_str(), true, image_url),
+            None => image_element(&caps[2], false, image_url),
+        })
+        .into_owned()
+}
+
+/// Length of the `<span>` element at the start of `html`, through its
+/// matching `</span>`, or all of `html` when it is never closed.
+fn span_len(html: &str) -> usize {
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < html.len() {
+        let rest = &html[i..];
+        if rest.starts_with("<span") {
+            depth += 1;
+        } else if rest.starts_with("</span>") {
+            depth -= 1;
+            if depth == 0 {
+                return i + "</span>".len();
+            }
+        }
+        i += rest.chars().next().map_or(1, char::len_utf8);
+    }
+    html.len()
+}
+
+/// The escaped TeX as the renderer shows it without a math engine.
+fn tex_element(escaped_tex: &str, inline: bool) -> String {
+    if inline {
+        format!("<span class=\"math-inline\">{}</span>", escaped_tex)
+    } else {
+        format!("<div class=\"math-display\">{}</div>", escaped_tex)
+    }
+}
+
+fn image_element(escaped_tex: &str, inline: bool, url_template: &str) -> String {
+    let src = url_template.replace("{tex}", &percent_encode(&unescape_html(escaped_tex)));
+    let class = if inline {
+        "math-inline"
+    } else {
+        "math-display"
+    };
+    format!(
+        "<img src=\"{}\" alt=\"{}\" class=\"{}\"/>",
+        escape_html(&src),
+        escaped_tex,
+        class
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KATEX: &str = "<p>So <span class=\"katex\"><span class=\"katex-mathml\"><math xmlns=\"http://www.w3.org/1998/Math/MathML\"><semantics><mi>x</mi><annotation encoding=\"application/x-tex\">x &lt; y</annotation></semantics></math></span><span class=\"katex-html\" aria-hidden=\"true\"><span class=\"base\">x</span></span></span> holds.</p>";
+
+    #[test]
+    fn replaces_katex_with_the_chosen_form() {
+        assert_eq!(convert(KATEX, FeedMath::Html, ""), KATEX);
+        assert_eq!(
+            convert(KATEX, FeedMath::Tex, ""),
+            "<p>So <span class=\"math-inline\">x &lt; y</span> holds.</p>"
+        );
+        assert_eq!(
+            convert(KATEX, FeedMath::Mathml, ""),
+            "<p>So <math xmlns=\"http://www.w3.org/1998/Math/MathML\"><semantics><mi>x</mi><annotation encoding=\"application/x-tex\">x &lt; y</annotation></semantics></math> holds.</p>"
+        );
+        assert_eq!(
+            convert(KATEX, FeedMath::Image, "https://math.example/?{tex}"),
+            "<p>So <img src=\"https://math.example/?x%20%3C%20y\" alt=\"x &lt; y\" class=\"math-inline\"/> holds.</p>"
+        );
+    }
+
+    #[test]
+    fn fallback_tex_becomes_an_image() {
+        let html = "<div class=\"math-display\">e = mc^2</div>";
+        assert_eq!(
+            convert(html, FeedMath::Image, "/tex?{tex}"),
+            "<img src=\"/tex?e%20%3D%20mc%5E2\" alt=\"e = mc^2\" class=\"math-display\"/>"
+        );
+        assert_eq!(convert(html, FeedMath::Mathml, "/tex?{tex}"), html);
+    }
+}
@@ -0,0 +1,781 @@
+//! `dllup-rs fmt`: rewrites dllu sources in one canonical style by parsing them
+//! and printing the article back out.
+//!
+//! Tables get padded columns, lists get `*`/`1.` markers with sequential
+//! numbers, blocks are separated by one blank line and paragraphs are wrapped
+//! at `fmt.width`. A file is only rewritten when its re-parsed article matches
+//! the original, so formatting never changes what a page renders.
+
+use crate::ast::{Article, ArticleHeader, Block, InlineElement, ListItem, TableCell};
+use crate::parser::Parser;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Entry point for `dllup-rs fmt [--check] <file or dir>...`.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let inputs: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if inputs.is_empty() {
+        return Err("Usage: dllup-rs fmt [--check] <file or dir>...".into());
+    }
+    let mut files: Vec<PathBuf> = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            let build = crate::site_config(path, None)?.build;
+            files.extend(crate::walk::collect_dllu_files(path, &build)?);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    let mut unformatted = 0;
+    for path in &files {
+        let config = crate::resolve_config(path, None)?;
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let formatted = match format_source(&source, config.fmt.width) {
+            Ok(formatted) => formatted,
+            Err(reason) => {
+                eprintln!("skipping {}: {}", path.display(), reason);
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            eprintln!("would reformat {}", path.display());
+            unformatted += 1;
+        } else {
+            fs::write(path, &formatted)
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            eprintln!("formatted {}", path.display());
+        }
+    }
+    if unformatted > 0 {
+        return Err(format!("{} file(s) need formatting", unformatted));
+    }
+    Ok(())
+}
+
+/// `source` in canonical style, wrapping paragraphs at `width` columns (`0`
+/// keeps each paragraph on one line). Fails instead of dropping content the
+/// parser does not keep.
+pub fn format_source(source: &str, width: usize) -> Result<String, String> {
+    let article = parse(source);
+    if article
+        .blocks()
+        .into_iter()
+        .any(|block| matches!(block, Block::Paragraph(inlines) if inlines.is_empty()))
+    {
+        return Err("it has a malformed `pic` or `::` line".into());
+    }
+
+    let mut out = String::new();
+    if let Some(header) = &article.header {
+        out.push_str(&format_header(header, source));
+        out.push_str("\n\n===\n\n");
+    }
+    let blocks: Vec<String> = article
+        .body
+        .iter()
+        .map(|block| format_block(block, width))
+        .collect();
+    out.push_str(&blocks.join("\n\n"));
+    out.push('\n');
+
+    if normalized(&parse(&out)) != normalized(&article) {
+        return Err("formatting would change how it renders".into());
+    }
+    Ok(out)
+}
+
+fn parse(source: &str) -> Article {
+    let mut parser = Parser::default();
+    parser.parse(source);
+    parser.article
+}
+
+/// Title, date, then `pinned`/`featured` flags, `template`, other `key: value`
+/// fields and `x-` variables; a header with other lines (which the parser
+/// ignores) is kept as written.
+fn format_header(header: &ArticleHeader, source: &str) -> String {
+    let written = source.split("\n===\n").next().unwrap_or_default();
+    let lines = written
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .count();
+    let mut fields: Vec<String> = [
+        (header.pinned, "pinned: true"),
+        (header.featured, "featured: true"),
+    ]
+    .into_iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, line)| line.to_string())
+    .collect();
+    fields.extend(
+        header
+            .template
+            .as_ref()
+            .map(|name| format!("template: {}", name)),
+    );
+    fields.extend(
+        header
+            .meta
+            .iter()
+            .chain(&header.vars)
+            .map(|(name, value)| format!("{}: {}", name, value)),
+    );
+    // `pinned: false` and the like count here too, so such headers are kept
+    if lines != 1 + usize::from(header.date.is_some()) + fields.len() {
+        return written.trim_end().to_string();
+    }
+    let mut sections = vec![header.title.trim().to_string()];
+    sections.extend(header.date.as_ref().map(|date| date.trim().to_string()));
+    if !fields.is_empty() {
+        sections.push(fields.join("\n"));
+    }
+    sections.join("\n\n")
+}
+
+fn format_block(block: &Block, width: usize) -> String {
+    match block {
+        Block::Raw { format, content } if format == "comment" && !content.is_empty() => content
+            .lines()
+            .map(|line| format!("%% {}", line).trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Block::Raw { format, content } => {
+            let fence = if format == "html" {
+                "???".to_string()
+            } else {
+                format!("??? {}", format)
+            };
+            format!("{}\n{}???", fence, content)
+        }
+        Block::CodeBlock {
+            language,
+            code,
+            line_numbers,
+            highlight_lines,
+            filename,
+            caption,
+            ..
+        } => {
+            let mut directives = Vec::new();
+            let has_header = language.is_some()
+                || line_numbers.is_some()
+                || filename.is_some()
+                || caption.is_some()
+                || !highlight_lines.is_empty();
+            // `file` and `hl` lines are only read after a `lang` line, bare without a language
+            if has_header {
+                let flag = match line_numbers {
+                    Some(true) => ":numbers",
+                    Some(false) => ":nonumbers",
+                    None => "",
+                };
+                let name = language.as_deref().unwrap_or("");
+                directives.push(format!("lang {}{}", name, flag).trim_end().to_string());
+            }
+            if filename.is_some() || caption.is_some() {
+                let mut line = format!("file {}", filename.as_deref().unwrap_or(""));
+                if let Some(caption) = caption {
+                    line.push_str(" : ");
+                    line.push_str(&inline_line(caption));
+                }
+                directives.push(line);
+            }
+            if !highlight_lines.is_empty() {
+                directives.push(format!("hl {}", line_ranges(highlight_lines)));
+            }
+            // Four tildes for a block without directives (its first line is never
+            // read as one), unless the code contains that fence itself
+            let has_fence = code.lines().any(|line| line.trim() == "~~~~");
+            let fence = if directives.is_empty() && !has_fence {
+                "~~~~"
+            } else {
+                "~~~"
+            };
+            let mut out = format!("{}\n", fence);
+            for directive in directives {
+                out.push_str(&directive);
+                out.push('\n');
+            }
+            out.push_str(code);
+            out.push_str(fence);
+            out
+        }
+        Block::SectionHeader {
+            level,
+            id,
+            text,
+            custom_id,
+        } => {
+            let heading = format!("{} {}", "#".repeat(*level), text);
+            if *custom_id {
+                format!("{} {{#{}}}", heading, id)
+            } else {
+                heading
+            }
+        }
+        Block::BlockQuote(inlines) => wrap(&words(inlines), width.saturating_sub(2))
+            .iter()
+            .map(|line| format!("> {}", line))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Block::ImageFigure {
+            url,
+            alt,
+            decorative,
+            text,
+            credit,
+            crop,
+            no_resize,
+            ..
+        } => {
+            let mut out = format!("pic {}", url);
+            if let Some(crop) = crop {
+                out.push_str(&format!(" crop={}", crop));
+            }
+            if *no_resize {
+                out.push_str(" resize=off");
+            }
+            let alt = if *decorative { "-" } else { alt.as_str() };
+            if !alt.is_empty() {
+                out.push(' ');
+                out.push_str(alt);
+            }
+            out.push_str(" : ");
+            out.push_str(&inline_line(text));
+            if let Some(credit) = credit {
+                out.push_str(" :: ");
+                out.push_str(&inline_line(credit));
+            }
+            out
+        }
+        Block::Embed {
+            provider,
+            video,
+            text,
+            ..
+        } => {
+            let mut out = format!("embed {} {}", provider.name(), video);
+            if !text.is_empty() {
+                out.push_str(" : ");
+                out.push_str(&inline_line(text));
+            }
+            out
+        }
+        Block::DisplayMath { id, content, .. } => match id {
+            Some(id) => format!("$ {} \\label{{{}}}", content, id),
+            None => format!("$ {}", content),
+        },
+        Block::Table {
+            header,
+            rows,
+            caption,
+            ..
+        } => format_table(header, rows, caption.as_deref()),
+        Block::BigButton { text, url } => format!(":: {} {}", inline_line(text), url),
+        Block::Include { path } => format!("include {}", path),
+        Block::Details { summary, blocks } => {
+            let mut parts = vec![format!("+++ {}", inline_line(summary))];
+            parts.extend(blocks.iter().map(|block| format_block(block, width)));
+            parts.push("+++".into());
+            parts.join("\n\n")
+        }
+        Block::UnorderedList(items) => format_list(items, width, |item| "*".repeat(item.level)),
+        Block::OrderedList(items) => {
+            let mut number = 0;
+            format_list(items, width, |_| {
+                number += 1;
+                format!("{}.", number)
+            })
+        }
+        Block::Paragraph(inlines) => guard_lines(wrap(&words(inlines), width), 0).join("\n"),
+    }
+}
+
+fn format_list(
+    items: &[ListItem],
+    width: usize,
+    mut marker: impl FnMut(&ListItem) -> String,
+) -> String {
+    let mut lines = Vec::new();
+    for item in items {
+        let prefix = match item.checked {
+            Some(true) => format!("{} [x]", marker(item)),
+            Some(false) => format!("{} [ ]", marker(item)),
+            None => marker(item),
+        };
+        let indent = " ".repeat(prefix.len() + 1);
+        let wrapped = wrap(&item_words(&item.text), width.saturating_sub(indent.len()));
+        // Only continuation lines can be mistaken for the next item
+        for (index, line) in guard_lines(wrapped, 1).iter().enumerate() {
+            if index == 0 {
+                lines.push(format!("{} {}", prefix, line));
+            } else {
+                lines.push(format!("{}{}", indent, line));
+            }
+        }
+        // Blocks below the item follow a blank line, indented to its text
+        for block in &item.blocks {
+            lines.push(String::new());
+            let formatted = format_block(block, width.saturating_sub(indent.len()));
+            lines.extend(formatted.lines().map(|line| match line {
+                "" => String::new(),
+                line => format!("{}{}", indent, line),
+            }));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Rows padded so the column separators line up, then the caption line.
+fn format_table(
+    header: &[TableCell],
+    rows: &[Vec<TableCell>],
+    caption: Option<&[InlineElement]>,
+) -> String {
+    let cells = table_grid(header, rows);
+    let columns = cells.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            cells
+                .iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+                .max(3)
+        })
+        .collect();
+    let format_row = |row: &[String]| {
+        let padded: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = *width))
+            .collect();
+        format!("| {} |", padded.join(" | "))
+    };
+
+    let mut lines = vec![format_row(&cells[0])];
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    lines.push(format!("| {} |", separator.join(" | ")));
+    lines.extend(cells[1..].iter().map(|row| format_row(row)));
+    if let Some(caption) = caption {
+        lines.push(inline_line(caption));
+    }
+    lines.join("\n")
+}
+
+/// `3,7-9` for lines 3, 7, 8 and 9.
+fn line_ranges(lines: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &line in lines {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == line => *end = line,
+            _ => ranges.push((line, line)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn inline_line(inlines: &[InlineElement]) -> String {
+    words(inlines).join(" ")
+}
+
+/// Inline markup split at the spaces where a line may break.
+fn words(inlines: &[InlineElement]) -> Vec<String> {
+    let mut words = Words::default();
+    words.inlines(inlines);
+    words.words
+}
+
+/// Like [`words`], but never breaking inside a link or emphasis: the parser
+/// reads each continuation line of a list item as inline markup on its own.
+fn item_words(inlines: &[InlineElement]) -> Vec<String> {
+    let mut words = Words {
+        keep_spans: true,
+        ..Words::default()
+    };
+    words.inlines(inlines);
+    words.words
+}
+
+#[derive(Default)]
+struct Words {
+    words: Vec<String>,
+    space: bool,
+    keep_spans: bool,
+    /// How many links and emphasis spans the current text is inside.
+    depth: usize,
+    /// How many `{lang|...}` spans, where literal braces must be escaped.
+    lang_depth: usize,
+}
+
+impl Words {
+    /// Appends unbreakable markup.
+    fn push(&mut self, markup: &str) {
+        if markup.is_empty() {
+            return;
+        }
+        if self.space || self.words.is_empty() {
+            self.words.push(String::new());
+            self.space = false;
+        }
+        if let Some(word) = self.words.last_mut() {
+            word.push_str(markup);
+        }
+    }
+
+    fn space(&mut self) {
+        if self.keep_spans && self.depth > 0 {
+            if !self.words.last().is_some_and(|word| word.ends_with(' ')) {
+                self.push(" ");
+            }
+            return;
+        }
+        self.space = !self.words.is_empty();
+    }
+
+    fn span(&mut self, inlines: &[InlineElement]) {
+        self.depth += 1;
+        self.inlines(inlines);
+        self.depth -= 1;
+    }
+
+    fn inlines(&mut self, inlines: &[InlineElement]) {
+        for inline in inlines {
+            match inline {
+                InlineElement::Text(text) => self.text(text),
+                InlineElement::Code(code) => {
+                    self.push(&format!("`{}`", one_line(code).replace('`', "\\`")))
+                }
+                InlineElement::InlineMath(math) => {
+                    self.push(&format!("${}$", one_line(math).replace('$', "\\$")))
+                }
+                InlineElement::Link { text, url } => {
+                    self.push("[");
+                    self.span(text);
+                    let url = url.replace('\\', "\\\\").replace(')', "\\)");
+                    self.push(&format!("]({})", url));
+                }
+                InlineElement::Emphasis(inner) => {
+                    self.push("_");
+                    self.span(inner);
+                    self.push("_");
+                }
+                InlineElement::Strong(inner) => {
+                    self.push("**");
+                    self.span(inner);
+                    self.push("**");
+                }
+                InlineElement::Lang { lang, text } => {
+                    self.push(&format!("{{{}|", lang));
+                    self.lang_depth += 1;
+                    self.span(text);
+                    self.lang_depth -= 1;
+                    self.push("}");
+                }
+                InlineElement::Reference(name) => self.push(&format!("(#{})", name)),
+                InlineElement::ReferenceAnchor { content, .. } => {
+                    self.push(&format!("[#{}]", content))
+                }
+            }
+        }
+    }
+
+    /// Plain text with the characters that would start markup escaped.
+    fn text(&mut self, text: &str) {
+        for (index, c) in text.char_indices() {
+            // Only ASCII whitespace: a no-break space must stay one
+            if c.is_ascii_whitespace() {
+                self.space();
+                continue;
+            }
+            let rest = &text[index + c.len_utf8()..];
+            let next = rest.chars().next();
+            let escape = match c {
+                '\\' | '`' | '$' | '[' | '_' => true,
+                '*' => next == Some('*'),
+                '(' => next == Some('#'),
+                '{' | '}' if self.lang_depth > 0 => true,
+                '{' => rest
+                    .split_once('|')
+                    .is_some_and(|(tag, _)| crate::parser::is_language_tag(tag)),
+                _ => false,
+            };
+            let mut markup = String::new();
+            if escape {
+                markup.push('\\');
+            }
+            markup.push(c);
+            self.push(&markup);
+        }
+    }
+}
+
+/// The rows of a table as the text of each column, with `<` and `^` in the
+/// places covered by a cell spanning several columns or rows.
+fn table_grid(header: &[TableCell], rows: &[Vec<TableCell>]) -> Vec<Vec<String>> {
+    // Places covered from a row above, and whether they are a span's first column
+    let mut covered: BTreeMap<(usize, usize), bool> = BTreeMap::new();
+    let mut grid = Vec::new();
+    for (r, row) in std::iter::once(header)
+        .chain(rows.iter().map(Vec::as_slice))
+        .enumerate()
+    {
+        let mut line: Vec<String> = Vec::new();
+        let cover = |line: &mut Vec<String>, covered: &mut BTreeMap<_, _>| {
+            while let Some(first) = covered.remove(&(r, line.len())) {
+                line.push(if first { "^" } else { "<" }.to_string());
+            }
+        };
+        for cell in row {
+            cover(&mut line, &mut covered);
+            let c = line.len();
+            let text = inline_line(&cell.content);
+            line.push(if text == "<" || text == "^" {
+                format!("\\{}", text)
+            } else {
+                text
+            });
+            for _ in 1..cell.colspan {
+                line.push("<".to_string());
+            }
+            for below in r + 1..r + cell.rowspan {
+                for column in c..c + cell.colspan {
+                    covered.insert((below, column), column == c);
+                }
+            }
+        }
+        cover(&mut line, &mut covered);
+        grid.push(line);
+    }
+    grid
+}
+
+fn one_line(text: &str) -> String {
+    text.replace('\n', " ")
+}
+
+/// Fills lines up to `width` columns (`0` for no limit).
+fn wrap(words: &[String], width: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in words {
+        match lines.last_mut() {
+            Some(line)
+                if width == 0 || line.chars().count() + 1 + word.chars().count() <= width =>
+            {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.clone()),
+        }
+    }
+    lines
+}
+
+/// Escapes the first character of lines from index `first` on that would read
+/// as the start of another block, or joins the line to the one before when
+/// that character opens markup (inline math, strong text).
+fn guard_lines(lines: Vec<String>, first: usize) -> Vec<String> {
+    let mut guarded: Vec<String> = Vec::with_capacity(lines.len());
+    for (index, line) in lines.into_iter().enumerate() {
+        if index >= first && starts_block(&line) {
+            if !line.starts_with('$') && !line.starts_with("**") {
+                guarded.push(format!("\\{}", line));
+                continue;
+            }
+            if let Some(previous) = guarded.last_mut() {
+                previous.push(' ');
+                previous.push_str(&line);
+                continue;
+            }
+        }
+        guarded.push(line);
+    }
+    guarded
+}
+
+/// Whether a paragraph or list line would be parsed as the start of a new block.
+fn starts_block(line: &str) -> bool {
+    let line = line.trim_start();
+    let stars = line.chars().take_while(|&c| c == '*').count();
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    line == "~~~"
+        || line == "~~~~"
+        || line == "???"
+        || line.starts_with("??? ")
+        || line == "+++"
+        || line.starts_with("+++ ")
+        || line.starts_with("%%")
+        || line.starts_with("include ")
+        || line.starts_with('#')
+        || ["> ", "pic ", "$ ", "| ", ":: "]
+            .iter()
+            .any(|prefix| line.starts_with(prefix))
+        || ((1..=6).contains(&stars) && line[stars..].starts_with(' '))
+        || (digits > 0 && line[digits..].starts_with(". "))
+}
+
+/// The article as JSON with runs of adjacent text merged and whitespace
+/// collapsed, which is what decides the rendered page.
+fn normalized(article: &Article) -> Value {
+    fn normalize(value: Value) -> Value {
+        match value {
+            Value::String(text) => {
+                Value::String(text.split_ascii_whitespace().collect::<Vec<_>>().join(" "))
+            }
+            Value::Array(items) => {
+                let mut merged: Vec<Value> = Vec::new();
+                for item in items {
+                    if let (Some(Value::Object(last)), Value::Object(item)) =
+                        (merged.last_mut(), &item)
+                    {
+                        if let (Some(Value::String(a)), Some(Value::String(b))) =
+                            (last.get_mut("Text"), item.get("Text"))
+                        {
+                            a.push_str(b);
+                            continue;
+                        }
+                    }
+                    merged.push(item);
+                }
+                Value::Array(merged.into_iter().map(normalize).collect())
+            }
+            Value::Object(map) => {
+                Value::Object(map.into_iter().map(|(k, v)| (k, normalize(v))).collect())
+            }
+            other => other,
+        }
+    }
+    normalize(serde_json::to_value(article).unwrap_or(Value::Null))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = "Notes\n2024-01-02\n===\n# Intro\nSome _text_ with `code` and\na [link](https://example.com/a_(b\\)) over   two lines.\n*   first\n** nested\n\n1. one\n3. two\n\n| a | long header |\n|---|---|\n| wide cell | x |\nTable caption\n\n$ x^2 \\label{sq}\n\npic cat.jpg crop=16:9@top A cat : A cat [#cat] :: Jo\n";
+
+    #[test]
+    fn formats_into_canonical_style() {
+        let formatted = format_source(MESSY, 0).unwrap();
+        assert_eq!(
+            formatted,
+            "Notes\n\n2024-01-02\n\n===\n\n# Intro\n\nSome _text_ with `code` and a [link](https://example.com/a_(b\\)) over two lines.\n\n* first\n** nested\n\n1. one\n2. two\n\n| a         | long header |\n| --------- | ----------- |\n| wide cell | x           |\nTable caption\n\n$ x^2 \\label{sq}\n\npic cat.jpg crop=16:9@top A cat : A cat [#cat] :: Jo\n"
+        );
+        assert_eq!(format_source(&formatted, 0).unwrap(), formatted);
+    }
+
+    #[test]
+    fn wraps_without_starting_new_blocks() {
+        let source = "T\n\n===\n\nalpha beta 1. gamma aaaaaaaaa # b * c\n";
+        let formatted = format_source(source, 10).unwrap();
+        assert_eq!(
+            formatted,
+            "T\n\n===\n\nalpha beta\n\\1. gamma\naaaaaaaaa\n\\# b * c\n"
+        );
+    }
+
+    #[test]
+    fn keeps_links_in_list_items_on_one_line() {
+        let source = "T\n\n===\n\n* see [a long link](x.html) and _more words_ here\n";
+        let formatted = format_source(source, 16).unwrap();
+        assert_eq!(
+            formatted,
+            "T\n\n===\n\n* see\n  [a long link](x.html)\n  and\n  _more words_\n  here\n"
+        );
+    }
+
+    #[test]
+    fn indents_blocks_below_list_items() {
+        let source = "T\n\n===\n\n1. Install it.\n\n    Then  check:\n\n    ~~~~\n    make  check\n    ~~~~\n1. Done.\n";
+        let formatted = format_source(source, 0).unwrap();
+        assert_eq!(
+            formatted,
+            "T\n\n===\n\n1. Install it.\n\n   Then check:\n\n   ~~~~\n   make  check\n   ~~~~\n2. Done.\n"
+        );
+        assert_eq!(format_source(&formatted, 0).unwrap(), formatted);
+    }
+
+    #[test]
+    fn keeps_details_blocks() {
+        let source = "T\n\n===\n\n+++  Spoiler\n\nThe  butler.\n\n+++\n";
+        let formatted = format_source(source, 0).unwrap();
+        assert_eq!(formatted, "T\n\n===\n\n+++ Spoiler\n\nThe butler.\n\n+++\n");
+        assert_eq!(format_source(&formatted, 0).unwrap(), formatted);
+    }
+
+    #[test]
+    fn keeps_table_cell_spans() {
+        let source = "T\n\n===\n\n| A | < | \\< |\n| B | C | ^ |\n| ^ | D | E |\n";
+        let formatted = format_source(source, 0).unwrap();
+        assert_eq!(
+            formatted,
+            "T\n\n===\n\n| A   | <   | \\<  |\n| --- | --- | --- |\n| B   | C   | ^   |\n| ^   | D   | E   |\n"
+        );
+    }
+
+    #[test]
+    fn keeps_custom_heading_ids() {
+        let source = "T\n\n===\n\n#  Setup   {#install}\n";
+        let formatted = format_source(source, 0).unwrap();
+        assert_eq!(formatted, "T\n\n===\n\n# Setup {#install}\n");
+    }
+
+    #[test]
+    fn keeps_include_lines_unexpanded() {
+        let source = "T\n\n===\n\ninclude  parts/my_bio.dllu\n";
+        let formatted = format_source(source, 0).unwrap();
+        assert_eq!(formatted, "T\n\n===\n\ninclude parts/my_bio.dllu\n");
+    }
+
+    #[test]
+    fn opens_code_block_directives_with_a_lang_line() {
+        let source = "T\n\n===\n\n~~~\nlang\nfile build.rs\nhl 1\nfn build() {}\n~~~\n\n~~~\nfile foo.bin\n~~~\n";
+        let formatted = format_source(source, 0).unwrap();
+        assert_eq!(
+            formatted,
+            "T\n\n===\n\n~~~\nlang\nfile build.rs\nhl 1\nfn build() {}\n~~~\n\n~~~~\nfile foo.bin\n~~~~\n"
+        );
+    }
+
+    #[test]
+    fn writes_comments_as_comment_lines() {
+        let source = "T\n\n===\n\n??? comment\nTODO\n\n  indented\n???\n\n%%  spaced\n";
+        let formatted = format_source(source, 0).unwrap();
+        assert_eq!(
+            formatted,
+            "T\n\n===\n\n%% TODO\n%%\n%%   indented\n\n%%  spaced\n"
+        );
+    }
+
+    #[test]
+    fn escapes_markup_characters_in_text() {
+        let source = "T\n\n===\n\nA \\_literal\\_ \\$5 and \\(#x) and \\**stars and \\{fr|x} {de|a \\} b} {{x-y}}\n";
+        let formatted = format_source(source, 0).unwrap();
+        assert_eq!(formatted, source);
+    }
+
+    #[test]
+    fn refuses_to_drop_malformed_lines() {
+        assert!(format_source("T\n\n===\n\npic missing-caption.jpg\n", 0).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+//! Rendered HTML of blocks kept on disk as `fragments/<hash>.html` in
+//! `images.cache_dir` when `build.fragment_cache` is on, so rebuilding an
+//! edited page only renders the blocks that changed. The hash covers the
+//! block, the page state it can read and the settings that change the output;
+//! `HtmlRenderer` decides which blocks are safe to take from the cache.
+
+use crate::config;
+use crate::disk_cache::DiskCache;
+use std::path::Path;
+
+/// The cache for `config`, or `None` when `build.fragment_cache` is off.
+pub fn for_config(config: &config::Config) -> Option<DiskCache> {
+    if !config.build.fragment_cache {
+        return None;
+    }
+    // The version too, since a new release may render the same block
+    // differently, and `[images]` for the image URLs a block can hold
+    let settings = format!(
+        "{} {:?} {:?} {:?} {:?} {:?}",
+        env!("CARGO_PKG_VERSION"),
+        config.root_url,
+        config.html,
+        config.images,
+        config.math,
+        config.vars
+    );
+    Some(DiskCache::new(
+        Path::new(&config.images.cache_dir).join("fragments"),
+        "html",
+        settings,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_settings_are_part_of_the_key() {
+        let mut config = config::Config::default();
+        assert!(for_config(&config).is_none());
+        config.build.fragment_cache = true;
+        let before = for_config(&config).unwrap();
+        config.images.sizes = vec![640];
+        let after = for_config(&config).unwrap();
+        assert_ne!(before.settings(), after.settings());
+    }
+}
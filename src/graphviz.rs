@@ -0,0 +1,62 @@
+//! `~~~ lang dot` graphs drawn at build time by `html.graphviz_command`
+//! (`dot -Tsvg` by default), which reads the graph on stdin and prints SVG.
+//! Each drawing is cached in `graphviz/` by a hash of the command and the
+//! graph, so Graphviz only runs for new or changed graphs.
+
+use crate::disk_cache::DiskCache;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// SVG markup of the graph, ready to put in a page.
+pub fn render_svg(code: &str, command: &str, cache_dir: &Path) -> Result<String, String> {
+    let cache = DiskCache::new(cache_dir.join("graphviz"), "svg", command.to_string());
+    let svg = match cache.get(code) {
+        Some(svg) => svg,
+        None => {
+            let svg = String::from_utf8_lossy(&run(command, code)?).into_owned();
+            cache.put(code, &svg);
+            svg
+        }
+    };
+    // The XML declaration, doctype and comments before the element may not
+    // appear inside HTML
+    Ok(match svg.find("<svg") {
+        Some(start) => svg[start..].trim_end().to_string(),
+        None => svg,
+    })
+}
+
+fn run(command: &str, code: &str) -> Result<Vec<u8>, String> {
+    let parts = shell_words::split(command)
+        .map_err(|e| format!("invalid graphviz command `{}`: {}", command, e))?;
+    let (program, args) = parts
+        .split_first()
+        .ok_or_else(|| "empty graphviz command".to_string())?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run `{}`: {}", command, e))?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or("failed to open graphviz command stdin")?;
+    let input = code.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("failed waiting for `{}`: {}", command, e))?;
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(format!(
+            "`{}` failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
@@ -1,19 +1,37 @@
 use crate::ast::*;
 use crate::config;
+use crate::disk_cache::DiskCache;
+use crate::emoji;
 use crate::image_processor;
-use crate::math_engine::{ExternalCmdEngine, MathEngine};
-use inkjet::formatter::ThemedHtml;
-use inkjet::theme::vendored::ONEDARKER;
-use inkjet::theme::Theme;
+use crate::math_cache::MathCache;
+use crate::math_engine::{
+    ExternalCmdEngine, FallbackEngine, KatexOptions, KatexPool, MathEngine, PooledKatexEngine,
+    TypstEngine,
+};
+use crate::xref::SiteReferences;
+use inkjet::constants::HIGHLIGHT_NAMES;
+use inkjet::formatter::{Formatter, ThemedHtml};
+use inkjet::theme::{vendored, Modifier, Theme};
+use inkjet::tree_sitter_highlight::HighlightEvent;
 use inkjet::{Highlighter, Language};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct HtmlRenderer {
     engine: Option<Box<dyn MathEngine>>, // external command or none
     memo_math: std::collections::HashMap<(bool, String), String>,
+    /// Equations rendered by earlier builds or `warm-math`.
+    math_cache: Option<MathCache>,
+    /// Blocks rendered by earlier builds, with `build.fragment_cache`.
+    fragment_cache: Option<DiskCache>,
+    /// Digest of the page state that cached blocks can read, set by `render`.
+    fragment_page_key: String,
     config: config::Config,
     toc: Vec<TocEntry>,
     section_counters: Vec<usize>,
@@ -21,14 +39,66 @@ pub struct HtmlRenderer {
     meta_image: Option<String>,
     image_processor: image_processor::ImageProcessor,
     asset_root: PathBuf,
+    page_url: Option<String>,
+    /// Show figures' EXIF panels expanded, as on photo stream posts.
+    exif_open: bool,
+    page_vars: BTreeMap<String, String>,
+    site_data: Arc<serde_json::Value>,
+    /// Targets of the site's other pages, for `(#page:label)` references.
+    site_references: Arc<SiteReferences>,
+    uses_copy_button: bool,
+    /// An `embed` was rendered as a click-to-load facade that needs its script.
+    uses_embed_facade: bool,
+    /// A figure was linked for `html.lightbox = "script"`, which needs its viewer.
+    uses_lightbox: bool,
+    /// A Mermaid diagram is left for the browser to draw.
+    uses_mermaid: bool,
+    /// `\label` names of display equations mapped to their 1-based number.
+    equation_labels: std::collections::HashMap<String, usize>,
+    /// Equations every math engine failed on during the last `render`.
+    math_failures: Vec<String>,
+    image_errors: Vec<(String, String)>,
+    resizes_deferred: bool,
+    /// Figure images emitted so far in the current `render`.
+    figures_rendered: usize,
+    /// `html.paragraph_ids` anchor of the top-level paragraph being rendered.
+    paragraph_id: Option<String>,
+    /// Figure images of the current `render`, in page order.
+    page_images: Vec<PageImage>,
 }
 
-#[derive(Debug, Clone)]
+/// An image shown by a rendered page, for the `{{preload}}` and `{{images}}`
+/// template placeholders and the sitemap's image entries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageImage {
+    /// The `<img src>`, with `root_url` applied.
+    pub src: String,
+    /// Resized variants as an `srcset` list, for processed images.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub srcset: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sizes: Option<String>,
+    /// Display size, when the image could be measured.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub alt: String,
+    /// The original, or the largest variant linked, for image search.
+    pub full_size: String,
+    /// The figure caption as plain text.
+    pub caption: String,
+}
+
+/// One heading, also the element of the `html.toc_json` array.
+#[derive(Debug, Clone, Serialize)]
 struct TocEntry {
     level: usize,
     title: String,
+    #[serde(rename = "number")]
     numbering_label: String,
+    #[serde(rename = "id")]
     anchor_id: String,
+    /// Id of the heading's slug anchor, which stays put when sections are renumbered.
+    slug: String,
 }
 
 impl HtmlRenderer {
@@ -48,6 +118,9 @@ impl HtmlRenderer {
         Self {
             engine: Self::make_engine_from_config(config),
             memo_math: std::collections::HashMap::new(),
+            math_cache: MathCache::new(config),
+            fragment_cache: crate::fragment_cache::for_config(config),
+            fragment_page_key: String::new(),
             config: config.clone(),
             toc: Vec::new(),
             section_counters: Vec::new(),
@@ -55,29 +128,128 @@ impl HtmlRenderer {
             meta_image: None,
             image_processor: image_processor::ImageProcessor::new(config),
             asset_root,
+            page_url: None,
+            exif_open: false,
+            page_vars: BTreeMap::new(),
+            site_data: Arc::new(serde_json::Value::Null),
+            site_references: Arc::default(),
+            uses_copy_button: false,
+            uses_embed_facade: false,
+            uses_lightbox: false,
+            uses_mermaid: false,
+            equation_labels: std::collections::HashMap::new(),
+            math_failures: Vec::new(),
+            image_errors: Vec::new(),
+            resizes_deferred: false,
+            figures_rendered: 0,
+            paragraph_id: None,
+            page_images: Vec::new(),
+        }
+    }
+
+    /// Drops the math engine so equations fall back to escaped TeX.
+    pub fn without_math_engine(mut self) -> Self {
+        self.engine = None;
+        self
+    }
+
+    /// Absolute URL of the page being rendered, used for self-referencing markup.
+    pub fn set_page_url(&mut self, url: Option<String>) {
+        self.page_url = url;
+    }
+
+    /// ` data-permalink="<page url>#<id>"` for an already-escaped element id, when
+    /// `html.data_permalinks` is on and the page URL is known; empty otherwise.
+    fn permalink_attr(&self, escaped_id: &str) -> String {
+        match &self.page_url {
+            Some(url) if self.config.html.data_permalinks => format!(
+                " data-permalink=\"{}#{}\"",
+                html_escape_attr(url),
+                escaped_id
+            ),
+            _ => String::new(),
+        }
+    }
+
+    /// Data files exposed as `{{data.file.key}}` in article text.
+    /// Expands the EXIF panels of figures, for the posts of a photo stream.
+    pub fn set_exif_open(&mut self, open: bool) {
+        self.exif_open = open;
+    }
+
+    pub fn set_site_data(&mut self, data: Arc<serde_json::Value>) {
+        self.site_data = data;
+    }
+
+    pub fn set_site_references(&mut self, references: Arc<SiteReferences>) {
+        self.site_references = references;
+    }
+
+    /// TeX of equations that no engine could render in the last `render`, with
+    /// the reason; these were emitted as escaped TeX.
+    pub fn math_failures(&self) -> &[String] {
+        &self.math_failures
+    }
+
+    /// `(reference, error)` for each figure image that could not be processed in
+    /// the last `render`; those figures link the reference as given.
+    pub fn image_errors(&self) -> &[(String, String)] {
+        &self.image_errors
+    }
+
+    /// ` loading="lazy" decoding="async"` for the next figure image, unless it is
+    /// one of the first `images.eager_figures` on the page (likely above the
+    /// fold, where lazy loading delays the largest paint) or lazy loading is off.
+    fn image_loading_attrs(&mut self) -> &'static str {
+        self.figures_rendered += 1;
+        let images = &self.config.images;
+        if images.lazy_loading && self.figures_rendered > images.eager_figures {
+            " loading=\"lazy\" decoding=\"async\""
+        } else {
+            ""
         }
     }
 
+    /// Whether a figure in the last `render` links its original because its
+    /// resizing was left for a later build (`images.resize_budget_mb`).
+    pub fn resizes_deferred(&self) -> bool {
+        self.resizes_deferred
+    }
+
     fn make_engine_from_config(config: &config::Config) -> Option<Box<dyn MathEngine>> {
-        // Prefer V8 engine if built-in feature is enabled
-        // Prefer persistent katex node process if available
-        if config.math.prefer_persistent {
-            match crate::math_engine::PersistentKatexEngine::spawn() {
-                Ok(engine) => return Some(Box::new(engine)),
-                Err(e) => eprintln!("Failed to spawn persistent KaTeX: {}. Falling back.", e),
+        match config.math.engine {
+            config::MathEngineKind::Auto => {}
+            config::MathEngineKind::Persistent => {
+                return match persistent_engine(config) {
+                    Ok(engine) => Some(Box::new(engine)),
+                    Err(e) => {
+                        crate::diagnostics::warn(format!(
+                            "Failed to spawn persistent KaTeX: {}. Emitting raw TeX.",
+                            e
+                        ));
+                        None
+                    }
+                };
             }
+            config::MathEngineKind::Command => return Some(Box::new(command_engine(config))),
+            config::MathEngineKind::Native => return native_engine(config),
+            config::MathEngineKind::Typst => return Some(Box::new(typst_engine(config))),
+            config::MathEngineKind::None => return None,
         }
-        if let Some(command) = &config.math.command {
-            let parts = shell_words::split(command).unwrap_or_else(|_| vec![command.clone()]);
-            return Some(Box::new(ExternalCmdEngine { cmd: parts }));
-        }
-        // Default to persistent node engine; if that fails, fallback to npx katex
-        if let Ok(engine) = crate::math_engine::PersistentKatexEngine::spawn() {
-            return Some(Box::new(engine));
+        // Persistent KaTeX first (unless an explicit command is preferred), then
+        // the external command; equations both fail on are emitted as escaped TeX.
+        let mut engines: Vec<Box<dyn MathEngine>> = Vec::new();
+        if config.math.prefer_persistent || config.math.command.is_none() {
+            match persistent_engine(config) {
+                Ok(engine) => engines.push(Box::new(engine)),
+                Err(e) => crate::diagnostics::warn(format!(
+                    "Failed to spawn persistent KaTeX: {}. Falling back.",
+                    e
+                )),
+            }
         }
-        Some(Box::new(ExternalCmdEngine {
-            cmd: vec!["npx".into(), "katex".into()],
-        }))
+        engines.push(Box::new(command_engine(config)));
+        Some(Box::new(FallbackEngine { engines }))
     }
 
     pub fn render(&mut self, article: &Article) -> String {
@@ -85,16 +257,76 @@ impl HtmlRenderer {
         self.section_counters.clear();
         self.meta_description = None;
         self.meta_image = None;
+        self.uses_copy_button = false;
+        self.uses_embed_facade = false;
+        self.uses_lightbox = false;
+        self.uses_mermaid = false;
+        self.math_failures.clear();
+        self.image_errors.clear();
+        self.resizes_deferred = false;
+        self.figures_rendered = 0;
+        self.page_images.clear();
+        self.equation_labels = article
+            .blocks()
+            .into_iter()
+            .filter_map(|block| match block {
+                Block::DisplayMath {
+                    id: Some(id),
+                    id_number,
+                    ..
+                } => Some((id.clone(), id_number + 1)),
+                _ => None,
+            })
+            .collect();
+        self.page_vars = article
+            .header
+            .as_ref()
+            .map(|h| h.vars.clone())
+            .unwrap_or_default();
+        if self.fragment_cache.is_some() {
+            let labels: BTreeMap<_, _> = self.equation_labels.iter().collect();
+            // Whether there is an engine too, so TeX shown without one is not reused
+            let state = format!(
+                "{} {:?} {:?} {:?} {} {:?}",
+                self.engine.is_some(),
+                self.page_url,
+                self.page_vars,
+                labels,
+                self.site_data,
+                self.site_references
+            );
+            self.fragment_page_key = blake3::hash(state.as_bytes()).to_hex().to_string();
+        }
         let mut html = String::new();
+        let microformats = self.config.html.microformats && article.header.is_some();
 
+        if microformats {
+            html.push_str("<article class=\"h-entry\">\n");
+        }
         if let Some(header) = &article.header {
             html.push_str(&self.render_header(header));
         }
+        if microformats {
+            html.push_str("<div class=\"e-content\">\n");
+        }
 
-        for block in &article.body {
-            html.push_str(&self.render_block(block));
+        let paragraph_ids = if self.config.html.paragraph_ids {
+            article.paragraph_ids()
+        } else {
+            Vec::new()
+        };
+        for (index, block) in article.body.iter().enumerate() {
+            self.paragraph_id = paragraph_ids.get(index).cloned().flatten();
+            html.push_str(&self.render_cached_block(block));
+        }
+        self.paragraph_id = None;
+        if self.config.cards.enabled {
+            self.capture_social_card(article);
         }
 
+        if microformats {
+            html.push_str("</div>\n</article>\n");
+        }
         html
     }
 
@@ -131,6 +363,47 @@ impl HtmlRenderer {
         Some(html)
     }
 
+    /// The headings of the last rendered page as a JSON array, for scripts that
+    /// build a floating TOC or reading progress without scraping the page.
+    pub fn table_of_contents_json(&self) -> Option<String> {
+        if self.toc.is_empty() {
+            return None;
+        }
+        serde_json::to_string(&self.toc).ok()
+    }
+
+    /// Figure images of the last rendered page, in page order.
+    pub fn page_images(&self) -> &[PageImage] {
+        &self.page_images
+    }
+
+    /// Inline scripts needed by the last rendered page, for the `{{scripts}}` placeholder.
+    pub fn page_scripts(&self) -> String {
+        let mut scripts = String::new();
+        if self.config.html.toc_json == config::TocJson::Embed {
+            if let Some(json) = self.table_of_contents_json() {
+                // `</` would end the script element early
+                scripts.push_str(&format!(
+                    "<script type=\"application/json\" id=\"toc-data\">{}</script>\n",
+                    json.replace("</", "<\\/")
+                ));
+            }
+        }
+        if self.uses_copy_button {
+            scripts.push_str(COPY_CODE_SCRIPT);
+        }
+        if self.uses_embed_facade {
+            scripts.push_str(EMBED_FACADE_SCRIPT);
+        }
+        if self.uses_lightbox {
+            scripts.push_str(LIGHTBOX_SCRIPT);
+        }
+        if self.uses_mermaid {
+            scripts.push_str(&mermaid_script(&self.config.html.mermaid_script));
+        }
+        scripts
+    }
+
     pub fn meta_tags(&self, title: &str) -> String {
         let mut tags = Vec::new();
         if let Some(image) = &self.meta_image {
@@ -185,6 +458,9 @@ impl HtmlRenderer {
     }
 
     fn render_header(&self, header: &ArticleHeader) -> String {
+        if self.config.html.microformats {
+            return self.render_header_microformats(header);
+        }
         let mut html = String::new();
         html.push_str("<header>\n");
         html.push_str(&format!(
@@ -198,26 +474,197 @@ impl HtmlRenderer {
         html
     }
 
+    fn render_header_microformats(&self, header: &ArticleHeader) -> String {
+        let mut html = String::new();
+        html.push_str("<header>\n");
+        html.push_str(&format!(
+            "<h1 id=\"top\" class=\"p-name\">{}</h1>\n",
+            escape_html(&header.title)
+        ));
+        if let Some(date) = &header.date {
+            let trimmed = date.trim();
+            let datetime_attr = if is_iso_date(trimmed) {
+                format!(" datetime=\"{}\"", html_escape_attr(trimmed))
+            } else {
+                String::new()
+            };
+            html.push_str(&format!(
+                "<p class=\"date\"><time class=\"dt-published\"{}>{}</time></p>\n",
+                datetime_attr,
+                escape_html(date)
+            ));
+        }
+        if let Some(url) = &self.page_url {
+            html.push_str(&format!(
+                "<data class=\"u-url\" value=\"{}\"></data>\n",
+                html_escape_attr(url)
+            ));
+        }
+        html.push_str("</header>\n");
+        html
+    }
+
+    /// `render_block` through the fragment cache. Only blocks whose HTML
+    /// depends on nothing but the block, the page key and the paragraph id are
+    /// cached; headings, figures and embeds also number sections and collect
+    /// images, so they always render.
+    fn render_cached_block(&mut self, block: &Block) -> String {
+        if self.fragment_cache.is_none() || !cacheable(block) {
+            return self.render_block(block);
+        }
+        let key = format!(
+            "{}\n{:?}\n{:?}",
+            self.fragment_page_key, self.paragraph_id, block
+        );
+        if let Some(html) = self.fragment_cache.as_ref().and_then(|c| c.get(&key)) {
+            self.paragraph_id = None;
+            self.replay_cached_block(block);
+            return html;
+        }
+        let failures = self.math_failures.len();
+        let html = self.render_block(block);
+        // Equations shown as TeX are left out so the next build tries them again
+        if self.math_failures.len() == failures {
+            if let Some(cache) = &self.fragment_cache {
+                cache.put(&key, &html);
+            }
+        }
+        html
+    }
+
+    /// The page state that rendering `block` records, for a cached block.
+    fn replay_cached_block(&mut self, block: &Block) {
+        match block {
+            Block::Paragraph(elements) => self.capture_description(elements),
+            Block::CodeBlock { .. } => self.uses_copy_button |= self.config.html.copy_button,
+            Block::UnorderedList(items) | Block::OrderedList(items) => {
+                for block in items.iter().flat_map(|item| &item.blocks) {
+                    self.replay_cached_block(block);
+                }
+            }
+            Block::Details { blocks, .. } => {
+                for block in blocks {
+                    self.replay_cached_block(block);
+                }
+            }
+            _ => {}
+        }
+    }
+
     fn render_block(&mut self, block: &Block) -> String {
         match block {
-            Block::Raw(content) => content.to_string(),
-            Block::CodeBlock { language, code } => {
-                self.render_code_block(language.as_deref(), code)
+            Block::Raw { format, content } if format == "html" => content.to_string(),
+            // An include the parser did not follow shows nothing
+            Block::Raw { .. } | Block::Include { .. } => String::new(),
+            Block::CodeBlock {
+                language,
+                code,
+                caption,
+                id,
+                id_number,
+                ..
+            } if is_graphviz(language.as_deref()) => {
+                self.render_graphviz(code, caption.as_deref(), id.as_deref(), *id_number)
             }
-            Block::SectionHeader { level, id, text } => {
-                self.render_section_header(*level, id, text)
+            Block::CodeBlock {
+                language,
+                code,
+                line_numbers,
+                highlight_lines,
+                filename,
+                caption,
+                id,
+                id_number,
+            } => {
+                let is_mermaid = language
+                    .as_deref()
+                    .is_some_and(|language| language.eq_ignore_ascii_case("mermaid"));
+                let pre = if is_mermaid && self.config.html.mermaid != config::Mermaid::Off {
+                    self.render_mermaid(code)
+                } else {
+                    let pre = self.render_code_block(
+                        language.as_deref(),
+                        code,
+                        *line_numbers,
+                        highlight_lines,
+                    );
+                    if self.config.html.copy_button {
+                        self.uses_copy_button = true;
+                        wrap_copy_button(&pre)
+                    } else {
+                        pre
+                    }
+                };
+                self.render_listing(
+                    pre,
+                    filename.as_deref(),
+                    caption.as_deref(),
+                    id.as_deref(),
+                    *id_number,
+                )
             }
+            Block::SectionHeader {
+                level,
+                id,
+                text,
+                custom_id,
+            } => self.render_section_header(*level, id, text, *custom_id),
             Block::BlockQuote(elements) => {
                 let content = self.render_inlines(elements);
                 format!("<blockquote>{}</blockquote>\n", content)
             }
+            Block::Details { summary, blocks } => {
+                let mut out = format!(
+                    "<details class=\"fold\"><summary>{}</summary>\n",
+                    self.render_inlines(summary)
+                );
+                for block in blocks {
+                    out.push_str(&self.render_block(block));
+                }
+                out.push_str("</details>\n");
+                out
+            }
             Block::ImageFigure {
                 url,
                 id,
                 id_number,
                 alt,
+                decorative,
+                text,
+                credit,
+                crop,
+                no_resize,
+            } => {
+                self.capture_cropped_meta_image(url, *crop);
+                let (alt, fallback_caption) = self.figure_fallbacks(alt, *decorative, text);
+                let (alt, text) = (alt.as_ref(), fallback_caption.as_deref().unwrap_or(text));
+                if *no_resize {
+                    self.render_unprocessed_figure(
+                        url,
+                        id.as_deref(),
+                        *id_number,
+                        alt,
+                        text,
+                        credit.as_deref(),
+                    )
+                } else {
+                    self.render_image_figure(
+                        url,
+                        id.as_deref(),
+                        *id_number,
+                        alt,
+                        text,
+                        credit.as_deref(),
+                    )
+                }
+            }
+            Block::Embed {
+                provider,
+                video,
+                id,
+                id_number,
                 text,
-            } => self.render_image_figure(url, id.as_deref(), *id_number, alt, text),
+            } => self.render_embed(*provider, video, id.as_deref(), *id_number, text),
             Block::DisplayMath {
                 id,
                 id_number,
@@ -234,7 +681,7 @@ impl HtmlRenderer {
                 header,
                 rows,
                 caption,
-            } => self.render_table(*id_number, header, rows, caption),
+            } => self.render_table(*id_number, header, rows, caption.as_deref()),
             Block::BigButton { text, url } => {
                 let inner = self.render_inlines(text);
                 let href = self.escape_url(url);
@@ -246,31 +693,170 @@ impl HtmlRenderer {
         }
     }
 
-    fn render_code_block(&self, language: Option<&str>, code: &str) -> String {
+    fn render_code_block(
+        &self,
+        language: Option<&str>,
+        code: &str,
+        line_numbers: Option<bool>,
+        highlight_lines: &[usize],
+    ) -> String {
+        let numbered = line_numbers.unwrap_or(self.config.html.code_line_numbers);
+        let per_line = numbered || !highlight_lines.is_empty();
+        let pre_class = if numbered { " class=\"numbered\"" } else { "" };
         // Try inkjet syntax highlighting; fall back to plain code block
-        match highlight_with_inkjet(language, code) {
+        match highlight_with_inkjet(&self.config.html, language, code) {
+            Some(html) if per_line => match split_pre(&html) {
+                Some((open_tag, inner)) => format!(
+                    "{}{}</pre>\n",
+                    add_pre_class(open_tag, numbered),
+                    wrap_code_lines(inner, numbered, highlight_lines)
+                ),
+                None => html,
+            },
             Some(html) => html,
             None => {
                 let lang_class = language
                     .map(|l| format!(" class=\"language-{}\"", escape_html(l)))
                     .unwrap_or_default();
+                let escaped = escape_html(code);
+                let body = if per_line {
+                    wrap_code_lines(&escaped, numbered, highlight_lines)
+                } else {
+                    escaped
+                };
+                format!(
+                    "<pre{}><code{}>{}</code></pre>\n",
+                    pre_class, lang_class, body
+                )
+            }
+        }
+    }
+
+    /// A Mermaid diagram as an SVG drawn by `mmdc` (`html.mermaid = "server"`),
+    /// or as its source for the browser to draw with `mermaid_script`.
+    fn render_mermaid(&mut self, code: &str) -> String {
+        if self.config.html.mermaid == config::Mermaid::Server {
+            match crate::mermaid::render_svg(code, self.image_processor.cache_dir()) {
+                Ok(svg) => return format!("<div class=\"mermaid-diagram\">{}</div>\n", svg),
+                Err(err) => crate::diagnostics::warn(format!(
+                    "[mermaid] {}; leaving the diagram to the browser",
+                    err
+                )),
+            }
+        }
+        self.uses_mermaid = true;
+        format!("<pre class=\"mermaid\">{}</pre>\n", escape_html(code))
+    }
+
+    /// A `~~~ lang dot` graph as a numbered figure, drawn by
+    /// `html.graphviz_command` or shown as its source when that is empty or fails.
+    fn render_graphviz(
+        &mut self,
+        code: &str,
+        caption: Option<&[InlineElement]>,
+        id: Option<&str>,
+        id_number: usize,
+    ) -> String {
+        let command = self.config.html.graphviz_command.trim();
+        let svg = if command.is_empty() {
+            None
+        } else {
+            match crate::graphviz::render_svg(code, command, self.image_processor.cache_dir()) {
+                Ok(svg) => Some(svg),
+                Err(err) => {
+                    crate::diagnostics::warn(format!(
+                        "[graphviz] {}; showing the graph's source",
+                        err
+                    ));
+                    None
+                }
+            }
+        };
+        let drawing = svg.unwrap_or_else(|| {
+            format!(
+                "<pre class=\"graphviz\"><code>{}</code></pre>",
+                escape_html(code)
+            )
+        });
+        let (fig_id_attr, caption_html) =
+            self.figure_caption(id, id_number, caption.unwrap_or_default(), None);
+        format!(
+            "<figure id=\"{}\" class=\"diagram\"{}>{}<figcaption>{}</figcaption></figure>\n",
+            fig_id_attr,
+            self.permalink_attr(&fig_id_attr),
+            drawing,
+            caption_html
+        )
+    }
+
+    fn render_listing(
+        &mut self,
+        pre: String,
+        filename: Option<&str>,
+        caption: Option<&[InlineElement]>,
+        id: Option<&str>,
+        id_number: usize,
+    ) -> String {
+        if filename.is_none() && caption.is_none() {
+            return pre;
+        }
+        let filename_html = filename
+            .map(|name| format!("<code class=\"filename\">{}</code>", escape_html(name)))
+            .unwrap_or_default();
+        match caption {
+            Some(caption) => {
+                let listing_num = id_number + 1;
+                let listing_id = id
+                    .map(escape_html)
+                    .unwrap_or_else(|| format!("listing{}", listing_num));
+                let caption_html = self.render_inlines(caption);
+                let mut parts = vec![format!(
+                    "<a href=\"#{}\" class=\"fignum\">Listing {}</a>",
+                    listing_id, listing_num
+                )];
+                if !filename_html.is_empty() {
+                    parts.push(filename_html);
+                }
+                let caption_html = caption_html.trim();
+                if !caption_html.is_empty() {
+                    parts.push(caption_html.to_string());
+                }
                 format!(
-                    "<pre><code{}>{}</code></pre>\n",
-                    lang_class,
-                    escape_html(code)
+                    "<figure id=\"{}\" class=\"listing\"{}><figcaption>{}</figcaption>{}</figure>\n",
+                    listing_id,
+                    self.permalink_attr(&listing_id),
+                    parts.join(" "),
+                    pre.trim_end()
                 )
             }
+            None => format!(
+                "<figure class=\"listing\"><figcaption>{}</figcaption>{}</figure>\n",
+                filename_html,
+                pre.trim_end()
+            ),
         }
     }
 
-    fn render_section_header(&mut self, level: usize, id: &str, text: &str) -> String {
+    fn render_section_header(
+        &mut self,
+        level: usize,
+        id: &str,
+        text: &str,
+        custom_id: bool,
+    ) -> String {
         let level = std::cmp::min(level, 6);
         let tag = format!("h{}", level);
-        let (anchor_id, numbering_label) = self.register_section(level, text);
+        let (anchor_id, numbering_label) = self.register_section(level, id, text, custom_id);
         let escaped_anchor = escape_html(&anchor_id);
         let escaped_number = escape_html(&numbering_label);
         let escaped_title = escape_html(text);
-        let escaped_slug = escape_html(id);
+        // The id that is not the heading's own still leads to it
+        let other_id = if custom_id {
+            format!("s{}", numbering_label)
+        } else {
+            id.to_string()
+        };
+        let escaped_slug = escape_html(&other_id);
         let slug_anchor = if escaped_slug != escaped_anchor {
             format!(
                 "<span id=\"{}\" class=\"section-anchor\" aria-hidden=\"true\"></span>",
@@ -280,8 +866,9 @@ impl HtmlRenderer {
             String::new()
         };
         format!(
-            "<{tag} id=\"{anchor}\">{slug_anchor}<a href=\"#{anchor}\" class=\"hnum\">{number}</a> <span>{title}</span></{tag}>\n",
+            "<{tag} id=\"{anchor}\"{permalink}>{slug_anchor}<a href=\"#{anchor}\" class=\"hnum\">{number}</a> <span>{title}</span></{tag}>\n",
             tag = tag,
+            permalink = self.permalink_attr(&escaped_anchor),
             anchor = escaped_anchor,
             slug_anchor = slug_anchor,
             number = escaped_number,
@@ -289,26 +876,26 @@ impl HtmlRenderer {
         )
     }
 
-    fn register_section(&mut self, level: usize, text: &str) -> (String, String) {
+    fn register_section(
+        &mut self,
+        level: usize,
+        slug: &str,
+        text: &str,
+        custom_id: bool,
+    ) -> (String, String) {
         let level = level.clamp(1, 6);
-        if self.section_counters.len() < level {
-            self.section_counters.resize(level, 0);
-        }
-        self.section_counters[level - 1] += 1;
-        for idx in level..self.section_counters.len() {
-            self.section_counters[idx] = 0;
-        }
-        let numbering_label = self.section_counters[..level]
-            .iter()
-            .map(|n| n.to_string())
-            .collect::<Vec<_>>()
-            .join(".");
-        let anchor_id = format!("s{}", numbering_label);
+        let numbering_label = next_section_number(&mut self.section_counters, level);
+        let anchor_id = if custom_id {
+            slug.to_string()
+        } else {
+            format!("s{}", numbering_label)
+        };
         self.toc.push(TocEntry {
             level,
             title: text.to_string(),
             numbering_label: numbering_label.clone(),
             anchor_id: anchor_id.clone(),
+            slug: slug.to_string(),
         });
         (anchor_id, numbering_label)
     }
@@ -335,6 +922,82 @@ impl HtmlRenderer {
         self.meta_image = Some(resolved.into_owned());
     }
 
+    /// Uses a crop of the page's first figure as the social card image when
+    /// `images.meta_crop` or the figure's `crop=` hint asks for one.
+    fn capture_cropped_meta_image(&mut self, url: &str, crop: Option<Crop>) {
+        if self.meta_image.is_some() {
+            return;
+        }
+        let Some(crop) = self.config.images.meta_image_crop(crop) else {
+            return;
+        };
+        let Ok(processed) = self.image_processor.process(url, &self.asset_root) else {
+            return;
+        };
+        let images = &self.config.images;
+        let width = images.meta_size.unwrap_or(images.layout_width);
+        if let Ok(Some(variant)) =
+            self.image_processor
+                .crop(&processed, &self.asset_root, crop, width)
+        {
+            self.capture_image(&variant.url);
+        }
+    }
+
+    /// Swaps in a generated `[cards]` card when the page has no figure fit for
+    /// `og:image`: the first figure letterboxed when it is a portrait photo
+    /// that no crop applies to, else a title card when there is no figure or
+    /// it is one social sites can't show (SVG, or one that failed to load).
+    fn capture_social_card(&mut self, article: &Article) {
+        let Some(header) = &article.header else {
+            return;
+        };
+        let figure = article.blocks().into_iter().find_map(|block| match block {
+            Block::ImageFigure { url, crop, .. } => Some((url.as_str(), *crop)),
+            _ => None,
+        });
+        let processed = figure.and_then(|(url, _)| {
+            self.image_processor
+                .process(url, &self.asset_root)
+                .ok()
+                .and_then(|processed| processed.original)
+        });
+        let photo = match (figure, processed) {
+            (Some((_, crop)), _) if self.config.images.meta_image_crop(crop).is_some() => return,
+            (Some(_), Some(original)) if original.mime_type == "image/svg+xml" => None,
+            (Some((url, _)), Some(original)) if original.width < original.height => {
+                if image_processor::is_remote(url) {
+                    return;
+                }
+                Some(image_processor::local_source_path(url, &self.asset_root))
+            }
+            (Some(_), Some(_)) if self.meta_image.is_some() => return,
+            _ => None,
+        };
+        let cards = &self.config.cards;
+        let feed_title = self.config.feed.channel_title.as_deref();
+        let content = match &photo {
+            Some(path) => crate::social_card::CardContent::Photo(path),
+            None => crate::social_card::CardContent::Title {
+                title: header.title.trim(),
+                site_name: cards.site_name.as_deref().or(feed_title),
+            },
+        };
+        let cache_dir = self.image_processor.cache_dir();
+        match crate::social_card::generate(cards, &content, cache_dir) {
+            Ok(path) => {
+                let url = self.image_processor.public_url_for(&path);
+                self.meta_image = None;
+                self.capture_image(&url);
+            }
+            Err(err) => crate::diagnostics::warn(format!(
+                "[cards] no card for {:?}: {}",
+                header.title.trim(),
+                err
+            )),
+        }
+    }
+
     fn capture_meta_image_from_variants(
         &mut self,
         variants: &[(&image_processor::ImageVariant, bool)],
@@ -385,33 +1048,119 @@ impl HtmlRenderer {
             .map(|(variant, _)| variant.url.clone())
     }
 
-    fn render_image_figure(
+    /// A figure whose image is linked as written (`resize=off`), skipping the
+    /// image pipeline.
+    fn render_unprocessed_figure(
         &mut self,
         url: &str,
         id: Option<&str>,
         id_number: usize,
         alt: &str,
         text: &[InlineElement],
+        credit: Option<&[InlineElement]>,
     ) -> String {
+        let (fig_id_attr, caption_html) = self.figure_caption(id, id_number, text, credit);
+        self.capture_image(url);
+        let images_before = self.page_images.len();
+        let figure = self.render_image_figure_fallback(url, None, &fig_id_attr, alt, &caption_html);
+        self.set_page_image_caption(images_before, text);
+        figure
+    }
+
+    /// A figure's alt text and caption after `html.alt_from_caption` and
+    /// `html.caption_from_alt` fill in whichever one is empty; decorative
+    /// figures keep their empty alt text. The caption is `None` when unchanged.
+    fn figure_fallbacks<'a>(
+        &self,
+        alt: &'a str,
+        decorative: bool,
+        caption: &[InlineElement],
+    ) -> (Cow<'a, str>, Option<Vec<InlineElement>>) {
+        let html = &self.config.html;
+        let caption_text =
+            crate::excerpt::collapse_whitespace(&crate::excerpt::plain_text(caption));
+        if alt.trim().is_empty() && !decorative && html.alt_from_caption {
+            return (Cow::Owned(caption_text), None);
+        }
+        if caption_text.is_empty() && !alt.trim().is_empty() && html.caption_from_alt {
+            let caption = vec![InlineElement::Text(alt.trim().to_string())];
+            return (Cow::Borrowed(alt), Some(caption));
+        }
+        (Cow::Borrowed(alt), None)
+    }
+
+    /// The figure's id attribute and its numbered caption with any credit line.
+    fn figure_caption(
+        &mut self,
+        id: Option<&str>,
+        id_number: usize,
+        text: &[InlineElement],
+        credit: Option<&[InlineElement]>,
+    ) -> (String, String) {
         let fig_id_num = id_number + 1;
         let fig_id_attr = id
             .map(escape_html)
             .unwrap_or_else(|| format!("fig{}", fig_id_num));
 
-        let caption_html = self.render_inlines(text);
-        match self.image_processor.process(url, &self.asset_root) {
-            Ok(processed) if processed.original.is_some() || !processed.variants.is_empty() => self
-                .render_processed_figure(processed, &fig_id_attr, fig_id_num, alt, &caption_html),
-            Ok(_) => {
-                eprintln!("image processing produced no variants for {}", url);
+        let mut caption_html = format!(
+            "<p><a href=\"#{}\" class=\"fignum\">FIGURE {}</a> {}</p>",
+            fig_id_attr,
+            fig_id_num,
+            // Without the space left before an invisible `[#label]`
+            self.render_inlines(text).trim()
+        );
+        if let Some(credit) = credit {
+            caption_html.push_str(&format!(
+                "<p class=\"credit\"><small>{}</small></p>",
+                self.render_inlines(credit)
+            ));
+        }
+        (fig_id_attr, caption_html)
+    }
+
+    fn render_image_figure(
+        &mut self,
+        url: &str,
+        id: Option<&str>,
+        id_number: usize,
+        alt: &str,
+        text: &[InlineElement],
+        credit: Option<&[InlineElement]>,
+    ) -> String {
+        let (fig_id_attr, caption_html) = self.figure_caption(id, id_number, text, credit);
+        let processed = self.image_processor.process(url, &self.asset_root);
+        if let Ok(processed) = &processed {
+            self.resizes_deferred |= processed.deferred;
+        }
+        let images_before = self.page_images.len();
+        let figure = match processed {
+            Ok(processed) if !processed.videos.is_empty() => {
+                self.render_video_figure(processed, &fig_id_attr, alt, &caption_html)
+            }
+            Ok(processed) if processed.original.is_some() || !processed.variants.is_empty() => {
+                self.render_processed_figure(processed, &fig_id_attr, alt, &caption_html)
+            }
+            Ok(processed) => {
+                self.image_errors
+                    .push((url.to_string(), "processing produced no variants".into()));
                 self.capture_image(url);
-                self.render_image_figure_fallback(url, &fig_id_attr, fig_id_num, alt, &caption_html)
+                let size = (processed.display_width, processed.display_height.max(1));
+                self.render_image_figure_fallback(url, Some(size), &fig_id_attr, alt, &caption_html)
             }
             Err(err) => {
-                eprintln!("image processing error for {}: {}", url, err);
+                self.image_errors.push((url.to_string(), err.to_string()));
                 self.capture_image(url);
-                self.render_image_figure_fallback(url, &fig_id_attr, fig_id_num, alt, &caption_html)
+                self.render_image_figure_fallback(url, None, &fig_id_attr, alt, &caption_html)
             }
+        };
+        self.set_page_image_caption(images_before, text);
+        figure
+    }
+
+    /// Fills in the caption of the page image a figure recorded, if it recorded one.
+    fn set_page_image_caption(&mut self, index: usize, text: &[InlineElement]) {
+        if let Some(image) = self.page_images.get_mut(index) {
+            image.caption = crate::excerpt::collapse_whitespace(&crate::excerpt::plain_text(text));
         }
     }
 
@@ -419,7 +1168,6 @@ impl HtmlRenderer {
         &mut self,
         processed: image_processor::ProcessedImage,
         fig_id_attr: &str,
-        fig_id_num: usize,
         alt: &str,
         caption_html: &str,
     ) -> String {
@@ -437,12 +1185,13 @@ impl HtmlRenderer {
             available_variants.push((original_variant, true));
         }
 
+        let size = (processed.display_width, processed.display_height.max(1));
         if available_variants.is_empty() {
             self.capture_image(&processed.original_reference);
             return self.render_image_figure_fallback(
                 &processed.original_reference,
+                Some(size),
                 fig_id_attr,
-                fig_id_num,
                 alt,
                 caption_html,
             );
@@ -458,7 +1207,12 @@ impl HtmlRenderer {
         } else {
             ""
         };
-        figure.push_str(&format!("<figure id=\"{}\"{}>", fig_id_attr, class_attr));
+        figure.push_str(&format!(
+            "<figure id=\"{}\"{}{}>",
+            fig_id_attr,
+            class_attr,
+            self.permalink_attr(fig_id_attr)
+        ));
 
         let mut srcset_entries: Vec<(u32, String)> = Vec::new();
         for width in &self.config.images.display_sizes {
@@ -473,7 +1227,7 @@ impl HtmlRenderer {
                 {
                     continue;
                 }
-                srcset_entries.push((*width, self.escape_url(&variant.url)));
+                srcset_entries.push((*width, self.url_with_root(&variant.url).into_owned()));
             }
         }
         let max_display_size = self
@@ -501,7 +1255,7 @@ impl HtmlRenderer {
                                 pos,
                                 (
                                     original_variant.width,
-                                    self.escape_url(&original_variant.url),
+                                    self.url_with_root(&original_variant.url).into_owned(),
                                 ),
                             );
                         }
@@ -509,6 +1263,28 @@ impl HtmlRenderer {
                 }
             }
         }
+        // Each pixel density of the display width, capped at the original's width
+        for density in &self.config.images.densities {
+            let target = processed.display_width.saturating_mul(*density);
+            let Some((variant, _)) = available_variants
+                .iter()
+                .filter(|(variant, _)| variant.width <= target)
+                .max_by_key(|(variant, _)| variant.width)
+            else {
+                continue;
+            };
+            if variant.width < target && variant.width < processed.display_width {
+                continue;
+            }
+            if let Err(pos) =
+                srcset_entries.binary_search_by_key(&variant.width, |(width, _)| *width)
+            {
+                srcset_entries.insert(
+                    pos,
+                    (variant.width, self.url_with_root(&variant.url).into_owned()),
+                );
+            }
+        }
         if srcset_entries.is_empty() {
             for (variant, _) in &available_variants {
                 if srcset_entries
@@ -518,15 +1294,15 @@ impl HtmlRenderer {
                 {
                     continue;
                 }
-                srcset_entries.push((variant.width, self.escape_url(&variant.url)));
+                srcset_entries.push((variant.width, self.url_with_root(&variant.url).into_owned()));
             }
         }
         if srcset_entries.is_empty() {
             self.capture_image(&processed.original_reference);
             return self.render_image_figure_fallback(
                 &processed.original_reference,
+                Some(size),
                 fig_id_attr,
-                fig_id_num,
                 alt,
                 caption_html,
             );
@@ -547,25 +1323,100 @@ impl HtmlRenderer {
             .join(", ");
 
         let (_, fallback_url) = &srcset_entries[0];
+        let full_size = match &processed.original {
+            Some(original) => self.url_with_root(&original.url).into_owned(),
+            None => srcset_entries[srcset_entries.len() - 1].1.clone(),
+        };
+        let lightbox_link = self.lightbox_link(
+            &full_size,
+            processed
+                .original
+                .as_ref()
+                .map(|original| (original.width, original.height)),
+        );
+        self.page_images.push(PageImage {
+            src: fallback_url.clone(),
+            srcset: Some(srcset.clone()),
+            sizes: Some(sizes_attr.clone()),
+            width: Some(size.0),
+            height: Some(size.1),
+            alt: alt.to_string(),
+            full_size,
+            caption: String::new(),
+        });
 
-        figure.push_str(&format!(
-            "<img src=\"{}\" alt=\"{}\" width=\"{}\" height=\"{}\" loading=\"lazy\" decoding=\"async\" srcset=\"{}\" sizes=\"{}\"/>",
-            fallback_url,
-            escape_html(alt),
-            processed.display_width,
-            processed.display_height.max(1),
-            srcset,
-            html_escape_attr(&sizes_attr),
-        ));
+        // Alternate encodings of the same widths, offered before the <img> fallback
+        let mut sources_html = String::new();
+        for source in &processed.sources {
+            let source_srcset = source
+                .variants
+                .iter()
+                .filter(|variant| {
+                    srcset_entries
+                        .iter()
+                        .any(|(width, _)| *width == variant.width)
+                })
+                .map(|variant| format!("{} {}w", self.escape_url(&variant.url), variant.width))
+                .collect::<Vec<_>>()
+                .join(", ");
+            if !source_srcset.is_empty() {
+                sources_html.push_str(&format!(
+                    "<source type=\"{}\" srcset=\"{}\" sizes=\"{}\">",
+                    html_escape_attr(&source.mime_type),
+                    source_srcset,
+                    html_escape_attr(&sizes_attr),
+                ));
+            }
+        }
+        if let Some(link) = &lightbox_link {
+            figure.push_str(link);
+        }
+        if !sources_html.is_empty() {
+            figure.push_str("<picture>");
+            figure.push_str(&sources_html);
+        }
+        let loading = self.image_loading_attrs();
+        if let Some(svg) = &processed.inline_svg {
+            // Small SVGs are part of the page, where they inherit its CSS
+            let label = if alt.is_empty() {
+                " aria-hidden=\"true\"".to_string()
+            } else {
+                format!(" role=\"img\" aria-label=\"{}\"", escape_html(alt))
+            };
+            figure.push_str(&svg.replacen(
+                "<svg",
+                &format!("<svg width=\"{}\" height=\"{}\"{}", size.0, size.1, label),
+                1,
+            ));
+        } else {
+            figure.push_str(&format!(
+                "<img src=\"{}\" alt=\"{}\" width=\"{}\" height=\"{}\"{} srcset=\"{}\" sizes=\"{}\"{}/>",
+                escape_html(fallback_url),
+                escape_html(alt),
+                size.0,
+                size.1,
+                loading,
+                escape_html(&srcset),
+                html_escape_attr(&sizes_attr),
+                placeholder_style(&processed),
+            ));
+        }
+        if !sources_html.is_empty() {
+            figure.push_str("</picture>");
+        }
+        if lightbox_link.is_some() {
+            figure.push_str("</a>");
+        }
         figure.push_str("<figcaption>");
-        figure.push_str(&format!(
-            "<p><a href=\"#{}\" class=\"fignum\">FIGURE {}</a> {}</p>",
-            fig_id_attr, fig_id_num, caption_html
-        ));
+        figure.push_str(caption_html);
 
         if let Some(exif) = processed.exif.as_ref() {
             if !exif.entries.is_empty() {
-                figure.push_str("<details><summary>EXIF data</summary><dl>");
+                figure.push_str(if self.exif_open {
+                    "<details open><summary>EXIF data</summary><dl>"
+                } else {
+                    "<details><summary>EXIF data</summary><dl>"
+                });
                 for (label, value) in &exif.entries {
                     let (dt, dd) = format_exif_entry(label, value);
                     figure.push_str(&dt);
@@ -631,32 +1482,107 @@ impl HtmlRenderer {
         figure
     }
 
-    fn render_image_figure_fallback(
-        &self,
-        url: &str,
+    /// An animated GIF converted to video: a muted looping `<video>` with the
+    /// GIF as the fallback for browsers that cannot play any source.
+    fn render_video_figure(
+        &mut self,
+        processed: image_processor::ProcessedImage,
         fig_id_attr: &str,
-        fig_id_num: usize,
         alt: &str,
         caption_html: &str,
     ) -> String {
-        let href = self.escape_url(url);
-        let layout_width = self.config.images.layout_width;
-        let layout_height = layout_width;
-
-        let mut figure = String::new();
-        figure.push_str(&format!("<figure id=\"{}\">", fig_id_attr));
-        figure.push_str(&format!(
-            "<img src=\"{}\" alt=\"{}\" width=\"{}\" height=\"{}\" loading=\"lazy\" decoding=\"async\"/>",
-            href,
-            escape_html(alt),
-            layout_width,
-            layout_height
+        let class_attr = if processed.is_wide {
+            " class=\"wide\""
+        } else {
+            ""
+        };
+        let mut figure = format!(
+            "<figure id=\"{}\"{}{}>",
+            fig_id_attr,
+            class_attr,
+            self.permalink_attr(fig_id_attr)
+        );
+        let label_attr = if alt.is_empty() {
+            " aria-hidden=\"true\"".to_string()
+        } else {
+            format!(" aria-label=\"{}\"", html_escape_attr(alt))
+        };
+        figure.push_str(&format!(
+            "<video autoplay loop muted playsinline width=\"{}\" height=\"{}\"{}{}>",
+            processed.display_width,
+            processed.display_height.max(1),
+            label_attr,
+            placeholder_style(&processed),
         ));
-        figure.push_str("<figcaption>");
+        for video in &processed.videos {
+            figure.push_str(&format!(
+                "<source src=\"{}\" type=\"{}\">",
+                self.escape_url(&video.url),
+                html_escape_attr(&video.mime_type),
+            ));
+        }
+        if let Some(gif) = processed.original.as_ref() {
+            self.capture_meta_image_from_variants(&[(gif, true)]);
+            figure.push_str(&format!(
+                "<img src=\"{}\" alt=\"{}\" width=\"{}\" height=\"{}\"/>",
+                self.escape_url(&gif.url),
+                escape_html(alt),
+                processed.display_width,
+                processed.display_height.max(1),
+            ));
+        }
+        figure.push_str("</video><figcaption>");
+        figure.push_str(caption_html);
+        figure.push_str("</figcaption></figure>\n");
+        figure
+    }
+
+    /// Links the image as given, with its display `size` when known; an
+    /// unknown size is left out rather than guessed.
+    fn render_image_figure_fallback(
+        &mut self,
+        url: &str,
+        size: Option<(u32, u32)>,
+        fig_id_attr: &str,
+        alt: &str,
+        caption_html: &str,
+    ) -> String {
+        let src = self.url_with_root(url).into_owned();
+        let lightbox_link = self.lightbox_link(&src, None);
+        self.page_images.push(PageImage {
+            full_size: src.clone(),
+            src,
+            srcset: None,
+            sizes: None,
+            width: size.map(|(width, _)| width),
+            height: size.map(|(_, height)| height),
+            alt: alt.to_string(),
+            caption: String::new(),
+        });
+        let href = self.escape_url(url);
+        let size_attrs = size
+            .map(|(width, height)| format!(" width=\"{}\" height=\"{}\"", width, height))
+            .unwrap_or_default();
+
+        let mut figure = String::new();
         figure.push_str(&format!(
-            "<p><a href=\"#{}\" class=\"fignum\">FIGURE {}</a> {}</p>",
-            fig_id_attr, fig_id_num, caption_html
+            "<figure id=\"{}\"{}>",
+            fig_id_attr,
+            self.permalink_attr(fig_id_attr)
         ));
+        let img = format!(
+            "<img src=\"{}\" alt=\"{}\"{}{}/>",
+            href,
+            escape_html(alt),
+            size_attrs,
+            self.image_loading_attrs()
+        );
+        match lightbox_link {
+            Some(link) => figure.push_str(&format!("{}{}</a>", link, img)),
+            None => figure.push_str(&img),
+        }
+        figure.push_str("<figcaption>");
+        figure.push_str(caption_html);
         figure.push_str(
             "<details><summary>Download</summary><nav aria-label=\"Download sizes\"><ul>",
         );
@@ -666,6 +1592,93 @@ impl HtmlRenderer {
         figure
     }
 
+    /// With `html.lightbox`, the opening `<a>` that links a figure image to its
+    /// full-size file. Figures are grouped by top-level section (`gallery`
+    /// before the first heading), so a viewer pages through one section's
+    /// figures; the original's size is given for viewers that want it upfront.
+    fn lightbox_link(&mut self, full_size: &str, original: Option<(u32, u32)>) -> Option<String> {
+        match self.config.html.lightbox {
+            config::Lightbox::None => return None,
+            config::Lightbox::Markup => {}
+            config::Lightbox::Script => self.uses_lightbox = true,
+        }
+        let group = self
+            .toc
+            .iter()
+            .rev()
+            .find(|entry| entry.level == 1)
+            .map_or("gallery", |entry| entry.slug.as_str());
+        let size_attrs = original
+            .map(|(width, height)| format!(" data-width=\"{}\" data-height=\"{}\"", width, height))
+            .unwrap_or_default();
+        Some(format!(
+            "<a class=\"lightbox\" href=\"{}\" data-lightbox=\"{}\"{}>",
+            escape_html(full_size),
+            escape_html(group),
+            size_attrs
+        ))
+    }
+
+    /// A YouTube or Vimeo player from its privacy-enhanced domain, or with
+    /// `html.embed_facade` a link to the video (with YouTube's thumbnail) that
+    /// a small script swaps for the player on click, so nothing loads from the
+    /// video site until the reader asks for it.
+    fn render_embed(
+        &mut self,
+        provider: EmbedProvider,
+        video: &str,
+        id: Option<&str>,
+        id_number: usize,
+        text: &[InlineElement],
+    ) -> String {
+        let (fig_id_attr, caption_html) = self.figure_caption(id, id_number, text, None);
+        let caption_text = crate::excerpt::collapse_whitespace(&crate::excerpt::plain_text(text));
+        let title = if caption_text.is_empty() {
+            format!("{} video", provider.label())
+        } else {
+            caption_text
+        };
+
+        let mut figure = format!(
+            "<figure id=\"{}\" class=\"embed\"{}><div class=\"embed-frame\">",
+            fig_id_attr,
+            self.permalink_attr(&fig_id_attr)
+        );
+        if self.config.html.embed_facade {
+            self.uses_embed_facade = true;
+            let player = match provider {
+                EmbedProvider::YouTube => format!("{}?autoplay=1", provider.player_url(video)),
+                EmbedProvider::Vimeo => format!("{}&autoplay=1", provider.player_url(video)),
+            };
+            figure.push_str(&format!(
+                "<a class=\"embed-facade\" href=\"{}\" data-embed-src=\"{}\" data-embed-title=\"{}\">",
+                html_escape_attr(&provider.watch_url(video)),
+                html_escape_attr(&player),
+                html_escape_attr(&title)
+            ));
+            if provider == EmbedProvider::YouTube {
+                figure.push_str(&format!(
+                    "<img src=\"https://i.ytimg.com/vi/{}/hqdefault.jpg\" alt=\"\" width=\"480\" height=\"360\" loading=\"lazy\" decoding=\"async\">",
+                    video
+                ));
+            }
+            figure.push_str(&format!(
+                "<span class=\"embed-play\">Play: {}</span></a>",
+                escape_html(&title)
+            ));
+        } else {
+            figure.push_str(&format!(
+                "<iframe src=\"{}\" title=\"{}\" loading=\"lazy\" allow=\"autoplay; encrypted-media; fullscreen; picture-in-picture\" referrerpolicy=\"strict-origin-when-cross-origin\" allowfullscreen></iframe>",
+                html_escape_attr(&provider.player_url(video)),
+                html_escape_attr(&title)
+            ));
+        }
+        figure.push_str("</div><figcaption>");
+        figure.push_str(&caption_html);
+        figure.push_str("</figcaption></figure>\n");
+        figure
+    }
+
     fn render_display_math(&mut self, id: Option<&str>, id_number: usize, content: &str) -> String {
         let eqnum = id_number + 1;
         let eq_id_attr = id
@@ -674,8 +1687,12 @@ impl HtmlRenderer {
 
         let html = self.render_math_html(content, false);
         format!(
-            "<div class=\"math\" id=\"{}\"><a href=\"#{}\" class=\"eqnum\">{}</a> {}</div>\n",
-            eq_id_attr, eq_id_attr, eqnum, html
+            "<div class=\"math\" id=\"{}\"{}><a href=\"#{}\" class=\"eqnum\">{}</a> {}</div>\n",
+            eq_id_attr,
+            self.permalink_attr(&eq_id_attr),
+            eq_id_attr,
+            eqnum,
+            html
         )
     }
 
@@ -705,8 +1722,8 @@ impl HtmlRenderer {
                     out.push_str("</li>");
                 }
             }
-            let content = self.render_inlines(&item.text);
-            out.push_str("<li>");
+            let content = self.render_list_item(item);
+            out.push_str(list_item_open(item));
             out.push_str(&content);
             if let Some(last) = first_flags.last_mut() {
                 *last = false;
@@ -724,13 +1741,27 @@ impl HtmlRenderer {
         out
     }
 
+    /// An item's text followed by the blocks indented below it, where the
+    /// text becomes a paragraph of its own.
+    fn render_list_item(&mut self, item: &ListItem) -> String {
+        let content = format!("{}{}", task_checkbox(item), self.render_inlines(&item.text));
+        if item.blocks.is_empty() {
+            return content;
+        }
+        let mut out = format!("<p>{}</p>", content);
+        for block in &item.blocks {
+            out.push_str(&self.render_block(block));
+        }
+        out
+    }
+
     fn render_ordered_list(&mut self, items: &[ListItem]) -> String {
         // The parser stores the number in `level`, but we render as a simple <ol>
         let mut out = String::new();
         out.push_str("<ol>");
         for item in items {
-            out.push_str("<li>");
-            out.push_str(&self.render_inlines(&item.text));
+            out.push_str(list_item_open(item));
+            out.push_str(&self.render_list_item(item));
             out.push_str("</li>");
         }
         out.push_str("</ol>\n");
@@ -739,29 +1770,56 @@ impl HtmlRenderer {
 
     fn render_paragraph(&mut self, elements: &[InlineElement]) -> String {
         let content = self.render_inlines(elements);
-        format!("<p>{}</p>\n", content)
+        match self.paragraph_id.take() {
+            Some(id) => format!(
+                "<p id=\"{}\"{}>{}</p>\n",
+                id,
+                self.permalink_attr(&id),
+                content
+            ),
+            None => format!("<p>{}</p>\n", content),
+        }
     }
 
+    /// Concatenates rendered inlines; spacing comes from the source text, which
+    /// the parser keeps in the surrounding `Text` elements.
     fn render_inlines(&mut self, elements: &[InlineElement]) -> String {
         let mut out = String::new();
         for el in elements {
-            let frag = self.render_inline(el);
-            if needs_space_between(&out, &frag) {
-                out.push(' ');
-            }
-            out.push_str(&frag);
+            out.push_str(&self.render_inline(el));
         }
         out
     }
 
     fn render_inline(&mut self, element: &InlineElement) -> String {
         match element {
-            InlineElement::Text(text) => typographer(text),
+            InlineElement::Text(text) => {
+                let text = match self.config.html.soft_wrap {
+                    config::SoftWrap::Space => Cow::Borrowed(text.as_str()),
+                    config::SoftWrap::Cjk => join_cjk_soft_wraps(text),
+                };
+                let html = typographer(&substitute_page_vars(
+                    &text,
+                    &self.page_vars,
+                    &self.config.vars,
+                    &self.site_data,
+                    false,
+                ));
+                let html_config = &self.config.html;
+                emoji::replace(&html, html_config.emoji, &html_config.emoji_svg_url).into_owned()
+            }
             InlineElement::Code(code) => format!("<code>{}</code>", escape_html(code)),
             InlineElement::InlineMath(math) => self.render_math_html(math, true),
             InlineElement::Link { text, url } => {
                 let inner = self.render_inlines(text);
-                let href = self.escape_url(url);
+                let url = substitute_page_vars(
+                    url,
+                    &self.page_vars,
+                    &self.config.vars,
+                    &self.site_data,
+                    false,
+                );
+                let href = self.escape_url(&url);
                 format!("<a href=\"{}\">{}</a>", href, inner)
             }
             InlineElement::Emphasis(content) => {
@@ -772,8 +1830,25 @@ impl HtmlRenderer {
                 let inner = self.render_inlines(content);
                 format!("<strong>{}</strong>", inner)
             }
+            InlineElement::Lang { lang, text } => {
+                let inner = self.render_inlines(text);
+                format!("<span lang=\"{}\">{}</span>", html_escape_attr(lang), inner)
+            }
             InlineElement::Reference(content) => {
+                if let Some((page, text)) = self.site_references.resolve(content) {
+                    let label = content.split_once(':').map_or("", |(_, label)| label);
+                    return format!(
+                        "<a class=\"xref\" href=\"{}#{}\" title=\"{}\">{}</a>",
+                        html_escape_attr(&page.url),
+                        html_escape_attr(label),
+                        html_escape_attr(&page.title),
+                        escape_html(text)
+                    );
+                }
                 let esc = escape_html(content);
+                if let Some(number) = self.equation_labels.get(content) {
+                    return format!("<a class=\"eqref\" href=\"#{}\">({})</a>", esc, number);
+                }
                 format!(
                     "<a class=\"refname\" href=\"#{}\"><cite>{}</cite></a>",
                     esc, esc
@@ -790,9 +1865,18 @@ impl HtmlRenderer {
         }
     }
 
+    /// Renders one equation into the math cache ahead of a build, as
+    /// `dllup-rs warm-math` does; false when every engine failed on it.
+    pub fn warm_math(&mut self, latex: &str, inline: bool) -> bool {
+        let failures = self.math_failures.len();
+        self.render_math_html(latex, inline);
+        self.math_failures.len() == failures
+    }
+
     fn render_math_html(&mut self, latex: &str, inline: bool) -> String {
-        // For display mode, wrap in an aligned environment unless already present
-        let wrapped = if inline {
+        // For display mode, wrap TeX in an aligned environment unless already present
+        let takes_tex = self.engine.as_deref().is_none_or(|e| e.takes_tex());
+        let wrapped = if inline || !takes_tex {
             latex.to_string()
         } else {
             let has_align = latex.contains("\\begin{align") || latex.contains("\\begin{aligned}");
@@ -807,11 +1891,22 @@ impl HtmlRenderer {
             return cached.clone();
         }
         if let Some(engine) = self.engine.as_deref_mut() {
-            match engine.tex_to_html(&wrapped, inline) {
-                Ok(s) if !s.trim().is_empty() => return s,
-                Ok(_) => {}
-                Err(e) => eprintln!("math render error: {}", e),
+            let cache = self.math_cache.as_ref();
+            if let Some(html) = cache.and_then(|cache| cache.get(&wrapped, inline)) {
+                return html;
             }
+            let error = match engine.tex_to_html(&wrapped, inline) {
+                Ok(s) if !s.trim().is_empty() => {
+                    if let Some(cache) = cache {
+                        cache.put(&wrapped, inline, &s);
+                    }
+                    return s;
+                }
+                Ok(_) => "empty output".to_string(),
+                Err(e) => e,
+            };
+            self.math_failures
+                .push(format!("{}: {}", latex.trim(), error));
         }
         // Fallback: just show the raw TeX in a code span/div
         if inline {
@@ -844,6 +1939,71 @@ impl HtmlRenderer {
 
 // removed SVG metric extraction: KaTeX HTML is inlined directly
 
+/// Counts a heading at `level` (1 to 6) and returns its number, such as `2.1`.
+pub fn next_section_number(counters: &mut Vec<usize>, level: usize) -> String {
+    if counters.len() < level {
+        counters.resize(level, 0);
+    }
+    counters[level - 1] += 1;
+    for counter in &mut counters[level..] {
+        *counter = 0;
+    }
+    counters[..level]
+        .iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Whether `render_cached_block` may take `block` from the fragment cache.
+fn cacheable(block: &Block) -> bool {
+    match block {
+        Block::Paragraph(_)
+        | Block::BlockQuote(_)
+        | Block::DisplayMath { .. }
+        | Block::Table { .. } => true,
+        // Diagrams have their own caches, and Mermaid may need the browser script
+        Block::CodeBlock { language, .. } => {
+            let language = language.as_deref();
+            !is_graphviz(language)
+                && !language.is_some_and(|language| language.eq_ignore_ascii_case("mermaid"))
+        }
+        Block::UnorderedList(items) | Block::OrderedList(items) => {
+            items.iter().flat_map(|item| &item.blocks).all(cacheable)
+        }
+        Block::Details { blocks, .. } => blocks.iter().all(cacheable),
+        _ => false,
+    }
+}
+
+fn list_item_open(item: &ListItem) -> &'static str {
+    match item.checked {
+        Some(_) => "<li class=\"task\">",
+        None => "<li>",
+    }
+}
+
+/// Read-only checkbox in front of a task list item's text.
+fn task_checkbox(item: &ListItem) -> &'static str {
+    match item.checked {
+        Some(true) => "<input type=\"checkbox\" disabled checked/> ",
+        Some(false) => "<input type=\"checkbox\" disabled/> ",
+        None => "",
+    }
+}
+
+/// ` colspan`/` rowspan` attributes of a cell spanning more than one column or row.
+fn cell_spans(cell: &TableCell) -> String {
+    let mut attrs = String::new();
+    if cell.colspan > 1 {
+        attrs.push_str(&format!(" colspan=\"{}\"", cell.colspan));
+    }
+    if cell.rowspan > 1 {
+        attrs.push_str(&format!(" rowspan=\"{}\"", cell.rowspan));
+    }
+    attrs
+}
+
 fn toc_link(entry: &TocEntry) -> String {
     let href = format!("#{}", entry.anchor_id);
     format!(
@@ -861,7 +2021,9 @@ fn extract_text(elements: &[InlineElement]) -> String {
             InlineElement::Text(t) => out.push_str(t),
             InlineElement::Code(c) => out.push_str(c),
             InlineElement::InlineMath(m) => out.push_str(m),
-            InlineElement::Link { text, .. } => out.push_str(&extract_text(text)),
+            InlineElement::Link { text, .. } | InlineElement::Lang { text, .. } => {
+                out.push_str(&extract_text(text))
+            }
             InlineElement::Emphasis(inner) | InlineElement::Strong(inner) => {
                 out.push_str(&extract_text(inner))
             }
@@ -1033,10 +2195,75 @@ fn extract_first_number(value: &str) -> Option<u32> {
     digits.parse::<u32>().ok()
 }
 
-fn escape_html(s: &str) -> String {
+fn is_iso_date(s: &str) -> bool {
+    let parts: Vec<&str> = s.split('-').collect();
+    parts.len() == 3
+        && parts[0].len() == 4
+        && parts[1].len() == 2
+        && parts[2].len() == 2
+        && parts.iter().all(|p| p.chars().all(|c| c.is_ascii_digit()))
+}
+
+pub fn escape_html(s: &str) -> String {
     html_escape_attr(s)
 }
 
+/// Text escaped by [`escape_html`], as it was.
+pub fn unescape_html(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Removes source line breaks (and the indentation around them) that sit between
+/// two CJK characters, where joining with a space would be wrong.
+fn join_cjk_soft_wraps(text: &str) -> Cow<'_, str> {
+    if !text.contains('\n') {
+        return Cow::Borrowed(text);
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if !chars[i].is_whitespace() {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+        let run = &chars[start..i];
+        let joins_cjk = run.contains(&'\n')
+            && start > 0
+            && is_cjk(chars[start - 1])
+            && chars.get(i).copied().is_some_and(is_cjk);
+        if !joins_cjk {
+            out.extend(run);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Han, kana, bopomofo and CJK/fullwidth punctuation. Hangul is left out since
+/// Korean separates words with spaces.
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3000..=0x303F // CJK symbols and punctuation
+        | 0x3040..=0x30FF // hiragana, katakana
+        | 0x3100..=0x312F // bopomofo
+        | 0x31F0..=0x31FF // katakana phonetic extensions
+        | 0x3400..=0x4DBF // CJK extension A
+        | 0x4E00..=0x9FFF // CJK unified ideographs
+        | 0xF900..=0xFAFF // CJK compatibility ideographs
+        | 0xFF00..=0xFFEF // halfwidth and fullwidth forms
+        | 0x20000..=0x3FFFF // CJK extensions B onwards
+    )
+}
+
 fn html_escape_attr(s: &str) -> String {
     s.replace('&', "&amp;")
         .replace('<', "&lt;")
@@ -1045,32 +2272,6 @@ fn html_escape_attr(s: &str) -> String {
         .replace('\'', "&#x27;")
 }
 
-fn needs_space_between(prev: &str, next: &str) -> bool {
-    if prev.is_empty() || next.is_empty() {
-        return false;
-    }
-    let prev_last = prev
-        .chars()
-        .rev()
-        .find(|c| !c.is_whitespace())
-        .unwrap_or(' ');
-    let next_first = next.chars().find(|c| !c.is_whitespace()).unwrap_or(' ');
-    // Insert space when HTML tag boundary meets a word character
-    let punct = [',', '.', ';', ':', '?', '!', ')', ']', '}', '"', '\'', '/'];
-    if !punct.contains(&next_first)
-        && !prev_last.is_whitespace()
-        && prev_last == '>'
-        && next_first.is_alphanumeric()
-    {
-        // avoid double spaces if already present
-        if prev.ends_with(' ') || next.starts_with(' ') {
-            return false;
-        }
-        return true;
-    }
-    false
-}
-
 fn typographer(input: &str) -> String {
     let mut s = input.to_string();
     // Dashes, ellipsis first
@@ -1124,254 +2325,1587 @@ impl HtmlRenderer {
     fn render_table(
         &mut self,
         id_number: usize,
-        header: &[Vec<InlineElement>],
-        rows: &[Vec<Vec<InlineElement>>],
-        caption: &[InlineElement],
+        header: &[TableCell],
+        rows: &[Vec<TableCell>],
+        caption: Option<&[InlineElement]>,
     ) -> String {
         let table_id = format!("table{}", id_number + 1);
         let mut out = String::new();
-        out.push_str(&format!("<figure id=\"{}\"><table>", table_id));
-        out.push_str("<tr>");
+        // Only a captioned table is a numbered figure
+        if caption.is_some() {
+            out.push_str(&format!(
+                "<figure id=\"{}\"{}>",
+                table_id,
+                self.permalink_attr(&table_id)
+            ));
+        }
+        out.push_str("<table><tr>");
         for cell in header {
-            out.push_str("<th>");
-            out.push_str(&self.render_inlines(cell));
+            out.push_str(&format!("<th{}>", cell_spans(cell)));
+            out.push_str(&self.render_inlines(&cell.content));
             out.push_str("</th>");
         }
         out.push_str("</tr>");
         for row in rows {
             out.push_str("<tr>");
             for cell in row {
-                out.push_str("<td>");
-                out.push_str(&self.render_inlines(cell));
+                out.push_str(&format!("<td{}>", cell_spans(cell)));
+                out.push_str(&self.render_inlines(&cell.content));
                 out.push_str("</td>");
             }
             out.push_str("</tr>");
         }
         out.push_str("</table>");
-        let caption_html = self.render_inlines(caption);
-        out.push_str(&format!(
-            "<figcaption><a href=\"#{}\" class=\"fignum\">Table {}</a> {}</figcaption>",
-            table_id,
-            id_number + 1,
-            caption_html
-        ));
-        out.push_str("</figure>\n");
+        match caption {
+            Some(caption) => {
+                let caption_html = self.render_inlines(caption);
+                out.push_str(&format!(
+                    "<figcaption><a href=\"#{}\" class=\"fignum\">Table {}</a> {}</figcaption>",
+                    table_id,
+                    id_number + 1,
+                    caption_html
+                ));
+                out.push_str("</figure>\n");
+            }
+            None => out.push('\n'),
+        }
         out
     }
 }
 
-fn highlight_with_inkjet(language: Option<&str>, code: &str) -> Option<String> {
-    let mut highlighter = Highlighter::new();
-    let theme = Theme::from_helix(ONEDARKER).ok()?;
-    let formatter = ThemedHtml::new(theme);
-    let lang = language.and_then(Language::from_token).unwrap_or_else(|| {
-        Language::from_token("plaintext").unwrap_or(Language::from_token("none").unwrap())
+/// `math.command` split into arguments, or `npx katex` when unset.
+/// A handle on the shared persistent KaTeX pool, sized by `math.workers`.
+fn persistent_engine(config: &config::Config) -> Result<PooledKatexEngine, String> {
+    let workers = config.math.workers.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
     });
-    highlighter.highlight_to_string(lang, &formatter, code).ok()
+    let options = KatexOptions {
+        macros: config.math.macros.clone(),
+        extensions: config.math.katex_extensions.clone(),
+        timeout: math_timeout(config),
+    };
+    KatexPool::shared(&options, workers).map(PooledKatexEngine::new)
 }
 
-pub fn wrap_html_document(
-    config: &config::Config,
-    title: &str,
-    body: &str,
-    table_of_contents: &str,
-    metas: &str,
-    index: &str,
-) -> Result<String, String> {
-    let template_path = &config.html.template_path;
-    let template = fs::read_to_string(template_path)
-        .map_err(|e| format!("failed to read HTML template {}: {}", template_path, e))?;
-
-    let css_href_resolved = prepare_css_href(config)?;
-    let css_href = html_escape_attr(&css_href_resolved);
-
-    Ok(template
-        .replace("{{title}}", &html_escape_attr(title))
-        .replace("{{css}}", &css_href)
-        .replace("{{tableofcontents}}", table_of_contents)
-        .replace("{{metas}}", metas)
-        .replace("{{index}}", index)
-        .replace("{{body}}", body))
+fn command_engine(config: &config::Config) -> ExternalCmdEngine {
+    let cmd = match &config.math.command {
+        Some(command) => shell_words::split(command).unwrap_or_else(|_| vec![command.clone()]),
+        None => vec!["npx".into(), "katex".into()],
+    };
+    ExternalCmdEngine {
+        cmd,
+        macros: config.math.macros.clone(),
+        timeout: math_timeout(config),
+    }
 }
 
-fn prepare_css_href(config: &config::Config) -> Result<String, String> {
-    let raw = config.html.css_href.trim();
-    if raw.is_empty() {
-        return Ok(String::new());
+/// ` style="..."` painting the image's placeholder and/or average color behind
+/// the `<img>` until it loads.
+fn placeholder_style(processed: &image_processor::ProcessedImage) -> String {
+    let mut rules = Vec::new();
+    if let Some(color) = processed.dominant_color {
+        rules.push(format!(
+            "background-color:{}",
+            image_processor::format_hex_color(color)
+        ));
     }
-    if css_is_remote(raw) {
-        return Ok(raw.to_string());
+    if let Some(uri) = &processed.placeholder {
+        rules.push(format!("background-image:url({})", uri));
+        rules.push("background-size:cover".to_string());
     }
-
-    let (source_path, location) = stylesheet_source_path(raw);
-    let contents = fs::read(&source_path)
-        .map_err(|e| format!("failed to read stylesheet {}: {}", source_path.display(), e))?;
-    let hash = blake3::hash(&contents);
-    let hash_hex = hash.to_hex().to_string();
-    let short_hash = &hash_hex[..12];
-
-    let hashed_source_path =
-        append_hash_to_filename(&source_path, short_hash).map_err(|e| e.to_string())?;
-    if let Some(parent) = hashed_source_path.parent() {
-        fs::create_dir_all(parent)
-            .map_err(|e| format!("failed to ensure directory {}: {}", parent.display(), e))?;
+    if rules.is_empty() {
+        String::new()
+    } else {
+        format!(" style=\"{}\"", html_escape_attr(&rules.join(";")))
     }
-    fs::write(&hashed_source_path, &contents).map_err(|e| {
-        format!(
-            "failed to write hashed stylesheet {}: {}",
-            hashed_source_path.display(),
-            e
-        )
-    })?;
-
-    let hashed_path_str = hashed_source_path.to_string_lossy().replace('\\', "/");
+}
 
-    let public_path = match location {
-        StylesheetLocation::RootRelative => {
-            format!("/{}", hashed_path_str.trim_start_matches('/'))
-        }
-        _ => hashed_path_str,
+fn typst_engine(config: &config::Config) -> TypstEngine {
+    let cmd = match &config.math.command {
+        Some(command) => shell_words::split(command).unwrap_or_else(|_| vec![command.clone()]),
+        None => vec!["typst".into()],
     };
+    TypstEngine {
+        cmd,
+        timeout: math_timeout(config),
+    }
+}
 
-    let final_href = match location {
-        StylesheetLocation::Relative | StylesheetLocation::RootRelative => {
-            css_href_with_root(config, &public_path)
-        }
-        StylesheetLocation::Absolute => public_path,
-    };
+#[cfg(feature = "native-math")]
+fn native_engine(config: &config::Config) -> Option<Box<dyn MathEngine>> {
+    Some(Box::new(crate::math_engine::NativeKatexEngine {
+        macros: config.math.macros.clone(),
+    }))
+}
 
-    Ok(final_href)
+#[cfg(not(feature = "native-math"))]
+fn native_engine(_config: &config::Config) -> Option<Box<dyn MathEngine>> {
+    crate::diagnostics::warn(
+        "math.engine = \"native\" needs a build with `--features native-math`. Emitting raw TeX.",
+    );
+    None
 }
 
-fn css_href_with_root(config: &config::Config, raw: &str) -> String {
-    let raw = raw.trim();
-    if raw.is_empty() {
-        return String::new();
+fn math_timeout(config: &config::Config) -> Option<Duration> {
+    match config.math.timeout_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
     }
+}
 
-    match config.root_url.as_deref() {
-        Some(root) if raw.starts_with('/') && !raw.starts_with("//") => {
-            if root == "/" {
-                raw.to_string()
-            } else {
-                format!("{}{}", root, raw)
-            }
+fn highlight_with_inkjet(
+    html_config: &config::HtmlConfig,
+    language: Option<&str>,
+    code: &str,
+) -> Option<String> {
+    let mut highlighter = Highlighter::new();
+    let lang = language.and_then(Language::from_token).unwrap_or_else(|| {
+        Language::from_token("plaintext").unwrap_or(Language::from_token("none").unwrap())
+    });
+    let light = load_theme(&html_config.highlight_theme)?;
+    match html_config
+        .highlight_theme_dark
+        .as_deref()
+        .and_then(load_theme)
+    {
+        Some(dark) => {
+            let formatter = DualThemedHtml { light, dark };
+            highlighter.highlight_to_string(lang, &formatter, code).ok()
         }
-        Some(root) if is_relative_href(raw) => {
-            let trimmed = raw.trim_start_matches('/');
-            if root == "/" {
-                format!("/{}", trimmed)
-            } else {
-                format!("{}/{}", root, trimmed)
-            }
+        None => {
+            let formatter = ThemedHtml::new(light);
+            highlighter.highlight_to_string(lang, &formatter, code).ok()
         }
-        _ => raw.to_string(),
     }
 }
 
-fn css_is_remote(href: &str) -> bool {
-    if href.starts_with("//") {
-        return true;
+const VENDORED_THEMES: &[(&str, &str)] = &[
+    ("ayu_dark", vendored::AYU_DARK),
+    ("ayu_light", vendored::AYU_LIGHT),
+    ("catppuccin_mocha", vendored::CATPPUCCIN_MOCHA),
+    ("dracula", vendored::DRACULA),
+    ("everforest_dark", vendored::EVERFOREST_DARK),
+    ("everforest_light", vendored::EVERFOREST_LIGHT),
+    ("github_dark", vendored::GITHUB_DARK),
+    ("github_light", vendored::GITHUB_LIGHT),
+    ("gruvbox", vendored::GRUVBOX),
+    ("monokai", vendored::MONOKAI),
+    ("nord", vendored::NORD),
+    ("nord_light", vendored::NORD_LIGHT),
+    ("onedark", vendored::ONEDARK),
+    ("onedarker", vendored::ONEDARKER),
+    ("onelight", vendored::ONELIGHT),
+    ("papercolor_light", vendored::PAPERCOLOR_LIGHT),
+    ("solarized_dark", vendored::SOLARIZED_DARK),
+    ("solarized_light", vendored::SOLARIZED_LIGHT),
+];
+
+/// Resolves a theme by vendored name (e.g. `onedarker`) or, for values ending
+/// in `.toml`, by reading a Helix theme file from disk.
+fn load_theme(name: &str) -> Option<Theme> {
+    lazy_static! {
+        static ref THEME_CACHE: std::sync::Mutex<std::collections::HashMap<String, Option<Theme>>> =
+            std::sync::Mutex::new(std::collections::HashMap::new());
     }
-    if let Some(pos) = href.find(':') {
-        let scheme = &href[..pos];
-        if scheme.len() == 1 && scheme.chars().all(|c| c.is_ascii_alphabetic()) {
-            // Likely a Windows drive letter.
-            return false;
-        }
-        return true;
+    let mut cache = THEME_CACHE.lock().ok()?;
+    if let Some(theme) = cache.get(name) {
+        return theme.clone();
     }
-    false
+    let theme = if name.ends_with(".toml") {
+        fs::read_to_string(name)
+            .ok()
+            .and_then(|data| Theme::from_helix(&data).ok())
+    } else {
+        VENDORED_THEMES
+            .iter()
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, data)| Theme::from_helix(data).ok())
+    };
+    if theme.is_none() {
+        crate::diagnostics::warn(format!(
+            "Unknown highlight theme '{}', falling back to plain code",
+            name
+        ));
+    }
+    cache.insert(name.to_string(), theme.clone());
+    theme
 }
 
-fn stylesheet_source_path(raw: &str) -> (PathBuf, StylesheetLocation) {
-    if raw.starts_with('/') && !raw.starts_with("//") {
-        let absolute = Path::new(raw);
-        if absolute.exists() {
-            return (absolute.to_path_buf(), StylesheetLocation::Absolute);
+/// Highlights with two themes at once. Colors are emitted as CSS custom
+/// properties (`--hl-fg`, `--hl-dark-fg`, ...) and `pre.themed` rules in the
+/// stylesheet pick a set based on `prefers-color-scheme`.
+struct DualThemedHtml {
+    light: Theme,
+    dark: Theme,
+}
+
+impl Formatter for DualThemedHtml {
+    fn write<W>(&self, source: &str, writer: &mut W, event: HighlightEvent) -> inkjet::Result<()>
+    where
+        W: std::fmt::Write,
+    {
+        match event {
+            HighlightEvent::Source { start, end } => {
+                let span = source.get(start..end).unwrap_or("");
+                writer.write_str(&escape_html(span))?;
+            }
+            HighlightEvent::HighlightStart(idx) => {
+                let name = HIGHLIGHT_NAMES[idx.0];
+                let light = self.light.get_style(name);
+                let dark = self.dark.get_style(name);
+                let mut style = format!(
+                    "--hl-fg: {}; --hl-dark-fg: {};",
+                    light.and_then(|s| s.fg).unwrap_or(self.light.fg).into_hex(),
+                    dark.and_then(|s| s.fg).unwrap_or(self.dark.fg).into_hex()
+                );
+                if let Some(bg) = light.and_then(|s| s.bg) {
+                    style.push_str(&format!(" --hl-bg: {};", bg.into_hex()));
+                }
+                if let Some(bg) = dark.and_then(|s| s.bg) {
+                    style.push_str(&format!(" --hl-dark-bg: {};", bg.into_hex()));
+                }
+                if let Some(light) = light {
+                    if light.modifiers.contains(&Modifier::Bold) {
+                        style.push_str(" font-weight: bold;");
+                    }
+                    if light.modifiers.contains(&Modifier::Italic) {
+                        style.push_str(" font-style: italic;");
+                    }
+                }
+                write!(writer, "<span style=\"{}\">", style)?;
+            }
+            HighlightEvent::HighlightEnd => writer.write_str("</span>")?,
         }
-        return (
-            Path::new(raw.trim_start_matches('/')).to_path_buf(),
-            StylesheetLocation::RootRelative,
-        );
+        Ok(())
     }
 
-    let path = Path::new(raw);
-    if path.is_absolute() {
-        (path.to_path_buf(), StylesheetLocation::Absolute)
+    fn start<W>(&self, _: &str, writer: &mut W) -> inkjet::Result<()>
+    where
+        W: std::fmt::Write,
+    {
+        writeln!(
+            writer,
+            "<pre class=\"themed\" style=\"--hl-bg: {}; --hl-fg: {}; --hl-dark-bg: {}; --hl-dark-fg: {};\">",
+            self.light.bg.into_hex(),
+            self.light.fg.into_hex(),
+            self.dark.bg.into_hex(),
+            self.dark.fg.into_hex()
+        )?;
+        Ok(())
+    }
+
+    fn finish<W>(&self, _: &str, writer: &mut W) -> inkjet::Result<()>
+    where
+        W: std::fmt::Write,
+    {
+        writeln!(writer, "</pre>")?;
+        Ok(())
+    }
+}
+
+/// Replaces a clicked `embed` facade with the player it points at.
+const EMBED_FACADE_SCRIPT: &str = r#"<script>
+document.addEventListener("click", function (e) {
+  var link = e.target.closest ? e.target.closest("a.embed-facade") : null;
+  if (!link) return;
+  e.preventDefault();
+  var frame = document.createElement("iframe");
+  frame.src = link.dataset.embedSrc;
+  frame.title = link.dataset.embedTitle;
+  frame.allow = "autoplay; encrypted-media; fullscreen; picture-in-picture";
+  frame.referrerPolicy = "strict-origin-when-cross-origin";
+  frame.allowFullscreen = true;
+  link.replaceWith(frame);
+});
+</script>
+"#;
+
+/// Loads Mermaid from `url` and draws every `<pre class="mermaid">`, in its
+/// dark theme when the reader prefers a dark color scheme.
+fn mermaid_script(url: &str) -> String {
+    format!(
+        r#"<script type="module">
+import mermaid from "{}";
+var dark = window.matchMedia("(prefers-color-scheme: dark)").matches;
+mermaid.initialize({{ startOnLoad: false, theme: dark ? "dark" : "default" }});
+mermaid.run({{ querySelector: "pre.mermaid" }});
+</script>
+"#,
+        html_escape_attr(url)
+    )
+}
+
+/// Opens `html.lightbox` links in an overlay; arrow keys page through the
+/// figures of the same group and Escape or a click closes it.
+const LIGHTBOX_SCRIPT: &str = r#"<style>
+a.lightbox { cursor: zoom-in; }
+.lightbox-view { position: fixed; inset: 0; z-index: 1000; display: flex; flex-direction: column; align-items: center; justify-content: center; background: rgba(0, 0, 0, 0.9); color: #fff; cursor: zoom-out; }
+.lightbox-view img { max-width: 95vw; max-height: 85vh; object-fit: contain; }
+.lightbox-view p { margin: 0.5em 1em; text-align: center; }
+</style>
+<script>
+(function () {
+  var view = null, links = [], index = 0;
+  function show(i) {
+    index = (i + links.length) % links.length;
+    var link = links[index], caption = link.closest("figure").querySelector("figcaption p");
+    var img = view.querySelector("img");
+    img.src = link.href;
+    img.alt = link.querySelector("img").alt;
+    view.querySelector("p").textContent = caption ? caption.textContent : "";
+  }
+  function close() { view.remove(); view = null; }
+  document.addEventListener("click", function (e) {
+    if (view) { close(); return; }
+    var link = e.target.closest ? e.target.closest("a[data-lightbox]") : null;
+    if (!link || e.ctrlKey || e.metaKey || e.shiftKey) return;
+    e.preventDefault();
+    links = Array.prototype.filter.call(document.querySelectorAll("a[data-lightbox]"), function (a) {
+      return a.dataset.lightbox === link.dataset.lightbox;
+    });
+    view = document.createElement("div");
+    view.className = "lightbox-view";
+    view.setAttribute("role", "dialog");
+    view.setAttribute("aria-modal", "true");
+    view.appendChild(document.createElement("img"));
+    view.appendChild(document.createElement("p"));
+    document.body.appendChild(view);
+    show(links.indexOf(link));
+  });
+  document.addEventListener("keydown", function (e) {
+    if (!view) return;
+    if (e.key === "Escape") close();
+    else if (e.key === "ArrowRight") show(index + 1);
+    else if (e.key === "ArrowLeft") show(index - 1);
+  });
+})();
+</script>
+"#;
+
+const COPY_CODE_SCRIPT: &str = r#"<script>
+document.addEventListener("click", function (e) {
+  var button = e.target.closest ? e.target.closest("button.copy-code") : null;
+  if (!button || !navigator.clipboard) return;
+  var pre = button.parentNode.querySelector("pre").cloneNode(true);
+  pre.querySelectorAll(".lineno").forEach(function (n) { n.remove(); });
+  navigator.clipboard.writeText(pre.textContent).then(function () {
+    button.textContent = "Copied";
+    setTimeout(function () { button.textContent = "Copy"; }, 1500);
+  });
+});
+</script>
+"#;
+
+/// Wraps a rendered `<pre>` in a container with a copy button; the button is
+/// wired up by `COPY_CODE_SCRIPT`.
+fn wrap_copy_button(pre: &str) -> String {
+    format!(
+        "<div class=\"code-block\"><button type=\"button\" class=\"copy-code\" aria-label=\"Copy code to clipboard\">Copy</button>{}</div>\n",
+        pre.trim_end()
+    )
+}
+
+fn add_pre_class(open_tag: &str, numbered: bool) -> String {
+    if !numbered {
+        open_tag.to_string()
+    } else if open_tag.contains(" class=\"") {
+        open_tag.replacen(" class=\"", " class=\"numbered ", 1)
+    } else {
+        open_tag.replacen("<pre", "<pre class=\"numbered\"", 1)
+    }
+}
+
+/// Splits inkjet output into its opening `<pre ...>` tag and the highlighted body.
+fn split_pre(html: &str) -> Option<(&str, &str)> {
+    let open_end = html.find('>')? + 1;
+    let open_tag = &html[..open_end];
+    let inner = html[open_end..]
+        .strip_prefix('\n')
+        .unwrap_or(&html[open_end..]);
+    let inner = inner.trim_end_matches('\n').strip_suffix("</pre>")?;
+    Some((open_tag, inner))
+}
+
+/// Splits highlighted HTML into lines, closing and reopening any spans that
+/// straddle a line break so every line is balanced on its own.
+fn split_highlighted_lines(html: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut open_tags: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut rest = html;
+    while let Some(ch) = rest.chars().next() {
+        if ch == '<' {
+            let end = rest.find('>').map(|i| i + 1).unwrap_or(rest.len());
+            let tag = &rest[..end];
+            if tag.starts_with("</") {
+                open_tags.pop();
+            } else if !tag.ends_with("/>") {
+                open_tags.push(tag.to_string());
+            }
+            current.push_str(tag);
+            rest = &rest[end..];
+            continue;
+        }
+        if ch == '\n' {
+            for _ in &open_tags {
+                current.push_str("</span>");
+            }
+            lines.push(std::mem::take(&mut current));
+            for tag in &open_tags {
+                current.push_str(tag);
+            }
+        } else {
+            current.push(ch);
+        }
+        rest = &rest[ch.len_utf8()..];
+    }
+    if !current.is_empty() && current != open_tags.concat() {
+        lines.push(current);
+    }
+    lines
+}
+
+fn wrap_code_lines(html: &str, numbered: bool, highlight_lines: &[usize]) -> String {
+    let mut out = String::new();
+    for (idx, line) in split_highlighted_lines(html).iter().enumerate() {
+        let line_number = idx + 1;
+        let class = if highlight_lines.contains(&line_number) {
+            "line hl"
+        } else {
+            "line"
+        };
+        out.push_str(&format!("<span class=\"{}\">", class));
+        if numbered {
+            out.push_str(&format!(
+                "<span class=\"lineno\" aria-hidden=\"true\">{}</span>",
+                line_number
+            ));
+        }
+        out.push_str(line);
+        out.push_str("</span>\n");
+    }
+    out
+}
+
+/// Values substituted into the HTML template by `wrap_html_document`.
+pub struct TemplateContext<'a> {
+    pub title: &'a str,
+    /// The article header's date line, filled in as `{{date}}`.
+    pub date: &'a str,
+    pub body: &'a str,
+    pub table_of_contents: &'a str,
+    pub metas: &'a str,
+    pub index: &'a str,
+    pub scripts: &'a str,
+    pub vars: &'a BTreeMap<String, String>,
+    /// Other header fields, filled in as `{{meta.key}}`.
+    pub meta: &'a BTreeMap<String, String>,
+    pub data: &'a serde_json::Value,
+    /// The article header's `featured` flag, filled in as `{{featured}}`.
+    pub featured: bool,
+    /// Words of the article's prose and the minutes they take to read,
+    /// filled in as `{{wordcount}}` and `{{readingtime}}`.
+    pub word_count: usize,
+    pub reading_minutes: usize,
+    /// `html.related_posts` links of a collection post, filled in as `{{related}}`.
+    pub related: &'a str,
+    /// Posts of a collection index, in index order, for `{{#each posts}}`.
+    pub posts: &'a [serde_json::Value],
+    /// Figure images of the page; the first is preloaded by `{{preload}}` and
+    /// all are listed as JSON by `{{images}}`.
+    pub images: &'a [PageImage],
+}
+
+pub fn wrap_html_document(
+    config: &config::Config,
+    ctx: &TemplateContext,
+) -> Result<String, String> {
+    let template_path = &config.html.template_path;
+    let (template, _) = read_template(Path::new(template_path))?;
+
+    let css_href_resolved = prepare_css_href(config)?;
+    let css_href = html_escape_attr(&css_href_resolved);
+    let tags: Vec<serde_json::Value> = ctx
+        .vars
+        .get("x-tags")
+        .map(|tags| {
+            tags.split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(|tag| serde_json::Value::String(tag.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let is_set = |name: &str| {
+        let set = match name {
+            "title" => !ctx.title.is_empty(),
+            "date" => !ctx.date.is_empty(),
+            "tags" => !tags.is_empty(),
+            "posts" => !ctx.posts.is_empty(),
+            "css" => !css_href.is_empty(),
+            "toc" | "tableofcontents" => !ctx.table_of_contents.trim().is_empty(),
+            "metas" => !ctx.metas.trim().is_empty(),
+            "index" => !ctx.index.trim().is_empty(),
+            "scripts" => !ctx.scripts.trim().is_empty(),
+            "featured" => ctx.featured,
+            "wordcount" | "readingtime" => ctx.word_count > 0,
+            "related" => !ctx.related.is_empty(),
+            "preload" | "images" => !ctx.images.is_empty(),
+            "body" => !ctx.body.trim().is_empty(),
+            _ => {
+                let value = match name.strip_prefix("data.") {
+                    Some(path) => crate::data::lookup(ctx.data, path),
+                    None if name.starts_with("meta.") => ctx
+                        .meta
+                        .get(&name["meta.".len()..].to_ascii_lowercase())
+                        .cloned(),
+                    None if name.starts_with("x-") => {
+                        ctx.vars.get(&name.to_ascii_lowercase()).cloned()
+                    }
+                    None => return Err(format!("unknown condition {{{{#if {}}}}}", name)),
+                };
+                value.is_some_and(|value| !value.trim().is_empty())
+            }
+        };
+        Ok(set)
+    };
+    let items = |name: &str| {
+        let list = match name {
+            "tags" => return Ok(tags.clone()),
+            "posts" => return Ok(ctx.posts.to_vec()),
+            _ => match name.strip_prefix("data.") {
+                Some(path) => crate::data::value(ctx.data, path),
+                None => return Err(format!("unknown list {{{{#each {}}}}}", name)),
+            },
+        };
+        Ok(list
+            .and_then(serde_json::Value::as_array)
+            .cloned()
+            .unwrap_or_default())
+    };
+    let template = template_loops(&template, items, is_set)
+        .and_then(|template| template_conditionals(&template, is_set))
+        .map_err(|e| format!("HTML template {}: {}", template_path, e))?;
+
+    let template = template
+        .replace("{{title}}", &html_escape_attr(ctx.title))
+        .replace("{{date}}", &html_escape_attr(ctx.date))
+        .replace("{{css}}", &css_href)
+        .replace("{{tableofcontents}}", ctx.table_of_contents)
+        .replace("{{metas}}", ctx.metas)
+        .replace("{{index}}", ctx.index)
+        .replace("{{scripts}}", ctx.scripts)
+        .replace("{{featured}}", if ctx.featured { "featured" } else { "" })
+        .replace("{{wordcount}}", &ctx.word_count.to_string())
+        .replace("{{readingtime}}", &ctx.reading_minutes.to_string())
+        .replace("{{related}}", ctx.related)
+        .replace("{{preload}}", &image_preload_link(ctx.images))
+        .replace("{{images}}", &page_images_json(ctx.images));
+    let template = header_fields(&template, ctx.meta);
+    // Page variables are filled before the body so `{{x-...}}` text inside
+    // the rendered article is never treated as a placeholder.
+    let template = substitute_page_vars(&template, ctx.vars, &config.vars, ctx.data, true);
+    Ok(
+        crate::assets::fingerprint_references(&template, config.root_url.as_deref())
+            .replace("{{body}}", ctx.body),
+    )
+}
+
+/// Replaces `{{meta.key}}` placeholders with the page's other header
+/// fields, attribute-escaped; fields the page doesn't set render empty.
+fn header_fields<'a>(template: &'a str, meta: &BTreeMap<String, String>) -> Cow<'a, str> {
+    lazy_static! {
+        static ref FIELD: Regex = Regex::new(r"\{\{meta\.([A-Za-z0-9_-]+)\}\}").unwrap();
+    }
+    FIELD.replace_all(template, |caps: &regex::Captures| {
+        meta.get(&caps[1].to_ascii_lowercase())
+            .map(|value| html_escape_attr(value))
+            .unwrap_or_default()
+    })
+}
+
+/// `<link rel="preload">` for the page's first image, usually the hero image
+/// above the fold, so the browser fetches it before it parses the body.
+fn image_preload_link(images: &[PageImage]) -> String {
+    let Some(image) = images.first() else {
+        return String::new();
+    };
+    let mut link = format!(
+        "<link rel=\"preload\" as=\"image\" href=\"{}\"",
+        html_escape_attr(&image.src)
+    );
+    if let Some(srcset) = &image.srcset {
+        link.push_str(&format!(" imagesrcset=\"{}\"", html_escape_attr(srcset)));
+    }
+    if let Some(sizes) = &image.sizes {
+        link.push_str(&format!(" imagesizes=\"{}\"", html_escape_attr(sizes)));
+    }
+    link.push_str(" fetchpriority=\"high\">");
+    link
+}
+
+/// The page's images as a JSON array, safe to place inside a `<script>`.
+fn page_images_json(images: &[PageImage]) -> String {
+    serde_json::to_string(images)
+        .unwrap_or_else(|_| "[]".into())
+        // `</` would end a surrounding script element early
+        .replace("</", "<\\/")
+}
+
+/// Partials may include partials this many levels deep, which stops a
+/// partial that includes itself.
+const MAX_PARTIAL_DEPTH: usize = 8;
+
+/// The template at `path` with each `{{> file}}` replaced by that file, read
+/// relative to the file including it, and the partials it read.
+fn read_template(path: &Path) -> Result<(String, Vec<PathBuf>), String> {
+    let template = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read HTML template {}: {}", path.display(), e))?;
+    let mut partials = Vec::new();
+    let template = include_partials(&template, path, &mut partials, 0)?;
+    Ok((template, partials))
+}
+
+/// The partials the template at `path` includes, which the pages using it
+/// depend on; a template that can't be read has none.
+pub fn template_partials(path: &Path) -> Vec<PathBuf> {
+    read_template(path)
+        .map(|(_, partials)| partials)
+        .unwrap_or_default()
+}
+
+fn include_partials(
+    template: &str,
+    path: &Path,
+    partials: &mut Vec<PathBuf>,
+    depth: usize,
+) -> Result<String, String> {
+    lazy_static! {
+        static ref PARTIAL: Regex = Regex::new(r"\{\{>\s*([^\s{}]+)\s*\}\}").unwrap();
+    }
+    if !template.contains("{{>") {
+        return Ok(template.to_string());
+    }
+    if depth == MAX_PARTIAL_DEPTH {
+        return Err(format!(
+            "template partials nest more than {} deep at {}; does a partial include itself?",
+            MAX_PARTIAL_DEPTH,
+            path.display()
+        ));
+    }
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut out = String::new();
+    let mut last = 0;
+    for caps in PARTIAL.captures_iter(template) {
+        let tag = caps.get(0).unwrap();
+        out.push_str(&template[last..tag.start()]);
+        last = tag.end();
+        let partial = dir.join(&caps[1]);
+        let text = fs::read_to_string(&partial).map_err(|e| {
+            format!(
+                "failed to read template partial {} included by {}: {}",
+                partial.display(),
+                path.display(),
+                e
+            )
+        })?;
+        partials.push(partial.clone());
+        out.push_str(&include_partials(&text, &partial, partials, depth + 1)?);
+    }
+    out.push_str(&template[last..]);
+    Ok(out)
+}
+
+/// Repeats each `{{#each name}}...{{/each}}` section of a template once per
+/// item of the list `items` gives for `name`, filling `{{this}}` with the item
+/// and `{{this.key}}` with its fields. Conditionals inside a section are
+/// decided per item, with `{{#if this.key}}` testing the item and other names
+/// left to `is_set`; sections can't nest.
+fn template_loops(
+    template: &str,
+    items: impl Fn(&str) -> Result<Vec<serde_json::Value>, String>,
+    is_set: impl Fn(&str) -> Result<bool, String>,
+) -> Result<String, String> {
+    lazy_static! {
+        static ref TAG: Regex = Regex::new(r"\{\{(?:#each\s+([A-Za-z0-9_.-]+)|/each)\}\}").unwrap();
+        static ref THIS: Regex = Regex::new(r"\{\{this((?:\.[A-Za-z0-9_-]+)*)\}\}").unwrap();
+    }
+    let mut out = String::new();
+    // The list and body start of the open section
+    let mut open: Option<(&str, usize)> = None;
+    let mut last = 0;
+    for caps in TAG.captures_iter(template) {
+        let tag = caps.get(0).unwrap();
+        match (caps.get(1), open) {
+            (Some(name), None) => {
+                out.push_str(&template[last..tag.start()]);
+                open = Some((name.as_str(), tag.end()));
+            }
+            (Some(_), Some(_)) => return Err("{{#each}} sections can't nest".into()),
+            (None, None) => return Err("{{/each}} without a matching {{#each}}".into()),
+            (None, Some((name, start))) => {
+                let body = &template[start..tag.start()];
+                for item in items(name)? {
+                    let field = |path: &str| match path.strip_prefix('.') {
+                        Some(path) => crate::data::value(&item, path),
+                        None => Some(&item),
+                    };
+                    let body =
+                        template_conditionals(body, |name| match name.strip_prefix("this") {
+                            Some(path) if path.is_empty() || path.starts_with('.') => {
+                                Ok(field(path).is_some_and(is_truthy))
+                            }
+                            _ => is_set(name),
+                        })?;
+                    out.push_str(&THIS.replace_all(&body, |caps: &regex::Captures| {
+                        field(&caps[1])
+                            .and_then(crate::data::text)
+                            .map(|value| html_escape_attr(&value))
+                            .unwrap_or_default()
+                    }));
+                }
+                open = None;
+                last = tag.end();
+            }
+        }
+    }
+    if open.is_some() {
+        return Err("{{#each}} without a closing {{/each}}".into());
+    }
+    out.push_str(&template[last..]);
+    Ok(out)
+}
+
+/// Whether a loop item's field counts as set: `false`, `null`, blank text
+/// and empty lists and tables don't.
+fn is_truthy(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Null => false,
+        serde_json::Value::Bool(b) => *b,
+        serde_json::Value::Number(_) => true,
+        serde_json::Value::String(s) => !s.trim().is_empty(),
+        serde_json::Value::Array(items) => !items.is_empty(),
+        serde_json::Value::Object(map) => !map.is_empty(),
+    }
+}
+
+/// Keeps the `{{#if name}}...{{else}}...{{/if}}` branch chosen by `is_set`
+/// for each section of a template and drops the other; sections can nest.
+fn template_conditionals(
+    template: &str,
+    is_set: impl Fn(&str) -> Result<bool, String>,
+) -> Result<String, String> {
+    lazy_static! {
+        static ref TAG: Regex =
+            Regex::new(r"\{\{(?:#if\s+([A-Za-z0-9_.-]+)|(else)|/if)\}\}").unwrap();
+    }
+    let mut out = String::new();
+    // Whether each open section is showing its current branch
+    let mut shown: Vec<bool> = Vec::new();
+    let mut last = 0;
+    for caps in TAG.captures_iter(template) {
+        let tag = caps.get(0).unwrap();
+        if shown.iter().all(|&shown| shown) {
+            out.push_str(&template[last..tag.start()]);
+        }
+        last = tag.end();
+        if let Some(name) = caps.get(1) {
+            shown.push(is_set(name.as_str())?);
+        } else if caps.get(2).is_some() {
+            let branch = shown.last_mut().ok_or("{{else}} outside {{#if}}")?;
+            *branch = !*branch;
+        } else if shown.pop().is_none() {
+            return Err("{{/if}} without a matching {{#if}}".into());
+        }
+    }
+    if !shown.is_empty() {
+        return Err("{{#if}} without a closing {{/if}}".into());
+    }
+    out.push_str(&template[last..]);
+    Ok(out)
+}
+
+/// Replaces `{{x-name}}` placeholders with custom header values,
+/// `{{data.file.key}}` with values from data files and `{{name}}` with the
+/// site's `[vars]`. Templates (`is_template`) drop unknown `x-` and `data.`
+/// placeholders and get attribute-escaped values; article text keeps unknown
+/// placeholders verbatim so typos show. Unknown `{{name}}` placeholders are
+/// always kept, as templates fill `{{body}}` afterwards.
+fn substitute_page_vars<'a>(
+    input: &'a str,
+    vars: &BTreeMap<String, String>,
+    site_vars: &BTreeMap<String, String>,
+    data: &serde_json::Value,
+    is_template: bool,
+) -> Cow<'a, str> {
+    lazy_static! {
+        static ref PLACEHOLDER: Regex =
+            Regex::new(r"\{\{(x-[A-Za-z0-9_-]+|data(?:\.[A-Za-z0-9_-]+)+|[A-Za-z0-9_-]+)\}\}")
+                .unwrap();
+    }
+    if !input.contains("{{") {
+        return Cow::Borrowed(input);
+    }
+    PLACEHOLDER.replace_all(input, |caps: &regex::Captures| {
+        let name = &caps[1];
+        let value = if let Some(path) = name.strip_prefix("data.") {
+            crate::data::lookup(data, path)
+        } else if name.starts_with("x-") {
+            vars.get(&name.to_ascii_lowercase()).cloned()
+        } else {
+            match site_vars.get(name) {
+                Some(value) => Some(value.clone()),
+                None => return caps[0].to_string(),
+            }
+        };
+        match value {
+            Some(value) if is_template => html_escape_attr(&value),
+            Some(value) => value,
+            None if is_template => String::new(),
+            None => caps[0].to_string(),
+        }
+    })
+}
+
+fn prepare_css_href(config: &config::Config) -> Result<String, String> {
+    let raw = config.html.css_href.trim();
+    if raw.is_empty() {
+        return Ok(String::new());
+    }
+    if css_is_remote(raw) {
+        return Ok(raw.to_string());
+    }
+
+    let (source_path, location) = stylesheet_source_path(raw);
+    let contents = fs::read(&source_path)
+        .map_err(|e| format!("failed to read stylesheet {}: {}", source_path.display(), e))?;
+    let hash = blake3::hash(&contents);
+    let hash_hex = hash.to_hex().to_string();
+    let short_hash = &hash_hex[..12];
+
+    let hashed_source_path =
+        append_hash_to_filename(&source_path, short_hash).map_err(|e| e.to_string())?;
+    if let Some(parent) = hashed_source_path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("failed to ensure directory {}: {}", parent.display(), e))?;
+    }
+    fs::write(&hashed_source_path, &contents).map_err(|e| {
+        format!(
+            "failed to write hashed stylesheet {}: {}",
+            hashed_source_path.display(),
+            e
+        )
+    })?;
+
+    let hashed_path_str = hashed_source_path.to_string_lossy().replace('\\', "/");
+
+    let public_path = match location {
+        StylesheetLocation::RootRelative => {
+            format!("/{}", hashed_path_str.trim_start_matches('/'))
+        }
+        _ => hashed_path_str,
+    };
+
+    let final_href = match location {
+        StylesheetLocation::Relative | StylesheetLocation::RootRelative => {
+            css_href_with_root(config, &public_path)
+        }
+        StylesheetLocation::Absolute => public_path,
+    };
+
+    Ok(final_href)
+}
+
+fn css_href_with_root(config: &config::Config, raw: &str) -> String {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return String::new();
+    }
+
+    match config.root_url.as_deref() {
+        Some(root) if raw.starts_with('/') && !raw.starts_with("//") => {
+            if root == "/" {
+                raw.to_string()
+            } else {
+                format!("{}{}", root, raw)
+            }
+        }
+        Some(root) if is_relative_href(raw) => {
+            let trimmed = raw.trim_start_matches('/');
+            if root == "/" {
+                format!("/{}", trimmed)
+            } else {
+                format!("{}/{}", root, trimmed)
+            }
+        }
+        _ => raw.to_string(),
+    }
+}
+
+fn css_is_remote(href: &str) -> bool {
+    if href.starts_with("//") {
+        return true;
+    }
+    if let Some(pos) = href.find(':') {
+        let scheme = &href[..pos];
+        if scheme.len() == 1 && scheme.chars().all(|c| c.is_ascii_alphabetic()) {
+            // Likely a Windows drive letter.
+            return false;
+        }
+        return true;
+    }
+    false
+}
+
+fn stylesheet_source_path(raw: &str) -> (PathBuf, StylesheetLocation) {
+    if raw.starts_with('/') && !raw.starts_with("//") {
+        let absolute = Path::new(raw);
+        if absolute.exists() {
+            return (absolute.to_path_buf(), StylesheetLocation::Absolute);
+        }
+        return (
+            Path::new(raw.trim_start_matches('/')).to_path_buf(),
+            StylesheetLocation::RootRelative,
+        );
+    }
+
+    let path = Path::new(raw);
+    if path.is_absolute() {
+        (path.to_path_buf(), StylesheetLocation::Absolute)
     } else {
         (path.to_path_buf(), StylesheetLocation::Relative)
     }
-}
+}
+
+fn append_hash_to_filename(path: &Path, hash: &str) -> Result<PathBuf, &'static str> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("stylesheet path is missing a valid file name")?;
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => (stem, Some(ext)),
+        _ if !file_name.is_empty() => (file_name, None),
+        _ => return Err("stylesheet path has an empty file name"),
+    };
+
+    let hashed_name = match extension {
+        Some(ext) => format!("{}-{}.{}", stem, hash, ext),
+        None => format!("{}-{}", stem, hash),
+    };
+
+    Ok(path.with_file_name(hashed_name))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StylesheetLocation {
+    Relative,
+    RootRelative,
+    Absolute,
+}
+
+fn is_relative_href(href: &str) -> bool {
+    !href.is_empty()
+        && !href.starts_with('/')
+        && !href.starts_with('#')
+        && !href.starts_with("//")
+        && !href.contains(':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use std::path::{Path, PathBuf};
+
+    fn renderer_with_config(cfg: crate::config::Config) -> HtmlRenderer {
+        renderer_with_assets(cfg, PathBuf::from("."))
+    }
+
+    fn renderer_with_assets(cfg: crate::config::Config, asset_root: PathBuf) -> HtmlRenderer {
+        HtmlRenderer {
+            engine: None,
+            memo_math: std::collections::HashMap::new(),
+            math_cache: None,
+            fragment_cache: crate::fragment_cache::for_config(&cfg),
+            fragment_page_key: String::new(),
+            config: cfg.clone(),
+            toc: Vec::new(),
+            section_counters: Vec::new(),
+            meta_description: None,
+            meta_image: None,
+            image_processor: crate::image_processor::ImageProcessor::new(&cfg),
+            asset_root,
+            page_url: None,
+            exif_open: false,
+            page_vars: BTreeMap::new(),
+            site_data: Arc::new(serde_json::Value::Null),
+            site_references: Arc::default(),
+            uses_copy_button: false,
+            uses_embed_facade: false,
+            uses_lightbox: false,
+            uses_mermaid: false,
+            equation_labels: std::collections::HashMap::new(),
+            math_failures: Vec::new(),
+            image_errors: Vec::new(),
+            resizes_deferred: false,
+            figures_rendered: 0,
+            paragraph_id: None,
+            page_images: Vec::new(),
+        }
+    }
+
+    fn write_test_png(path: &Path) {
+        let img = RgbImage::from_pixel(1, 1, Rgb([255, 0, 0]));
+        img.save(path).unwrap();
+    }
+
+    #[test]
+    fn fallback_inline_math() {
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render_paragraph(&[
+            InlineElement::Text("A ".into()),
+            InlineElement::InlineMath("x+y".into()),
+            InlineElement::Text(" B".into()),
+        ]);
+        assert!(html.contains("<span class=\"math-inline\">x+y</span>"));
+    }
+
+    #[test]
+    fn labelled_equations_resolve_eqref_and_refs() {
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n$ a = b\n\n$ E = mc^2 \\label{energy}\n\nBy \\eqref{energy} and (#energy), not (#other).\n");
+        let html = r.render(&parser.article);
+        assert!(html.contains("<div class=\"math\" id=\"eq1\">"));
+        assert!(html.contains(
+            "<div class=\"math\" id=\"energy\"><a href=\"#energy\" class=\"eqnum\">2</a>"
+        ));
+        assert!(!html.contains("\\label"));
+        assert!(html.contains("By <a class=\"eqref\" href=\"#energy\">(2)</a> and <a class=\"eqref\" href=\"#energy\">(2)</a>"));
+        assert!(html.contains("<a class=\"refname\" href=\"#other\"><cite>other</cite></a>"));
+    }
+
+    #[test]
+    fn inline_spacing_follows_source() {
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n[a](/a)b, _c_ d and $x$-ish `e`.\n");
+        let html = r.render(&parser.article);
+        assert!(html.contains(
+            "<p><a href=\"/a\">a</a>b, <em>c</em> d and <span class=\"math-inline\">x</span>-ish <code>e</code>.</p>"
+        ));
+    }
+
+    #[test]
+    fn cjk_soft_wrap_joins_lines_without_spaces() {
+        let source = "Doc\n\n===\n\n我喜欢\n  吃饭。\nThen English\ntext 和\n中文.\n";
+        let mut parser = crate::parser::Parser::default();
+        parser.parse(source);
+
+        let mut r = renderer_with_config(crate::config::Config::default());
+        assert!(r.render(&parser.article).contains("我喜欢\n  吃饭。"));
+
+        let mut cfg = crate::config::Config::default();
+        cfg.html.soft_wrap = crate::config::SoftWrap::Cjk;
+        let mut r = renderer_with_config(cfg);
+        let html = r.render(&parser.article);
+        assert!(html.contains("<p>我喜欢吃饭。\nThen English\ntext 和中文.</p>"));
+    }
+
+    #[test]
+    fn code_block_line_numbers_wrap_each_line() {
+        let r = renderer_with_config(crate::config::Config::default());
+        let html = r.render_code_block(Some("rust"), "let a = 1;\n/* x\ny */\n", Some(true), &[]);
+        assert!(html.starts_with("<pre class=\"numbered\""));
+        assert!(html.contains("<span class=\"lineno\" aria-hidden=\"true\">1</span>"));
+        assert!(html.contains("<span class=\"lineno\" aria-hidden=\"true\">3</span>"));
+        assert!(!html.contains("aria-hidden=\"true\">4</span>"));
+        for line in html
+            .lines()
+            .filter(|l| l.starts_with("<span class=\"line\">"))
+        {
+            assert_eq!(
+                line.matches("<span").count(),
+                line.matches("</span>").count()
+            );
+        }
+
+        let plain = r.render_code_block(Some("rust"), "let a = 1;\n", None, &[]);
+        assert!(!plain.contains("lineno"));
+    }
+
+    #[test]
+    fn page_vars_fill_article_text_and_templates() {
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\nx-project: Lidar\n\n===\n\nSee {{x-project}} and {{x-missing}}.\n");
+        let html = r.render(&parser.article);
+        assert!(html.contains("See Lidar and {{x-missing}}."));
+
+        let vars = parser.article.header.unwrap().vars;
+        let data = serde_json::json!({ "talks": [{ "title": "Lidar & SLAM" }] });
+        let site_vars = BTreeMap::from([("camera".to_string(), "Sony a7R IV".to_string())]);
+        let filled = substitute_page_vars(
+            "<b style=\"{{x-project}}\">{{x-none}}</b>{{data.talks.0.title}} {{camera}}{{body}}",
+            &vars,
+            &site_vars,
+            &data,
+            true,
+        );
+        assert_eq!(
+            filled,
+            "<b style=\"Lidar\"></b>Lidar &amp; SLAM Sony a7R IV{{body}}"
+        );
+    }
+
+    #[test]
+    fn site_vars_fill_text_and_links() {
+        let mut cfg = crate::config::Config::default();
+        cfg.vars.insert("camera".into(), "Sony a7R IV".into());
+        cfg.vars
+            .insert("shop".into(), "https://shop.example/a7r4".into());
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\nShot on the {{camera}} ([buy]({{shop}})).\n");
+        let html = renderer_with_config(cfg).render(&parser.article);
+        assert!(html
+            .contains("Shot on the Sony a7R IV (<a href=\"https://shop.example/a7r4\">buy</a>)."));
+    }
+
+    #[test]
+    fn template_conditionals_drop_unset_sections() {
+        let is_set = |name: &str| match name {
+            "toc" => Ok(true),
+            "index" | "x-tags" => Ok(false),
+            _ => Err(format!("unknown condition {}", name)),
+        };
+        let template =
+            "<body>{{#if toc}}<nav>{{tableofcontents}}{{#if index}}i{{/if}}</nav>{{/if}}\
+            {{#if x-tags}}<p>{{x-tags}}</p>{{else}}<p>untagged</p>{{/if}}</body>";
+        assert_eq!(
+            template_conditionals(template, is_set).unwrap(),
+            "<body><nav>{{tableofcontents}}</nav><p>untagged</p></body>"
+        );
+        assert!(template_conditionals("{{#if toc}}x", is_set).is_err());
+        assert!(template_conditionals("x{{/if}}", is_set).is_err());
+        assert!(template_conditionals("{{#if tocc}}x{{/if}}", is_set).is_err());
+    }
+
+    #[test]
+    fn header_fields_fill_meta_placeholders() {
+        let meta = BTreeMap::from([("subtitle".to_string(), "Kites & lidar".to_string())]);
+        assert_eq!(
+            header_fields("<h2>{{meta.Subtitle}}</h2>{{meta.hero}}{{title}}", &meta),
+            "<h2>Kites &amp; lidar</h2>{{title}}"
+        );
+    }
+
+    #[test]
+    fn template_loops_repeat_sections_per_item() {
+        let posts = serde_json::json!([
+            {"title": "Kites & lidar", "href": "kites/", "featured": true},
+            {"title": "Scanners", "href": "scanners/", "featured": false},
+        ]);
+        let items = |name: &str| match name {
+            "posts" => Ok(posts.as_array().unwrap().clone()),
+            "tags" => Ok(vec!["rust".into(), "lidar".into()]),
+            _ => Err(format!("unknown list {}", name)),
+        };
+        let is_set = |name: &str| match name {
+            "toc" => Ok(false),
+            _ => Err(format!("unknown condition {}", name)),
+        };
+        let template = "<ul>{{#each posts}}<li><a href=\"{{this.href}}\">{{this.title}}</a>\
+            {{#if this.featured}}*{{/if}}{{#if toc}}{{tableofcontents}}{{/if}}</li>{{/each}}</ul>\
+            {{#each tags}}#{{this}} {{/each}}";
+        assert_eq!(
+            template_loops(template, items, is_set).unwrap(),
+            "<ul><li><a href=\"kites/\">Kites &amp; lidar</a>*</li>\
+            <li><a href=\"scanners/\">Scanners</a></li></ul>#rust #lidar "
+        );
+        assert!(template_loops("{{#each posts}}x", items, is_set).is_err());
+        assert!(template_loops(
+            "{{#each tags}}{{#each tags}}{{/each}}{{/each}}",
+            items,
+            is_set
+        )
+        .is_err());
+        assert!(template_loops("{{#each talks}}{{/each}}", items, is_set).is_err());
+    }
+
+    #[test]
+    fn template_partials_are_read_relative_to_their_includer() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("partials")).unwrap();
+        fs::write(
+            dir.path().join("page.html"),
+            "<html>{{> partials/head.html}}{{body}}</html>",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("partials").join("head.html"),
+            "<head>{{>  nav.html }}</head>",
+        )
+        .unwrap();
+        fs::write(dir.path().join("partials").join("nav.html"), "<nav></nav>").unwrap();
+        let (template, partials) = read_template(&dir.path().join("page.html")).unwrap();
+        assert_eq!(template, "<html><head><nav></nav></head>{{body}}</html>");
+        assert_eq!(partials.len(), 2);
+
+        fs::write(dir.path().join("loop.html"), "{{> loop.html}}").unwrap();
+        let err = read_template(&dir.path().join("loop.html")).unwrap_err();
+        assert!(err.contains("nest more than 8 deep"), "{}", err);
+    }
+
+    #[test]
+    fn code_block_copy_button_wraps_pre_and_requests_script() {
+        let mut cfg = crate::config::Config::default();
+        cfg.html.copy_button = true;
+        let mut r = renderer_with_config(cfg);
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n~~~~\nlet a = 1;\n~~~~\n");
+        let html = r.render(&parser.article);
+        assert!(
+            html.contains("<div class=\"code-block\"><button type=\"button\" class=\"copy-code\"")
+        );
+        assert!(html.contains("</pre></div>"));
+        assert!(r.page_scripts().contains("navigator.clipboard"));
+
+        parser.parse("Doc\n\n===\n\nNo code.\n");
+        r.render(&parser.article);
+        assert!(r.page_scripts().is_empty());
+    }
+
+    #[test]
+    fn graphviz_blocks_become_numbered_figures() {
+        let cache = tempfile::tempdir().unwrap();
+        let mut cfg = crate::config::Config::default();
+        cfg.images.cache_dir = cache.path().to_string_lossy().into_owned();
+        cfg.html.graphviz_command =
+            r#"sh -c "echo '<?xml version=\"1.0\"?>'; echo '<svg><g/></svg>'""#.into();
+        let mut parser = crate::parser::Parser::default();
+        parser.parse(
+            "Doc\n\n===\n\n~~~\nlang dot\nfile deps.dot : The _crates_ [#deps]\ndigraph { a -> b }\n~~~\n",
+        );
+        let html = renderer_with_config(cfg.clone()).render(&parser.article);
+        assert!(html.contains(
+            "<figure id=\"deps\" class=\"diagram\"><svg><g/></svg><figcaption><p><a href=\"#deps\" class=\"fignum\">FIGURE 1</a> The <em>crates</em></p></figcaption></figure>"
+        ));
+
+        cfg.html.graphviz_command = String::new();
+        let html = renderer_with_config(cfg).render(&parser.article);
+        assert!(html.contains("<pre class=\"graphviz\"><code>digraph { a -&gt; b }\n</code></pre>"));
+    }
+
+    #[test]
+    fn mermaid_blocks_are_left_for_the_browser() {
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n~~~\nlang mermaid\ngraph TD; A-->B\n~~~\n");
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render(&parser.article);
+        assert!(html.contains("<pre class=\"mermaid\">graph TD; A--&gt;B\n</pre>"));
+        assert!(r.page_scripts().contains("mermaid.run("));
+
+        let mut cfg = crate::config::Config::default();
+        cfg.html.mermaid = crate::config::Mermaid::Off;
+        let mut r = renderer_with_config(cfg);
+        assert!(!r.render(&parser.article).contains("class=\"mermaid\""));
+        assert!(r.page_scripts().is_empty());
+    }
+
+    #[test]
+    fn embeds_use_privacy_enhanced_players_or_a_facade() {
+        let source =
+            "Doc\n\n===\n\nembed youtube dQw4w9WgXcQ : A \"talk\"\n\nembed vimeo 76979871\n";
+        let mut parser = crate::parser::Parser::default();
+        parser.parse(source);
+
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render(&parser.article);
+        assert!(html.contains("<figure id=\"fig1\" class=\"embed\"><div class=\"embed-frame\"><iframe src=\"https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ\" title=\"A &quot;talk&quot;\" loading=\"lazy\""));
+        assert!(html.contains(
+            "src=\"https://player.vimeo.com/video/76979871?dnt=1\" title=\"Vimeo video\""
+        ));
+        assert!(html.contains("FIGURE 2</a>"));
+        assert!(r.page_scripts().is_empty());
+
+        let mut cfg = crate::config::Config::default();
+        cfg.html.embed_facade = true;
+        let mut r = renderer_with_config(cfg);
+        let html = r.render(&parser.article);
+        assert!(!html.contains("<iframe"));
+        assert!(html.contains("<a class=\"embed-facade\" href=\"https://www.youtube.com/watch?v=dQw4w9WgXcQ\" data-embed-src=\"https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ?autoplay=1\""));
+        assert!(html.contains("<img src=\"https://i.ytimg.com/vi/dQw4w9WgXcQ/hqdefault.jpg\""));
+        assert!(html.contains(
+            "data-embed-src=\"https://player.vimeo.com/video/76979871?dnt=1&amp;autoplay=1\""
+        ));
+        assert!(r.page_scripts().contains("embed-facade"));
+    }
+
+    #[test]
+    fn lightbox_links_figures_grouped_by_section() {
+        let source = "Doc\n\n===\n\npic /a.svg resize=off A : First\n\n# Trip\n\npic /b.svg resize=off B : Second\n";
+        let mut parser = crate::parser::Parser::default();
+        parser.parse(source);
+
+        let mut r = renderer_with_config(crate::config::Config::default());
+        assert!(!r.render(&parser.article).contains("data-lightbox"));
+
+        let mut cfg = crate::config::Config::default();
+        cfg.html.lightbox = crate::config::Lightbox::Markup;
+        let mut r = renderer_with_config(cfg.clone());
+        let html = r.render(&parser.article);
+        assert!(html.contains(
+            "<a class=\"lightbox\" href=\"/a.svg\" data-lightbox=\"gallery\"><img src=\"/a.svg\" alt=\"A\""
+        ));
+        assert!(html.contains("<a class=\"lightbox\" href=\"/b.svg\" data-lightbox=\"trip\"><img"));
+        assert!(r.page_scripts().is_empty());
+
+        cfg.html.lightbox = crate::config::Lightbox::Script;
+        let mut r = renderer_with_config(cfg);
+        r.render(&parser.article);
+        assert!(r.page_scripts().contains("lightbox-view"));
+    }
+
+    #[test]
+    fn language_spans_carry_lang_attributes() {
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\nShe said {fr|_bonjour_} and left.\n");
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render(&parser.article);
+        assert!(html.contains("She said <span lang=\"fr\"><em>bonjour</em></span> and left."));
+    }
+
+    #[test]
+    fn task_list_items_show_checkboxes() {
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n* [x] Parser\n** [ ] Lexer\n* [link](a.html) stays\n");
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render(&parser.article);
+        assert!(html.contains(
+            "<ul><li class=\"task\"><input type=\"checkbox\" disabled checked/> Parser<ul><li class=\"task\"><input type=\"checkbox\" disabled/> Lexer</li></ul></li><li><a "
+        ));
+    }
+
+    #[test]
+    fn details_blocks_hold_rendered_blocks() {
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n+++ Spoiler\n\nThe butler.\n\nIn the hall.\n+++\n");
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render(&parser.article);
+        assert!(html.contains(
+            "<details class=\"fold\"><summary>Spoiler</summary>\n<p>The butler.</p>\n<p>In the hall.</p>\n</details>\n"
+        ));
+    }
+
+    #[test]
+    fn fragment_cache_reuses_blocks_but_not_headings() {
+        use tempfile::tempdir;
+
+        let tmp = tempdir().unwrap();
+        let mut cfg = crate::config::Config::default();
+        cfg.images.cache_dir = tmp.path().join("cache").to_string_lossy().into_owned();
+        cfg.build.fragment_cache = true;
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n# Intro\n\nThe butler.\n");
+
+        let mut r = renderer_with_config(cfg.clone());
+        let first = r.render(&parser.article);
+        let fragments = tmp.path().join("cache").join("fragments");
+        let cached: Vec<PathBuf> = std::fs::read_dir(&fragments)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(cached.len(), 1);
+        std::fs::write(&cached[0], "<p>From the cache.</p>\n").unwrap();
+
+        let mut r = renderer_with_config(cfg);
+        let second = r.render(&parser.article);
+        assert!(first.contains("<p>The butler.</p>"));
+        assert!(second.contains("<p>From the cache.</p>"));
+        assert!(second.contains("<span>Intro</span></h1>"));
+        assert_eq!(r.meta_description.as_deref(), Some("The butler."));
+    }
 
-fn append_hash_to_filename(path: &Path, hash: &str) -> Result<PathBuf, &'static str> {
-    let file_name = path
-        .file_name()
-        .and_then(|name| name.to_str())
-        .ok_or("stylesheet path is missing a valid file name")?;
-    let (stem, extension) = match file_name.rsplit_once('.') {
-        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => (stem, Some(ext)),
-        _ if !file_name.is_empty() => (file_name, None),
-        _ => return Err("stylesheet path has an empty file name"),
-    };
+    #[test]
+    fn table_cells_carry_their_spans() {
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n| Model | Speed | < |\n| A | 1 | 2 |\n| ^ | 3 | 4 |\nSpeeds\n");
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render(&parser.article);
+        assert!(html.contains(
+            "<tr><th>Model</th><th colspan=\"2\">Speed</th></tr><tr><td rowspan=\"2\">A</td><td>1</td><td>2</td></tr><tr><td>3</td><td>4</td></tr>"
+        ));
+    }
 
-    let hashed_name = match extension {
-        Some(ext) => format!("{}-{}.{}", stem, hash, ext),
-        None => format!("{}-{}", stem, hash),
-    };
+    #[test]
+    fn uncaptioned_tables_are_not_numbered() {
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n| A |\n\n| B |\nSecond\n");
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render(&parser.article);
+        assert!(html.contains("<table><tr><th>A</th></tr></table>\n<figure id=\"table1\">"));
+        assert!(html.contains("class=\"fignum\">Table 1</a> Second"));
+    }
 
-    Ok(path.with_file_name(hashed_name))
-}
+    #[test]
+    fn list_items_wrap_their_indented_paragraphs() {
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n1. First\n\n   More about it.\n1. Second\n");
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render(&parser.article);
+        assert!(
+            html.contains("<ol><li><p>First</p><p>More about it.</p>\n</li><li>Second</li></ol>")
+        );
+    }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum StylesheetLocation {
-    Relative,
-    RootRelative,
-    Absolute,
-}
+    #[test]
+    fn figures_fall_back_between_alt_text_and_caption() {
+        let source = "Doc\n\n===\n\npic /a.svg resize=off : A _red_ kite [#kite]\n\npic /b.svg resize=off Two gulls : [#gulls]\n";
+        let mut parser = crate::parser::Parser::default();
+        parser.parse(source);
 
-fn is_relative_href(href: &str) -> bool {
-    !href.is_empty()
-        && !href.starts_with('/')
-        && !href.starts_with('#')
-        && !href.starts_with("//")
-        && !href.contains(':')
-}
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render(&parser.article);
+        assert!(html.contains("<img src=\"/a.svg\" alt=\"\""));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use image::{Rgb, RgbImage};
-    use std::path::{Path, PathBuf};
+        let mut cfg = crate::config::Config::default();
+        cfg.html.alt_from_caption = true;
+        cfg.html.caption_from_alt = true;
+        let mut r = renderer_with_config(cfg);
+        let html = r.render(&parser.article);
+        assert!(html.contains("<img src=\"/a.svg\" alt=\"A red kite\""));
+        assert!(html.contains("<img src=\"/b.svg\" alt=\"Two gulls\""));
+        assert!(html.contains("FIGURE 2</a> Two gulls"));
+    }
 
-    fn renderer_with_config(cfg: crate::config::Config) -> HtmlRenderer {
-        HtmlRenderer {
-            engine: None,
-            memo_math: std::collections::HashMap::new(),
-            config: cfg.clone(),
-            toc: Vec::new(),
-            section_counters: Vec::new(),
-            meta_description: None,
-            meta_image: None,
-            image_processor: crate::image_processor::ImageProcessor::new(&cfg),
-            asset_root: PathBuf::from("."),
-        }
+    #[test]
+    fn toc_json_is_embedded_for_scripts() {
+        let mut cfg = crate::config::Config::default();
+        cfg.html.toc_json = crate::config::TocJson::Embed;
+        let mut r = renderer_with_config(cfg);
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\n# Intro\n\nText.\n\n## A </script> B\n\nMore.\n");
+        r.render(&parser.article);
+        assert_eq!(
+            r.table_of_contents_json().unwrap(),
+            r#"[{"level":1,"title":"Intro","number":"1","id":"s1","slug":"intro"},{"level":2,"title":"A </script> B","number":"1.1","id":"s1.1","slug":"a-script-b"}]"#
+        );
+        let scripts = r.page_scripts();
+        assert!(scripts.starts_with("<script type=\"application/json\" id=\"toc-data\">[{"));
+        assert!(scripts.contains(r#""A <\/script> B""#));
+
+        parser.parse("Doc\n\n===\n\nNo headings.\n");
+        r.render(&parser.article);
+        assert!(r.page_scripts().is_empty());
     }
 
-    fn write_test_png(path: &Path) {
-        let img = RgbImage::from_pixel(1, 1, Rgb([255, 0, 0]));
-        img.save(path).unwrap();
+    #[test]
+    fn code_block_dark_theme_emits_css_variables() {
+        let mut cfg = crate::config::Config::default();
+        cfg.html.highlight_theme = "onelight".into();
+        cfg.html.highlight_theme_dark = Some("onedark".into());
+        let r = renderer_with_config(cfg);
+        let html = r.render_code_block(Some("rust"), "fn main() {}\n", Some(true), &[]);
+        assert!(html.starts_with("<pre class=\"numbered themed\" style=\"--hl-bg: "));
+        assert!(html.contains("--hl-dark-fg: "));
+        assert!(!html.contains("<span style=\"color:"));
     }
 
     #[test]
-    fn fallback_inline_math() {
+    fn code_block_highlight_lines_marks_selected_lines() {
+        let r = renderer_with_config(crate::config::Config::default());
+        let html = r.render_code_block(None, "a\nb\nc\n", Some(false), &[2, 3]);
+        assert!(!html.contains("lineno"));
+        assert!(html.contains("<span class=\"line\">a</span>"));
+        assert!(html.contains("<span class=\"line hl\">b</span>"));
+        assert!(html.contains("<span class=\"line hl\">c</span>"));
+    }
+
+    #[test]
+    fn code_block_with_file_caption_renders_numbered_listing() {
+        use crate::parser::Parser;
+
+        let input = "Doc\n\n===\n\n~~~\nlang rust\nfile src/main.rs : Entry point [#main]\nfn main() {}\n~~~\n\n~~~\nlang\nfile build.rs\nfn build() {}\n~~~\n\nSee (#main).\n";
+        let mut parser = Parser::default();
+        parser.parse(input);
         let mut r = renderer_with_config(crate::config::Config::default());
-        let html = r.render_paragraph(&[
-            InlineElement::Text("A ".into()),
-            InlineElement::InlineMath("x+y".into()),
-            InlineElement::Text(" B".into()),
-        ]);
-        assert!(html.contains("<span class=\"math-inline\">x+y</span>"));
+        let html = r.render(&parser.article);
+        assert!(html.contains("<figure id=\"main\" class=\"listing\"><figcaption><a href=\"#main\" class=\"fignum\">Listing 1</a> <code class=\"filename\">src/main.rs</code> Entry point</figcaption><pre"));
+        assert!(html.contains("<figure class=\"listing\"><figcaption><code class=\"filename\">build.rs</code></figcaption><pre"));
+        assert!(html.contains("href=\"#main\"><cite>main</cite>"));
+    }
+
+    #[test]
+    fn data_permalinks_on_anchored_elements() {
+        use crate::parser::Parser;
+
+        let mut cfg = crate::config::Config::default();
+        cfg.html.data_permalinks = true;
+        let mut parser = Parser::default();
+        parser.parse("Doc\n\n===\n\n# Intro\n\n$ x = 1 \\label{one}\n\n| A |\nCaption\n");
+        let mut r = renderer_with_config(cfg);
+        assert!(!r.render(&parser.article).contains("data-permalink"));
+
+        r.set_page_url(Some("https://example.com/post".into()));
+        let html = r.render(&parser.article);
+        assert!(html.contains("<h1 id=\"s1\" data-permalink=\"https://example.com/post#s1\">"));
+        assert!(html.contains(
+            "<div class=\"math\" id=\"one\" data-permalink=\"https://example.com/post#one\">"
+        ));
+        assert!(html.contains(
+            "<figure id=\"table1\" data-permalink=\"https://example.com/post#table1\"><table>"
+        ));
+    }
+
+    #[test]
+    fn paragraph_ids_survive_edits_elsewhere() {
+        use crate::parser::Parser;
+
+        let mut cfg = crate::config::Config::default();
+        cfg.html.paragraph_ids = true;
+        let mut r = renderer_with_config(cfg);
+        let mut parser = Parser::default();
+        parser.parse("Doc\n\n===\n\nSame words.\n\nSame words.\n\nAn _emphasized_\nline.\n");
+        let ids: Vec<String> = parser
+            .article
+            .paragraph_ids()
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[1], format!("{}-2", ids[0]));
+        let html = r.render(&parser.article);
+        assert!(html.contains(&format!("<p id=\"{}\">Same words.</p>", ids[0])));
+
+        let mut edited = Parser::default();
+        edited.parse("Doc\n\n===\n\n# Intro\n\nNew first paragraph.\n\nAn _emphasized_ line.\n");
+        let html = r.render(&edited.article);
+        assert!(html.contains(&format!("<p id=\"{}\">An ", ids[2])));
+    }
+
+    #[test]
+    fn microformats_wrap_post_in_h_entry() {
+        use crate::parser::Parser;
+
+        let mut cfg = crate::config::Config::default();
+        cfg.html.microformats = true;
+        let mut parser = Parser::default();
+        parser.parse("Post\n\n2020-01-02\n\n===\n\nHello.\n");
+        let mut r = renderer_with_config(cfg);
+        r.set_page_url(Some("https://example.com/blog/post".into()));
+        let html = r.render(&parser.article);
+        assert!(html.starts_with("<article class=\"h-entry\">"));
+        assert!(html.contains("<h1 id=\"top\" class=\"p-name\">Post</h1>"));
+        assert!(
+            html.contains("<time class=\"dt-published\" datetime=\"2020-01-02\">2020-01-02</time>")
+        );
+        assert!(
+            html.contains("<data class=\"u-url\" value=\"https://example.com/blog/post\"></data>")
+        );
+        assert!(html.contains("<div class=\"e-content\">\n<p>Hello.</p>\n</div>\n</article>"));
     }
 
     #[test]
@@ -1387,25 +3921,26 @@ mod tests {
         cfg.images.sizes = vec![1200];
         cfg.images.layout_width = 1200;
 
-        let mut r = HtmlRenderer {
-            engine: None,
-            memo_math: std::collections::HashMap::new(),
-            config: cfg.clone(),
-            toc: Vec::new(),
-            section_counters: Vec::new(),
-            meta_description: None,
-            meta_image: None,
-            image_processor: crate::image_processor::ImageProcessor::new(&cfg),
-            asset_root: tmp.path().to_path_buf(),
-        };
+        let mut r = renderer_with_assets(cfg, tmp.path().to_path_buf());
 
         let caption = vec![
             InlineElement::Text("An ".into()),
             InlineElement::Emphasis(vec![InlineElement::Text("example".into())]),
         ];
-        let html = r.render_image_figure("tiny.png", None, 0, "An example", &caption);
+        let credit = [InlineElement::Text("Photo by A. Person, CC BY 4.0".into())];
+        let html = r.render_image_figure(
+            "tiny.png",
+            None,
+            0,
+            "An example",
+            &caption,
+            Some(&credit[..]),
+        );
         assert!(html.contains("FIGURE 1"));
         assert!(html.contains("alt=\"An example\""));
+        assert!(html.contains(
+            "<p class=\"credit\"><small>Photo by A. Person, CC BY 4.0</small></p><details>"
+        ));
         assert!(html.contains("<img src=\""));
         assert!(html.contains("srcset=\""));
         assert!(!html.contains("<picture>"));
@@ -1413,6 +3948,87 @@ mod tests {
         assert!(html.contains("aria-label=\"Download sizes\""));
     }
 
+    #[test]
+    fn resize_off_links_the_image_as_written() {
+        use tempfile::tempdir;
+
+        let tmp = tempdir().unwrap();
+        write_test_png(&tmp.path().join("tiny.png"));
+
+        let mut cfg = crate::config::Config::default();
+        cfg.images.cache_dir = tmp.path().join("cache").to_string_lossy().into_owned();
+        cfg.images.sizes = vec![1200];
+
+        let mut r = renderer_with_assets(cfg, tmp.path().to_path_buf());
+        let html = r.render_unprocessed_figure("tiny.png", None, 0, "Tiny", &[], None);
+        assert!(html.contains("<img src=\"tiny.png\" alt=\"Tiny\""));
+        assert!(html.contains("FIGURE 1"));
+        assert!(!html.contains("srcset="));
+        let cached = std::fs::read_dir(tmp.path().join("cache")).unwrap();
+        assert_eq!(cached.count(), 0);
+    }
+
+    #[test]
+    fn first_figures_load_eagerly() {
+        use tempfile::tempdir;
+
+        let tmp = tempdir().unwrap();
+        write_test_png(&tmp.path().join("tiny.png"));
+
+        let mut cfg = crate::config::Config::default();
+        cfg.images.cache_dir = tmp.path().join("cache").to_string_lossy().into_owned();
+        cfg.images.sizes = vec![1200];
+        cfg.images.eager_figures = 1;
+
+        let mut r = renderer_with_assets(cfg, tmp.path().to_path_buf());
+        let first = r.render_image_figure("tiny.png", None, 0, "First", &[], None);
+        assert!(first.contains("width=\""));
+        assert!(!first.contains("loading=\"lazy\""));
+        let second = r.render_image_figure("tiny.png", None, 1, "Second", &[], None);
+        assert!(second.contains("loading=\"lazy\" decoding=\"async\""));
+
+        // Nothing to measure, so no guessed size
+        let missing = r.render_image_figure("missing.png", None, 2, "Missing", &[], None);
+        assert!(missing.contains("<img src=\""));
+        assert!(!missing.contains("width=\""));
+    }
+
+    #[test]
+    fn page_images_feed_the_preload_and_images_placeholders() {
+        use tempfile::tempdir;
+
+        let tmp = tempdir().unwrap();
+        write_test_png(&tmp.path().join("tiny.png"));
+
+        let mut cfg = crate::config::Config::default();
+        cfg.images.cache_dir = tmp.path().join("cache").to_string_lossy().into_owned();
+        cfg.images.sizes = vec![1200];
+
+        let mut r = renderer_with_assets(cfg, tmp.path().to_path_buf());
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Doc\n\n===\n\npic tiny.png Hero : One\n\npic missing.png </b> : Two\n");
+        r.render(&parser.article);
+        let images = r.page_images();
+        assert_eq!(images.len(), 2);
+        assert!(images[0].srcset.is_some());
+        assert_eq!(images[1].src, "missing.png");
+        assert_eq!((images[1].width, images[1].srcset.as_deref()), (None, None));
+        assert_eq!(images[1].full_size, "missing.png");
+        assert_eq!(
+            (images[0].caption.as_str(), images[1].caption.as_str()),
+            ("One", "Two")
+        );
+
+        let preload = image_preload_link(images);
+        assert!(preload.starts_with("<link rel=\"preload\" as=\"image\" href=\""));
+        assert!(preload.contains(" imagesrcset=\""));
+        assert!(preload.ends_with(" fetchpriority=\"high\">"));
+        let json = page_images_json(images);
+        assert!(json.contains(r#""src":"missing.png","width":null,"height":null,"alt":"<\/b>""#));
+        assert_eq!(image_preload_link(&[]), "");
+        assert_eq!(page_images_json(&[]), "[]");
+    }
+
     #[test]
     fn render_figure_adds_original_variant_when_smaller_than_display_max() {
         use tempfile::tempdir;
@@ -1428,25 +4044,108 @@ mod tests {
         cfg.images.display_sizes = vec![480, 960];
         cfg.images.layout_width = 960;
 
-        let mut renderer = HtmlRenderer {
-            engine: None,
-            memo_math: std::collections::HashMap::new(),
-            config: cfg.clone(),
-            toc: Vec::new(),
-            section_counters: Vec::new(),
-            meta_description: None,
-            meta_image: None,
-            image_processor: crate::image_processor::ImageProcessor::new(&cfg),
-            asset_root: tmp.path().to_path_buf(),
-        };
+        let mut renderer = renderer_with_assets(cfg, tmp.path().to_path_buf());
 
         let caption: Vec<InlineElement> = Vec::new();
-        let html = renderer.render_image_figure("medium.png", None, 0, "Medium image", &caption);
+        let html =
+            renderer.render_image_figure("medium.png", None, 0, "Medium image", &caption, None);
         assert!(html.contains("srcset=\""));
         assert!(html.contains(" 480w"));
         assert!(html.contains(" 640w"));
     }
 
+    #[test]
+    fn render_figure_offers_each_density_of_the_display_width() {
+        use tempfile::tempdir;
+
+        let tmp = tempdir().unwrap();
+        let img = RgbImage::from_pixel(1000, 600, Rgb([255, 128, 0]));
+        img.save(tmp.path().join("photo.png")).unwrap();
+
+        let mut cfg = crate::config::Config::default();
+        cfg.images.cache_dir = tmp.path().join("cache").to_string_lossy().into_owned();
+        cfg.images.sizes = vec![480];
+        cfg.images.display_sizes = vec![480];
+        cfg.images.layout_width = 400;
+
+        let mut renderer = renderer_with_assets(cfg, tmp.path().to_path_buf());
+        let html = renderer.render_image_figure("photo.png", None, 0, "Photo", &[], None);
+        let srcset = Regex::new(r#"srcset="([^"]*)""#)
+            .unwrap()
+            .captures(&html)
+            .unwrap()[1]
+            .to_string();
+        let widths: Vec<&str> = srcset
+            .split(", ")
+            .filter_map(|entry| entry.rsplit(' ').next())
+            .collect();
+        assert_eq!(widths, vec!["400w", "480w", "800w"]);
+        assert!(html.contains("sizes=\"(max-width: 400px) 100vw, 400px\""));
+    }
+
+    #[test]
+    fn render_video_figure_keeps_gif_as_fallback() {
+        let variant = |url: &str, mime_type: &str| crate::image_processor::ImageVariant {
+            width: 400,
+            height: 300,
+            url: url.into(),
+            mime_type: mime_type.into(),
+        };
+        let processed = crate::image_processor::ProcessedImage {
+            variants: Vec::new(),
+            original: Some(variant("/img/spin.gif", "image/gif")),
+            display_width: 400,
+            display_height: 300,
+            original_reference: "spin.gif".into(),
+            exif: None,
+            is_wide: false,
+            sources: Vec::new(),
+            placeholder: None,
+            dominant_color: None,
+            deferred: false,
+            inline_svg: None,
+            videos: vec![
+                variant("/img/spin-abc.webm", "video/webm"),
+                variant("/img/spin-abc.mp4", "video/mp4"),
+            ],
+        };
+        let mut r = renderer_with_config(crate::config::Config::default());
+        let html = r.render_video_figure(processed, "fig1", "Spinner", "<p>cap</p>");
+        assert!(html.contains(
+            "<video autoplay loop muted playsinline width=\"400\" height=\"300\" aria-label=\"Spinner\">"
+        ));
+        let webm = html.find("<source src=\"/img/spin-abc.webm\" type=\"video/webm\">");
+        let mp4 = html.find("<source src=\"/img/spin-abc.mp4\" type=\"video/mp4\">");
+        assert!(webm.unwrap() < mp4.unwrap());
+        assert!(html.contains("<img src=\"/img/spin.gif\" alt=\"Spinner\""));
+        assert!(html.contains("</video><figcaption><p>cap</p></figcaption>"));
+    }
+
+    #[test]
+    fn render_figure_offers_alternate_formats_in_picture() {
+        use tempfile::tempdir;
+
+        let tmp = tempdir().unwrap();
+        let img = RgbImage::from_pixel(960, 480, Rgb([0, 0, 255]));
+        img.save(tmp.path().join("wide.png")).unwrap();
+
+        let mut cfg = crate::config::Config::default();
+        cfg.images.cache_dir = tmp.path().join("cache").to_string_lossy().into_owned();
+        cfg.images.sizes = vec![480];
+        cfg.images.display_sizes = vec![480];
+        cfg.images.layout_width = 960;
+        cfg.images.formats = vec!["webp".into(), "png".into()];
+
+        let mut renderer = renderer_with_assets(cfg, tmp.path().to_path_buf());
+        let html = renderer.render_image_figure("wide.png", None, 0, "Wide", &[], None);
+        assert!(html.contains("<picture><source type=\"image/webp\" srcset=\""));
+        assert!(Regex::new(r"/wide-480-[0-9a-f]{8}\.webp 480w")
+            .unwrap()
+            .is_match(&html));
+        assert_eq!(html.matches("<source ").count(), 1);
+        assert!(html.contains("/></picture><figcaption>"));
+    }
+
     #[test]
     fn render_reference_and_anchor() {
         use crate::parser::Parser;
@@ -1471,6 +4170,15 @@ mod tests {
         assert!(html.contains("<h2 id=\"s1.1\"><span id=\"details\" class=\"section-anchor\" aria-hidden=\"true\"></span><a href=\"#s1.1\" class=\"hnum\">1.1</a> <span>Details</span></h2>"));
     }
 
+    #[test]
+    fn custom_heading_ids_replace_the_number_anchor() {
+        let mut parser = crate::parser::Parser::default();
+        parser.parse("Title\n\n===\n\n# Intro {#start}\n");
+        let mut renderer = HtmlRenderer::new(&crate::config::Config::default());
+        let html = renderer.render(&parser.article);
+        assert!(html.contains("<h1 id=\"start\"><span id=\"s1\" class=\"section-anchor\" aria-hidden=\"true\"></span><a href=\"#start\" class=\"hnum\">1</a> <span>Intro</span></h1>"));
+    }
+
     #[test]
     fn root_url_prefixes_internal_links() {
         let cfg = crate::config::Config {
@@ -1625,17 +4333,7 @@ mod tests {
         let mut parser = Parser::default();
         parser.parse(&source);
 
-        let mut renderer = HtmlRenderer {
-            engine: None,
-            memo_math: std::collections::HashMap::new(),
-            config: cfg.clone(),
-            toc: Vec::new(),
-            section_counters: Vec::new(),
-            meta_description: None,
-            meta_image: None,
-            image_processor: crate::image_processor::ImageProcessor::new(&cfg),
-            asset_root: assets.to_path_buf(),
-        };
+        let mut renderer = renderer_with_assets(cfg, assets.to_path_buf());
 
         renderer.render(&parser.article);
         let title = parser
@@ -1711,17 +4409,7 @@ mod tests {
         let mut parser = Parser::default();
         parser.parse(&source);
 
-        let mut renderer = HtmlRenderer {
-            engine: None,
-            memo_math: std::collections::HashMap::new(),
-            config: cfg.clone(),
-            toc: Vec::new(),
-            section_counters: Vec::new(),
-            meta_description: None,
-            meta_image: None,
-            image_processor: crate::image_processor::ImageProcessor::new(&cfg),
-            asset_root: assets.to_path_buf(),
-        };
+        let mut renderer = renderer_with_assets(cfg, assets.to_path_buf());
 
         renderer.render(&parser.article);
         let metas = renderer.meta_tags("Sized Meta");
@@ -1737,4 +4425,44 @@ mod tests {
             image_line
         );
     }
+
+    #[test]
+    fn meta_image_uses_configured_crop() {
+        use crate::parser::Parser;
+        use tempfile::tempdir;
+
+        let tmp = tempdir().unwrap();
+        let assets = tmp.path();
+        let image_path = assets.join("wide.png");
+        RgbImage::from_pixel(2000, 1000, Rgb([0, 128, 255]))
+            .save(&image_path)
+            .unwrap();
+
+        let mut cfg = crate::config::Config::default();
+        cfg.images.cache_dir = assets.join("cache").to_string_lossy().into_owned();
+        cfg.images.img_root_url = Some("https://cdn.example.com/images".into());
+        cfg.images.meta_crop = Some("1:1@right".into());
+        cfg.images.layout_width = 1200;
+
+        let source = format!(
+            "Cropped\n\n===\n\npic {} : Cropped card\n",
+            image_path.display()
+        );
+        let mut parser = Parser::default();
+        parser.parse(&source);
+        let mut renderer = renderer_with_assets(cfg, assets.to_path_buf());
+        renderer.render(&parser.article);
+        crate::image_processor::wait_for_pending_resizes();
+
+        let metas = renderer.meta_tags("Cropped");
+        let image_line = metas.lines().next().expect("og:image meta");
+        let name = image_line
+            .split("https://cdn.example.com/images/")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .expect("cropped meta image url");
+        assert!(name.starts_with("wide-1000x1000-"), "got {}", image_line);
+        let (width, height) = image::image_dimensions(assets.join("cache").join(name)).unwrap();
+        assert_eq!((width, height), (1000, 1000));
+    }
 }
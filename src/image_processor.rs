@@ -1,18 +1,22 @@
+use crate::ast::Crop;
 use crate::config;
+use crate::downloads;
 use image::codecs::gif::GifDecoder;
 use image::codecs::jpeg::JpegEncoder;
 use image::imageops::FilterType;
 use image::{DynamicImage, ImageDecoder, ImageFormat};
-use rexif::{parse_buffer_quiet, ExifData, ExifTag, TagValue};
-use roxmltree::Document;
+use regex::Regex;
+use rexif::{parse_buffer_quiet, ExifData, ExifEntry, ExifTag, IfdKind, TagValue};
+use roxmltree::{Document, ParsingOptions};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::io::{self, Cursor, Read};
+use std::io::{self, Cursor};
 use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Condvar, Mutex,
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Condvar, Mutex, OnceLock,
 };
-use std::time::{Duration, Instant};
+use std::time::Instant;
 use thiserror::Error;
 
 #[derive(Debug, Clone)]
@@ -20,10 +24,29 @@ pub struct ImageProcessor {
     config: config::ImagesConfig,
     cache_dir: PathBuf,
     root_url: Option<String>,
+    /// Encodable `images.formats`, in preference order.
+    output_formats: Vec<ImageFormat>,
 }
 
 lazy_static! {
     static ref RESIZE_DISPATCHER: Arc<ResizeDispatcher> = Arc::new(ResizeDispatcher::new());
+    /// Images already processed during this build, so pages that show the same
+    /// image again (e.g. collection index thumbnails) reuse its dimensions.
+    static ref PROCESSED_IMAGES: Mutex<HashMap<MetadataKey, ProcessedImage>> =
+        Mutex::new(HashMap::new());
+    /// Source bytes of each image whose resizes went over `images.resize_budget_mb`.
+    static ref DEFERRED_RESIZES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+    /// `url(#id)` references in SVGs, which get the same prefix as the ids.
+    static ref SVG_URL_REF: Regex = Regex::new(r#"url\(\s*(['"]?)#([^'")\s]+)"#).unwrap();
+    /// `#id` selectors in an SVG's `<style>`.
+    static ref SVG_ID_SELECTOR: Regex = Regex::new(r"#([A-Za-z_][\w-]*)").unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct MetadataKey {
+    cache_dir: PathBuf,
+    asset_root: PathBuf,
+    reference: String,
 }
 
 #[derive(Debug, Clone)]
@@ -35,6 +58,30 @@ pub struct ProcessedImage {
     pub original_reference: String,
     pub exif: Option<ExifSummary>,
     pub is_wide: bool,
+    /// The same resize targets in each alternate `images.formats` encoding.
+    pub sources: Vec<ImageSource>,
+    /// Tiny blurred JPEG data URI shown while the image loads (`images.placeholders`).
+    pub placeholder: Option<String>,
+    /// Average color of the image (`images.dominant_color`).
+    pub dominant_color: Option<[u8; 3]>,
+    /// Looping video encodings of an animated GIF (`images.gif_to_video`), in
+    /// order of preference.
+    pub videos: Vec<ImageVariant>,
+    /// Resizing was left for a later build (`images.resize_budget_mb`), so only
+    /// the original is linked for now.
+    pub deferred: bool,
+    /// Sanitized markup of an SVG small enough for `images.inline_svg_max_bytes`,
+    /// to put in the page instead of linking the file.
+    pub inline_svg: Option<String>,
+}
+
+/// Longest side, in pixels, of the placeholder image.
+const PLACEHOLDER_SIZE: u32 = 16;
+
+#[derive(Debug, Clone)]
+pub struct ImageSource {
+    pub mime_type: String,
+    pub variants: Vec<ImageVariant>,
 }
 
 #[derive(Debug, Clone)]
@@ -50,6 +97,22 @@ pub struct ExifSummary {
     pub entries: Vec<(String, String)>,
 }
 
+impl ExifSummary {
+    /// The `DateTimeOriginal` day as `YYYY-MM-DD`, the form of a post's date line.
+    pub fn date(&self) -> Option<String> {
+        let (_, value) = self.entries.iter().find(|(label, _)| label == "Date")?;
+        let mut parts = value.split_whitespace().next()?.split(':');
+        let year: i32 = parts.next()?.parse().ok()?;
+        let month: u32 = parts.next()?.parse().ok()?;
+        let day: u32 = parts.next()?.parse().ok()?;
+        // Cameras without a set clock write `0000:00:00`
+        if year == 0 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+        Some(format!("{:04}-{:02}-{:02}", year, month, day))
+    }
+}
+
 #[derive(Debug)]
 struct SourceImage {
     reference: String,
@@ -70,6 +133,9 @@ struct VariantJob {
     width: u32,
     height: u32,
     path: PathBuf,
+    format: ImageFormat,
+    /// `[x, y, width, height]` of the upright source to cut out before resizing.
+    crop: Option<[u32; 4]>,
 }
 
 #[derive(Debug, Clone)]
@@ -94,10 +160,27 @@ impl ImageProcessor {
     pub fn new(config: &config::Config) -> Self {
         let cache_dir = PathBuf::from(&config.images.cache_dir);
         let _ = fs::create_dir_all(&cache_dir);
+        let mut output_formats = Vec::new();
+        for name in &config.images.formats {
+            match output_format_from_name(name) {
+                Some(format) if !output_formats.contains(&format) => output_formats.push(format),
+                Some(_) => {}
+                None => crate::diagnostics::warn(format!(
+                    "[images] cannot encode format {:?}; skipping",
+                    name
+                )),
+            }
+        }
+        if config.images.optimize && !cfg!(feature = "optimize") {
+            crate::diagnostics::warn(
+                "[images] images.optimize needs a build with `--features optimize`",
+            );
+        }
         Self {
             config: config.images.clone(),
             cache_dir,
             root_url: config.root_url.clone(),
+            output_formats,
         }
     }
 
@@ -119,6 +202,11 @@ impl ImageProcessor {
             url: original_url.clone(),
             mime_type: "image/gif".into(),
         };
+        let videos = if self.config.gif_to_video {
+            self.gif_videos(&source, &original_path, width, height)
+        } else {
+            Vec::new()
+        };
 
         Ok(ProcessedImage {
             variants: Vec::new(),
@@ -128,22 +216,167 @@ impl ImageProcessor {
             original_reference: source.reference,
             exif: None,
             is_wide,
+            sources: Vec::new(),
+            placeholder: None,
+            dominant_color: None,
+            videos,
+            deferred: false,
+            inline_svg: None,
         })
     }
 
+    /// WebM and MP4 encodings of a GIF, converted once with ffmpeg and cached
+    /// next to the original. A failed conversion leaves just the GIF.
+    fn gif_videos(
+        &self,
+        source: &SourceImage,
+        original_path: &Path,
+        width: u32,
+        height: u32,
+    ) -> Vec<ImageVariant> {
+        let stem = original_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("animation");
+        let hash = short_content_hash(&source.bytes);
+        let mut videos = Vec::new();
+        for (extension, mime_type, codec_args) in GIF_VIDEO_ENCODINGS {
+            let path = self
+                .cache_dir
+                .join(format!("{}-{}.{}", stem, hash, extension));
+            if !path.exists() {
+                if let Err(err) = convert_gif(original_path, &path, extension, codec_args) {
+                    crate::diagnostics::warn(format!(
+                        "[images] could not convert {} to {}: {}",
+                        source.reference, extension, err
+                    ));
+                    return Vec::new();
+                }
+            }
+            videos.push(ImageVariant {
+                width,
+                height,
+                url: self.public_url_for(&path),
+                mime_type: mime_type.to_string(),
+            });
+        }
+        videos
+    }
+
     pub fn process(
         &self,
         reference: &str,
         asset_root: &Path,
     ) -> Result<ProcessedImage, ImageError> {
+        let key = MetadataKey {
+            cache_dir: self.cache_dir.clone(),
+            asset_root: asset_root.to_path_buf(),
+            reference: reference.to_string(),
+        };
+        if let Some(processed) = PROCESSED_IMAGES
+            .lock()
+            .ok()
+            .and_then(|store| store.get(&key).cloned())
+        {
+            return Ok(processed);
+        }
         let source = self.load_source(reference, asset_root)?;
-        match source.format {
+        let processed = match source.format {
             SourceFormat::Svg => self.process_svg(source),
             SourceFormat::Raster(format) => self.process_raster(source, format),
+        }?;
+        if let Ok(mut store) = PROCESSED_IMAGES.lock() {
+            store.insert(key, processed.clone());
+        }
+        Ok(processed)
+    }
+
+    /// A variant of a processed raster image, at most `width` wide, cut to the
+    /// aspect ratio of `crop` around its focal point (for index thumbnails and
+    /// social cards). `None` for SVGs and GIFs, which are only shown whole, and
+    /// when the crop is left for a later build by `images.resize_budget_mb`.
+    pub fn crop(
+        &self,
+        processed: &ProcessedImage,
+        asset_root: &Path,
+        crop: Crop,
+        width: u32,
+    ) -> Result<Option<ImageVariant>, ImageError> {
+        let (Some(aspect), Some(original)) = (crop.aspect, processed.original.as_ref()) else {
+            return Ok(None);
+        };
+        let source = self.load_source(&processed.original_reference, asset_root)?;
+        let format = match source.format {
+            SourceFormat::Raster(format) if format != ImageFormat::Gif => format,
+            _ => return Ok(None),
+        };
+        let extension = extension_for_format(format).ok_or(ImageError::UnsupportedFormat)?;
+        let original_path = self.ensure_original_cached(&source, extension)?;
+        let focus = crop.focus.unwrap_or((0.5, 0.5));
+        let rect = crop_rect(original.width, original.height, aspect, focus);
+        let target_width = width.clamp(1, rect[2]);
+        let target_height =
+            ((target_width as f64 * rect[3] as f64 / rect[2] as f64).round() as u32).max(1);
+
+        // `stem-<width>x<height>-<hash>.<ext>`, the hash covering the source and the crop
+        let stem = original_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("image");
+        let content_hash = self.variant_hash(&source.bytes);
+        let hash = short_content_hash(format!("{}{:?}", content_hash, rect).as_bytes());
+        let path = self.cache_dir.join(format!(
+            "{}-{}x{}-{}.{}",
+            stem, target_width, target_height, hash, extension
+        ));
+        if !path.exists() {
+            let exif_data = parse_buffer_quiet(source.bytes.as_ref()).0.ok();
+            let scheduled = schedule_resize_generation(
+                source.reference.clone(),
+                Arc::clone(&source.bytes),
+                exif_data.as_ref().and_then(exif_orientation),
+                vec![VariantJob {
+                    width: target_width,
+                    height: target_height,
+                    path: path.clone(),
+                    format,
+                    crop: Some(rect),
+                }],
+                self.variant_exif(exif_data.as_ref()),
+                self.config.jpeg_quality,
+                self.config.optimize,
+            );
+            if !scheduled {
+                return Ok(None);
+            }
         }
+        Ok(Some(ImageVariant {
+            width: target_width,
+            height: target_height,
+            url: self.public_url_for(&path),
+            mime_type: mime_type_for_format(format).to_string(),
+        }))
     }
 
     fn process_svg(&self, source: SourceImage) -> Result<ProcessedImage, ImageError> {
+        let cleanup = SvgCleanOptions {
+            standalone: true,
+            strip_scripts: self.config.svg_cleanup == config::SvgCleanup::Sanitize,
+            id_prefix: "",
+        };
+        let cleaned = match self.config.svg_cleanup {
+            config::SvgCleanup::Off => None,
+            _ => clean_svg(&source.bytes, &cleanup),
+        };
+        // A cleaned remote SVG is cached next to the download it came from
+        let source = match cleaned {
+            Some(cleaned) => SourceImage {
+                bytes: Arc::from(cleaned.into_bytes()),
+                cached_path: None,
+                ..source
+            },
+            None => source,
+        };
         let original_path = self.ensure_original_cached(&source, "svg")?;
         let original_url = self.public_url_for(&original_path);
 
@@ -165,6 +398,19 @@ impl ImageProcessor {
             compute_display_dimensions(scaled_dimensions.0, scaled_dimensions.1, layout_limit);
         let svg_width = display_width.max(1);
         let svg_height = display_height.max(1);
+        let inline_svg = self
+            .config
+            .inline_svg_max_bytes
+            .filter(|&max| source.bytes.len() as u64 <= max)
+            .and_then(|_| {
+                let hash = blake3::hash(&source.bytes).to_hex();
+                let cleanup = SvgCleanOptions {
+                    standalone: false,
+                    strip_scripts: true,
+                    id_prefix: &format!("svg-{}-", &hash[..8]),
+                };
+                clean_svg(&source.bytes, &cleanup)
+            });
 
         Ok(ProcessedImage {
             variants: Vec::new(),
@@ -179,6 +425,12 @@ impl ImageProcessor {
             original_reference: source.reference,
             exif: None,
             is_wide,
+            sources: Vec::new(),
+            placeholder: None,
+            dominant_color: None,
+            videos: Vec::new(),
+            deferred: false,
+            inline_svg,
         })
     }
 
@@ -192,18 +444,16 @@ impl ImageProcessor {
         }
         let extension = extension_for_format(format).ok_or(ImageError::UnsupportedFormat)?;
         let original_path = self.ensure_original_cached(&source, extension)?;
-        if let Some(mut processed) = self.try_build_processed_from_cache(
-            &source,
-            &original_path,
-            format,
-            extension,
-        ) {
+        if let Some(mut processed) =
+            self.try_build_processed_from_cache(&source, &original_path, format, extension)
+        {
+            let exif_data = parse_buffer_quiet(source.bytes.as_ref()).0.ok();
             if processed.exif.is_none() {
-                processed.exif = parse_buffer_quiet(source.bytes.as_ref())
-                    .0
-                    .ok()
-                    .map(|data| summarize_exif(&data));
+                processed.exif = exif_data.as_ref().map(summarize_exif);
             }
+            let orientation = exif_data.as_ref().and_then(exif_orientation);
+            (processed.placeholder, processed.dominant_color) =
+                self.previews(&source, &original_path, orientation);
             return Ok(processed);
         }
 
@@ -211,19 +461,11 @@ impl ImageProcessor {
         let original_url = self.public_url_for(&original_path);
         let mime_type = mime_type_for_format(format).to_string();
 
-        let mut exif_bytes_raw = exif_data
-            .as_ref()
-            .and_then(|data| data.serialize().ok())
-            .map(ensure_exif_header);
+        let exif_bytes = self.variant_exif(exif_data.as_ref());
         let original_orientation = exif_data.as_ref().and_then(exif_orientation);
 
-        if let Some(bytes) = exif_bytes_raw.as_mut() {
-            normalize_exif_orientation(bytes);
-        }
-        let exif_bytes = exif_bytes_raw.map(Arc::new);
-
-        let (mut width, mut height) =
-            image::image_dimensions(&original_path).map_err(|e| ImageError::Decode(e.to_string()))?;
+        let (mut width, mut height) = image::image_dimensions(&original_path)
+            .map_err(|e| ImageError::Decode(e.to_string()))?;
         if matches!(original_orientation, Some(5..=8)) {
             std::mem::swap(&mut width, &mut height);
         }
@@ -237,17 +479,14 @@ impl ImageProcessor {
             .filter(|s| !s.trim().is_empty())
             .map(|s| s.to_string())
             .unwrap_or_else(|| "image".to_string());
+        let content_hash = self.variant_hash(&source.bytes);
 
         let target_widths = self.target_resize_widths(width, display_width);
         let mut variant_specs: Vec<VariantSpec> = Vec::new();
         let mut resize_jobs: Vec<VariantJob> = Vec::new();
-        for target_width in target_widths {
-            let filename = if extension.is_empty() {
-                format!("{}-{}", original_stem, target_width)
-            } else {
-                format!("{}-{}.{}", original_stem, target_width, extension)
-            };
-            let target_path = self.cache_dir.join(filename);
+        for &target_width in &target_widths {
+            let target_path =
+                self.variant_path(&original_stem, target_width, &content_hash, extension);
             let target_height = ((target_width as f64 / width as f64) * height as f64)
                 .round()
                 .max(1.0) as u32;
@@ -256,6 +495,8 @@ impl ImageProcessor {
                     width: target_width,
                     height: target_height,
                     path: target_path.clone(),
+                    format,
+                    crop: None,
                 });
             }
             variant_specs.push(VariantSpec {
@@ -265,19 +506,59 @@ impl ImageProcessor {
             });
         }
 
+        let mut sources = Vec::new();
+        for alternate in self.alternate_formats(format) {
+            let Some(alternate_extension) = extension_for_format(alternate) else {
+                continue;
+            };
+            let mut alternate_variants = Vec::new();
+            for spec in &variant_specs {
+                let path = self.variant_path(
+                    &original_stem,
+                    spec.width,
+                    &content_hash,
+                    alternate_extension,
+                );
+                if !path.exists() {
+                    resize_jobs.push(VariantJob {
+                        width: spec.width,
+                        height: spec.height,
+                        path: path.clone(),
+                        format: alternate,
+                        crop: None,
+                    });
+                }
+                alternate_variants.push(ImageVariant {
+                    width: spec.width,
+                    height: spec.height,
+                    url: self.public_url_for(&path),
+                    mime_type: mime_type_for_format(alternate).to_string(),
+                });
+            }
+            sources.push(ImageSource {
+                mime_type: mime_type_for_format(alternate).to_string(),
+                variants: alternate_variants,
+            });
+        }
+
+        let mut deferred = false;
         if !resize_jobs.is_empty() {
             fs::create_dir_all(&self.cache_dir)?;
             let dispatch_exif = exif_bytes.clone();
-            schedule_resize_generation(
+            deferred = !schedule_resize_generation(
                 source.reference.clone(),
                 Arc::clone(&source.bytes),
-                format,
                 original_orientation,
                 resize_jobs,
                 dispatch_exif,
                 self.config.jpeg_quality,
+                self.config.optimize,
             );
         }
+        if deferred {
+            variant_specs.clear();
+            sources.clear();
+        }
 
         let mut variants: Vec<ImageVariant> = variant_specs
             .into_iter()
@@ -290,6 +571,8 @@ impl ImageProcessor {
             .collect();
         variants.sort_by_key(|v| v.width);
         let entries = exif_data.as_ref().map(summarize_exif);
+        let (placeholder, dominant_color) =
+            self.previews(&source, &original_path, original_orientation);
         let original_variant = ImageVariant {
             width,
             height,
@@ -305,6 +588,12 @@ impl ImageProcessor {
             original_reference: source.reference,
             exif: entries,
             is_wide,
+            sources,
+            placeholder,
+            dominant_color,
+            videos: Vec::new(),
+            deferred,
+            inline_svg: None,
         })
     }
 
@@ -319,46 +608,79 @@ impl ImageProcessor {
     fn fetch_remote(&self, reference: &str) -> Result<SourceImage, ImageError> {
         fs::create_dir_all(&self.cache_dir)?;
         let candidates = self.remote_cache_candidates(reference);
+        if let Some(source) = self.try_load_cached_remote(reference, &candidates)? {
+            return Ok(self.revalidate_remote(source));
+        }
         let primary_path = candidates
             .first()
             .cloned()
             .unwrap_or_else(|| self.cache_dir.join("image"));
-        if let Some(source) = self.try_load_cached_remote(reference, &candidates)? {
-            return Ok(source);
-        }
 
         eprintln!("[images] fetching remote {}", reference);
+        self.download(reference, &primary_path, None)
+            .and_then(|source| {
+                source.ok_or_else(|| ImageError::Network("unexpected 304 Not Modified".into()))
+            })
+            .inspect_err(|e| downloads::record_failure(reference, &e.to_string()))
+    }
+
+    /// The cached copy of a remote image, replaced if the origin changed it
+    /// since it was downloaded; a failed check keeps the cached copy.
+    fn revalidate_remote(&self, cached: SourceImage) -> SourceImage {
+        let Some(path) = cached.cached_path.clone() else {
+            return cached;
+        };
+        let validators = downloads::Validators::load(&path);
+        if !downloads::should_revalidate(&cached.reference, validators.is_some()) {
+            return cached;
+        }
+        let validators = validators.filter(|_| !downloads::refreshing());
+        match self.download(&cached.reference, &path, validators.as_ref()) {
+            Ok(Some(source)) => source,
+            Ok(None) => cached,
+            Err(e) => {
+                crate::diagnostics::warn(format!(
+                    "[images] could not revalidate {}: {}; using the cached copy",
+                    cached.reference, e
+                ));
+                cached
+            }
+        }
+    }
+
+    /// Downloads `reference` into the cache at `path`, conditionally when the
+    /// `validators` of the copy already there are given; `None` if the origin
+    /// says that copy is current.
+    fn download(
+        &self,
+        reference: &str,
+        path: &Path,
+        validators: Option<&downloads::Validators>,
+    ) -> Result<Option<SourceImage>, ImageError> {
         let fetch_start = Instant::now();
-        let agent = ureq::AgentBuilder::new()
-            .timeout(Duration::from_secs(self.config.remote_fetch_timeout_secs))
-            .build();
-        let response = agent
-            .get(reference)
-            .call()
-            .map_err(|e| ImageError::Network(e.to_string()))?;
-        if response.status() >= 400 {
-            return Err(ImageError::Network(format!(
-                "failed to fetch {}: HTTP {}",
-                reference,
-                response.status()
-            )));
-        }
-        let mut reader = response.into_reader();
-        let mut buf = Vec::new();
-        reader.read_to_end(&mut buf)?;
-        fs::write(&primary_path, &buf)?;
+        let policy = downloads::FetchPolicy::new(&self.config);
+        let fetched =
+            downloads::fetch(reference, policy, validators).map_err(ImageError::Network)?;
+        let downloads::Fetched::Modified(buf, fresh) = fetched else {
+            return Ok(None);
+        };
+        // Checked first so an error page never replaces a good cached copy
+        let format = detect_format(reference, &buf)?;
+        fs::write(path, &buf)?;
+        fresh.save(path)?;
+        forget_cached_derivatives(path);
         eprintln!(
             "[images] fetched remote {} in {:?}",
             reference,
             fetch_start.elapsed()
         );
 
-        Ok(SourceImage {
+        Ok(Some(SourceImage {
             reference: reference.to_string(),
-            cached_path: Some(primary_path),
-            format: detect_format(reference, &buf)?,
+            cached_path: Some(path.to_path_buf()),
+            format,
             bytes: Arc::from(buf),
-        })
+        }))
     }
 
     fn try_load_cached_remote(
@@ -408,10 +730,7 @@ impl ImageProcessor {
 
         push_unique_name(&mut names, canonical.clone());
         push_unique_name(&mut names, base);
-        if let Some(stem) = Path::new(&canonical)
-            .file_stem()
-            .and_then(|s| s.to_str())
-        {
+        if let Some(stem) = Path::new(&canonical).file_stem().and_then(|s| s.to_str()) {
             push_unique_name(&mut names, stem.to_string());
         }
 
@@ -422,16 +741,7 @@ impl ImageProcessor {
     }
 
     fn read_local(&self, reference: &str, asset_root: &Path) -> Result<SourceImage, ImageError> {
-        let path = if reference.starts_with("./") || reference.starts_with("../") {
-            asset_root.join(reference)
-        } else {
-            let candidate = Path::new(reference);
-            if candidate.is_absolute() {
-                candidate.to_path_buf()
-            } else {
-                asset_root.join(candidate)
-            }
-        };
+        let path = local_source_path(reference, asset_root);
         let bytes = fs::read(&path)?;
         Ok(SourceImage {
             reference: reference.to_string(),
@@ -458,28 +768,37 @@ impl ImageProcessor {
             compute_display_dimensions(width as f64, height as f64, self.config.layout_width);
 
         let target_widths = self.target_resize_widths(width, display_width);
-        let mut variants = Vec::new();
-        for target_width in target_widths {
-            let filename = if extension.is_empty() {
-                format!("{}-{}", original_stem, target_width)
-            } else {
-                format!("{}-{}.{}", original_stem, target_width, extension)
-            };
-            let variant_path = self.cache_dir.join(&filename);
-            if !variant_path.exists() {
-                return None;
+        let content_hash = self.variant_hash(&source.bytes);
+        let cached_variants = |extension: &str, mime_type: &str| -> Option<Vec<ImageVariant>> {
+            let mut variants = Vec::new();
+            for &target_width in &target_widths {
+                let variant_path =
+                    self.variant_path(original_stem, target_width, &content_hash, extension);
+                if !variant_path.exists() {
+                    return None;
+                }
+                let target_height = ((target_width as f64 / width as f64) * height as f64)
+                    .round()
+                    .max(1.0) as u32;
+                variants.push(ImageVariant {
+                    width: target_width,
+                    height: target_height,
+                    url: self.public_url_for(&variant_path),
+                    mime_type: mime_type.to_string(),
+                });
             }
-            let target_height = ((target_width as f64 / width as f64) * height as f64)
-                .round()
-                .max(1.0) as u32;
-            variants.push(ImageVariant {
-                width: target_width,
-                height: target_height,
-                url: self.public_url_for(&variant_path),
-                mime_type: mime_type.clone(),
+            variants.sort_by_key(|v| v.width);
+            Some(variants)
+        };
+        let variants = cached_variants(extension, &mime_type)?;
+        let mut sources = Vec::new();
+        for alternate in self.alternate_formats(format) {
+            let alternate_mime = mime_type_for_format(alternate);
+            sources.push(ImageSource {
+                mime_type: alternate_mime.to_string(),
+                variants: cached_variants(extension_for_format(alternate)?, alternate_mime)?,
             });
         }
-        variants.sort_by_key(|v| v.width);
 
         Some(ProcessedImage {
             variants,
@@ -494,33 +813,164 @@ impl ImageProcessor {
             original_reference: source.reference.clone(),
             exif: None,
             is_wide,
+            sources,
+            placeholder: None,
+            dominant_color: None,
+            videos: Vec::new(),
+            deferred: false,
+            inline_svg: None,
         })
     }
 
-fn target_resize_widths(&self, original_width: u32, display_width: u32) -> Vec<u32> {
-    let mut sizes = self.config.sizes.clone();
-    if !sizes.contains(&self.config.layout_width) {
-        sizes.push(self.config.layout_width);
+    /// Placeholder data URI (a tiny blurred copy) and average color of the image,
+    /// as enabled in the config. Both are cached next to the original so only the
+    /// first build pays for decoding the full image.
+    fn previews(
+        &self,
+        source: &SourceImage,
+        original_path: &Path,
+        orientation: Option<u16>,
+    ) -> (Option<String>, Option<[u8; 3]>) {
+        let placeholder_path = original_path.with_extension("lqip");
+        let color_path = original_path.with_extension("color");
+        let mut placeholder = self
+            .config
+            .placeholders
+            .then(|| fs::read_to_string(&placeholder_path).ok())
+            .flatten()
+            .map(|cached| cached.trim().to_string());
+        let mut color = self
+            .config
+            .dominant_color
+            .then(|| fs::read_to_string(&color_path).ok())
+            .flatten()
+            .and_then(|cached| parse_hex_color(cached.trim()));
+        let needs_placeholder = self.config.placeholders && placeholder.is_none();
+        let needs_color = self.config.dominant_color && color.is_none();
+        if !needs_placeholder && !needs_color {
+            return (placeholder, color);
+        }
+        let Ok(mut image) = image::load_from_memory(source.bytes.as_ref()) else {
+            return (placeholder, color);
+        };
+        if let Some(orientation) = orientation {
+            image = apply_orientation(image, orientation);
+        }
+        let tiny = image
+            .thumbnail(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE)
+            .to_rgb8();
+        if needs_color {
+            let average = average_color(&tiny);
+            let _ = fs::write(&color_path, format_hex_color(average));
+            color = Some(average);
+        }
+        if needs_placeholder {
+            let blurred = image::imageops::blur(&tiny, 1.0);
+            if let Ok(jpeg) = encode_image(
+                &DynamicImage::ImageRgb8(blurred),
+                ImageFormat::Jpeg,
+                None,
+                50,
+            ) {
+                let uri = format!("data:image/jpeg;base64,{}", base64_encode(&jpeg));
+                let _ = fs::write(&placeholder_path, &uri);
+                placeholder = Some(uri);
+            }
+        }
+        (placeholder, color)
+    }
+
+    /// `images.formats` other than the source's own, which is the `<img>` fallback.
+    fn alternate_formats(&self, source: ImageFormat) -> Vec<ImageFormat> {
+        self.output_formats
+            .iter()
+            .copied()
+            .filter(|format| *format != source)
+            .collect()
+    }
+
+    /// EXIF copied into variants, minus the tag groups in `images.strip_exif`.
+    fn published_exif(&self, exif: &ExifData) -> ExifData {
+        let gps = self.config.strips_exif(config::ExifStrip::Gps);
+        let serial = self.config.strips_exif(config::ExifStrip::Serial);
+        let mut entries: Vec<ExifEntry> = exif
+            .entries
+            .iter()
+            .filter(|entry| !(gps && entry.kind == IfdKind::Gps))
+            .filter(|entry| !(serial && SERIAL_EXIF_TAGS.contains(&entry.ifd.tag)))
+            .cloned()
+            .collect();
+        // Drop sub-IFD pointers left without entries, which would serialize dangling
+        let has_exif = entries.iter().any(|entry| entry.kind == IfdKind::Exif);
+        let has_gps = entries.iter().any(|entry| entry.kind == IfdKind::Gps);
+        entries.retain(|entry| match entry.tag {
+            ExifTag::ExifOffset => has_exif,
+            ExifTag::GPSOffset => has_gps,
+            _ => true,
+        });
+        ExifData::new(exif.mime, entries, exif.le)
+    }
+
+    /// Hash in variant names; it covers `images.strip_exif` so changing it
+    /// regenerates variants rather than reusing ones with the old tags.
+    fn variant_hash(&self, source_bytes: &[u8]) -> String {
+        if self.config.strip_exif.is_empty() {
+            return short_content_hash(source_bytes);
+        }
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(source_bytes);
+        hasher.update(format!("{:?}", self.config.strip_exif).as_bytes());
+        hasher.finalize().to_hex()[..8].to_string()
+    }
+
+    /// EXIF segment written into variants: the published tags with the orientation
+    /// reset, since variants are stored upright.
+    fn variant_exif(&self, exif: Option<&ExifData>) -> Option<Arc<Vec<u8>>> {
+        let mut bytes = exif
+            .and_then(|data| self.published_exif(data).serialize().ok())
+            .map(ensure_exif_header)?;
+        normalize_exif_orientation(&mut bytes);
+        Some(Arc::new(bytes))
+    }
+
+    /// `stem-<width>-<hash>.<ext>`, so replacing the source image changes the URL
+    /// of every variant and busts browser and CDN caches.
+    fn variant_path(&self, stem: &str, width: u32, hash: &str, extension: &str) -> PathBuf {
+        let filename = if extension.is_empty() {
+            format!("{}-{}-{}", stem, width, hash)
+        } else {
+            format!("{}-{}-{}.{}", stem, width, hash, extension)
+        };
+        self.cache_dir.join(filename)
     }
+
+    fn target_resize_widths(&self, original_width: u32, display_width: u32) -> Vec<u32> {
+        let mut sizes = self.config.sizes.clone();
+        if !sizes.contains(&self.config.layout_width) {
+            sizes.push(self.config.layout_width);
+        }
         if display_width > 0 && !sizes.contains(&display_width) {
             sizes.push(display_width);
         }
+        for density in &self.config.densities {
+            sizes.push(display_width.saturating_mul(*density));
+        }
         sizes.sort_unstable();
         sizes.dedup();
 
         let mut widths = Vec::new();
-    for size in sizes {
-        let target_width = size.min(original_width);
-        if target_width == 0 || target_width == original_width {
-            continue;
-        }
+        for size in sizes {
+            let target_width = size.min(original_width);
+            if target_width == 0 || target_width == original_width {
+                continue;
+            }
             if widths.last().copied() == Some(target_width) {
                 continue;
+            }
+            widths.push(target_width);
         }
-        widths.push(target_width);
+        widths
     }
-    widths
-}
 
     fn ensure_original_cached(
         &self,
@@ -562,7 +1012,13 @@ fn target_resize_widths(&self, original_width: u32, display_width: u32) -> Vec<u
         Ok(target)
     }
 
-    fn public_url_for(&self, path: &Path) -> String {
+    /// Directory generated images are written to.
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// URL of a file in the cache directory, honouring `images.img_root_url`.
+    pub fn public_url_for(&self, path: &Path) -> String {
         use std::path::Component;
 
         let relative = path.strip_prefix(&self.cache_dir).unwrap_or(path);
@@ -671,10 +1127,19 @@ fn extension_for_format(format: ImageFormat) -> Option<&'static str> {
         ImageFormat::Jpeg => Some("jpg"),
         ImageFormat::Png => Some("png"),
         ImageFormat::WebP => Some("webp"),
+        ImageFormat::Avif => Some("avif"),
         _ => None,
     }
 }
 
+/// Parses an `images.formats` entry, rejecting formats this build cannot encode.
+fn output_format_from_name(name: &str) -> Option<ImageFormat> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "avif" if cfg!(feature = "avif") => Some(ImageFormat::Avif),
+        other => image_format_from_extension(other),
+    }
+}
+
 fn mime_type_for_format(format: ImageFormat) -> &'static str {
     match format {
         ImageFormat::Jpeg => "image/jpeg",
@@ -683,6 +1148,7 @@ fn mime_type_for_format(format: ImageFormat) -> &'static str {
         ImageFormat::Bmp => "image/bmp",
         ImageFormat::Tiff => "image/tiff",
         ImageFormat::WebP => "image/webp",
+        ImageFormat::Avif => "image/avif",
         _ => "application/octet-stream",
     }
 }
@@ -704,6 +1170,15 @@ fn encode_image(
                 insert_exif_segment(&mut buf, exif_data);
             }
         }
+        #[cfg(feature = "avif")]
+        ImageFormat::Avif => {
+            use image::codecs::avif::AvifEncoder;
+            use image::ImageEncoder;
+            let rgba = image.to_rgba8();
+            AvifEncoder::new_with_speed_quality(&mut buf, 6, jpeg_quality)
+                .write_image(&rgba, rgba.width(), rgba.height(), image::ColorType::Rgba8)
+                .map_err(|e| ImageError::Decode(e.to_string()))?;
+        }
         _ => {
             let mut cursor = io::Cursor::new(&mut buf);
             let format = image::ImageOutputFormat::from(format);
@@ -718,12 +1193,22 @@ fn encode_image(
 fn generate_variant_file(
     job: &VariantJob,
     source_image: &DynamicImage,
-    format: ImageFormat,
     exif_bytes: Option<&[u8]>,
     jpeg_quality: u8,
+    optimize: bool,
 ) -> Result<(), ImageError> {
-    let resized = source_image.resize(job.width, job.height, FilterType::Lanczos3);
-    let encoded = encode_image(&resized, format, exif_bytes, jpeg_quality)?;
+    let resized = match job.crop {
+        // Already cut to the target aspect ratio, so scale to the exact size
+        Some([x, y, width, height]) => {
+            let cropped = source_image.crop_imm(x, y, width, height);
+            cropped.resize_exact(job.width, job.height, FilterType::Lanczos3)
+        }
+        None => source_image.resize(job.width, job.height, FilterType::Lanczos3),
+    };
+    let mut encoded = encode_image(&resized, job.format, exif_bytes, jpeg_quality)?;
+    if optimize {
+        encoded = optimize_variant(&resized, job.format, encoded, exif_bytes, jpeg_quality);
+    }
     if let Some(parent) = job.path.parent() {
         fs::create_dir_all(parent)?;
     }
@@ -731,17 +1216,89 @@ fn generate_variant_file(
     Ok(())
 }
 
+/// Smaller re-encoding of a variant: oxipng for PNG and mozjpeg (trellis
+/// quantization, optimized progressive scans) for JPEG. Other formats, failures
+/// and results that are not smaller keep `encoded`.
+#[cfg(feature = "optimize")]
+fn optimize_variant(
+    image: &DynamicImage,
+    format: ImageFormat,
+    encoded: Vec<u8>,
+    exif_bytes: Option<&[u8]>,
+    jpeg_quality: u8,
+) -> Vec<u8> {
+    let optimized = match format {
+        ImageFormat::Png => {
+            oxipng::optimize_from_memory(&encoded, &oxipng::Options::from_preset(2))
+                .map_err(|e| e.to_string())
+        }
+        ImageFormat::Jpeg => encode_mozjpeg(image, exif_bytes, jpeg_quality),
+        _ => return encoded,
+    };
+    match optimized {
+        Ok(optimized) if optimized.len() < encoded.len() => optimized,
+        Ok(_) => encoded,
+        Err(err) => {
+            crate::diagnostics::warn(format!(
+                "[images] optimization failed, keeping plain encoding: {}",
+                err
+            ));
+            encoded
+        }
+    }
+}
+
+#[cfg(not(feature = "optimize"))]
+fn optimize_variant(
+    _image: &DynamicImage,
+    _format: ImageFormat,
+    encoded: Vec<u8>,
+    _exif_bytes: Option<&[u8]>,
+    _jpeg_quality: u8,
+) -> Vec<u8> {
+    encoded
+}
+
+#[cfg(feature = "optimize")]
+fn encode_mozjpeg(
+    image: &DynamicImage,
+    exif_bytes: Option<&[u8]>,
+    jpeg_quality: u8,
+) -> Result<Vec<u8>, String> {
+    let rgb = image.to_rgb8();
+    let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    compress.set_size(rgb.width() as usize, rgb.height() as usize);
+    compress.set_quality(jpeg_quality as f32);
+    compress.set_optimize_scans(true);
+    let mut started = compress
+        .start_compress(Vec::new())
+        .map_err(|e| e.to_string())?;
+    started
+        .write_scanlines(rgb.as_raw())
+        .map_err(|e| e.to_string())?;
+    let mut jpeg = started.finish().map_err(|e| e.to_string())?;
+    if let Some(exif_data) = exif_bytes {
+        insert_exif_segment(&mut jpeg, exif_data);
+    }
+    Ok(jpeg)
+}
+
+/// Queues the variants of one image on the resize pool; `false` when they
+/// were deferred because the build's resize budget is used up.
 fn schedule_resize_generation(
     reference: String,
     bytes: Arc<[u8]>,
-    format: ImageFormat,
     orientation: Option<u16>,
     jobs: Vec<VariantJob>,
     exif_bytes: Option<Arc<Vec<u8>>>,
     jpeg_quality: u8,
-) {
+    optimize: bool,
+) -> bool {
     if jobs.is_empty() {
-        return;
+        return true;
+    }
+    if !reserve_resize_budget(&reference, bytes.len() as u64) {
+        return false;
     }
 
     let dispatcher = Arc::clone(&RESIZE_DISPATCHER);
@@ -751,7 +1308,7 @@ fn schedule_resize_generation(
         let mut image = match image::load_from_memory(bytes.as_ref()) {
             Ok(img) => img,
             Err(err) => {
-                eprintln!("Failed to load {}: {}", reference, err);
+                crate::diagnostics::warn(format!("Failed to load {}: {}", reference, err));
                 return;
             }
         };
@@ -763,22 +1320,136 @@ fn schedule_resize_generation(
             reference,
             start.elapsed()
         );
-        let exif_slice = exif_bytes
-            .as_deref()
-            .map(|buf| buf.as_slice());
+        let exif_slice = exif_bytes.as_deref().map(|buf| buf.as_slice());
         for job in jobs {
             if let Err(err) =
-                generate_variant_file(&job, &image, format, exif_slice, jpeg_quality)
+                generate_variant_file(&job, &image, exif_slice, jpeg_quality, optimize)
             {
-                eprintln!(
+                crate::diagnostics::warn(format!(
                     "Failed to build variant {} for {}: {}",
                     job.path.display(),
                     reference,
                     err
-                );
+                ));
             }
         }
     });
+    true
+}
+
+/// Counts `bytes` of source image against the build's resize budget; `false`
+/// once it is used up. The image that crosses the budget still goes ahead, so
+/// every build makes progress however large its images are.
+fn reserve_resize_budget(reference: &str, bytes: u64) -> bool {
+    let reserved = RESIZE_BUDGET.reserve(bytes);
+    if !reserved {
+        if let Ok(mut deferred) = DEFERRED_RESIZES.lock() {
+            deferred.insert(reference.to_string(), bytes);
+        }
+    }
+    reserved
+}
+
+/// Container, MIME type and ffmpeg codec arguments of each `images.gif_to_video` encoding.
+const GIF_VIDEO_ENCODINGS: [(&str, &str, &[&str]); 2] = [
+    (
+        "webm",
+        "video/webm",
+        &["-c:v", "libvpx-vp9", "-b:v", "0", "-crf", "40"],
+    ),
+    (
+        "mp4",
+        "video/mp4",
+        &["-c:v", "libx264", "-crf", "23", "-movflags", "+faststart"],
+    ),
+];
+
+/// Runs ffmpeg on a GIF, writing to a temporary file that is renamed into
+/// place only once the encode succeeds.
+fn convert_gif(
+    gif_path: &Path,
+    output: &Path,
+    container: &str,
+    codec_args: &[&str],
+) -> Result<(), String> {
+    let partial = output.with_extension(format!("{}.part", container));
+    let result = std::process::Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(gif_path)
+        .args(codec_args)
+        // yuv420p (needed for broad playback support) requires even dimensions
+        .args([
+            "-pix_fmt",
+            "yuv420p",
+            "-vf",
+            "scale=trunc(iw/2)*2:trunc(ih/2)*2",
+        ])
+        .args(["-an", "-f", container])
+        .arg(&partial)
+        .output();
+    let output_result = match result {
+        Ok(out) if out.status.success() => fs::rename(&partial, output).map_err(|e| e.to_string()),
+        Ok(out) => Err(String::from_utf8_lossy(&out.stderr).trim().to_string()),
+        Err(err) => Err(format!("failed to run ffmpeg: {}", err)),
+    };
+    if output_result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    output_result
+}
+
+/// Camera owner name and body/lens serial numbers (Exif IFD), and the DNG
+/// camera serial number (IFD0).
+const SERIAL_EXIF_TAGS: [u16; 4] = [0xa430, 0xa431, 0xa435, 0xc62f];
+
+/// First 8 hex digits of the BLAKE3 hash of `bytes`.
+fn short_content_hash(bytes: &[u8]) -> String {
+    blake3::hash(bytes).to_hex()[..8].to_string()
+}
+
+fn average_color(image: &image::RgbImage) -> [u8; 3] {
+    let mut sums = [0u64; 3];
+    for pixel in image.pixels() {
+        for (sum, channel) in sums.iter_mut().zip(pixel.0) {
+            *sum += channel as u64;
+        }
+    }
+    let count = (image.width() as u64 * image.height() as u64).max(1);
+    sums.map(|sum| (sum / count) as u8)
+}
+
+pub fn format_hex_color([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+pub fn parse_hex_color(hex: &str) -> Option<[u8; 3]> {
+    let digits = hex.strip_prefix('#')?;
+    if digits.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
 }
 
 fn ensure_exif_header(bytes: Vec<u8>) -> Vec<u8> {
@@ -798,7 +1469,7 @@ fn insert_exif_segment(jpeg: &mut Vec<u8>, exif_data: &[u8]) {
         return;
     }
     if exif_data.len() + 2 > u16::MAX as usize {
-        eprintln!("skipping EXIF attachment: data too large");
+        crate::diagnostics::warn("skipping EXIF attachment: data too large");
         return;
     }
 
@@ -1046,6 +1717,26 @@ fn shutter_value(exif: &ExifData) -> Option<String> {
     exif_value(exif, ExifTag::ExposureTime).or_else(|| exif_value(exif, ExifTag::ShutterSpeedValue))
 }
 
+/// `[x, y, width, height]` of the largest region with the given aspect ratio,
+/// centered on the `focus` point as far as the image edges allow.
+fn crop_rect(width: u32, height: u32, aspect: f64, (focus_x, focus_y): (f64, f64)) -> [u32; 4] {
+    let (crop_width, crop_height) = if width as f64 / height as f64 > aspect {
+        ((height as f64 * aspect).round() as u32, height)
+    } else {
+        (width, (width as f64 / aspect).round() as u32)
+    };
+    let (crop_width, crop_height) = (crop_width.clamp(1, width), crop_height.clamp(1, height));
+    let offset = |size: u32, crop: u32, focus: f64| {
+        ((size as f64 * focus - crop as f64 / 2.0).round().max(0.0) as u32).min(size - crop)
+    };
+    [
+        offset(width, crop_width, focus_x),
+        offset(height, crop_height, focus_y),
+        crop_width,
+        crop_height,
+    ]
+}
+
 fn compute_display_dimensions(width: f64, height: f64, layout_limit: u32) -> (u32, u32, bool) {
     let layout = layout_limit.max(1) as f64;
     let mut original_width = if width > 0.0 { width } else { layout };
@@ -1108,6 +1799,253 @@ fn estimate_svg_dimensions(bytes: &[u8]) -> Option<(f64, f64)> {
         .filter(|(w, h)| *w > 0.0 && *h > 0.0)
 }
 
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+const XHTML_NS: &str = "http://www.w3.org/1999/xhtml";
+
+/// How [`clean_svg`] rewrites an SVG.
+struct SvgCleanOptions<'a> {
+    /// Writing a file of its own: namespace declarations and the root's size
+    /// are kept. Otherwise the markup is for a page, where the root's size is
+    /// set by the figure.
+    standalone: bool,
+    /// Drop scripts, foreign objects, event handlers and `javascript:` URLs.
+    strip_scripts: bool,
+    /// Put in front of every id kept, so two inlined images cannot clash.
+    id_prefix: &'a str,
+}
+
+/// Elements whose whitespace is content.
+fn keeps_whitespace(name: &str) -> bool {
+    matches!(
+        name,
+        "text" | "tspan" | "textPath" | "style" | "title" | "desc"
+    )
+}
+
+/// Ids named by `url(#id)`, `href="#id"`, ARIA references, animation timing
+/// or `<style>` selectors.
+fn referenced_svg_ids<'a>(doc: &'a Document) -> HashSet<&'a str> {
+    let mut ids = HashSet::new();
+    for node in doc.descendants() {
+        if node.is_text() && node.parent().map(|p| p.tag_name().name()) == Some("style") {
+            let text = node.text().unwrap_or_default();
+            ids.extend(
+                SVG_ID_SELECTOR
+                    .captures_iter(text)
+                    .map(|c| c.get(1).unwrap().as_str()),
+            );
+            continue;
+        }
+        for attr in node.attributes() {
+            let value = attr.value();
+            ids.extend(
+                SVG_URL_REF
+                    .captures_iter(value)
+                    .map(|c| c.get(2).unwrap().as_str()),
+            );
+            match attr.name() {
+                "href" => ids.extend(value.strip_prefix('#')),
+                "aria-labelledby" | "aria-describedby" => ids.extend(value.split_whitespace()),
+                "begin" | "end" => ids.extend(
+                    value
+                        .split(';')
+                        .filter_map(|timing| timing.trim().split_once('.'))
+                        .map(|(id, _)| id),
+                ),
+                _ => {}
+            }
+        }
+    }
+    ids
+}
+
+/// An SVG without editor metadata, comments, unused ids, whitespace between
+/// elements or attributes from other namespaces (Inkscape and Sodipodi keep
+/// local file paths there), and with `cleanup.strip_scripts` nothing that
+/// runs code. Ids that stay get `cleanup.id_prefix`, and so do the references
+/// to them. `None` when the bytes are not an SVG document.
+fn clean_svg(bytes: &[u8], cleanup: &SvgCleanOptions) -> Option<String> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..ParsingOptions::default()
+    };
+    let doc = Document::parse_with_options(text, options).ok()?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "svg" || root.tag_name().namespace() != Some(SVG_NS) {
+        return None;
+    }
+    let referenced = referenced_svg_ids(&doc);
+    // Symbols and views are meant to be addressed from outside the file
+    let kept: HashSet<&str> = doc
+        .descendants()
+        .filter(|node| {
+            matches!(node.tag_name().name(), "symbol" | "view")
+                || node
+                    .attribute("id")
+                    .is_some_and(|id| referenced.contains(id))
+        })
+        .filter_map(|node| node.attribute("id"))
+        .collect();
+    let prefixed = |id: &str| format!("{}{}", cleanup.id_prefix, id);
+    let prefix_refs = |value: &str| -> String {
+        SVG_URL_REF
+            .replace_all(value, |caps: &regex::Captures| {
+                if kept.contains(&caps[2]) {
+                    format!("url({}#{}", &caps[1], prefixed(&caps[2]))
+                } else {
+                    caps[0].to_string()
+                }
+            })
+            .into_owned()
+    };
+    let prefix_selectors = |css: &str| -> String {
+        SVG_ID_SELECTOR
+            .replace_all(css, |caps: &regex::Captures| {
+                if kept.contains(&caps[1]) {
+                    format!("#{}", prefixed(&caps[1]))
+                } else {
+                    caps[0].to_string()
+                }
+            })
+            .into_owned()
+    };
+
+    let uses_xlink = doc
+        .descendants()
+        .any(|node| node.attributes().any(|a| a.namespace() == Some(XLINK_NS)));
+
+    let mut out = String::new();
+    let mut stack = vec![(root, false)];
+    while let Some((node, closing)) = stack.pop() {
+        let name = node.tag_name().name();
+        if closing {
+            out.push_str(&format!("</{}>", name));
+            continue;
+        }
+        if node.is_text() {
+            let parent = node
+                .parent()
+                .map(|p| p.tag_name().name())
+                .unwrap_or_default();
+            let text = node.text().unwrap_or_default();
+            if parent == "style" {
+                out.push_str(&escape_svg_text(&prefix_selectors(&prefix_refs(text))));
+            } else if keeps_whitespace(parent) || !text.trim().is_empty() {
+                out.push_str(&escape_svg_text(text));
+            }
+            continue;
+        }
+        let namespace = node.tag_name().namespace();
+        let allowed = match namespace {
+            Some(SVG_NS) => match name {
+                "metadata" => false,
+                "script" | "foreignObject" => !cleanup.strip_scripts,
+                _ => true,
+            },
+            // The HTML inside a foreignObject
+            Some(XHTML_NS) => !cleanup.strip_scripts && name != "script",
+            _ => false,
+        };
+        if !node.is_element() || !allowed {
+            continue;
+        }
+        out.push('<');
+        out.push_str(name);
+        let is_root = node == root;
+        let parent_namespace = node.parent_element().map(|p| p.tag_name().namespace());
+        if cleanup.standalone && (is_root || parent_namespace != Some(namespace)) {
+            out.push_str(&format!(" xmlns=\"{}\"", namespace.unwrap_or_default()));
+        }
+        if cleanup.standalone && is_root && uses_xlink {
+            out.push_str(&format!(" xmlns:xlink=\"{}\"", XLINK_NS));
+        }
+        for attr in node.attributes() {
+            let attr_name = match attr.namespace() {
+                None => attr.name().to_string(),
+                Some(XLINK_NS) => format!("xlink:{}", attr.name()),
+                Some(XML_NS) => format!("xml:{}", attr.name()),
+                Some(_) => continue,
+            };
+            let compact: String = attr
+                .value()
+                .chars()
+                .filter(|c| !c.is_whitespace() && !c.is_control())
+                .collect::<String>()
+                .to_ascii_lowercase();
+            let runs_code = attr_name.to_ascii_lowercase().starts_with("on")
+                || compact.contains("javascript:")
+                || compact.contains("vbscript:")
+                || (compact.starts_with("data:") && !compact.starts_with("data:image/"));
+            if (cleanup.strip_scripts && runs_code)
+                || compact.starts_with("file:")
+                || (!cleanup.standalone
+                    && is_root
+                    && matches!(attr_name.as_str(), "width" | "height"))
+            {
+                continue;
+            }
+            let value = match (attr_name.as_str(), attr.value().strip_prefix('#')) {
+                ("id", _) if kept.contains(attr.value()) => prefixed(attr.value()),
+                ("id", _) => continue,
+                ("href" | "xlink:href", Some(id)) if kept.contains(id) => {
+                    format!("#{}", prefixed(id))
+                }
+                ("aria-labelledby" | "aria-describedby", _) => attr
+                    .value()
+                    .split_whitespace()
+                    .map(|id| {
+                        if kept.contains(id) {
+                            prefixed(id)
+                        } else {
+                            id.to_string()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                ("style", _) => strip_editor_styles(&prefix_refs(attr.value())),
+                _ => prefix_refs(attr.value()),
+            };
+            out.push_str(&format!(" {}=\"{}\"", attr_name, escape_svg_attr(&value)));
+        }
+        if !cleanup.standalone && is_root && root.attribute("viewBox").is_none() {
+            if let Some((width, height)) = estimate_svg_dimensions(bytes) {
+                out.push_str(&format!(" viewBox=\"0 0 {} {}\"", width, height));
+            }
+        }
+        if node.has_children() {
+            out.push('>');
+            stack.push((node, true));
+            let children: Vec<_> = node.children().collect();
+            stack.extend(children.into_iter().rev().map(|child| (child, false)));
+        } else {
+            out.push_str("/>");
+        }
+    }
+    Some(out)
+}
+
+/// A `style` attribute without the `-inkscape-*` declarations editors add.
+fn strip_editor_styles(style: &str) -> String {
+    style
+        .split(';')
+        .filter(|rule| !rule.trim().is_empty() && !rule.trim_start().starts_with("-inkscape-"))
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn escape_svg_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn escape_svg_attr(value: &str) -> String {
+    escape_svg_text(value).replace('"', "&quot;")
+}
 fn parse_viewbox(value: &str) -> Option<(f64, f64)> {
     let parts: Vec<&str> = value
         .split(|c: char| c.is_whitespace() || c == ',')
@@ -1139,7 +2077,17 @@ fn parse_dimension(input: &str) -> Option<f64> {
     numeric.parse::<f64>().ok()
 }
 
-fn is_remote(reference: &str) -> bool {
+/// Where a non-remote image reference is read from.
+pub fn local_source_path(reference: &str, asset_root: &Path) -> PathBuf {
+    let candidate = Path::new(reference);
+    if candidate.is_absolute() {
+        candidate.to_path_buf()
+    } else {
+        asset_root.join(candidate)
+    }
+}
+
+pub fn is_remote(reference: &str) -> bool {
     let lower = reference.to_ascii_lowercase();
     lower.starts_with("http://") || lower.starts_with("https://")
 }
@@ -1235,6 +2183,17 @@ fn dimension_cache_path(original_path: &Path) -> PathBuf {
     original_path.with_extension("txt")
 }
 
+/// Drops what earlier builds cached about an original that has since changed.
+fn forget_cached_derivatives(original_path: &Path) {
+    for path in [
+        dimension_cache_path(original_path),
+        original_path.with_extension("lqip"),
+        original_path.with_extension("color"),
+    ] {
+        let _ = fs::remove_file(path);
+    }
+}
+
 fn load_cached_dimensions(original_path: &Path) -> Option<(u32, u32)> {
     let cache_path = dimension_cache_path(original_path);
     let contents = fs::read_to_string(cache_path).ok()?;
@@ -1249,7 +2208,46 @@ fn save_cached_dimensions(original_path: &Path, width: u32, height: u32) -> Resu
     fs::write(cache_path, format!("{} {}\n", width, height))
 }
 
+/// Threads in the resize pool; 0 means one per CPU.
+static RESIZE_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+static RESIZE_BUDGET: ResizeBudget = ResizeBudget::new();
+
+/// Source bytes that may be resized in this build (`images.resize_budget_mb`).
+struct ResizeBudget {
+    limit: AtomicU64,
+    /// Source bytes queued for resizing so far.
+    spent: AtomicU64,
+}
+
+impl ResizeBudget {
+    const fn new() -> Self {
+        Self {
+            limit: AtomicU64::new(u64::MAX),
+            spent: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts `bytes` against the budget, or returns `false` once it is used up.
+    fn reserve(&self, bytes: u64) -> bool {
+        let limit = self.limit.load(Ordering::SeqCst);
+        self.spent
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |spent| {
+                (spent < limit).then(|| spent + bytes)
+            })
+            .is_ok()
+    }
+
+    fn set_limit(&self, megabytes: Option<u64>) {
+        let limit = megabytes.map_or(u64::MAX, |mb| mb.saturating_mul(1024 * 1024));
+        self.limit.store(limit, Ordering::SeqCst);
+    }
+}
+
+/// Runs resizes on a pool of their own so they cannot starve page rendering,
+/// which uses rayon's global pool.
 struct ResizeDispatcher {
+    pool: OnceLock<rayon::ThreadPool>,
     pending: AtomicUsize,
     lock: Mutex<()>,
     condvar: Condvar,
@@ -1258,17 +2256,29 @@ struct ResizeDispatcher {
 impl ResizeDispatcher {
     fn new() -> Self {
         Self {
+            pool: OnceLock::new(),
             pending: AtomicUsize::new(0),
             lock: Mutex::new(()),
             condvar: Condvar::new(),
         }
     }
 
+    fn pool(&self) -> &rayon::ThreadPool {
+        self.pool.get_or_init(|| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(RESIZE_THREADS.load(Ordering::SeqCst))
+                .thread_name(|index| format!("resize-{}", index))
+                .build()
+                .expect("failed to start image resize threads")
+        })
+    }
+
     fn spawn(self: Arc<Self>, job: impl FnOnce() + Send + 'static) {
         self.pending.fetch_add(1, Ordering::SeqCst);
-        rayon::spawn_fifo(move || {
+        let dispatcher = Arc::clone(&self);
+        self.pool().spawn_fifo(move || {
             job();
-            self.job_finished();
+            dispatcher.job_finished();
         });
     }
 
@@ -1287,14 +2297,110 @@ impl ResizeDispatcher {
     }
 }
 
+/// Sizes the resize pool (`build.image_jobs`, at most `images.max_concurrent_resizes`);
+/// call before any image is processed.
+pub fn set_resize_threads(threads: usize, max_concurrent: Option<usize>) {
+    RESIZE_THREADS.store(resize_threads(threads, max_concurrent), Ordering::SeqCst);
+}
+
+/// Threads for the resize pool, where 0 means one per CPU.
+fn resize_threads(threads: usize, max_concurrent: Option<usize>) -> usize {
+    match max_concurrent {
+        Some(max) if threads == 0 => std::thread::available_parallelism()
+            .map_or(1, |cpus| cpus.get())
+            .min(max),
+        Some(max) => threads.min(max),
+        None => threads,
+    }
+}
+
+/// Limits the source images resized in this build to `megabytes`
+/// (`images.resize_budget_mb`); the rest are left for later builds.
+pub fn set_resize_budget(megabytes: Option<u64>) {
+    RESIZE_BUDGET.set_limit(megabytes);
+}
+
 pub fn wait_for_pending_resizes() {
     RESIZE_DISPATCHER.wait();
 }
 
+/// Lists the images whose resizing was deferred by the resize budget, if any.
+pub fn report_deferred_resizes() {
+    let Ok(deferred) = DEFERRED_RESIZES.lock() else {
+        return;
+    };
+    if deferred.is_empty() {
+        return;
+    }
+    let megabytes = deferred.values().sum::<u64>() as f64 / (1024.0 * 1024.0);
+    eprintln!(
+        "{} image(s) ({:.1} MB) went over images.resize_budget_mb and link their originals \
+         until a later build resizes them:",
+        deferred.len(),
+        megabytes
+    );
+    for reference in deferred.keys() {
+        eprintln!("  {}", reference);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn inline_svg_drops_scripts_and_prefixes_ids() {
+        let svg = br##"<?xml version="1.0"?>
+<!-- drawn by hand -->
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"
+     xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape" width="40" height="20">
+  <script>alert(1)</script>
+  <defs><linearGradient id="g"><stop offset="0" stop-color="#fff"/></linearGradient></defs>
+  <rect id="unused" width="40" height="20" fill="url(#g)" onclick="alert(2)" inkscape:label="bg"/>
+  <a xlink:href="javascript:alert(3)"><use xlink:href="#g"/></a>
+  <text x="1" y="15">a &lt; b</text>
+</svg>"##;
+        let cleanup = SvgCleanOptions {
+            standalone: false,
+            strip_scripts: true,
+            id_prefix: "p-",
+        };
+        assert_eq!(
+            clean_svg(svg, &cleanup).unwrap(),
+            "<svg viewBox=\"0 0 40 20\"><defs><linearGradient id=\"p-g\"><stop offset=\"0\" stop-color=\"#fff\"/></linearGradient></defs>\
+             <rect width=\"40\" height=\"20\" fill=\"url(#p-g)\"/><a><use xlink:href=\"#p-g\"/></a>\
+             <text x=\"1\" y=\"15\">a &lt; b</text></svg>"
+        );
+        assert!(clean_svg(b"<html/>", &cleanup).is_none());
+    }
+
+    #[test]
+    fn svg_cleanup_strips_editor_metadata() {
+        let svg = br##"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Created with Inkscape -->
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape"
+     xmlns:sodipodi="http://sodipodi.sourceforge.net/DTD/sodipodi-0.dtd"
+     width="10" height="10" id="svg1" sodipodi:docname="/home/me/drawing.svg">
+  <sodipodi:namedview id="base" inkscape:zoom="2"/>
+  <metadata id="metadata1"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"/></metadata>
+  <g id="layer1" inkscape:label="Layer 1" inkscape:groupmode="layer">
+    <circle id="path1" cx="5" cy="5" r="4" style="fill:#f00;-inkscape-font-specification:Sans" onclick="go()"/>
+  </g>
+</svg>"##;
+        let mut cleanup = SvgCleanOptions {
+            standalone: true,
+            strip_scripts: false,
+            id_prefix: "",
+        };
+        assert_eq!(
+            clean_svg(svg, &cleanup).unwrap(),
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"10\" height=\"10\">\
+             <g><circle cx=\"5\" cy=\"5\" r=\"4\" style=\"fill:#f00\" onclick=\"go()\"/></g></svg>"
+        );
+        cleanup.strip_scripts = true;
+        assert!(!clean_svg(svg, &cleanup).unwrap().contains("onclick"));
+    }
+
     #[test]
     fn detects_webp_extension() {
         assert!(matches!(
@@ -1307,4 +2413,171 @@ mod tests {
     fn webp_extension_roundtrip() {
         assert_eq!(extension_for_format(ImageFormat::WebP), Some("webp"));
     }
+
+    #[test]
+    fn exif_date_becomes_a_post_date() {
+        let summary = |date: &str| ExifSummary {
+            entries: vec![
+                ("Camera".to_string(), "X100V".to_string()),
+                ("Date".to_string(), date.to_string()),
+            ],
+        };
+        assert_eq!(
+            summary("2023:07:04 18:30:02").date().as_deref(),
+            Some("2023-07-04")
+        );
+        assert_eq!(summary("0000:00:00 00:00:00").date(), None);
+        assert_eq!(ExifSummary { entries: vec![] }.date(), None);
+    }
+
+    #[test]
+    fn average_color_roundtrips_through_hex() {
+        let mut image = image::RgbImage::from_pixel(2, 1, image::Rgb([200, 0, 10]));
+        image.put_pixel(1, 0, image::Rgb([100, 50, 30]));
+        let color = average_color(&image);
+        assert_eq!(color, [150, 25, 20]);
+        assert_eq!(format_hex_color(color), "#961914");
+        assert_eq!(parse_hex_color("#961914"), Some(color));
+        assert_eq!(parse_hex_color("961914"), None);
+    }
+
+    fn exif_entry(kind: IfdKind, tag: ExifTag, raw_tag: u16) -> ExifEntry {
+        ExifEntry {
+            namespace: rexif::Namespace::Standard,
+            ifd: rexif::IfdEntry {
+                namespace: rexif::Namespace::Standard,
+                tag: raw_tag,
+                format: rexif::IfdFormat::Ascii,
+                count: 1,
+                data: vec![0],
+                ifd_data: vec![0, 0, 0, 0],
+                ext_data: Vec::new(),
+                le: true,
+            },
+            tag,
+            value: TagValue::Ascii(String::new()),
+            unit: "none".into(),
+            value_more_readable: String::new().into(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn strip_exif_drops_gps_and_serial_numbers() {
+        let exif = ExifData::new(
+            "image/jpeg",
+            vec![
+                exif_entry(IfdKind::Ifd0, ExifTag::Make, 0x010f),
+                exif_entry(IfdKind::Ifd0, ExifTag::ExifOffset, 0x8769),
+                exif_entry(IfdKind::Ifd0, ExifTag::GPSOffset, 0x8825),
+                exif_entry(IfdKind::Exif, ExifTag::UnknownToMe, 0xa431),
+                exif_entry(IfdKind::Gps, ExifTag::GPSLatitude, 0x0002),
+            ],
+            true,
+        );
+        let mut cfg = config::Config::default();
+        cfg.images.strip_exif = vec![config::ExifStrip::Privacy];
+        let published = ImageProcessor::new(&cfg).published_exif(&exif);
+        let tags: Vec<u16> = published.entries.iter().map(|e| e.ifd.tag).collect();
+        assert_eq!(tags, vec![0x010f]);
+
+        cfg.images.strip_exif = Vec::new();
+        let published = ImageProcessor::new(&cfg).published_exif(&exif);
+        assert_eq!(published.entries.len(), 5);
+    }
+
+    #[test]
+    fn content_hash_changes_with_the_source() {
+        let hash = short_content_hash(b"first photo");
+        assert_eq!(hash.len(), 8);
+        assert_eq!(hash, short_content_hash(b"first photo"));
+        assert_ne!(hash, short_content_hash(b"replacement photo"));
+    }
+
+    #[test]
+    fn crop_keeps_the_focal_point_in_frame() {
+        let square = |focus| crop_rect(2000, 1000, 1.0, focus);
+        assert_eq!(square((0.5, 0.5)), [500, 0, 1000, 1000]);
+        assert_eq!(square((0.3, 0.5)), [100, 0, 1000, 1000]);
+        assert_eq!(square((0.9, 0.5)), [1000, 0, 1000, 1000]);
+        let banner = |focus| crop_rect(1000, 1000, 16.0 / 9.0, focus);
+        assert_eq!(banner((0.5, 0.0)), [0, 0, 1000, 563]);
+        assert_eq!(banner((0.5, 1.0)), [0, 437, 1000, 563]);
+    }
+
+    #[test]
+    fn optimized_variants_are_never_larger_and_keep_their_exif() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(96, 64, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 3) as u8, 90])
+        }));
+        let exif = b"Exif\0\0MM\0*\0\0\0\x08\0\0".as_slice();
+        for format in [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::WebP] {
+            let exif = (format == ImageFormat::Jpeg).then_some(exif);
+            let encoded = encode_image(&image, format, exif, 80).unwrap();
+            let optimized = optimize_variant(&image, format, encoded.clone(), exif, 80);
+            assert!(optimized.len() <= encoded.len(), "{:?}", format);
+            let decoded = image::load_from_memory(&optimized).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (96, 64));
+            if format == ImageFormat::WebP || !cfg!(feature = "optimize") {
+                assert_eq!(optimized, encoded, "{:?}", format);
+            }
+            if exif.is_some() {
+                assert!(optimized.windows(6).any(|window| window == b"Exif\0\0"));
+            }
+        }
+    }
+
+    #[test]
+    fn resize_budget_defers_images_once_used_up() {
+        let budget = ResizeBudget::new();
+        assert!(budget.reserve(u64::MAX / 2));
+
+        let budget = ResizeBudget::new();
+        budget.set_limit(Some(2));
+        let mb = 1024 * 1024;
+        assert!(budget.reserve(mb));
+        // The image that crosses the budget still goes ahead
+        assert!(budget.reserve(3 * mb));
+        assert!(!budget.reserve(1));
+        assert_eq!(budget.spent.load(Ordering::SeqCst), 4 * mb);
+
+        let budget = ResizeBudget::new();
+        budget.set_limit(Some(0));
+        assert!(!budget.reserve(1));
+    }
+
+    #[test]
+    fn max_concurrent_resizes_caps_the_resize_threads() {
+        assert_eq!(resize_threads(8, Some(2)), 2);
+        assert_eq!(resize_threads(1, Some(2)), 1);
+        assert_eq!(resize_threads(3, None), 3);
+        assert_eq!(resize_threads(0, None), 0);
+        assert_eq!(resize_threads(0, Some(1)), 1);
+    }
+
+    #[test]
+    fn base64_pads_partial_chunks() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn parses_output_formats() {
+        assert!(matches!(
+            output_format_from_name(" WebP"),
+            Some(ImageFormat::WebP)
+        ));
+        assert!(matches!(
+            output_format_from_name("jpeg"),
+            Some(ImageFormat::Jpeg)
+        ));
+        assert!(output_format_from_name("heic").is_none());
+        assert_eq!(
+            output_format_from_name("avif").is_some(),
+            cfg!(feature = "avif")
+        );
+    }
 }
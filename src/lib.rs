@@ -0,0 +1,9 @@
+//! The dllu parser as a library, for tools that read `.dllu` sources without
+//! rendering them; the `dllup-rs` binary builds the site on top of it.
+
+#[macro_use]
+extern crate lazy_static;
+
+pub mod ast;
+pub mod excerpt;
+pub mod parser;
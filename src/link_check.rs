@@ -0,0 +1,204 @@
+//! Links with a `#fragment` between the pages of a directory build, checked
+//! in the written HTML once every page is rendered (`lint.fragments`). A link
+//! must lead to a page that exists and to an element of that page with the
+//! fragment as its id, so section links that went stale when a heading was
+//! renumbered, or links into another page's figures, are reported instead of
+//! landing at the top of the page. `(#name)` references are left to
+//! `lint.references`.
+
+use crate::config::{self, LintLevel};
+use crate::diagnostics::{self, Diagnostic, Severity};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Emits the broken fragment links of the built `pages` (source and output
+/// paths) as diagnostics. Fails when `lint.fragments` is `error` and some are.
+pub fn check_site(
+    site_root: &Path,
+    pages: &[(&Path, &Path)],
+    config: &config::Config,
+) -> Result<(), String> {
+    let severity = match config.lint.fragments {
+        LintLevel::Off => return Ok(()),
+        LintLevel::Warn => Severity::Warning,
+        LintLevel::Error => Severity::Error,
+    };
+    let findings = broken_fragments(site_root, pages, config.root_url.as_deref(), severity);
+    for diagnostic in &findings {
+        diagnostics::emit(diagnostic);
+    }
+    if severity == Severity::Error && !findings.is_empty() {
+        return Err(format!("{} broken fragment link(s)", findings.len()));
+    }
+    Ok(())
+}
+
+fn broken_fragments(
+    site_root: &Path,
+    pages: &[(&Path, &Path)],
+    root_url: Option<&str>,
+    severity: Severity,
+) -> Vec<Diagnostic> {
+    // Ids of each page read so far, or `None` for a file that can't be read
+    let mut page_ids: HashMap<PathBuf, Option<HashSet<String>>> = HashMap::new();
+    let mut findings = Vec::new();
+    for &(source, output) in pages {
+        let Ok(html) = fs::read_to_string(output) else {
+            continue;
+        };
+        let page_dir = output.parent().unwrap_or_else(|| Path::new("."));
+        let source_text = fs::read_to_string(source).unwrap_or_default();
+        let mut reported = HashSet::new();
+        for href in fragment_links(&html) {
+            let Some((path, fragment)) = local_target(href, root_url) else {
+                continue;
+            };
+            let target = if path.is_empty() {
+                Some(output.to_path_buf())
+            } else {
+                page_file(path, page_dir, site_root)
+            };
+            let ids = target.as_ref().and_then(|target| {
+                page_ids
+                    .entry(target.clone())
+                    .or_insert_with(|| {
+                        fs::read_to_string(target)
+                            .ok()
+                            .map(|html| element_ids(&html))
+                    })
+                    .as_ref()
+            });
+            let message = match ids {
+                Some(ids) if ids.contains(fragment) => continue,
+                Some(_) => format!("link to {} has no element with id `{}`", href, fragment),
+                None => format!("link to {} does not lead to a built page", href),
+            };
+            if reported.insert(href) {
+                findings.push(
+                    Diagnostic::new(source, severity, "broken-fragment", message)
+                        .at(&source_text, &format!("#{}", fragment)),
+                );
+            }
+        }
+    }
+    findings
+}
+
+/// `href`s of the page's links that carry a fragment, leaving out
+/// references, which render as `refname`, `eqref` and `xref` links.
+fn fragment_links(html: &str) -> Vec<&str> {
+    lazy_static! {
+        static ref LINK: Regex = Regex::new(r#"<a\s[^>]*>"#).unwrap();
+        static ref HREF: Regex = Regex::new(r#"\shref="([^"]*#[^"]*)""#).unwrap();
+        static ref REFERENCE: Regex = Regex::new(r#"\sclass="(refname|eqref|xref)""#).unwrap();
+    }
+    LINK.find_iter(html)
+        .map(|tag| tag.as_str())
+        .filter(|tag| !REFERENCE.is_match(tag))
+        .filter_map(|tag| HREF.captures(tag))
+        .filter_map(|caps| caps.get(1))
+        .map(|href| href.as_str())
+        .collect()
+}
+
+fn element_ids(html: &str) -> HashSet<String> {
+    lazy_static! {
+        static ref ID: Regex = Regex::new(r#"\sid="([^"]*)""#).unwrap();
+    }
+    ID.captures_iter(html)
+        .map(|caps| caps[1].to_string())
+        .collect()
+}
+
+/// The path (empty for the page itself) and fragment of a link into the
+/// site, or `None` for other sites, other files and bare `#` links.
+fn local_target<'a>(href: &'a str, root_url: Option<&str>) -> Option<(&'a str, &'a str)> {
+    let (rest, fragment) = href.split_once('#')?;
+    if fragment.is_empty() {
+        return None;
+    }
+    let root = root_url.map(|root| root.trim_end_matches('/'));
+    let rest = match root.and_then(|root| rest.strip_prefix(root)) {
+        Some(rooted) if rooted.is_empty() || rooted.starts_with('/') => rooted,
+        _ => rest,
+    };
+    if rest.contains(':') || rest.starts_with("//") {
+        return None;
+    }
+    let path = rest.split('?').next().unwrap_or("");
+    let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+    if !path.ends_with('/') && extension.is_some_and(|ext| ext != "html") {
+        return None;
+    }
+    Some((path, fragment))
+}
+
+/// The HTML file a page path leads to, trying `path.html` and
+/// `path/index.html` for clean URLs.
+fn page_file(path: &str, page_dir: &Path, site_root: &Path) -> Option<PathBuf> {
+    let base = match path.strip_prefix('/') {
+        Some(rooted) => site_root.join(rooted),
+        None => page_dir.join(path),
+    };
+    [
+        base.clone(),
+        base.join("index.html"),
+        base.with_extension("html"),
+    ]
+    .into_iter()
+    .find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_fragments_without_a_matching_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let site = dir.path();
+        fs::create_dir(site.join("part-1")).unwrap();
+        fs::write(
+            site.join("part-1").join("index.html"),
+            "<h1 id=\"s1\"><span id=\"setup\"></span></h1>",
+        )
+        .unwrap();
+        fs::write(
+            site.join("index.html"),
+            concat!(
+                "<h1 id=\"top\"></h1>",
+                "<a href=\"#top\">a</a><a href=\"#s2\">b</a>",
+                "<a href=\"part-1/#setup\">c</a><a href=\"https://example.com/part-1#s1.1\">d</a>",
+                "<a href=\"/part-2.html#x\">e</a><a href=\"/cv.pdf#page=2\">f</a>",
+                "<a class=\"refname\" href=\"#gone\">g</a><a href=\"https://other.org/#x\">h</a>",
+            ),
+        )
+        .unwrap();
+        let source = site.join("index.dllu");
+        fs::write(&source, "T\n\n===\n\n[b](#s2)\n").unwrap();
+        let output = site.join("index.html");
+        let findings = broken_fragments(
+            site,
+            &[(&source, &output)],
+            Some("https://example.com/"),
+            Severity::Warning,
+        );
+        let messages: Vec<(&str, Option<usize>)> = findings
+            .iter()
+            .map(|finding| (finding.message.as_str(), finding.line))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                ("link to #s2 has no element with id `s2`", Some(5)),
+                (
+                    "link to https://example.com/part-1#s1.1 has no element with id `s1.1`",
+                    None
+                ),
+                ("link to /part-2.html#x does not lead to a built page", None),
+            ]
+        );
+    }
+}
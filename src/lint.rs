@@ -0,0 +1,503 @@
+//! Build-time checks over parsed articles, configured under `[lint]`.
+
+use crate::ast::{is_graphviz, Article, Block, InlineElement};
+use crate::config::{self, LintLevel};
+use crate::diagnostics::{self, Diagnostic, Severity};
+use crate::html_renderer::next_section_number;
+use crate::xref::{self, SiteReferences};
+use regex::Regex;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Alt text that says nothing about the image.
+const PLACEHOLDER_ALTS: &[&str] = &[
+    "alt",
+    "figure",
+    "image",
+    "img",
+    "photo",
+    "pic",
+    "picture",
+    "placeholder",
+    "screenshot",
+    "tbd",
+    "todo",
+    "untitled",
+];
+
+/// A lint finding on one page.
+pub struct Finding {
+    pub message: String,
+    /// Source text the finding is about, used to point at its line.
+    snippet: String,
+}
+
+/// Runs the configured lints on `article` and emits their findings as
+/// diagnostics. Fails when a lint set to `error` has findings.
+pub fn check_article(
+    input_path: &Path,
+    site_root: Option<&Path>,
+    source: &str,
+    article: &Article,
+    config: &config::Config,
+) -> Result<(), String> {
+    let findings = run(input_path, site_root, source, article, config);
+    for diagnostic in &findings {
+        diagnostics::emit(diagnostic);
+    }
+    let errors = findings
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(format!(
+            "{}: {} lint error(s)",
+            input_path.display(),
+            errors
+        ));
+    }
+    Ok(())
+}
+
+/// Findings of every enabled lint, pointed at lines of `source`.
+pub fn run(
+    input_path: &Path,
+    site_root: Option<&Path>,
+    source: &str,
+    article: &Article,
+    config: &config::Config,
+) -> Vec<Diagnostic> {
+    let mut out = Vec::new();
+    let mut report = |level: LintLevel, code: &'static str, findings: Vec<Finding>| {
+        let severity = match level {
+            LintLevel::Off => return,
+            LintLevel::Warn => Severity::Warning,
+            LintLevel::Error => Severity::Error,
+        };
+        for finding in findings {
+            out.push(
+                Diagnostic::new(input_path, severity, code, finding.message)
+                    .at(source, &finding.snippet),
+            );
+        }
+    };
+    if config.lint.alt_text != LintLevel::Off {
+        report(
+            config.lint.alt_text,
+            "alt-text",
+            alt_text_issues(article, config.html.alt_from_caption),
+        );
+    }
+    if config.lint.references != LintLevel::Off {
+        // Other pages are only known for a site, not for a lone file or in the editor
+        let site = site_root
+            .filter(|_| !xref::cross_page_references(article).is_empty())
+            .map(|site_root| xref::for_site(site_root, config));
+        report(
+            config.lint.references,
+            "unresolved-reference",
+            unresolved_references(article, site.as_deref()),
+        );
+    }
+    if config.lint.links != LintLevel::Off {
+        report(
+            config.lint.links,
+            "broken-link",
+            broken_links(article, input_path, site_root),
+        );
+    }
+    out
+}
+
+/// Describes each non-decorative figure whose alt text is empty or a placeholder.
+/// With `alt_from_caption` (`html.alt_from_caption`), an empty alt text only
+/// counts when the caption is empty too.
+pub fn alt_text_issues(article: &Article, alt_from_caption: bool) -> Vec<Finding> {
+    let mut issues = Vec::new();
+    for block in article.blocks() {
+        if let Block::ImageFigure {
+            url,
+            id_number,
+            alt,
+            decorative: false,
+            text,
+            ..
+        } = block
+        {
+            let alt = alt.trim();
+            let has_caption = !crate::excerpt::plain_text(text).trim().is_empty();
+            let message = if alt.is_empty() && !has_caption {
+                format!(
+                    "figure {} ({}) has neither alt text nor a caption",
+                    id_number + 1,
+                    url
+                )
+            } else if alt.is_empty() && alt_from_caption {
+                continue;
+            } else if alt.is_empty() {
+                format!("figure {} ({}) has no alt text", id_number + 1, url)
+            } else if is_placeholder_alt(alt, url) {
+                format!(
+                    "figure {} ({}) has placeholder alt text \"{}\"",
+                    id_number + 1,
+                    url,
+                    alt
+                )
+            } else {
+                continue;
+            };
+            issues.push(Finding {
+                message,
+                snippet: url.clone(),
+            });
+        }
+    }
+    issues
+}
+
+fn is_placeholder_alt(alt: &str, url: &str) -> bool {
+    lazy_static! {
+        // Camera and phone file names such as `IMG_1234` or `DSCF5250.jpg`
+        static ref CAMERA_NAME: Regex =
+            Regex::new(r"(?i)^(img|dsc|dscf|dscn|pxl|image)[_-]?\d+(\.\w+)?$").unwrap();
+    }
+    let lower = alt.to_lowercase();
+    if PLACEHOLDER_ALTS.contains(&lower.as_str()) || CAMERA_NAME.is_match(alt) {
+        return true;
+    }
+    let file_name = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(url)
+        .rsplit('/')
+        .next()
+        .unwrap_or(url)
+        .to_lowercase();
+    let file_stem = file_name
+        .rsplit_once('.')
+        .map(|(stem, _)| stem)
+        .unwrap_or(&file_name);
+    !file_name.is_empty() && (lower == file_name || lower == file_stem)
+}
+
+/// References (`(#name)`, `\eqref{name}`) with no `[#name]` anchor or block id to
+/// point at, and `page:label` references with no such page or label in `site`.
+fn unresolved_references(article: &Article, site: Option<&SiteReferences>) -> Vec<Finding> {
+    let targets = reference_targets(article);
+    let mut references = Vec::new();
+    visit_inlines(article, &mut |element| {
+        if let InlineElement::Reference(name) = element {
+            references.push(name.as_str());
+        }
+    });
+    let mut reported = HashSet::new();
+    references
+        .into_iter()
+        .filter(|name| {
+            if xref::is_cross_page(name) {
+                site.is_some_and(|site| site.resolve(name).is_none())
+            } else {
+                !targets.contains(*name)
+            }
+        })
+        .filter(|name| reported.insert(*name))
+        .map(|name| Finding {
+            message: if xref::is_cross_page(name) {
+                format!("reference `{}` has no matching page or label", name)
+            } else {
+                format!("reference `{}` has no matching anchor or label", name)
+            },
+            snippet: name.to_string(),
+        })
+        .collect()
+}
+
+/// Site-relative and page-relative links whose target neither exists nor is
+/// generated from a `.dllu` source. Absolute URLs and bare fragments are not checked.
+fn broken_links(article: &Article, input_path: &Path, site_root: Option<&Path>) -> Vec<Finding> {
+    let mut urls = Vec::new();
+    visit_inlines(article, &mut |element| {
+        if let InlineElement::Link { url, .. } = element {
+            urls.push(url.as_str());
+        }
+    });
+    for block in article.blocks() {
+        if let Block::BigButton { url, .. } = block {
+            urls.push(url.as_str());
+        }
+    }
+    let page_dir = input_path.parent().unwrap_or_else(|| Path::new("."));
+    let site_root = site_root.unwrap_or(page_dir);
+    urls.into_iter()
+        .filter(|url| !local_link_resolves(url, page_dir, site_root))
+        .map(|url| Finding {
+            message: format!("link to {} does not resolve to a file", url),
+            snippet: url.to_string(),
+        })
+        .collect()
+}
+
+fn local_link_resolves(url: &str, page_dir: &Path, site_root: &Path) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or("");
+    // Placeholders are only filled in when rendering
+    if path.is_empty() || url.contains(':') || url.starts_with("//") || url.contains("{{") {
+        return true;
+    }
+    let target = match path.strip_prefix('/') {
+        Some(rooted) => site_root.join(rooted),
+        None => page_dir.join(path),
+    };
+    target.exists()
+        || (target.extension().is_some_and(|ext| ext == "html")
+            && target.with_extension("dllu").exists())
+}
+
+/// Names a `(#name)` reference can point at: `[#name]` anchors, block ids and
+/// the ids the renderer numbers blocks with, such as `s1.2`, `fig1` and `eq1`.
+pub fn reference_targets(article: &Article) -> HashSet<String> {
+    let mut targets: HashSet<String> = article
+        .blocks()
+        .into_iter()
+        .filter_map(block_id)
+        .map(str::to_string)
+        .collect();
+    targets.extend(positional_ids(article));
+    visit_inlines(article, &mut |element| {
+        if let InlineElement::ReferenceAnchor { content, .. } = element {
+            targets.insert(content.clone());
+        }
+    });
+    targets
+}
+
+/// Ids the renderer gives sections by their number and figures, listings,
+/// equations and tables without an id of their own.
+fn positional_ids(article: &Article) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut section_counters = Vec::new();
+    for block in article.blocks() {
+        let id = match block {
+            Block::SectionHeader { level, .. } => {
+                let level = (*level).clamp(1, 6);
+                format!("s{}", next_section_number(&mut section_counters, level))
+            }
+            Block::ImageFigure {
+                id: None,
+                id_number,
+                ..
+            }
+            | Block::Embed {
+                id: None,
+                id_number,
+                ..
+            } => format!("fig{}", id_number + 1),
+            Block::CodeBlock {
+                id: None,
+                id_number,
+                language,
+                ..
+            } if is_graphviz(language.as_deref()) => format!("fig{}", id_number + 1),
+            Block::CodeBlock {
+                id: None,
+                id_number,
+                caption: Some(_),
+                ..
+            } => format!("listing{}", id_number + 1),
+            Block::DisplayMath {
+                id: None,
+                id_number,
+                ..
+            } => format!("eq{}", id_number + 1),
+            Block::Table {
+                id_number,
+                caption: Some(_),
+                ..
+            } => format!("table{}", id_number + 1),
+            _ => continue,
+        };
+        ids.push(id);
+    }
+    ids
+}
+
+fn block_id(block: &Block) -> Option<&str> {
+    match block {
+        Block::CodeBlock { id, .. }
+        | Block::ImageFigure { id, .. }
+        | Block::Embed { id, .. }
+        | Block::DisplayMath { id, .. } => id.as_deref(),
+        Block::SectionHeader { id, .. } => Some(id),
+        _ => None,
+    }
+}
+
+/// Calls `f` on every inline element of `article`, including nested ones.
+pub fn visit_inlines<'a>(article: &'a Article, f: &mut dyn FnMut(&'a InlineElement)) {
+    fn walk<'a>(elements: &'a [InlineElement], f: &mut dyn FnMut(&'a InlineElement)) {
+        for element in elements {
+            f(element);
+            if let InlineElement::Link { text, .. }
+            | InlineElement::Lang { text, .. }
+            | InlineElement::Emphasis(text)
+            | InlineElement::Strong(text) = element
+            {
+                walk(text, f);
+            }
+        }
+    }
+    for block in article.blocks() {
+        for inlines in block_inlines(block) {
+            walk(inlines, f);
+        }
+    }
+}
+
+/// The runs of inline text `block` shows itself, not counting the blocks
+/// nested in it: captions, credits, table cells and list item text.
+pub fn block_inlines(block: &Block) -> Vec<&[InlineElement]> {
+    match block {
+        Block::CodeBlock {
+            caption: Some(caption),
+            ..
+        } => vec![caption],
+        Block::BlockQuote(text)
+        | Block::Paragraph(text)
+        | Block::BigButton { text, .. }
+        | Block::Embed { text, .. }
+        | Block::Details { summary: text, .. } => vec![text],
+        Block::ImageFigure { text, credit, .. } => {
+            let mut out: Vec<&[InlineElement]> = vec![text];
+            out.extend(credit.as_deref());
+            out
+        }
+        Block::Table {
+            header,
+            rows,
+            caption,
+            ..
+        } => {
+            let mut out: Vec<&[InlineElement]> = header
+                .iter()
+                .chain(rows.iter().flatten())
+                .map(|cell| cell.content.as_slice())
+                .collect();
+            out.extend(caption.as_deref());
+            out
+        }
+        Block::UnorderedList(items) | Block::OrderedList(items) => {
+            items.iter().map(|item| item.text.as_slice()).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn flags_empty_and_placeholder_alt_text() {
+        let mut parser = Parser::default();
+        parser.parse(
+            "Doc\n\n===\n\npic a.jpg : One\n\npic b.jpg Image : Two\n\npic photos/IMG_0042.jpg IMG_0042 : Three\n\npic c.png c : Four\n\npic d.png - : Five\n\npic e.png A red kite over the hill : Six\n\npic f.png : [#f]\n",
+        );
+        let issues = alt_text_issues(&parser.article, false);
+        let messages: Vec<&str> = issues.iter().map(|issue| issue.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "figure 1 (a.jpg) has no alt text",
+                "figure 2 (b.jpg) has placeholder alt text \"Image\"",
+                "figure 3 (photos/IMG_0042.jpg) has placeholder alt text \"IMG_0042\"",
+                "figure 4 (c.png) has placeholder alt text \"c\"",
+                "figure 7 (f.png) has neither alt text nor a caption",
+            ]
+        );
+
+        // The caption stands in for missing alt text
+        let issues = alt_text_issues(&parser.article, true);
+        assert_eq!(
+            issues[0].message,
+            "figure 2 (b.jpg) has placeholder alt text \"Image\""
+        );
+        assert_eq!(issues.len(), 4);
+    }
+
+    #[test]
+    fn error_level_fails_the_build() {
+        let mut parser = Parser::default();
+        parser.parse("Doc\n\n===\n\npic a.jpg : One\n");
+        let mut config = config::Config::default();
+        let check = |config: &config::Config| {
+            check_article(Path::new("a.dllu"), None, "", &parser.article, config)
+        };
+        assert!(check(&config).is_ok());
+        config.lint.alt_text = LintLevel::Error;
+        assert!(check(&config).is_err());
+    }
+
+    #[test]
+    fn flags_references_without_anchors() {
+        let mut parser = Parser::default();
+        parser.parse(
+            "Doc\n\n===\n\nSee (#smith) and (#jones) and (#smith) and (#part-1:kite).\n\n[#jones] Jones, 2001.\n",
+        );
+        // Without a site, references to other pages are left to the build
+        let messages: Vec<String> = unresolved_references(&parser.article, None)
+            .into_iter()
+            .map(|finding| finding.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec!["reference `smith` has no matching anchor or label"]
+        );
+    }
+
+    #[test]
+    fn numbered_ids_are_reference_targets() {
+        let mut parser = Parser::default();
+        parser.parse(
+            "Doc\n\n===\n\n# One\n\n## Two {#two}\n\n\
+             pic a.jpg A : First\n\npic b.jpg B : Second [#kite]\n\n\
+             $ x = 1\n\n$ y = 2 \\label{why}\n\n\
+             See (#s1)(#s1.1)(#two)(#fig1)(#fig2)(#kite)(#eq1)(#eq2)(#why)(#s2).\n",
+        );
+        let messages: Vec<String> = unresolved_references(&parser.article, None)
+            .into_iter()
+            .map(|finding| finding.message)
+            .collect();
+        // Blocks with an id of their own are not also numbered by position
+        assert_eq!(
+            messages,
+            vec![
+                "reference `fig2` has no matching anchor or label",
+                "reference `eq2` has no matching anchor or label",
+                "reference `s2` has no matching anchor or label",
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_links_to_missing_local_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("about.dllu"), "About\n").unwrap();
+        std::fs::write(dir.path().join("cv.pdf"), "").unwrap();
+        let mut parser = Parser::default();
+        parser.parse(
+            "Doc\n\n===\n\n[a](about.html) [b](/cv.pdf#page=2) [c](https://example.com/x) [d](#top) [e](gone.html) [f](/missing/)\n",
+        );
+        let input = dir.path().join("index.dllu");
+        let messages: Vec<String> = broken_links(&parser.article, &input, Some(dir.path()))
+            .into_iter()
+            .map(|finding| finding.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "link to gone.html does not resolve to a file",
+                "link to /missing/ does not resolve to a file",
+            ]
+        );
+    }
+}
@@ -0,0 +1,446 @@
+//! `dllup-rs lsp`: a small language server for dllu documents over stdio.
+//!
+//! Publishes lint findings as diagnostics, lists section headers as document
+//! symbols, and completes reference labels after `(#` / `\eqref{` and image
+//! paths after `pic `.
+
+use crate::ast::{Article, Block};
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::lint;
+use crate::parser::Parser;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp", "svg", "avif"];
+
+/// Entry point for `dllup-rs lsp`; serves until the client sends `exit`.
+pub fn run(args: &[String]) -> Result<(), String> {
+    if !args.is_empty() {
+        return Err("Usage: dllup-rs lsp".into());
+    }
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let mut documents: HashMap<String, String> = HashMap::new();
+
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        let id = message.get("id").cloned();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        match method {
+            "initialize" => {
+                let capabilities = json!({
+                    "capabilities": {
+                        "textDocumentSync": 1,
+                        "documentSymbolProvider": true,
+                        "completionProvider": { "triggerCharacters": ["#", "{", " ", "/"] },
+                    },
+                    "serverInfo": { "name": "dllup-rs", "version": env!("CARGO_PKG_VERSION") },
+                });
+                respond(&mut output, id, capabilities)?;
+            }
+            "shutdown" => respond(&mut output, id, Value::Null)?,
+            "exit" => return Ok(()),
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                // Full document sync: the last change carries the whole text
+                let text = if method == "textDocument/didOpen" {
+                    params["textDocument"]["text"].as_str()
+                } else {
+                    params["contentChanges"]
+                        .as_array()
+                        .and_then(|changes| changes.last())
+                        .and_then(|change| change["text"].as_str())
+                };
+                if let Some(text) = text {
+                    documents.insert(uri.clone(), text.to_string());
+                    publish_diagnostics(&mut output, &uri, text)?;
+                }
+            }
+            "textDocument/didClose" => {
+                documents.remove(&uri);
+                notify(
+                    &mut output,
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                )?;
+            }
+            "textDocument/documentSymbol" => {
+                let symbols = documents
+                    .get(&uri)
+                    .map(|text| document_symbols(text, &parse(text)))
+                    .unwrap_or_default();
+                respond(&mut output, id, Value::Array(symbols))?;
+            }
+            "textDocument/completion" => {
+                let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+                let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+                let items = documents
+                    .get(&uri)
+                    .map(|text| completions(text, &uri_to_path(&uri), line, character))
+                    .unwrap_or_default();
+                respond(&mut output, id, Value::Array(items))?;
+            }
+            _ if id.is_some() => {
+                let error =
+                    json!({ "code": -32601, "message": format!("unsupported method {}", method) });
+                write_message(
+                    &mut output,
+                    &json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+                )?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn parse(text: &str) -> Article {
+    let mut parser = Parser::default();
+    parser.parse(text);
+    parser.article
+}
+
+fn publish_diagnostics(output: &mut impl Write, uri: &str, text: &str) -> Result<(), String> {
+    let path = uri_to_path(uri);
+    let config = crate::resolve_config(&path, None).unwrap_or_default();
+    let findings = lint::run(&path, None, text, &parse(text), &config);
+    let lines: Vec<&str> = text.lines().collect();
+    let diagnostics: Vec<Value> = findings
+        .iter()
+        .map(|finding| lsp_diagnostic(finding, &lines))
+        .collect();
+    notify(
+        output,
+        "textDocument/publishDiagnostics",
+        json!({ "uri": uri, "diagnostics": diagnostics }),
+    )
+}
+
+fn lsp_diagnostic(finding: &Diagnostic, lines: &[&str]) -> Value {
+    let line = finding.line.map_or(0, |line| line - 1);
+    let severity = match finding.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+    json!({
+        "range": line_range(line, lines.get(line).copied().unwrap_or_default()),
+        "severity": severity,
+        "code": finding.code,
+        "source": "dllup",
+        "message": finding.message,
+    })
+}
+
+/// Section headers as nested `DocumentSymbol`s, each spanning its section.
+fn document_symbols(text: &str, article: &Article) -> Vec<Value> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut headers: Vec<(usize, &str, usize)> = Vec::new();
+    let mut next_line = 0;
+    for block in &article.body {
+        let Block::SectionHeader { level, text, .. } = block else {
+            continue;
+        };
+        let found = lines[next_line.min(lines.len())..]
+            .iter()
+            .position(|line| {
+                let trimmed = line.trim();
+                trimmed.len() > *level
+                    && trimmed.chars().take_while(|&c| c == '#').count() == *level
+                    && trimmed[*level..].trim() == text.as_str()
+            })
+            .map(|offset| next_line + offset);
+        if let Some(line) = found {
+            headers.push((*level, text.as_str(), line));
+            next_line = line + 1;
+        }
+    }
+
+    let mut roots = Vec::new();
+    let mut stack: Vec<(usize, Value)> = Vec::new();
+    for (index, &(level, name, line)) in headers.iter().enumerate() {
+        while stack.last().is_some_and(|(open, _)| *open >= level) {
+            let (_, symbol) = stack.pop().unwrap();
+            attach(&mut stack, &mut roots, symbol);
+        }
+        // A section runs until the next header of the same or a higher level
+        let end = headers[index + 1..]
+            .iter()
+            .find(|(other, _, _)| *other <= level)
+            .map_or(lines.len(), |(_, _, next)| *next)
+            .max(line + 1)
+            - 1;
+        let end_character = utf16_len(lines.get(end).copied().unwrap_or_default());
+        let symbol = json!({
+            "name": name,
+            "kind": 15,
+            "range": {
+                "start": { "line": line, "character": 0 },
+                "end": { "line": end, "character": end_character },
+            },
+            "selectionRange": line_range(line, lines[line]),
+            "children": [],
+        });
+        stack.push((level, symbol));
+    }
+    while let Some((_, symbol)) = stack.pop() {
+        attach(&mut stack, &mut roots, symbol);
+    }
+    roots
+}
+
+fn attach(stack: &mut [(usize, Value)], roots: &mut Vec<Value>, symbol: Value) {
+    match stack.last_mut() {
+        Some((_, parent)) => {
+            if let Some(children) = parent["children"].as_array_mut() {
+                children.push(symbol);
+            }
+        }
+        None => roots.push(symbol),
+    }
+}
+
+fn completions(text: &str, path: &Path, line: usize, character: usize) -> Vec<Value> {
+    let Some(line_text) = text.lines().nth(line) else {
+        return Vec::new();
+    };
+    let prefix = utf16_prefix(line_text, character);
+
+    let label_start = [
+        prefix.rfind("(#").map(|i| i + 2),
+        prefix.rfind("\\eqref{").map(|i| i + 7),
+    ]
+    .into_iter()
+    .flatten()
+    .max();
+    if let Some(start) = label_start {
+        let partial = &prefix[start..];
+        if partial
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        {
+            let article = parse(text);
+            let mut labels: Vec<String> = lint::reference_targets(&article)
+                .into_iter()
+                .filter(|label| label.starts_with(partial))
+                .collect();
+            labels.sort_unstable();
+            return labels
+                .into_iter()
+                .map(|label| json!({ "label": label, "kind": 18 }))
+                .collect();
+        }
+    }
+
+    if let Some(partial) = prefix.trim_start().strip_prefix("pic ") {
+        if !partial.contains(' ') {
+            let base = path.parent().unwrap_or_else(|| Path::new("."));
+            return image_path_completions(base, partial);
+        }
+    }
+    Vec::new()
+}
+
+/// Directories and image files next to the document matching `partial`.
+fn image_path_completions(base: &Path, partial: &str) -> Vec<Value> {
+    let (dir_part, _) = partial.rsplit_once('/').unwrap_or(("", partial));
+    let dir = if dir_part.is_empty() {
+        base.to_path_buf()
+    } else {
+        base.join(dir_part)
+    };
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+    let mut items: Vec<(String, bool)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if name.starts_with('.') {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            let is_image = Path::new(&name)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()));
+            (is_dir || is_image).then_some((name, is_dir))
+        })
+        .collect();
+    items.sort();
+    items
+        .into_iter()
+        .map(|(name, is_dir)| {
+            let path = if dir_part.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", dir_part, name)
+            };
+            // Folder or File completion kinds
+            let (kind, insert) = if is_dir {
+                (19, format!("{}/", path))
+            } else {
+                (17, path.clone())
+            };
+            json!({ "label": name, "kind": kind, "filterText": path, "insertText": insert })
+        })
+        .collect()
+}
+
+fn line_range(line: usize, text: &str) -> Value {
+    json!({
+        "start": { "line": line, "character": 0 },
+        "end": { "line": line, "character": utf16_len(text) },
+    })
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+/// The part of `line` before the UTF-16 column `character`.
+fn utf16_prefix(line: &str, character: usize) -> &str {
+    let mut units = 0;
+    for (index, ch) in line.char_indices() {
+        if units >= character {
+            return &line[..index];
+        }
+        units += ch.len_utf16();
+    }
+    line
+}
+
+fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Reads one `Content-Length`-framed JSON-RPC message; `None` at end of input.
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>, String> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        let read = input.read_line(&mut header).map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or("LSP message without Content-Length")?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|e| e.to_string())?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| format!("invalid LSP message: {}", e))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> Result<(), String> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| output.flush())
+        .map_err(|e| e.to_string())
+}
+
+fn respond(output: &mut impl Write, id: Option<Value>, result: Value) -> Result<(), String> {
+    write_message(
+        output,
+        &json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+    )
+}
+
+fn notify(output: &mut impl Write, method: &str, params: Value) -> Result<(), String> {
+    write_message(
+        output,
+        &json!({ "jsonrpc": "2.0", "method": method, "params": params }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOC: &str = "Notes\n\n===\n\n# Intro\n\nSee (#kite).\n\n## Details\n\ntext\n\n# Method\n\n[#kite] Kite, 2020.\n";
+
+    #[test]
+    fn section_headers_nest_as_symbols() {
+        let symbols = document_symbols(DOC, &parse(DOC));
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[0]["name"], "Intro");
+        assert_eq!(symbols[0]["range"]["start"]["line"], 4);
+        assert_eq!(symbols[0]["range"]["end"]["line"], 11);
+        assert_eq!(symbols[0]["children"][0]["name"], "Details");
+        assert_eq!(symbols[1]["name"], "Method");
+    }
+
+    #[test]
+    fn completes_reference_labels() {
+        let doc = format!("{}\nAlso (#k", DOC);
+        let items = completions(&doc, Path::new("notes.dllu"), 16, 8);
+        let labels: Vec<&str> = items.iter().filter_map(|i| i["label"].as_str()).collect();
+        assert_eq!(labels, vec!["kite"]);
+    }
+
+    #[test]
+    fn completes_image_paths_after_pic() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("shots")).unwrap();
+        fs::write(dir.path().join("shots/kite.jpg"), "").unwrap();
+        fs::write(dir.path().join("shots/notes.txt"), "").unwrap();
+        let doc = "T\n\n===\n\npic shots/k";
+        let items = completions(doc, &dir.path().join("post.dllu"), 4, 11);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["insertText"], "shots/kite.jpg");
+    }
+
+    #[test]
+    fn reads_framed_messages() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+        let framed = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let mut input = io::Cursor::new(framed.into_bytes());
+        let message = read_message(&mut input).unwrap().unwrap();
+        assert_eq!(message["method"], "shutdown");
+        assert!(read_message(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn decodes_file_uris() {
+        assert_eq!(
+            uri_to_path("file:///home/me/My%20Site/index.dllu"),
+            PathBuf::from("/home/me/My Site/index.dllu")
+        );
+    }
+}
@@ -1,25 +1,54 @@
 #[macro_use]
 extern crate lazy_static;
 
-mod ast;
+mod assets;
+mod build_hooks;
+mod cache_headers;
+mod collate;
+mod compat;
 mod config;
+mod data;
+mod deps;
+mod diagnostics;
+mod disk_cache;
+mod downloads;
+mod emoji;
+mod feed_math;
+mod fmt;
+mod fragment_cache;
+mod graphviz;
 mod html_renderer;
 mod image_processor;
+mod link_check;
+mod lint;
+mod lsp;
+mod math_cache;
 mod math_engine;
-mod parser;
-
-use crate::ast::{Block, InlineElement};
+mod mermaid;
+mod newsletter;
+mod pandoc;
+mod plugins;
+mod prose;
+mod remote_files;
+mod social_card;
+mod walk;
+mod xref;
+
+use crate::ast::Block;
+use crate::diagnostics::{Diagnostic, Severity};
+use dllup_rs::{ast, excerpt, parser};
 use git2::{DiffOptions, Repository, Status};
 use parser::Parser;
 use rayon::prelude::*;
 use serde::Serialize;
 use serde_xml_rs::to_string;
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Instant;
 use time::{
     format_description::well_known::{Rfc2822, Rfc3339},
@@ -31,6 +60,10 @@ struct ProcessedPage {
     source_path: PathBuf,
     root_url: Option<String>,
     is_private: bool,
+    /// Figure images, listed under the page in the sitemap.
+    images: Vec<html_renderer::PageImage>,
+    /// Recorded for `build.incremental` directory builds.
+    deps: Option<deps::PageDeps>,
 }
 
 #[derive(Clone)]
@@ -42,6 +75,51 @@ struct BlogPostIndexEntry {
     permalink: String,
     summary: Option<String>,
     content_html: String,
+    /// Figure attributions, emitted as `media:credit` in feeds.
+    credits: Vec<String>,
+    /// First figure of the post, shown on the index with `html.index_thumbnails`.
+    thumbnail: Option<IndexThumbnail>,
+    /// First figure image, which leads the post's feed item in a photo stream.
+    image: Option<html_renderer::PageImage>,
+    /// Listed before the other posts on the index (the feed stays in date order).
+    pinned: bool,
+    featured: bool,
+    /// Prose words of the post, shown as a reading time with `html.index_reading_time`.
+    word_count: usize,
+    /// The `x-tags` header field, listing the post on its `html.tag_pages`.
+    tags: Vec<String>,
+}
+
+#[derive(Clone)]
+struct IndexThumbnail {
+    url: String,
+    width: u32,
+    height: u32,
+    alt: String,
+}
+
+/// Smallest generated width that still looks sharp as an index thumbnail.
+const INDEX_THUMBNAIL_WIDTH: u32 = 320;
+
+/// A directory of posts that gets a generated index and feed: the blog
+/// (`html.blog_dir` with `[feed]`) or one of the configured `[[collections]]`.
+struct CollectionSpec {
+    dir: String,
+    sort: config::CollectionSort,
+    mode: config::BlogMode,
+    template_path: Option<String>,
+    feed: config::FeedConfig,
+}
+
+/// A post of a photo stream, linked from the posts next to it.
+#[derive(Clone)]
+struct StreamPost {
+    dir: PathBuf,
+    title: String,
+    date_key: Option<(i32, u32, u32)>,
+    href: String,
+    /// The post's `x-tags`, which related posts are found by.
+    tags: Vec<String>,
 }
 
 struct BlogIndex {
@@ -54,13 +132,23 @@ struct BlogIndex {
 lazy_static! {
     static ref BLOG_POST_CACHE: Mutex<HashMap<PathBuf, BlogPostIndexEntry>> =
         Mutex::new(HashMap::new());
+    /// Equations that fell back to escaped TeX, reported once the build ends.
+    static ref MATH_FAILURES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    /// Posts of each photo stream directory in index order, read once per build.
+    static ref COLLECTION_POSTS: Mutex<HashMap<PathBuf, Vec<StreamPost>>> =
+        Mutex::new(HashMap::new());
 }
 
+/// `--base-url`, which replaces `root_url` in every config the build loads.
+static BASE_URL: OnceLock<String> = OnceLock::new();
+
 #[derive(Serialize)]
 #[serde(rename = "urlset")]
 struct SitemapUrlSet {
     #[serde(rename = "@xmlns")]
     xmlns: &'static str,
+    #[serde(rename = "@xmlns:image")]
+    image_namespace: &'static str,
     #[serde(rename = "url")]
     urls: Vec<SitemapUrl>,
 }
@@ -69,6 +157,18 @@ struct SitemapUrlSet {
 struct SitemapUrl {
     loc: String,
     lastmod: String,
+    #[serde(rename = "image:image", skip_serializing_if = "Vec::is_empty")]
+    images: Vec<SitemapImage>,
+}
+
+#[derive(Serialize)]
+struct SitemapImage {
+    #[serde(rename = "image:loc")]
+    loc: String,
+    #[serde(rename = "image:title", skip_serializing_if = "String::is_empty")]
+    title: String,
+    #[serde(rename = "image:caption", skip_serializing_if = "String::is_empty")]
+    caption: String,
 }
 
 #[derive(Serialize)]
@@ -78,6 +178,8 @@ struct RssFeed {
     version: &'static str,
     #[serde(rename = "@xmlns:content")]
     content_namespace: &'static str,
+    #[serde(rename = "@xmlns:media")]
+    media_namespace: &'static str,
     channel: RssChannel,
 }
 
@@ -102,6 +204,18 @@ struct RssItem {
     description: String,
     #[serde(rename = "content:encoded", skip_serializing_if = "Option::is_none")]
     content_encoded: Option<String>,
+    #[serde(rename = "media:content", skip_serializing_if = "Option::is_none")]
+    media_content: Option<RssMediaContent>,
+    #[serde(rename = "media:credit", skip_serializing_if = "Vec::is_empty")]
+    media_credits: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct RssMediaContent {
+    #[serde(rename = "@url")]
+    url: String,
+    #[serde(rename = "@medium")]
+    medium: &'static str,
 }
 
 #[derive(Serialize)]
@@ -112,10 +226,89 @@ struct RssGuid {
     value: String,
 }
 
+type Subcommand = fn(&[String]) -> Result<(), String>;
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let subcommand: Option<Subcommand> = match args.get(1).map(String::as_str) {
+        Some("newsletter") => Some(newsletter::run),
+        Some("pandoc") => Some(pandoc::run),
+        Some("lsp") => Some(lsp::run),
+        Some("fmt") => Some(fmt::run),
+        Some("excerpt") => Some(run_excerpt),
+        Some("warm-math") => Some(math_cache::run),
+        Some("compat") => Some(compat::run),
+        Some("lint") => Some(run_lint),
+        _ => None,
+    };
+    if let Some(run) = subcommand {
+        if let Err(e) = run(&args[2..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let jobs = match take_option(&mut args, "--jobs") {
+        Ok(None) => None,
+        Ok(Some(value)) => match value.parse::<usize>() {
+            Ok(jobs) => Some(jobs),
+            Err(_) => {
+                eprintln!("Invalid --jobs value: {}", value);
+                std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    match take_option(&mut args, "--message-format") {
+        Ok(None) => {}
+        Ok(Some(format)) if format == "human" => {}
+        Ok(Some(format)) if format == "json" => diagnostics::use_json_output(true),
+        Ok(Some(format)) => {
+            eprintln!(
+                "Unknown --message-format {}; expected human or json",
+                format
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    downloads::set_refresh(take_flag(&mut args, "--refresh-remote"));
+    let strict = take_flag(&mut args, "--strict");
+    match take_option(&mut args, "--base-url") {
+        Ok(None) => {}
+        Ok(Some(base_url)) => {
+            let trimmed = base_url.trim();
+            if !trimmed.is_empty() {
+                if let Err(e) = config::check_site_url("--base-url", trimmed) {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            let _ = BASE_URL.set(base_url);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
     if args.len() < 2 || args.len() > 3 {
-        eprintln!("Usage: dllup-rs <input.dllu|directory> [config.toml]");
+        eprintln!(
+            "Usage: dllup-rs [--jobs N] [--message-format human|json] [--refresh-remote] [--base-url URL] [--strict] <input.dllu|directory> [config.toml]"
+        );
+        eprintln!("       dllup-rs newsletter <post.dllu> [config.toml]");
+        eprintln!("       dllup-rs pandoc export <input.dllu>");
+        eprintln!("       dllup-rs pandoc import <input.json> [config.toml]");
+        eprintln!("       dllup-rs lsp");
+        eprintln!("       dllup-rs fmt [--check] <file or dir>...");
+        eprintln!("       dllup-rs warm-math <directory> [config.toml]");
+        eprintln!("       dllup-rs compat <directory> [config.toml]");
+        eprintln!("       dllup-rs lint [--prose] <input.dllu|directory> [config.toml]");
         std::process::exit(1);
     }
 
@@ -132,8 +325,43 @@ fn main() {
         None
     };
 
+    let site_root = if input_path.is_dir() {
+        input_path
+    } else {
+        input_path.parent().unwrap_or_else(|| Path::new("."))
+    };
+    let config = match site_config(input_path, explicit_config.as_ref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let (build_config, images_config) = (&config.build, &config.images);
+    let (jobs, image_jobs) = thread_counts(jobs, build_config);
+    if let Some(jobs) = jobs {
+        if let Err(e) = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+        {
+            diagnostics::warn(format!("Failed to set up {} render threads: {}", jobs, e));
+        }
+    }
+    image_processor::set_resize_threads(image_jobs, images_config.max_concurrent_resizes);
+    image_processor::set_resize_budget(images_config.resize_budget_mb);
+    downloads::set_fetch_jobs(build_config.fetch_jobs.unwrap_or(0));
+
+    if let Err(e) = build_hooks::run("pre_command", &build_config.pre_commands, site_root) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
     if input_path.is_dir() {
-        let files = match collect_dllu_files(input_path) {
+        if let Err(e) = assets::publish(&config) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        let files = match walk::collect_dllu_files(input_path, build_config) {
             Ok(files) => files,
             Err(e) => {
                 eprintln!("{}", e);
@@ -152,11 +380,23 @@ fn main() {
             files_by_depth.entry(depth).or_default().push(file);
         }
 
+        let graph_path = input_path.join(deps::GRAPH_FILE);
+        let previous_graph = build_config
+            .incremental
+            .then(|| deps::DepGraph::load(&graph_path));
+
         let mut processed_pages = Vec::new();
         for (_depth, group) in files_by_depth.into_iter().rev() {
             let result: Result<Vec<_>, String> = group
                 .into_par_iter()
-                .map(|file| process_file(&file, Some(input_path), explicit_config.as_ref()))
+                .map(|file| {
+                    process_file(
+                        &file,
+                        Some(input_path),
+                        explicit_config.as_ref(),
+                        previous_graph.as_ref(),
+                    )
+                })
                 .collect();
             match result {
                 Ok(mut pages) => processed_pages.append(&mut pages),
@@ -171,48 +411,253 @@ fn main() {
             eprintln!("{}", e);
             std::process::exit(1);
         }
-    } else if let Err(e) = process_file(input_path, input_path.parent(), explicit_config.as_ref()) {
+
+        let outputs: Vec<PathBuf> = processed_pages
+            .iter()
+            .map(|page| page.output_path.clone())
+            .collect();
+        if let Err(e) = cache_headers::write(input_path, &config, &outputs) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+
+        let built: Vec<(&Path, &Path)> = processed_pages
+            .iter()
+            .map(|page| (page.source_path.as_path(), page.output_path.as_path()))
+            .collect();
+        if let Err(e) = link_check::check_site(input_path, &built, &config) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+
+        if build_config.incremental {
+            let mut graph = deps::DepGraph::default();
+            for page in processed_pages {
+                if let Some(deps) = page.deps {
+                    graph.insert(page.source_path, deps);
+                }
+            }
+            if let Err(e) = graph.save(&graph_path) {
+                diagnostics::warn(e);
+            }
+        }
+    } else if let Err(e) = process_file(
+        input_path,
+        input_path.parent(),
+        explicit_config.as_ref(),
+        None,
+    ) {
         eprintln!("{}", e);
         std::process::exit(1);
     }
 
+    report_math_failures();
+    downloads::report_failures();
     image_processor::wait_for_pending_resizes();
+    image_processor::report_deferred_resizes();
+
+    if let Err(e) = check_strict(strict || config.strict) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = build_hooks::run("post_command", &build_config.post_commands, site_root) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// `dllup-rs excerpt <file>`: prints the file's [`excerpt::Excerpt`] as JSON.
+fn run_excerpt(args: &[String]) -> Result<(), String> {
+    let [path] = args else {
+        return Err("Usage: dllup-rs excerpt <file.dllu>".into());
+    };
+    let source = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let json = serde_json::to_string_pretty(&excerpt::excerpt(&source))
+        .map_err(|e| format!("Failed to serialize excerpt: {}", e))?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// `dllup-rs lint [--prose] <input.dllu|directory> [config.toml]`: runs the
+/// `[lint]` checks without building, and with `--prose` the spelling check.
+fn run_lint(args: &[String]) -> Result<(), String> {
+    let mut args = args.to_vec();
+    let check_prose = take_flag(&mut args, "--prose");
+    if args.is_empty() || args.len() > 2 {
+        return Err("Usage: dllup-rs lint [--prose] <input.dllu|directory> [config.toml]".into());
+    }
+    let input_path = Path::new(&args[0]);
+    let explicit_config = match args.get(1) {
+        Some(path) => Some(config::Config::load(Path::new(path))?),
+        None => None,
+    };
+    let (files, site_root) = if input_path.is_dir() {
+        let config = site_config(input_path, explicit_config.as_ref())?;
+        let files = walk::collect_dllu_files(input_path, &config.build)?;
+        (files, Some(input_path))
+    } else {
+        (vec![input_path.to_path_buf()], input_path.parent())
+    };
+    let mut errors = 0;
+    for file in &files {
+        let config = resolve_config(file, explicit_config.as_ref())?;
+        let source = fs::read_to_string(file)
+            .map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+        let mut parser = Parser::with_includes(file);
+        parser.parse(&source);
+        plugins::apply(&config.plugins, &mut parser.article, file)?;
+        let mut findings = lint::run(file, site_root, &source, &parser.article, &config);
+        if check_prose {
+            findings.extend(prose::check(file, &source, &parser.article, &config.lint)?);
+        }
+        for diagnostic in &findings {
+            diagnostics::emit(diagnostic);
+        }
+        errors += findings
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .count();
+    }
+    if errors > 0 {
+        return Err(format!("{} lint error(s)", errors));
+    }
+    Ok(())
+}
+
+/// Removes the flag `name` from `args`, returning whether it was there.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != before
+}
+
+/// Removes `name value` or `name=value` from `args` and returns the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let prefix = format!("{}=", name);
+    let Some(index) = args
+        .iter()
+        .position(|arg| arg == name || arg.starts_with(&prefix))
+    else {
+        return Ok(None);
+    };
+    let arg = args.remove(index);
+    if let Some(value) = arg.strip_prefix(&prefix) {
+        return Ok(Some(value.to_string()));
+    }
+    if index < args.len() {
+        Ok(Some(args.remove(index)))
+    } else {
+        Err(format!("{} needs a value", name))
+    }
+}
+
+/// Threads rendering pages (`None` keeps rayon's one per CPU) and resizing
+/// images (0 for one per CPU): `--jobs` wins over `build.jobs`, and
+/// `build.image_jobs` defaults to the page threads.
+fn thread_counts(cli_jobs: Option<usize>, build: &config::BuildConfig) -> (Option<usize>, usize) {
+    let jobs = cli_jobs.or(build.jobs);
+    (jobs, build.image_jobs.or(jobs).unwrap_or(0))
+}
+
+/// Fails a `--strict` build once any warning has been printed.
+fn check_strict(strict: bool) -> Result<(), String> {
+    let warnings = diagnostics::warning_count();
+    if strict && warnings > 0 {
+        return Err(format!(
+            "{} warning(s); failing the build in strict mode",
+            warnings
+        ));
+    }
+    Ok(())
+}
+
+fn report_math_failures() {
+    let Ok(failures) = MATH_FAILURES.lock() else {
+        return;
+    };
+    if failures.is_empty() {
+        return;
+    }
+    eprintln!(
+        "{} equation(s) could not be rendered and were emitted as TeX:",
+        failures.len()
+    );
+    for failure in failures.iter() {
+        diagnostics::warn(format!("  {}", failure));
+    }
 }
 
+/// Renders one page. With the previous build's dependency graph (incremental
+/// builds), a page whose dependencies are unchanged is left as is.
 fn process_file(
     input_path: &Path,
     site_root: Option<&Path>,
     explicit_config: Option<&config::Config>,
+    previous_graph: Option<&deps::DepGraph>,
 ) -> Result<ProcessedPage, String> {
-    let config = if let Some(cfg) = explicit_config {
-        cfg.clone()
-    } else {
-        let config_path = config::default_config_path(input_path);
-        if config_path.exists() {
-            config::Config::load(&config_path)?
-        } else {
-            config::Config::default()
-        }
-    };
+    let config = resolve_config(input_path, explicit_config)?;
 
     let is_private = page_is_private(input_path);
+    let out_path = input_path.with_extension("html");
+
+    if let Some(deps) = previous_graph.and_then(|graph| graph.unchanged(input_path, &config)) {
+        if out_path.exists() {
+            return Ok(ProcessedPage {
+                output_path: out_path,
+                source_path: input_path.to_path_buf(),
+                root_url: config.root_url.clone(),
+                is_private,
+                images: deps.images().to_vec(),
+                deps: Some(deps.clone()),
+            });
+        }
+    }
 
     let input = fs::read_to_string(input_path)
         .map_err(|e| format!("Failed to read {}: {}", input_path.display(), e))?;
 
     let t0 = Instant::now();
-    let mut parser = Parser::default();
+    let mut parser = Parser::with_includes(input_path);
     parser.parse(&input);
+    if let Some(e) = parser.include_errors.first() {
+        return Err(format!("{}: {}", input_path.display(), e));
+    }
+    plugins::apply(&config.plugins, &mut parser.article, input_path)?;
     let t_parse = t0.elapsed();
+    lint::check_article(input_path, site_root, &input, &parser.article, &config)?;
 
     let t1 = Instant::now();
     let asset_root = input_path
         .parent()
         .map(Path::to_path_buf)
         .unwrap_or_else(|| PathBuf::from("."));
-    let mut renderer = html_renderer::HtmlRenderer::with_asset_root(&config, asset_root);
-    let body = renderer.render(&parser.article);
+    let specs = collection_specs(&config);
+    let stream_nav = specs
+        .iter()
+        .find_map(|spec| photo_stream_nav(input_path, site_root, &config, spec));
+    let related = specs
+        .iter()
+        .find_map(|spec| related_posts_nav(input_path, site_root, &config, spec, &parser.article));
+    let mut renderer = html_renderer::HtmlRenderer::with_asset_root(&config, asset_root.clone());
+    renderer.set_page_url(page_permalink(input_path, site_root, &config));
+    renderer.set_exif_open(stream_nav.is_some());
+    let site_data = data::load_site_data(input_path, site_root, &config)?;
+    renderer.set_site_data(Arc::clone(&site_data));
+    let cross_references = xref::cross_page_references(&parser.article);
+    let site_references = match site_root {
+        Some(site_root) if !cross_references.is_empty() => xref::for_site(site_root, &config),
+        _ => Arc::default(),
+    };
+    renderer.set_site_references(Arc::clone(&site_references));
+    let mut body = renderer.render(&parser.article);
     let t_render = t1.elapsed();
+    diagnostics::emit_image_errors(input_path, &input, renderer.image_errors());
+    if let Ok(mut failures) = MATH_FAILURES.lock() {
+        for failure in renderer.math_failures() {
+            failures.push(format!("{}: {}", input_path.display(), failure));
+        }
+    }
     let title = parser
         .article
         .header
@@ -223,23 +668,163 @@ fn process_file(
     let toc_html = renderer.table_of_contents_html();
     let toc_str = toc_html.as_deref().unwrap_or("");
     let metas = renderer.meta_tags(title);
-    let blog_index = build_blog_index(input_path, site_root, &config)?;
+    let mut blog_index = None;
+    for spec in &specs {
+        if let Some(index) = build_blog_index(input_path, site_root, &config, spec)? {
+            blog_index = Some((spec, index));
+            break;
+        }
+    }
     let index_html_str = blog_index
         .as_ref()
-        .map(|idx| idx.html.as_str())
+        .map(|(_, idx)| idx.html.as_str())
         .unwrap_or("");
-    register_blog_post_if_applicable(input_path, site_root, &config, &parser.article, &body);
-    let html =
-        html_renderer::wrap_html_document(&config, title, &body, toc_str, &metas, index_html_str)
-            .map_err(|e| e.to_string())?;
+    for spec in &specs {
+        if register_blog_post_if_applicable(
+            input_path,
+            site_root,
+            &config,
+            spec,
+            &parser.article,
+            &body,
+            renderer.page_images(),
+        ) {
+            break;
+        }
+    }
+    // Added after registering, so the feed's copy of the post has no links
+    if let Some(nav) = &stream_nav {
+        body.push_str(nav);
+    }
+    // A template named in the header wins over the collection index's, which
+    // wins over the one for the page's directory
+    let header_template = parser
+        .article
+        .header
+        .as_ref()
+        .and_then(|h| h.template.as_deref());
+    let index_template = blog_index
+        .as_ref()
+        .and_then(|(spec, _)| spec.template_path.as_deref());
+    let template_path = match (header_template, index_template) {
+        (None, Some(template_path)) => template_path,
+        _ => {
+            let page_dir = input_path.parent().unwrap_or_else(|| Path::new(""));
+            let page_dir = site_root
+                .and_then(|root| page_dir.strip_prefix(root).ok())
+                .unwrap_or(page_dir);
+            config
+                .page_template(header_template, page_dir)
+                .map_err(|e| format!("{}: {}", input_path.display(), e))?
+        }
+    };
+    let template_config = if template_path == config.html.template_path {
+        Cow::Borrowed(&config)
+    } else {
+        let mut cfg = config.clone();
+        cfg.html.template_path = template_path.to_string();
+        Cow::Owned(cfg)
+    };
+    let page_vars = parser
+        .article
+        .header
+        .as_ref()
+        .map(|h| h.vars.clone())
+        .unwrap_or_default();
+    let scripts = renderer.page_scripts();
+    let word_count = excerpt::word_count(&parser.article);
+    let posts = blog_index
+        .as_ref()
+        .map(|(_, idx)| index_posts(&idx.entries, &config.html))
+        .unwrap_or_default();
+    let html = html_renderer::wrap_html_document(
+        &template_config,
+        &html_renderer::TemplateContext {
+            title,
+            date: parser
+                .article
+                .header
+                .as_ref()
+                .and_then(|h| h.date.as_deref())
+                .unwrap_or_default(),
+            body: &body,
+            table_of_contents: toc_str,
+            metas: &metas,
+            index: index_html_str,
+            scripts: &scripts,
+            vars: &page_vars,
+            meta: parser
+                .article
+                .header
+                .as_ref()
+                .map(|h| &h.meta)
+                .unwrap_or(&BTreeMap::new()),
+            data: &site_data,
+            featured: parser.article.header.as_ref().is_some_and(|h| h.featured),
+            word_count,
+            reading_minutes: reading_minutes(word_count, &config.html),
+            related: related.as_deref().unwrap_or_default(),
+            posts: &posts,
+            images: renderer.page_images(),
+        },
+    )
+    .map_err(|e| e.to_string())?;
     let t_wrap = t2.elapsed();
 
-    let out_path = input_path.with_extension("html");
+    let images = renderer.page_images().to_vec();
+    let deps = previous_graph.map(|_| {
+        let mut deps = deps::PageDeps::new(&config);
+        deps.add(input_path);
+        for included in &parser.included {
+            deps.add(included);
+        }
+        let template_path = Path::new(&template_config.html.template_path);
+        deps.add(template_path);
+        for partial in html_renderer::template_partials(template_path) {
+            deps.add(&partial);
+        }
+        for asset in assets::fingerprinted_sources() {
+            deps.add(&asset);
+        }
+        deps.add(&data::data_dir(input_path, site_root, &config));
+        deps.add_images(&parser.article, &asset_root);
+        // Pages linking originals in place of deferred resizes are redone next
+        // time, as are photo stream posts and posts listing related posts,
+        // whose neighbours can change
+        if blog_index.is_some()
+            || stream_nav.is_some()
+            || related.is_some()
+            || renderer.resizes_deferred()
+        {
+            deps.always_rebuild();
+        }
+        // Pages referring into other pages follow their numbering
+        for name in &cross_references {
+            match site_references.page(name) {
+                Some(page) => deps.add(&page.source),
+                None => deps.always_rebuild(),
+            }
+        }
+        deps.set_images(images.clone());
+        deps
+    });
+
     fs::write(&out_path, html)
         .map_err(|e| format!("Failed to write {}: {}", out_path.display(), e))?;
+    if config.html.toc_json == config::TocJson::Sidecar {
+        let toc_path = out_path.with_extension("toc.json");
+        let json = renderer
+            .table_of_contents_json()
+            .unwrap_or_else(|| "[]".into());
+        fs::write(&toc_path, json)
+            .map_err(|e| format!("Failed to write {}: {}", toc_path.display(), e))?;
+    }
 
-    if let Some(index_data) = blog_index {
-        generate_rss_feed(site_root, &index_data, &config)?;
+    if let Some((spec, index_data)) = blog_index {
+        generate_rss_feed(site_root, &index_data, &config, &spec.feed, spec.mode)?;
+        if config.html.tag_pages {
+            generate_tag_pages(&index_data, &template_config, &site_data, spec.mode)?;
+        }
     }
 
     let root_url = config.root_url.clone();
@@ -259,9 +844,49 @@ fn process_file(
         source_path: input_path.to_path_buf(),
         root_url,
         is_private,
+        images,
+        deps,
     })
 }
 
+/// Uses the explicit config when given, else `dllup.toml` next to the input, else defaults.
+fn resolve_config(
+    input_path: &Path,
+    explicit_config: Option<&config::Config>,
+) -> Result<config::Config, String> {
+    if let Some(cfg) = explicit_config {
+        return Ok(with_base_url(cfg.clone()));
+    }
+    let config_path = config::default_config_path(input_path);
+    if config_path.exists() {
+        config::Config::load(&config_path).map(with_base_url)
+    } else {
+        Ok(with_base_url(config::Config::default()))
+    }
+}
+
+/// The config with `--base-url` in place of its `root_url`, so a preview
+/// deployment under another path prefix needs no config edits.
+fn with_base_url(mut config: config::Config) -> config::Config {
+    if let Some(base_url) = BASE_URL.get() {
+        config.set_root_url(base_url);
+    }
+    config
+}
+
+/// Config for the whole build: the explicit one, `<dir>/dllup.toml` for a
+/// directory build, or the one that applies to a single input file.
+fn site_config(
+    input_path: &Path,
+    explicit_config: Option<&config::Config>,
+) -> Result<config::Config, String> {
+    let dir_config = input_path.join("dllup.toml");
+    if explicit_config.is_none() && input_path.is_dir() && dir_config.exists() {
+        return config::Config::load(&dir_config).map(with_base_url);
+    }
+    resolve_config(input_path, explicit_config)
+}
+
 fn generate_sitemap(site_root: &Path, pages: &[ProcessedPage]) -> Result<(), String> {
     if pages.is_empty() {
         return Ok(());
@@ -356,16 +981,30 @@ fn generate_sitemap(site_root: &Path, pages: &[ProcessedPage]) -> Result<(), Str
             )
         })?;
 
-        entries.push((loc, lastmod_str));
+        let images = page
+            .images
+            .iter()
+            .map(|image| SitemapImage {
+                loc: sitemap_image_url(&loc, page_root_url, &image.full_size),
+                title: image.alt.clone(),
+                caption: image.caption.clone(),
+            })
+            .collect();
+        entries.push((loc, lastmod_str, images));
     }
 
-    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.sort_by(|a, b| collate::compare(&a.0, &b.0));
 
     let sitemap = SitemapUrlSet {
         xmlns: "http://www.sitemaps.org/schemas/sitemap/0.9",
+        image_namespace: "http://www.google.com/schemas/sitemap-image/1.1",
         urls: entries
             .into_iter()
-            .map(|(loc, lastmod)| SitemapUrl { loc, lastmod })
+            .map(|(loc, lastmod, images)| SitemapUrl {
+                loc,
+                lastmod,
+                images,
+            })
             .collect(),
     };
 
@@ -378,6 +1017,18 @@ fn generate_sitemap(site_root: &Path, pages: &[ProcessedPage]) -> Result<(), Str
     Ok(())
 }
 
+/// An image URL as the page it appears on would resolve it.
+fn sitemap_image_url(page_loc: &str, root_url: Option<&str>, src: &str) -> String {
+    if src.contains("://") || src.starts_with("//") {
+        src.to_string()
+    } else if src.starts_with('/') {
+        build_blog_href(root_url, src)
+    } else {
+        let dir = page_loc.rfind('/').map_or("", |slash| &page_loc[..=slash]);
+        format!("{}{}", dir, src)
+    }
+}
+
 fn determine_lastmod(
     repo: Option<&Repository>,
     repo_workdir: Option<&Path>,
@@ -508,43 +1159,6 @@ fn offsetdatetime_from_git_time(
     Ok(base.to_offset(offset))
 }
 
-fn collect_dllu_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
-    let mut stack = vec![dir.to_path_buf()];
-    let mut files = Vec::new();
-
-    while let Some(path) = stack.pop() {
-        let entries = fs::read_dir(&path)
-            .map_err(|e| format!("Failed to read directory {}: {}", path.display(), e))?;
-        for entry in entries {
-            let entry =
-                entry.map_err(|e| format!("Failed to read entry in {}: {}", path.display(), e))?;
-            let entry_path = entry.path();
-            let file_type = entry.file_type().map_err(|e| {
-                format!("Failed to read entry type {}: {}", entry_path.display(), e)
-            })?;
-
-            if file_type.is_dir() {
-                stack.push(entry_path);
-            } else if file_type.is_file()
-                && entry_path
-                    .extension()
-                    .and_then(|ext| ext.to_str())
-                    .map(|ext| ext.eq_ignore_ascii_case("dllu"))
-                    .unwrap_or(false)
-            {
-                files.push(entry_path);
-            }
-        }
-    }
-
-    files.sort_by(|a, b| {
-        let depth_a = a.components().count();
-        let depth_b = b.components().count();
-        depth_b.cmp(&depth_a).then_with(|| a.cmp(b))
-    });
-    Ok(files)
-}
-
 fn directory_has_private_marker(dir: &Path) -> bool {
     let marker_path = dir.join("private");
     marker_path.is_file()
@@ -556,17 +1170,37 @@ fn page_is_private(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
+/// The blog (if `html.blog_dir` is set) followed by the configured collections.
+fn collection_specs(config: &config::Config) -> Vec<CollectionSpec> {
+    let mut specs = Vec::new();
+    if let Some(dir) = config.html.blog_dir.as_deref() {
+        specs.push(CollectionSpec {
+            dir: dir.to_string(),
+            sort: config::CollectionSort::Date,
+            mode: config.html.blog_mode,
+            template_path: None,
+            feed: config.feed.clone(),
+        });
+    }
+    for collection in &config.collections {
+        specs.push(CollectionSpec {
+            dir: collection.dir.clone(),
+            sort: collection.sort,
+            mode: collection.mode,
+            template_path: collection.template_path.clone(),
+            feed: collection.feed_config(&config.feed),
+        });
+    }
+    specs
+}
+
 fn build_blog_index(
     input_path: &Path,
     site_root: Option<&Path>,
     config: &config::Config,
+    spec: &CollectionSpec,
 ) -> Result<Option<BlogIndex>, String> {
-    let blog_dir_raw = match config.html.blog_dir.as_deref() {
-        Some(dir) if !dir.trim().is_empty() => dir.trim(),
-        _ => return Ok(None),
-    };
-
-    let blog_dir_clean = blog_dir_raw.trim_matches('/');
+    let blog_dir_clean = spec.dir.trim().trim_matches('/');
     if blog_dir_clean.is_empty() {
         return Ok(None);
     }
@@ -600,7 +1234,7 @@ fn build_blog_index(
         parent_dir.to_path_buf()
     };
 
-    let mut entries: Vec<BlogPostIndexEntry> = {
+    let (mut entries, cached_dirs): (Vec<BlogPostIndexEntry>, HashSet<PathBuf>) = {
         let cache = BLOG_POST_CACHE
             .lock()
             .expect("blog post cache mutex poisoned");
@@ -612,144 +1246,283 @@ fn build_blog_index(
                     .map(|p| p == blog_root.as_path())
                     .unwrap_or(false);
                 if matches_root && !directory_has_private_marker(dir) {
-                    Some(entry.clone())
+                    Some((entry.clone(), dir.clone()))
                 } else {
                     None
                 }
             })
-            .collect()
+            .unzip()
     };
 
-    if entries.is_empty() {
-        let blog_dir_entries = fs::read_dir(parent_dir).map_err(|e| {
+    // Posts missing from the cache (single-file builds, or posts an incremental
+    // build skipped) are read from disk.
+    let blog_dir_entries = fs::read_dir(parent_dir).map_err(|e| {
+        format!(
+            "Failed to read blog directory {}: {}",
+            parent_dir.display(),
+            e
+        )
+    })?;
+
+    // Posts left out of the index, reported together once the directory is read
+    let severity = match config.lint.skipped_posts {
+        config::LintLevel::Error => Severity::Error,
+        _ => Severity::Warning,
+    };
+    let mut skipped = Vec::new();
+    for entry in blog_dir_entries {
+        let entry = entry
+            .map_err(|e| format!("Failed to read entry in {}: {}", parent_dir.display(), e))?;
+        let file_type = entry.file_type().map_err(|e| {
             format!(
-                "Failed to read blog directory {}: {}",
-                parent_dir.display(),
+                "Failed to read entry type {}: {}",
+                entry.path().display(),
                 e
             )
         })?;
+        if !file_type.is_dir() {
+            continue;
+        }
 
-        for entry in blog_dir_entries {
-            let entry = entry
-                .map_err(|e| format!("Failed to read entry in {}: {}", parent_dir.display(), e))?;
-            let file_type = entry.file_type().map_err(|e| {
-                format!(
-                    "Failed to read entry type {}: {}",
-                    entry.path().display(),
-                    e
-                )
-            })?;
-            if !file_type.is_dir() {
+        let post_dir = entry.path();
+        if cached_dirs.contains(&post_dir) || directory_has_private_marker(&post_dir) {
+            continue;
+        }
+        let source = match find_blog_article_source(&post_dir)? {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let contents = match fs::read_to_string(&source) {
+            Ok(c) => c,
+            Err(e) => {
+                skipped.push(Diagnostic::new(
+                    &source,
+                    severity,
+                    "skipped-post",
+                    format!(
+                        "left out of the {} index: could not read it: {}",
+                        blog_dir_clean, e
+                    ),
+                ));
                 continue;
             }
+        };
 
-            let post_dir = entry.path();
-            if directory_has_private_marker(&post_dir) {
+        let mut parser = Parser::with_includes(&source);
+        parser.parse(&contents);
+        if let Err(e) = plugins::apply(&config.plugins, &mut parser.article, &source) {
+            skipped.push(Diagnostic::new(
+                &source,
+                severity,
+                "skipped-post",
+                format!("left out of the {} index: {}", blog_dir_clean, e),
+            ));
+            continue;
+        }
+        let header = match parser.article.header.as_ref() {
+            Some(h) => h,
+            None => {
+                skipped.push(Diagnostic::new(
+                    &source,
+                    severity,
+                    "skipped-post",
+                    format!(
+                        "left out of the {} index: no header; start the post with its title \
+                         and date lines followed by a `===` line",
+                        blog_dir_clean
+                    ),
+                ));
                 continue;
             }
-            let source = match find_blog_article_source(&post_dir)? {
-                Some(path) => path,
-                None => continue,
-            };
-
-            let contents = match fs::read_to_string(&source) {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Failed to read blog post {}: {}", source.display(), e);
-                    continue;
-                }
-            };
+        };
 
-            let mut parser = Parser::default();
-            parser.parse(&contents);
-            let header = match parser.article.header.as_ref() {
-                Some(h) => h,
-                None => {
-                    eprintln!(
-                        "Blog post {} missing header; skipping from index",
-                        source.display()
-                    );
-                    continue;
-                }
-            };
+        let title = header.title.trim();
+        if title.is_empty() {
+            skipped.push(Diagnostic::new(
+                &source,
+                severity,
+                "skipped-post",
+                format!(
+                    "left out of the {} index: the header has no title; put it on the first line",
+                    blog_dir_clean
+                ),
+            ));
+            continue;
+        }
 
-            let title = header.title.trim();
-            if title.is_empty() {
-                eprintln!(
-                    "Blog post {} missing title; skipping from index",
-                    source.display()
+        let header_date = header
+            .date
+            .as_deref()
+            .map(str::trim)
+            .filter(|d| !d.is_empty());
+        let exif_date = match header_date {
+            Some(_) => None,
+            None => exif_post_date(&parser.article.body, &post_dir, config),
+        };
+        let date = match header_date.or(exif_date.as_deref()) {
+            Some(d) => d,
+            None if spec.sort == config::CollectionSort::Title => "",
+            None => {
+                let message = format!(
+                    "left out of the {} index: the header has no date; add a line such as \
+                     `2024-05-01` below the title, or sort the collection by title",
+                    blog_dir_clean
+                );
+                skipped.push(
+                    Diagnostic::new(&source, severity, "skipped-post", message)
+                        .at(&contents, title),
                 );
                 continue;
             }
+        };
 
-            let date = match header.date.as_deref().map(str::trim) {
-                Some(d) if !d.is_empty() => d,
-                _ => {
-                    eprintln!(
-                        "Blog post {} missing date; skipping from index",
-                        source.display()
-                    );
-                    continue;
-                }
-            };
-
-            let slug = match entry.file_name().into_string() {
-                Ok(name) => name,
-                Err(_) => {
-                    eprintln!(
-                        "Blog directory name {:?} not UTF-8; skipping from index",
+        let slug = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => {
+                skipped.push(Diagnostic::new(
+                    &source,
+                    severity,
+                    "skipped-post",
+                    format!(
+                        "left out of the {} index: the directory name {:?} is not UTF-8; rename it",
+                        blog_dir_clean,
                         entry.file_name()
-                    );
-                    continue;
-                }
-            };
+                    ),
+                ));
+                continue;
+            }
+        };
 
-            let summary = first_paragraph_text(&parser.article.body);
-            let asset_root = source
-                .parent()
-                .map(Path::to_path_buf)
-                .unwrap_or_else(|| post_dir.clone());
-            let mut renderer = html_renderer::HtmlRenderer::with_asset_root(config, asset_root);
-            let content_html = renderer.render(&parser.article);
-            let relative_path = build_blog_relative_url(blog_dir_clean, &slug);
-            let permalink = build_blog_href(config.root_url.as_deref(), &relative_path);
-            let display_href = if config.root_url.is_some() {
-                permalink.clone()
-            } else {
-                slug.clone()
-            };
-            entries.push(BlogPostIndexEntry {
-                title: title.to_string(),
-                date_display: date.to_string(),
-                date_key: parse_date_key(date),
-                display_href,
-                permalink,
-                summary,
-                content_html,
-            });
+        let summary = excerpt::summary(&parser.article.body);
+        let asset_root = source
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| post_dir.clone());
+        let relative_path = build_blog_relative_url(blog_dir_clean, &slug);
+        let permalink = build_blog_href(config.root_url.as_deref(), &relative_path);
+        let mut renderer = html_renderer::HtmlRenderer::with_asset_root(config, asset_root);
+        if config.root_url.is_some() {
+            renderer.set_page_url(Some(permalink.clone()));
+        }
+        let content_html = renderer.render(&parser.article);
+        let display_href = if config.root_url.is_some() {
+            permalink.clone()
+        } else {
+            slug.clone()
+        };
+        entries.push(BlogPostIndexEntry {
+            title: title.to_string(),
+            date_display: date.to_string(),
+            date_key: parse_date_key(date),
+            display_href,
+            permalink,
+            summary,
+            content_html,
+            credits: figure_credits(&parser.article.body),
+            thumbnail: first_figure_thumbnail(&parser.article.body, &post_dir, config, spec.mode),
+            image: renderer.page_images().first().cloned(),
+            pinned: header.pinned,
+            featured: header.featured,
+            word_count: excerpt::word_count(&parser.article),
+            tags: excerpt::tags(&parser.article),
+        });
+    }
+
+    if config.lint.skipped_posts != config::LintLevel::Off {
+        for diagnostic in &skipped {
+            diagnostics::emit(diagnostic);
         }
     }
+    if config.lint.skipped_posts == config::LintLevel::Error && !skipped.is_empty() {
+        return Err(format!(
+            "{}: {} post(s) left out of the {} index",
+            blog_root.display(),
+            skipped.len(),
+            blog_dir_clean
+        ));
+    }
 
     if entries.is_empty() {
         return Ok(None);
     }
 
-    entries.sort_by(|a, b| match (a.date_key, b.date_key) {
-        (Some(ad), Some(bd)) => bd.cmp(&ad),
-        (Some(_), None) => std::cmp::Ordering::Less,
-        (None, Some(_)) => std::cmp::Ordering::Greater,
-        (None, None) => a.title.cmp(&b.title),
+    entries.sort_by(|a, b| {
+        compare_posts(
+            spec.sort,
+            (a.date_key, a.title.as_str()),
+            (b.date_key, b.title.as_str()),
+        )
     });
 
-    let mut out = String::from("<nav id=\"blogposts\">");
-    for entry in &entries {
-        out.push_str("<a href=\"");
-        out.push_str(&escape_html_attr_simple(&entry.display_href));
-        out.push_str("\"><span class=\"blogdate\">");
-        out.push_str(&escape_html_text(&entry.date_display));
-        out.push_str("</span><span class=\"blogtitle\">");
-        out.push_str(&escape_html_text(&entry.title));
-        out.push_str("</span></a>");
+    let microformats = config.html.microformats;
+    let group_by_year =
+        config.html.index_group_by_year && spec.sort == config::CollectionSort::Date;
+    let mut classes = Vec::new();
+    if microformats {
+        classes.push("h-feed");
+    }
+    if group_by_year {
+        classes.push("grouped");
+    }
+    if spec.mode == config::BlogMode::Photos {
+        classes.push("photos");
+    }
+    let mut out = if classes.is_empty() {
+        String::from("<nav id=\"blogposts\">")
+    } else {
+        format!("<nav id=\"blogposts\" class=\"{}\">", classes.join(" "))
+    };
+    // Pinned posts go first, still in the collection's order among themselves
+    let (pinned, unpinned): (Vec<&BlogPostIndexEntry>, Vec<&BlogPostIndexEntry>) =
+        entries.iter().partition(|entry| entry.pinned);
+    if group_by_year {
+        if !pinned.is_empty() {
+            out.push_str("<section class=\"blogpinned\"><div class=\"blogyearposts\">");
+            for entry in &pinned {
+                push_index_entry(&mut out, entry, &config.html);
+            }
+            out.push_str("</div></section>");
+        }
+        // Entries are newest first, so consecutive runs share a year
+        let mut groups: Vec<(Option<i32>, Vec<&BlogPostIndexEntry>)> = Vec::new();
+        for entry in unpinned {
+            let year = entry.date_key.map(|(year, _, _)| year);
+            match groups.last_mut() {
+                Some((group_year, group)) if *group_year == year => group.push(entry),
+                _ => groups.push((year, vec![entry])),
+            }
+        }
+        let open_years = config.html.index_open_years.unwrap_or(usize::MAX);
+        for (index, (year, group)) in groups.iter().enumerate() {
+            let label = year.map_or_else(|| "Undated".to_string(), |year| year.to_string());
+            let collapsed = index >= open_years;
+            if collapsed {
+                out.push_str(&format!(
+                    "<details class=\"blogyear\"><summary>{}</summary>",
+                    label
+                ));
+            } else {
+                let id = year.map_or_else(|| "undated".to_string(), |year| format!("y{}", year));
+                out.push_str(&format!(
+                    "<section class=\"blogyear\"><h2 id=\"{}\">{}</h2>",
+                    id, label
+                ));
+            }
+            out.push_str("<div class=\"blogyearposts\">");
+            for entry in group {
+                push_index_entry(&mut out, entry, &config.html);
+            }
+            out.push_str(if collapsed {
+                "</div></details>"
+            } else {
+                "</div></section>"
+            });
+        }
+    } else {
+        for entry in pinned.into_iter().chain(unpinned) {
+            push_index_entry(&mut out, entry, &config.html);
+        }
     }
     out.push_str("</nav>");
 
@@ -760,6 +1533,129 @@ fn build_blog_index(
         blog_dir: blog_path,
     }))
 }
+
+/// Index order of two posts given as their date key and title.
+fn compare_posts(
+    sort: config::CollectionSort,
+    a: (Option<(i32, u32, u32)>, &str),
+    b: (Option<(i32, u32, u32)>, &str),
+) -> std::cmp::Ordering {
+    match sort {
+        config::CollectionSort::Date => match (a.0, b.0) {
+            (Some(ad), Some(bd)) => bd.cmp(&ad),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => collate::compare(a.1, b.1),
+        },
+        config::CollectionSort::Title => collate::compare(a.1, b.1),
+    }
+}
+
+/// The posts of a collection index as `{{#each posts}}` items, pinned posts
+/// first as on the index.
+fn index_posts(
+    entries: &[BlogPostIndexEntry],
+    html: &config::HtmlConfig,
+) -> Vec<serde_json::Value> {
+    let (pinned, unpinned): (Vec<&BlogPostIndexEntry>, Vec<&BlogPostIndexEntry>) =
+        entries.iter().partition(|entry| entry.pinned);
+    pinned
+        .into_iter()
+        .chain(unpinned)
+        .map(|entry| {
+            serde_json::json!({
+                "title": entry.title,
+                "href": entry.display_href,
+                "permalink": entry.permalink,
+                "date": entry.date_display,
+                "datetime": entry.date_key.map(|(year, month, day)| {
+                    format!("{:04}-{:02}-{:02}", year, month, day)
+                }),
+                "summary": entry.summary,
+                "thumbnail": entry.thumbnail.as_ref().map(|thumbnail| &thumbnail.url),
+                "wordcount": entry.word_count,
+                "readingtime": reading_minutes(entry.word_count, html),
+                "pinned": entry.pinned,
+                "featured": entry.featured,
+            })
+        })
+        .collect()
+}
+
+fn push_index_entry(out: &mut String, entry: &BlogPostIndexEntry, html: &config::HtmlConfig) {
+    let microformats = html.microformats;
+    let mut classes = Vec::new();
+    if microformats {
+        classes.push("h-entry");
+    }
+    if entry.pinned {
+        classes.push("pinned");
+    }
+    if entry.featured {
+        classes.push("featured");
+    }
+    let class_attr = if classes.is_empty() {
+        String::new()
+    } else {
+        format!(" class=\"{}\"", classes.join(" "))
+    };
+    if microformats {
+        // An <a class="h-entry"> implies u-url from its href
+        out.push_str(&format!("<a{} href=\"", class_attr));
+        out.push_str(&escape_html_attr_simple(&entry.display_href));
+        out.push_str("\">");
+        push_index_thumbnail(out, entry.thumbnail.as_ref(), " u-photo");
+        out.push_str("<span class=\"blogdate\"><time class=\"dt-published\"");
+        if let Some((year, month, day)) = entry.date_key {
+            out.push_str(&format!(
+                " datetime=\"{:04}-{:02}-{:02}\"",
+                year, month, day
+            ));
+        }
+        out.push('>');
+        out.push_str(&escape_html_text(&entry.date_display));
+        out.push_str("</time></span><span class=\"blogtitle p-name\">");
+    } else {
+        out.push_str(&format!("<a{} href=\"", class_attr));
+        out.push_str(&escape_html_attr_simple(&entry.display_href));
+        out.push_str("\">");
+        push_index_thumbnail(out, entry.thumbnail.as_ref(), "");
+        if !entry.date_display.is_empty() {
+            out.push_str("<span class=\"blogdate\">");
+            out.push_str(&escape_html_text(&entry.date_display));
+            out.push_str("</span>");
+        }
+        out.push_str("<span class=\"blogtitle\">");
+    }
+    out.push_str(&escape_html_text(&entry.title));
+    out.push_str("</span>");
+    if html.index_reading_time {
+        out.push_str(&format!(
+            "<span class=\"blogreadingtime\">{} min</span>",
+            reading_minutes(entry.word_count, html)
+        ));
+    }
+    out.push_str("</a>");
+}
+
+/// Minutes `word_count` words take to read at `html.words_per_minute`, at least one.
+fn reading_minutes(word_count: usize, html: &config::HtmlConfig) -> usize {
+    word_count.div_ceil(html.words_per_minute.max(1)).max(1)
+}
+
+fn push_index_thumbnail(out: &mut String, thumbnail: Option<&IndexThumbnail>, extra_class: &str) {
+    if let Some(thumbnail) = thumbnail {
+        out.push_str(&format!(
+            "<img class=\"blogthumb{}\" src=\"{}\" width=\"{}\" height=\"{}\" alt=\"{}\" loading=\"lazy\" decoding=\"async\">",
+            extra_class,
+            escape_html_attr_simple(&thumbnail.url),
+            thumbnail.width,
+            thumbnail.height,
+            escape_html_attr_simple(&thumbnail.alt)
+        ));
+    }
+}
+
 fn find_blog_article_source(dir: &Path) -> Result<Option<PathBuf>, String> {
     let index_candidate = dir.join("index.dllu");
     if index_candidate.is_file() {
@@ -822,6 +1718,28 @@ fn build_blog_href(root_url: Option<&str>, relative: &str) -> String {
     }
 }
 
+/// Absolute URL of the generated page, when `root_url` is configured. Directory
+/// indexes map to their directory, matching blog permalinks.
+fn page_permalink(
+    input_path: &Path,
+    site_root: Option<&Path>,
+    config: &config::Config,
+) -> Option<String> {
+    let root_url = config.root_url.as_deref()?;
+    let site_root = site_root?;
+    let output_path = input_path.with_extension("html");
+    let relative = output_path.strip_prefix(site_root).ok()?;
+    let relative_url = pathbuf_to_url_path(relative);
+    let relative_url = if relative_url == "index.html" {
+        ""
+    } else {
+        relative_url
+            .strip_suffix("/index.html")
+            .unwrap_or(&relative_url)
+    };
+    Some(build_blog_href(Some(root_url), relative_url))
+}
+
 fn parse_date_key(date: &str) -> Option<(i32, u32, u32)> {
     let mut parts = date.splitn(3, '-');
     let year: i32 = parts.next()?.parse().ok()?;
@@ -843,12 +1761,152 @@ fn escape_html_text(input: &str) -> String {
     escape_html_attr_simple(input)
 }
 
+/// Points in-page links such as `#s1.2` at the post's permalink, since feed
+/// readers show the content away from the page its fragments belong to.
+/// Only `<a>` links change; `href="#id"` inside inline SVG stays local.
+fn absolutize_fragment_links(html: &str, permalink: &str) -> String {
+    lazy_static! {
+        static ref FRAGMENT_LINK: regex::Regex =
+            regex::Regex::new(r#"(<a\s[^>]*?\bhref=")#"#).unwrap();
+    }
+    let base = escape_html_attr_simple(permalink);
+    FRAGMENT_LINK
+        .replace_all(html, |caps: &regex::Captures| {
+            format!("{}{}#", &caps[1], base)
+        })
+        .into_owned()
+}
+
+/// The `tags/<tag>/index.html` page of each `x-tags` tag used by the posts of
+/// a collection, listing them as the index does, and `tags/index.html` linking
+/// every tag, so tag listings work without a search script.
+fn generate_tag_pages(
+    blog_index: &BlogIndex,
+    config: &config::Config,
+    site_data: &serde_json::Value,
+    mode: config::BlogMode,
+) -> Result<(), String> {
+    // Tags differing only in case or punctuation share a page, named by the
+    // first spelling in index order
+    let mut tags: BTreeMap<String, (&str, Vec<BlogPostIndexEntry>)> = BTreeMap::new();
+    for entry in &blog_index.entries {
+        for tag in &entry.tags {
+            let slug = tag_slug(tag);
+            if slug.is_empty() {
+                continue;
+            }
+            let (_, posts) = tags.entry(slug).or_insert_with(|| (tag, Vec::new()));
+            if !posts.iter().any(|post| post.permalink == entry.permalink) {
+                // Relative links were made for the index two directories up
+                let mut post = entry.clone();
+                post.display_href = rebase_relative_href(&post.display_href, "../../");
+                if let Some(thumbnail) = post.thumbnail.as_mut() {
+                    thumbnail.url = rebase_relative_href(&thumbnail.url, "../../");
+                }
+                posts.push(post);
+            }
+        }
+    }
+    if tags.is_empty() {
+        return Ok(());
+    }
+
+    let mut classes = Vec::new();
+    if config.html.microformats {
+        classes.push("h-feed");
+    }
+    if mode == config::BlogMode::Photos {
+        classes.push("photos");
+    }
+    let nav_open = if classes.is_empty() {
+        String::from("<nav id=\"blogposts\">")
+    } else {
+        format!("<nav id=\"blogposts\" class=\"{}\">", classes.join(" "))
+    };
+    let tags_dir = blog_index.directory.join("tags");
+    let write_page = |dir: &Path, title: &str, body: &str, posts: &[serde_json::Value]| {
+        let html = html_renderer::wrap_html_document(
+            config,
+            &html_renderer::TemplateContext {
+                title,
+                date: "",
+                body,
+                table_of_contents: "",
+                metas: "",
+                index: "",
+                scripts: "",
+                vars: &BTreeMap::new(),
+                meta: &BTreeMap::new(),
+                data: site_data,
+                featured: false,
+                word_count: 0,
+                reading_minutes: 0,
+                related: "",
+                posts,
+                images: &[],
+            },
+        )?;
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        let path = dir.join("index.html");
+        fs::write(&path, html).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    };
+
+    let mut list = String::from("<ul class=\"tags\">");
+    for (slug, (tag, posts)) in &tags {
+        let mut body = nav_open.clone();
+        for post in posts {
+            push_index_entry(&mut body, post, &config.html);
+        }
+        body.push_str("</nav>");
+        let title = format!("Posts tagged \u{201c}{}\u{201d}", tag);
+        write_page(
+            &tags_dir.join(slug),
+            &title,
+            &body,
+            &index_posts(posts, &config.html),
+        )?;
+        list.push_str(&format!(
+            "<li><a href=\"{}/\">{}</a> <span class=\"tagcount\">{}</span></li>",
+            escape_html_attr_simple(slug),
+            escape_html_text(tag),
+            posts.len()
+        ));
+    }
+    list.push_str("</ul>");
+    write_page(&tags_dir, "Tags", &list, &[])
+}
+
+/// A tag as a directory name: lowercase letters and digits, with runs of
+/// anything else turned into one `-`.
+fn tag_slug(tag: &str) -> String {
+    let mut slug = String::new();
+    for c in tag.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// `href` with `prefix` put before it when it is relative to the page.
+fn rebase_relative_href(href: &str, prefix: &str) -> String {
+    if href.starts_with('/') || href.starts_with('#') || href.contains("://") {
+        href.to_string()
+    } else {
+        format!("{}{}", prefix, href)
+    }
+}
+
 fn generate_rss_feed(
     _site_root: Option<&Path>,
     blog_index: &BlogIndex,
     config: &config::Config,
+    feed_cfg: &config::FeedConfig,
+    mode: config::BlogMode,
 ) -> Result<(), String> {
-    let feed_cfg = &config.feed;
     if !feed_cfg.enabled {
         return Ok(());
     }
@@ -887,22 +1945,43 @@ fn generate_rss_feed(
         .entries
         .iter()
         .take(max_items)
-        .map(|entry| RssItem {
-            title: entry.title.clone(),
-            link: entry.permalink.clone(),
-            guid: RssGuid {
-                is_perma_link: "true",
-                value: entry.permalink.clone(),
-            },
-            pub_date: entry.date_key.and_then(date_key_to_rfc2822),
-            description: entry.summary.as_deref().unwrap_or(&entry.title).to_string(),
-            content_encoded: Some(entry.content_html.clone()),
+        .map(|entry| {
+            // Photo stream items lead with the photo instead of the whole post
+            let photo = entry
+                .image
+                .as_ref()
+                .filter(|_| mode == config::BlogMode::Photos);
+            let content = match photo {
+                Some(image) => photo_feed_content(image, entry.summary.as_deref()),
+                None => feed_math::convert(
+                    &absolutize_fragment_links(&entry.content_html, &entry.permalink),
+                    feed_cfg.math,
+                    &feed_cfg.math_image_url,
+                ),
+            };
+            RssItem {
+                title: entry.title.clone(),
+                link: entry.permalink.clone(),
+                guid: RssGuid {
+                    is_perma_link: "true",
+                    value: entry.permalink.clone(),
+                },
+                pub_date: entry.date_key.and_then(date_key_to_rfc2822),
+                description: entry.summary.as_deref().unwrap_or(&entry.title).to_string(),
+                content_encoded: Some(content),
+                media_content: photo.map(|image| RssMediaContent {
+                    url: image.full_size.clone(),
+                    medium: "image",
+                }),
+                media_credits: entry.credits.clone(),
+            }
         })
         .collect();
 
     let feed = RssFeed {
         version: "2.0",
         content_namespace: "http://purl.org/rss/1.0/modules/content/",
+        media_namespace: "http://search.yahoo.com/mrss/",
         channel: RssChannel {
             title: channel_title,
             link: channel_link,
@@ -939,6 +2018,30 @@ fn generate_rss_feed(
     Ok(())
 }
 
+/// Feed content of a photo stream post: the photo, then its caption and the
+/// post's summary when they add anything.
+fn photo_feed_content(image: &html_renderer::PageImage, summary: Option<&str>) -> String {
+    let mut out = format!(
+        "<p><img src=\"{}\" alt=\"{}\"",
+        escape_html_attr_simple(&image.src),
+        escape_html_attr_simple(&image.alt)
+    );
+    if let (Some(width), Some(height)) = (image.width, image.height) {
+        out.push_str(&format!(" width=\"{}\" height=\"{}\"", width, height));
+    }
+    out.push_str("></p>");
+    let caption = image.caption.trim();
+    if !caption.is_empty() {
+        out.push_str(&format!("<p>{}</p>", escape_html_text(caption)));
+    }
+    if let Some(summary) = summary.map(str::trim) {
+        if !summary.is_empty() && summary != caption {
+            out.push_str(&format!("<p>{}</p>", escape_html_text(summary)));
+        }
+    }
+    out
+}
+
 fn pathbuf_to_url_path(path: &Path) -> String {
     let mut segments = Vec::new();
     for component in path.iter() {
@@ -961,42 +2064,104 @@ fn date_key_to_rfc2822(date: (i32, u32, u32)) -> Option<String> {
     datetime.format(&Rfc2822).ok()
 }
 
-fn first_paragraph_text(blocks: &[Block]) -> Option<String> {
+fn figure_credits(blocks: &[Block]) -> Vec<String> {
+    let mut credits: Vec<String> = Vec::new();
     for block in blocks {
-        if let Block::Paragraph(inlines) = block {
-            let text = inline_elements_to_plain_text(inlines);
-            let trimmed = text.trim();
-            if !trimmed.is_empty() {
-                let normalized = collapse_whitespace(trimmed);
-                if !normalized.is_empty() {
-                    return Some(normalized);
-                }
+        if let Block::ImageFigure {
+            credit: Some(credit),
+            ..
+        } = block
+        {
+            let text = excerpt::collapse_whitespace(&excerpt::plain_text(credit));
+            if !text.is_empty() && !credits.contains(&text) {
+                credits.push(text);
             }
         }
     }
-    None
+    credits
+}
+
+/// The post's first figure, sized from the image metadata already gathered
+/// while rendering the post so the index `<img>` carries width and height, or
+/// a crop of it when `images.index_crop` or the figure's `crop=` asks for one.
+/// Photo streams always have thumbnails, as their index is a grid of them.
+fn first_figure_thumbnail(
+    blocks: &[Block],
+    asset_root: &Path,
+    config: &config::Config,
+    mode: config::BlogMode,
+) -> Option<IndexThumbnail> {
+    if !config.html.index_thumbnails && mode != config::BlogMode::Photos {
+        return None;
+    }
+    let (url, alt, crop) = blocks.iter().find_map(|block| match block {
+        Block::ImageFigure { url, alt, crop, .. } => Some((url, alt, *crop)),
+        _ => None,
+    })?;
+    let processor = image_processor::ImageProcessor::new(config);
+    let processed = processor.process(url, asset_root).ok()?;
+    if let Some(crop) = config.images.index_thumbnail_crop(crop) {
+        if let Ok(Some(variant)) =
+            processor.crop(&processed, asset_root, crop, INDEX_THUMBNAIL_WIDTH)
+        {
+            return Some(IndexThumbnail {
+                url: variant.url,
+                width: variant.width,
+                height: variant.height,
+                alt: alt.clone(),
+            });
+        }
+    }
+    let mut variants = processed.variants;
+    variants.sort_by_key(|variant| variant.width);
+    let variant = variants
+        .iter()
+        .find(|variant| variant.width >= INDEX_THUMBNAIL_WIDTH)
+        .or_else(|| variants.last())
+        .or(processed.original.as_ref())?;
+    Some(IndexThumbnail {
+        url: variant.url.clone(),
+        width: variant.width,
+        height: variant.height,
+        alt: alt.clone(),
+    })
+}
+
+/// With `html.index_date_from_exif`, the day the post's first figure was taken,
+/// standing in for a missing header date.
+fn exif_post_date(blocks: &[Block], asset_root: &Path, config: &config::Config) -> Option<String> {
+    if !config.html.index_date_from_exif {
+        return None;
+    }
+    let url = blocks.iter().find_map(|block| match block {
+        Block::ImageFigure { url, .. } => Some(url),
+        _ => None,
+    })?;
+    let processed = image_processor::ImageProcessor::new(config)
+        .process(url, asset_root)
+        .ok()?;
+    processed.exif?.date()
 }
 
+/// Caches the rendered post for the collection index; returns whether the
+/// page belongs to `spec`'s directory.
 fn register_blog_post_if_applicable(
     input_path: &Path,
     site_root: Option<&Path>,
     config: &config::Config,
+    spec: &CollectionSpec,
     article: &ast::Article,
     rendered_body: &str,
-) {
-    let blog_dir_raw = match config.html.blog_dir.as_deref() {
-        Some(dir) if !dir.trim().is_empty() => dir.trim(),
-        _ => return,
-    };
-
-    let blog_dir_clean = blog_dir_raw.trim_matches('/');
+    images: &[html_renderer::PageImage],
+) -> bool {
+    let blog_dir_clean = spec.dir.trim().trim_matches('/');
     if blog_dir_clean.is_empty() {
-        return;
+        return false;
     }
 
     let site_root = match site_root {
         Some(root) => root,
-        None => return,
+        None => return false,
     };
 
     let mut blog_path = PathBuf::new();
@@ -1010,15 +2175,15 @@ fn register_blog_post_if_applicable(
 
     let post_dir = match input_path.parent() {
         Some(dir) => dir,
-        None => return,
+        None => return false,
     };
 
     if directory_has_private_marker(post_dir) {
-        return;
+        return false;
     }
 
     if post_dir == blog_root {
-        return;
+        return false;
     }
 
     if post_dir
@@ -1026,39 +2191,49 @@ fn register_blog_post_if_applicable(
         .map(|parent| parent != blog_root.as_path())
         .unwrap_or(true)
     {
-        return;
+        return false;
     }
 
     let source = match find_blog_article_source(post_dir) {
         Ok(Some(path)) => path,
-        _ => return,
+        _ => return true,
     };
 
     if source != input_path {
-        return;
+        return true;
     }
 
     let header = match article.header.as_ref() {
         Some(h) => h,
-        None => return,
+        None => return true,
     };
 
     let title = header.title.trim();
     if title.is_empty() {
-        return;
+        return true;
     }
 
-    let date = match header.date.as_deref().map(str::trim) {
-        Some(d) if !d.is_empty() => d,
-        _ => return,
+    let header_date = header
+        .date
+        .as_deref()
+        .map(str::trim)
+        .filter(|d| !d.is_empty());
+    let exif_date = match header_date {
+        Some(_) => None,
+        None => exif_post_date(&article.body, post_dir, config),
+    };
+    let date = match header_date.or(exif_date.as_deref()) {
+        Some(d) => d,
+        None if spec.sort == config::CollectionSort::Title => "",
+        None => return true,
     };
 
     let slug = match post_dir.file_name().and_then(|s| s.to_str()) {
         Some(s) => s,
-        None => return,
+        None => return true,
     };
 
-    let summary = first_paragraph_text(&article.body);
+    let summary = excerpt::summary(&article.body);
     let relative_path = build_blog_relative_url(blog_dir_clean, slug);
     let permalink = build_blog_href(config.root_url.as_deref(), &relative_path);
     let display_href = if config.root_url.is_some() {
@@ -1075,46 +2250,971 @@ fn register_blog_post_if_applicable(
         permalink,
         summary,
         content_html: rendered_body.to_string(),
+        credits: figure_credits(&article.body),
+        thumbnail: first_figure_thumbnail(&article.body, post_dir, config, spec.mode),
+        image: images.first().cloned(),
+        pinned: header.pinned,
+        featured: header.featured,
+        word_count: excerpt::word_count(article),
+        tags: excerpt::tags(article),
     };
 
     if let Ok(mut cache) = BLOG_POST_CACHE.lock() {
         cache.insert(post_dir.to_path_buf(), entry);
     }
+    true
 }
 
-fn inline_elements_to_plain_text(inlines: &[InlineElement]) -> String {
-    let mut out = String::new();
-    for inline in inlines {
-        match inline {
-            InlineElement::Text(t) => out.push_str(t),
-            InlineElement::Code(c) | InlineElement::InlineMath(c) => out.push_str(c),
-            InlineElement::Link { text, .. } => out.push_str(&inline_elements_to_plain_text(text)),
-            InlineElement::Emphasis(inner) | InlineElement::Strong(inner) => {
-                out.push_str(&inline_elements_to_plain_text(inner))
-            }
-            InlineElement::Reference(s) => out.push_str(s),
-            InlineElement::ReferenceAnchor { content, .. } => out.push_str(content),
+/// For a post of a photo stream (`mode = "photos"`), the `<nav class="photonav">`
+/// linking the posts before and after it on the index; `None` for other pages.
+fn photo_stream_nav(
+    input_path: &Path,
+    site_root: Option<&Path>,
+    config: &config::Config,
+    spec: &CollectionSpec,
+) -> Option<String> {
+    if spec.mode != config::BlogMode::Photos {
+        return None;
+    }
+    let (post_dir, blog_root) = collection_post(input_path, site_root, spec)?;
+    let posts = collection_posts(blog_root, spec, config);
+    let position = posts.iter().position(|post| post.dir == post_dir)?;
+    let mut out = String::from("<nav class=\"photonav\">");
+    let newer = position.checked_sub(1).and_then(|i| posts.get(i));
+    for (post, rel, label) in [
+        (newer, "prev", "Newer"),
+        (posts.get(position + 1), "next", "Older"),
+    ] {
+        if let Some(post) = post {
+            out.push_str(&format!(
+                "<a rel=\"{}\" href=\"{}\"><span>{}</span> {}</a>",
+                rel,
+                escape_html_attr_simple(&post.href),
+                label,
+                escape_html_text(&post.title)
+            ));
         }
     }
-    out
+    out.push_str("</nav>");
+    Some(out)
 }
 
-fn collapse_whitespace(input: &str) -> String {
-    let mut result = String::new();
-    let mut last_was_space = false;
-    for ch in input.chars() {
-        if ch.is_whitespace() {
-            if !last_was_space && !result.is_empty() {
-                result.push(' ');
+/// For a post of a collection with `html.related_posts` set, the
+/// `<nav class="related">` listing the other posts that share the most
+/// `x-tags` with it, empty when none do; `None` for other pages.
+fn related_posts_nav(
+    input_path: &Path,
+    site_root: Option<&Path>,
+    config: &config::Config,
+    spec: &CollectionSpec,
+    article: &ast::Article,
+) -> Option<String> {
+    let limit = config.html.related_posts;
+    if limit == 0 {
+        return None;
+    }
+    let (post_dir, blog_root) = collection_post(input_path, site_root, spec)?;
+    let tags = excerpt::tags(article);
+    let posts = collection_posts(blog_root, spec, config);
+    let related = rank_related(&tags, post_dir, &posts, limit);
+    if related.is_empty() {
+        return Some(String::new());
+    }
+    let mut out = String::from("<nav class=\"related\" aria-label=\"Related posts\"><ul>");
+    for post in related {
+        out.push_str(&format!(
+            "<li><a href=\"{}\">{}</a></li>",
+            escape_html_attr_simple(&post.href),
+            escape_html_text(&post.title)
+        ));
+    }
+    out.push_str("</ul></nav>");
+    Some(out)
+}
+
+/// Up to `limit` posts other than the one in `post_dir` sharing any of
+/// `tags`, ranked by the number they share divided by one plus the years the
+/// post is older than the newest one, so newer posts win similar overlaps.
+/// Undated posts count as old as the oldest dated one; equal scores keep
+/// index order.
+fn rank_related<'a>(
+    tags: &[String],
+    post_dir: &Path,
+    posts: &'a [StreamPost],
+    limit: usize,
+) -> Vec<&'a StreamPost> {
+    fn years((year, month, day): (i32, u32, u32)) -> f64 {
+        year as f64 + month.saturating_sub(1) as f64 / 12.0 + day.saturating_sub(1) as f64 / 365.0
+    }
+    let dated: Vec<f64> = posts
+        .iter()
+        .filter_map(|post| post.date_key.map(years))
+        .collect();
+    let newest = dated.iter().copied().fold(f64::MIN, f64::max);
+    let oldest = dated.iter().copied().fold(f64::MAX, f64::min);
+    let undated_age = if dated.is_empty() {
+        0.0
+    } else {
+        newest - oldest
+    };
+    let mut scored: Vec<(f64, &StreamPost)> = posts
+        .iter()
+        .filter(|post| post.dir != post_dir)
+        .filter_map(|post| {
+            let shared = post
+                .tags
+                .iter()
+                .filter(|tag| tags.iter().any(|own| own.eq_ignore_ascii_case(tag)))
+                .count();
+            if shared == 0 {
+                return None;
             }
-            last_was_space = true;
-        } else {
-            result.push(ch);
-            last_was_space = false;
+            let age = post
+                .date_key
+                .map_or(undated_age, |date| newest - years(date));
+            Some((shared as f64 / (1.0 + age), post))
+        })
+        .collect();
+    // Stable, so equal scores stay in index order
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(_, post)| post)
+        .collect()
+}
+
+/// The post directory and collection directory of `input_path` when it is
+/// the source of a post of `spec`'s collection.
+fn collection_post<'a>(
+    input_path: &'a Path,
+    site_root: Option<&Path>,
+    spec: &CollectionSpec,
+) -> Option<(&'a Path, &'a Path)> {
+    let blog_path: PathBuf = spec
+        .dir
+        .trim()
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    if blog_path.as_os_str().is_empty() {
+        return None;
+    }
+    let post_dir = input_path.parent()?;
+    let blog_root = post_dir.parent()?;
+    let in_collection = match site_root {
+        Some(root) => blog_root == root.join(&blog_path),
+        None => blog_root.ends_with(&blog_path),
+    };
+    if !in_collection || directory_has_private_marker(post_dir) {
+        return None;
+    }
+    if find_blog_article_source(post_dir).ok()?? != input_path {
+        return None;
+    }
+    Some((post_dir, blog_root))
+}
+
+/// The posts of a collection in index order. Only headers are read, and
+/// posts the index leaves out are skipped here too (the index reports them).
+fn collection_posts(
+    blog_root: &Path,
+    spec: &CollectionSpec,
+    config: &config::Config,
+) -> Vec<StreamPost> {
+    if let Some(posts) = COLLECTION_POSTS
+        .lock()
+        .ok()
+        .and_then(|streams| streams.get(blog_root).cloned())
+    {
+        return posts;
+    }
+
+    let blog_dir_clean = spec.dir.trim().trim_matches('/');
+    let mut posts = Vec::new();
+    let Ok(entries) = fs::read_dir(blog_root) else {
+        return posts;
+    };
+    for entry in entries.flatten() {
+        let post_dir = entry.path();
+        if !post_dir.is_dir() || directory_has_private_marker(&post_dir) {
+            continue;
         }
+        let Ok(Some(source)) = find_blog_article_source(&post_dir) else {
+            continue;
+        };
+        let Ok(contents) = fs::read_to_string(&source) else {
+            continue;
+        };
+        let mut parser = Parser::with_includes(&source);
+        parser.parse(&contents);
+        if plugins::apply(&config.plugins, &mut parser.article, &source).is_err() {
+            continue;
+        }
+        let Some(header) = parser.article.header.as_ref() else {
+            continue;
+        };
+        let title = header.title.trim();
+        let header_date = header
+            .date
+            .as_deref()
+            .map(str::trim)
+            .filter(|d| !d.is_empty());
+        let exif_date = match header_date {
+            Some(_) => None,
+            None => exif_post_date(&parser.article.body, &post_dir, config),
+        };
+        let date = match header_date.or(exif_date.as_deref()) {
+            Some(d) => d,
+            None if spec.sort == config::CollectionSort::Title => "",
+            None => continue,
+        };
+        let Some(slug) = post_dir.file_name().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        if title.is_empty() {
+            continue;
+        }
+        // Without a root URL, siblings are linked relative to the post's directory
+        let href = match config.root_url.as_deref() {
+            Some(root) => {
+                build_blog_href(Some(root), &build_blog_relative_url(blog_dir_clean, slug))
+            }
+            None => format!("../{}", slug),
+        };
+        posts.push(StreamPost {
+            dir: post_dir.clone(),
+            title: title.to_string(),
+            date_key: parse_date_key(date),
+            href,
+            tags: excerpt::tags(&parser.article),
+        });
+    }
+    posts.sort_by(|a, b| {
+        compare_posts(
+            spec.sort,
+            (a.date_key, a.title.as_str()),
+            (b.date_key, b.title.as_str()),
+        )
+    });
+
+    if let Ok(mut collections) = COLLECTION_POSTS.lock() {
+        collections.insert(blog_root.to_path_buf(), posts.clone());
+    }
+    posts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_post(root: &Path, dir: &str, title: &str, date: &str) {
+        let post_dir = root.join(dir);
+        fs::create_dir_all(&post_dir).unwrap();
+        fs::write(
+            post_dir.join("index.dllu"),
+            format!("{}\n\n{}\n\n===\n\nBody of {}.\n", title, date, title),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn strict_builds_fail_on_a_warning() {
+        let dir = tempfile::tempdir().unwrap();
+        let page = dir.path().join("page.dllu");
+        fs::write(&page, "Page\n\n===\n\npic missing.png : Gone\n").unwrap();
+        let mut config = config::Config::default();
+        config.images.cache_dir = dir.path().join("img").display().to_string();
+        config.html.css_href = String::new();
+
+        let before = diagnostics::warning_count();
+        process_file(&page, Some(dir.path()), Some(&config), None).unwrap();
+        assert!(diagnostics::warning_count() > before);
+        let err = check_strict(true).unwrap_err();
+        assert!(err.ends_with("failing the build in strict mode"), "{}", err);
+        assert_eq!(check_strict(false), Ok(()));
+    }
+
+    #[test]
+    fn page_and_resize_threads_are_set_separately() {
+        let mut build = config::BuildConfig::default();
+        assert_eq!(thread_counts(None, &build), (None, 0));
+        assert_eq!(thread_counts(Some(2), &build), (Some(2), 2));
+
+        build.jobs = Some(6);
+        assert_eq!(thread_counts(None, &build), (Some(6), 6));
+        assert_eq!(thread_counts(Some(2), &build), (Some(2), 2));
+
+        build.image_jobs = Some(1);
+        assert_eq!(thread_counts(None, &build), (Some(6), 1));
+        assert_eq!(thread_counts(Some(2), &build), (Some(2), 1));
+        build.jobs = None;
+        assert_eq!(thread_counts(None, &build), (None, 1));
+    }
+
+    fn photo_site() -> (tempfile::TempDir, config::Config) {
+        let dir = tempfile::tempdir().unwrap();
+        write_post(dir.path(), "photos/harbour", "Harbour", "2020-03-01");
+        write_post(dir.path(), "photos/bridge", "Bridge", "2022-07-15");
+        write_post(dir.path(), "photos/market", "Market", "2021-11-30");
+        let mut config = config::Config::default();
+        config.html.blog_dir = Some("photos".into());
+        config.html.blog_mode = config::BlogMode::Photos;
+        (dir, config)
+    }
+
+    fn photo(src: &str, caption: &str) -> html_renderer::PageImage {
+        html_renderer::PageImage {
+            src: src.into(),
+            srcset: None,
+            sizes: None,
+            width: Some(800),
+            height: Some(600),
+            alt: "Boats".into(),
+            full_size: src.replace("-800", ""),
+            caption: caption.into(),
+        }
+    }
+
+    fn stream_post(dir: &str, date_key: Option<(i32, u32, u32)>, tags: &[&str]) -> StreamPost {
+        StreamPost {
+            dir: PathBuf::from(dir),
+            title: dir.to_uppercase(),
+            date_key,
+            href: format!("../{}", dir),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    fn related_dirs(
+        tags: &[&str],
+        post_dir: &str,
+        posts: &[StreamPost],
+        limit: usize,
+    ) -> Vec<String> {
+        let tags: Vec<String> = tags.iter().map(|tag| tag.to_string()).collect();
+        rank_related(&tags, Path::new(post_dir), posts, limit)
+            .into_iter()
+            .map(|post| post.dir.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn related_posts_rank_by_shared_tags_then_age() {
+        let posts = [
+            stream_post("own", Some((2024, 1, 1)), &["rust", "cli"]),
+            stream_post("one-tag", Some((2024, 1, 1)), &["rust"]),
+            stream_post("go", Some((2024, 1, 1)), &["go"]),
+            stream_post("both", Some((2024, 1, 1)), &["Rust", "CLI"]),
+            stream_post("old", Some((2020, 1, 1)), &["rust", "cli"]),
+            stream_post("undated", None, &["rust", "cli"]),
+        ];
+        assert_eq!(
+            related_dirs(&["rust", "cli"], "own", &posts, 10),
+            ["both", "one-tag", "old", "undated"]
+        );
+        assert_eq!(
+            related_dirs(&["rust", "cli"], "own", &posts, 2),
+            ["both", "one-tag"]
+        );
+        assert!(related_dirs(&["rust"], "own", &posts, 0).is_empty());
+        assert!(related_dirs(&["python"], "own", &posts, 10).is_empty());
+    }
+
+    #[test]
+    fn related_posts_with_equal_scores_keep_index_order() {
+        let posts = [
+            stream_post("b", Some((2023, 5, 1)), &["rust"]),
+            stream_post("a", Some((2023, 5, 1)), &["rust"]),
+            stream_post("own", Some((2023, 5, 1)), &["rust"]),
+        ];
+        assert_eq!(related_dirs(&["rust"], "own", &posts, 10), ["b", "a"]);
+
+        // Without any dates, only the shared tags count
+        let posts = [
+            stream_post("one", None, &["rust"]),
+            stream_post("two", None, &["rust", "cli"]),
+            stream_post("also-one", None, &["cli"]),
+        ];
+        assert_eq!(
+            related_dirs(&["rust", "cli"], "own", &posts, 10),
+            ["two", "one", "also-one"]
+        );
+    }
+
+    #[test]
+    fn reading_time_rounds_up_to_whole_minutes() {
+        let mut html = config::HtmlConfig::default();
+        assert_eq!(reading_minutes(0, &html), 1);
+        assert_eq!(reading_minutes(200, &html), 1);
+        assert_eq!(reading_minutes(201, &html), 2);
+        html.words_per_minute = 0;
+        assert_eq!(reading_minutes(201, &html), 201);
+    }
+
+    #[test]
+    fn indexes_show_reading_times_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write_post(root, "blog/short", "Short", "2024-01-02");
+        let mut config = config::Config::default();
+        config.html.blog_dir = Some("blog".into());
+        let spec = collection_specs(&config).remove(0);
+        let index = build_blog_index(&root.join("blog/index.dllu"), Some(root), &config, &spec)
+            .unwrap()
+            .unwrap();
+        assert_eq!(index.entries[0].word_count, 3);
+        assert!(!index.html.contains("blogreadingtime"));
+
+        config.html.index_reading_time = true;
+        let mut out = String::new();
+        push_index_entry(&mut out, &index.entries[0], &config.html);
+        assert!(out.ends_with(
+            "<span class=\"blogtitle\">Short</span>\
+             <span class=\"blogreadingtime\">1 min</span></a>"
+        ));
+    }
+
+    #[test]
+    fn collections_get_their_own_order_template_and_feed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write_post(root, "projects/zephyr", "Zephyr", "2024-01-01");
+        write_post(root, "projects/emile", "Émile", "");
+        write_post(root, "projects/bramble", "bramble", "2019-05-05");
+        fs::write(
+            root.join("projects/index.dllu"),
+            "Projects\n\n===\n\nThings I built.\n",
+        )
+        .unwrap();
+        let template = root.join("projects.html");
+        fs::write(&template, "<main class=\"projects\">{{index}}</main>").unwrap();
+        fs::write(
+            root.join("dllup.toml"),
+            format!(
+                "root_url = \"https://example.com\"\n\n\
+                 [html]\nblog_dir = \"blog\"\ncss_href = \"\"\n\n\
+                 [images]\ncache_dir = {:?}\n\n\
+                 [[collections]]\ndir = \"/projects/\"\nsort = \"title\"\n\
+                 template_path = {:?}\ntitle = \"Projects\"\nfeed_path = \"feed.xml\"\n",
+                root.join("img").display().to_string(),
+                template.display().to_string()
+            ),
+        )
+        .unwrap();
+        let config = config::Config::load(&root.join("dllup.toml")).unwrap();
+
+        let specs = collection_specs(&config);
+        let dirs: Vec<&str> = specs.iter().map(|spec| spec.dir.as_str()).collect();
+        assert_eq!(dirs, ["blog", "projects"]);
+        assert_eq!(specs[0].sort, config::CollectionSort::Date);
+        assert_eq!(specs[1].sort, config::CollectionSort::Title);
+        assert_eq!(specs[1].feed.channel_title.as_deref(), Some("Projects"));
+
+        process_file(
+            &root.join("projects/index.dllu"),
+            Some(root),
+            Some(&config),
+            None,
+        )
+        .unwrap();
+        let html = fs::read_to_string(root.join("projects/index.html")).unwrap();
+        assert!(
+            html.starts_with("<main class=\"projects\"><nav id=\"blogposts\">"),
+            "{}",
+            html
+        );
+        // By title, ignoring case and accents; a title-sorted collection
+        // keeps its undated posts
+        let position = |title: &str| html.find(title).unwrap();
+        assert!(position("bramble") < position("Émile"));
+        assert!(position("Émile") < position("Zephyr"));
+
+        let feed = fs::read_to_string(root.join("projects/feed.xml")).unwrap();
+        assert!(feed.contains("<title>Projects</title>"));
+        assert!(feed.contains("<link>https://example.com/projects/zephyr</link>"));
+        assert!(!root.join("projects/rss.xml").exists());
+    }
+
+    #[test]
+    fn posts_compare_newest_first_with_undated_posts_last() {
+        use std::cmp::Ordering;
+        let date = config::CollectionSort::Date;
+        let new = (Some((2024, 5, 1)), "New");
+        let old = (Some((2019, 1, 1)), "Old");
+        let undated = (None, "Anything");
+        assert_eq!(compare_posts(date, new, old), Ordering::Less);
+        assert_eq!(compare_posts(date, undated, old), Ordering::Greater);
+        assert_eq!(
+            compare_posts(date, (None, "apple"), (None, "Banana")),
+            Ordering::Less
+        );
+        let title = config::CollectionSort::Title;
+        assert_eq!(compare_posts(title, new, old), Ordering::Less);
+        assert_eq!(compare_posts(title, old, undated), Ordering::Greater);
+    }
+
+    #[test]
+    fn indexes_group_posts_by_year_and_collapse_old_years() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write_post(root, "blog/spring", "Spring", "2024-04-01");
+        write_post(root, "blog/winter", "Winter", "2024-01-15");
+        write_post(root, "blog/autumn", "Autumn", "2022-10-01");
+        write_post(root, "blog/summer", "Summer", "2021-07-01");
+        let mut config = config::Config::default();
+        config.html.blog_dir = Some("blog".into());
+        config.html.index_group_by_year = true;
+        config.html.index_open_years = Some(1);
+        let spec = collection_specs(&config).remove(0);
+        let index = build_blog_index(&root.join("blog/index.dllu"), Some(root), &config, &spec)
+            .unwrap()
+            .unwrap();
+        let html = &index.html;
+        assert!(html.starts_with(
+            "<nav id=\"blogposts\" class=\"grouped\"><section class=\"blogyear\">\
+             <h2 id=\"y2024\">2024</h2><div class=\"blogyearposts\"><a href=\"spring\">"
+        ));
+        assert!(html.find("\"spring\"").unwrap() < html.find("\"winter\"").unwrap());
+        assert!(html.contains(
+            "</div></section><details class=\"blogyear\"><summary>2022</summary>\
+             <div class=\"blogyearposts\"><a href=\"autumn\">"
+        ));
+        assert!(
+            html.contains("</div></details><details class=\"blogyear\"><summary>2021</summary>")
+        );
+        assert!(html.ends_with("</div></details></nav>"));
+
+        // Every year stays open without a limit
+        config.html.index_open_years = None;
+        let index = build_blog_index(&root.join("blog/index.dllu"), Some(root), &config, &spec)
+            .unwrap()
+            .unwrap();
+        assert!(!index.html.contains("<details"));
+        assert!(index.html.contains("<h2 id=\"y2021\">2021</h2>"));
+
+        // A collection sorted by title has no years to group by
+        let spec = CollectionSpec {
+            sort: config::CollectionSort::Title,
+            ..spec
+        };
+        let index = build_blog_index(&root.join("blog/index.dllu"), Some(root), &config, &spec)
+            .unwrap()
+            .unwrap();
+        assert!(index
+            .html
+            .starts_with("<nav id=\"blogposts\"><a href=\"autumn\">"));
+    }
+
+    #[test]
+    fn pinned_posts_lead_the_index_but_not_the_feed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write_post(root, "blog/new", "New", "2024-04-01");
+        write_post(
+            root,
+            "blog/notice",
+            "Notice",
+            "2020-01-01\npinned: true\nfeatured: yes",
+        );
+        write_post(root, "blog/old", "Old", "2021-01-01");
+        let mut config = config::Config {
+            root_url: Some("https://example.com".into()),
+            ..Default::default()
+        };
+        config.html.blog_dir = Some("blog".into());
+        let spec = collection_specs(&config).remove(0);
+        let index = build_blog_index(&root.join("blog/index.dllu"), Some(root), &config, &spec)
+            .unwrap()
+            .unwrap();
+        assert!(index.html.starts_with(
+            "<nav id=\"blogposts\"><a class=\"pinned featured\" \
+             href=\"https://example.com/blog/notice\">"
+        ));
+        assert!(index.html.find("/blog/new").unwrap() < index.html.find("/blog/old").unwrap());
+
+        let posts = index_posts(&index.entries, &config.html);
+        let titles: Vec<&str> = posts.iter().map(|p| p["title"].as_str().unwrap()).collect();
+        assert_eq!(titles, ["Notice", "New", "Old"]);
+        assert_eq!(posts[0]["pinned"], true);
+        assert_eq!(posts[0]["featured"], true);
+        assert_eq!(posts[1]["featured"], false);
+
+        // The feed stays in date order
+        generate_rss_feed(Some(root), &index, &config, &spec.feed, spec.mode).unwrap();
+        let feed = fs::read_to_string(root.join("blog/rss.xml")).unwrap();
+        let item = |title: &str| feed.find(&format!("<title>{}</title>", title)).unwrap();
+        assert!(item("New") < item("Old"));
+        assert!(item("Old") < item("Notice"));
+
+        // Grouped by year, pinned posts get a section of their own
+        config.html.index_group_by_year = true;
+        let index = build_blog_index(&root.join("blog/index.dllu"), Some(root), &config, &spec)
+            .unwrap()
+            .unwrap();
+        assert!(index.html.contains(
+            "<section class=\"blogpinned\"><div class=\"blogyearposts\">\
+             <a class=\"pinned featured\""
+        ));
+        assert!(!index.html.contains("y2020"));
+    }
+
+    #[test]
+    fn tag_pages_list_the_posts_of_each_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("blog")).unwrap();
+        fs::write(root.join("blog/index.dllu"), "Blog\n\n===\n").unwrap();
+        write_post(root, "blog/kite", "Kite", "2024-01-02\nx-tags: Kites, Maps");
+        write_post(root, "blog/atlas", "Atlas", "2023-05-06\nx-tags: maps");
+        write_post(root, "blog/plain", "Plain", "2022-01-01");
+        let mut config = config::Config::default();
+        config.html.blog_dir = Some("blog".into());
+        config.html.css_href = String::new();
+        config.images.cache_dir = root.join("img").display().to_string();
+
+        process_file(
+            &root.join("blog/index.dllu"),
+            Some(root),
+            Some(&config),
+            None,
+        )
+        .unwrap();
+        assert!(!root.join("blog/tags").exists());
+
+        config.html.tag_pages = true;
+        process_file(
+            &root.join("blog/index.dllu"),
+            Some(root),
+            Some(&config),
+            None,
+        )
+        .unwrap();
+        let maps = fs::read_to_string(root.join("blog/tags/maps/index.html")).unwrap();
+        // Both spellings share the page, newest post first, linked from two levels down
+        let kite = maps.find("href=\"../../kite\"").unwrap();
+        let atlas = maps.find("href=\"../../atlas\"").unwrap();
+        assert!(kite < atlas, "{}", maps);
+        assert!(
+            maps.contains("Posts tagged \u{201c}Maps\u{201d}"),
+            "{}",
+            maps
+        );
+        assert!(!maps.contains("Plain"));
+        let kites = fs::read_to_string(root.join("blog/tags/kites/index.html")).unwrap();
+        assert!(kites.contains("../../kite") && !kites.contains("../../atlas"));
+        let list = fs::read_to_string(root.join("blog/tags/index.html")).unwrap();
+        assert!(list.contains(
+            "<li><a href=\"kites/\">Kites</a> <span class=\"tagcount\">1</span></li>\
+             <li><a href=\"maps/\">Maps</a> <span class=\"tagcount\">2</span></li>"
+        ));
+    }
+
+    #[test]
+    fn tags_become_lowercase_directory_names() {
+        assert_eq!(tag_slug("Rust"), "rust");
+        assert_eq!(tag_slug("  Open source / C++ "), "open-source-c");
+        assert_eq!(tag_slug("Zürich"), "zürich");
+        assert_eq!(tag_slug("!!"), "");
+    }
+
+    #[test]
+    fn index_thumbnails_carry_their_dimensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let post_dir = root.join("blog/harbour");
+        fs::create_dir_all(&post_dir).unwrap();
+        image::RgbImage::from_pixel(1000, 600, image::Rgb([20, 60, 120]))
+            .save(post_dir.join("photo.png"))
+            .unwrap();
+        fs::write(
+            post_dir.join("index.dllu"),
+            "Harbour\n\n2024-01-02\n\n===\n\npic photo.png Boats at dusk : The harbour\n",
+        )
+        .unwrap();
+        let mut config = config::Config::default();
+        config.html.blog_dir = Some("blog".into());
+        config.images.cache_dir = root.join("img").display().to_string();
+        config.images.sizes = vec![240, 480, 960];
+        let spec = collection_specs(&config).remove(0);
+        let build = |config: &config::Config| {
+            build_blog_index(&root.join("blog/index.dllu"), Some(root), config, &spec)
+                .unwrap()
+                .unwrap()
+        };
+        assert!(!build(&config).html.contains("blogthumb"));
+
+        config.html.index_thumbnails = true;
+        let index = build(&config);
+        // The smallest variant at least INDEX_THUMBNAIL_WIDTH wide
+        let thumbnail = index.entries[0].thumbnail.as_ref().unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (480, 288));
+        assert!(index.html.contains(&format!(
+            "<img class=\"blogthumb\" src=\"{}\" width=\"480\" height=\"288\" \
+             alt=\"Boats at dusk\" loading=\"lazy\" decoding=\"async\">",
+            thumbnail.url
+        )));
+        image_processor::wait_for_pending_resizes();
     }
-    if last_was_space {
-        result.pop();
+
+    #[test]
+    fn sitemaps_list_the_figure_images_of_public_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let page = |name: &str, is_private: bool, images: Vec<html_renderer::PageImage>| {
+            let source = root.join(name).join("index.dllu");
+            fs::create_dir_all(source.parent().unwrap()).unwrap();
+            fs::write(&source, "Page\n\n===\n").unwrap();
+            fs::write(source.with_extension("html"), "<p>Page</p>").unwrap();
+            ProcessedPage {
+                output_path: source.with_extension("html"),
+                source_path: source,
+                root_url: Some("https://example.com".into()),
+                is_private,
+                images,
+                deps: None,
+            }
+        };
+        let untitled = html_renderer::PageImage {
+            alt: String::new(),
+            caption: String::new(),
+            ..photo("/img/kite-800.jpg", "")
+        };
+        let pages = [
+            page(
+                "gallery",
+                false,
+                vec![
+                    photo("harbour-800.jpg", "Boats at <dawn>"),
+                    untitled,
+                    photo("https://cdn.example/c-800.jpg", ""),
+                ],
+            ),
+            page("drafts", true, vec![photo("secret-800.jpg", "Secret")]),
+        ];
+        generate_sitemap(root, &pages).unwrap();
+        let sitemap = fs::read_to_string(root.join("sitemap.xml")).unwrap();
+        assert!(sitemap.contains("xmlns:image=\"http://www.google.com/schemas/sitemap-image/1.1\""));
+        assert!(sitemap.contains(
+            "<image:image><image:loc>https://example.com/gallery/harbour.jpg</image:loc>\
+             <image:title>Boats</image:title><image:caption>Boats at &lt;dawn&gt;</image:caption>\
+             </image:image>"
+        ));
+        // Empty titles and captions are left out
+        assert!(sitemap.contains(
+            "<image:image><image:loc>https://example.com/img/kite.jpg</image:loc></image:image>"
+        ));
+        assert!(sitemap.contains("<image:loc>https://cdn.example/c.jpg</image:loc>"));
+        assert!(!sitemap.contains("secret"));
+    }
+
+    #[test]
+    fn posts_without_a_title_or_date_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write_post(root, "blog/kept", "Kept", "2024-01-02");
+        write_post(root, "blog/undated", "Undated", "");
+        fs::create_dir_all(root.join("blog/headless")).unwrap();
+        fs::write(root.join("blog/headless/index.dllu"), "Just a paragraph.\n").unwrap();
+        let mut config = config::Config::default();
+        config.html.blog_dir = Some("blog".into());
+        let spec = collection_specs(&config).remove(0);
+        let index_path = root.join("blog/index.dllu");
+
+        let before = diagnostics::warning_count();
+        let index = build_blog_index(&index_path, Some(root), &config, &spec)
+            .unwrap()
+            .unwrap();
+        let titles: Vec<&str> = index.entries.iter().map(|e| e.title.as_str()).collect();
+        assert_eq!(titles, ["Kept"]);
+        assert!(diagnostics::warning_count() >= before + 2);
+
+        config.lint.skipped_posts = config::LintLevel::Error;
+        let err = build_blog_index(&index_path, Some(root), &config, &spec)
+            .err()
+            .unwrap();
+        assert!(
+            err.ends_with("2 post(s) left out of the blog index"),
+            "{}",
+            err
+        );
+
+        // Sorted by title, undated posts belong in the index
+        let spec = CollectionSpec {
+            sort: config::CollectionSort::Title,
+            ..spec
+        };
+        let err = build_blog_index(&index_path, Some(root), &config, &spec)
+            .err()
+            .unwrap();
+        assert!(
+            err.ends_with("1 post(s) left out of the blog index"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn feed_fragment_links_point_at_the_post() {
+        assert_eq!(
+            absolutize_fragment_links(
+                "<a href=\"#s1.2\">x</a> <a class=\"fignum\" href=\"#fig1\">y</a> \
+                 <a href=\"other#s1\">z</a> <a href=\"https://a.b/#top\">w</a>",
+                "https://example.com/blog/post?a&b",
+            ),
+            "<a href=\"https://example.com/blog/post?a&amp;b#s1.2\">x</a> \
+             <a class=\"fignum\" href=\"https://example.com/blog/post?a&amp;b#fig1\">y</a> \
+             <a href=\"other#s1\">z</a> <a href=\"https://a.b/#top\">w</a>"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("blog/post")).unwrap();
+        fs::write(
+            root.join("blog/post/index.dllu"),
+            "Post\n\n2024-01-02\n\n===\n\n# Intro\n\nBack to [the intro](#s1).\n",
+        )
+        .unwrap();
+        let mut config = config::Config {
+            root_url: Some("https://example.com".into()),
+            ..Default::default()
+        };
+        config.html.blog_dir = Some("blog".into());
+        let spec = collection_specs(&config).remove(0);
+        let index = build_blog_index(&root.join("blog/index.dllu"), Some(root), &config, &spec)
+            .unwrap()
+            .unwrap();
+        generate_rss_feed(Some(root), &index, &config, &spec.feed, spec.mode).unwrap();
+        let feed = fs::read_to_string(root.join("blog/rss.xml")).unwrap();
+        assert!(
+            feed.contains("https://example.com/blog/post#s1"),
+            "{}",
+            feed
+        );
+        assert!(!feed.contains("href=&quot;#s1") && !feed.contains("href=\"#s1"));
+    }
+
+    #[test]
+    fn photo_stream_indexes_are_marked_and_newest_first() {
+        let (dir, config) = photo_site();
+        let root = dir.path();
+        let spec = collection_specs(&config).remove(0);
+        let index = build_blog_index(&root.join("photos/index.dllu"), Some(root), &config, &spec)
+            .unwrap()
+            .unwrap();
+        assert!(index
+            .html
+            .starts_with("<nav id=\"blogposts\" class=\"photos\"><a href=\"bridge\">"));
+        let titles: Vec<&str> = index.entries.iter().map(|e| e.title.as_str()).collect();
+        assert_eq!(titles, ["Bridge", "Market", "Harbour"]);
+
+        let spec = CollectionSpec {
+            mode: config::BlogMode::Posts,
+            ..spec
+        };
+        let index = build_blog_index(&root.join("photos/index.dllu"), Some(root), &config, &spec)
+            .unwrap()
+            .unwrap();
+        assert!(index
+            .html
+            .starts_with("<nav id=\"blogposts\"><a href=\"bridge\">"));
+    }
+
+    #[test]
+    fn photo_stream_posts_link_their_neighbours() {
+        let (dir, config) = photo_site();
+        let root = dir.path();
+        let spec = collection_specs(&config).remove(0);
+        let nav = |slug: &str| {
+            let source = root.join("photos").join(slug).join("index.dllu");
+            photo_stream_nav(&source, Some(root), &config, &spec)
+        };
+        assert_eq!(
+            nav("market").unwrap(),
+            "<nav class=\"photonav\">\
+             <a rel=\"prev\" href=\"../bridge\"><span>Newer</span> Bridge</a>\
+             <a rel=\"next\" href=\"../harbour\"><span>Older</span> Harbour</a></nav>"
+        );
+        assert_eq!(
+            nav("bridge").unwrap(),
+            "<nav class=\"photonav\">\
+             <a rel=\"next\" href=\"../market\"><span>Older</span> Market</a></nav>"
+        );
+        assert_eq!(
+            nav("harbour").unwrap(),
+            "<nav class=\"photonav\">\
+             <a rel=\"prev\" href=\"../market\"><span>Newer</span> Market</a></nav>"
+        );
+        assert_eq!(
+            photo_stream_nav(&root.join("photos/index.dllu"), Some(root), &config, &spec),
+            None
+        );
+
+        let spec = CollectionSpec {
+            mode: config::BlogMode::Posts,
+            ..spec
+        };
+        let source = root.join("photos/market/index.dllu");
+        assert_eq!(photo_stream_nav(&source, Some(root), &config, &spec), None);
+    }
+
+    #[test]
+    fn photo_feed_items_lead_with_the_photo() {
+        let image = photo("https://example.com/photos/a-800.jpg", " Boats at dawn ");
+        assert_eq!(
+            photo_feed_content(&image, Some("Boats at dawn")),
+            "<p><img src=\"https://example.com/photos/a-800.jpg\" alt=\"Boats\" \
+             width=\"800\" height=\"600\"></p><p>Boats at dawn</p>"
+        );
+        let image = html_renderer::PageImage {
+            width: None,
+            caption: String::new(),
+            ..image
+        };
+        assert_eq!(
+            photo_feed_content(&image, Some("Fog & <boats>")),
+            "<p><img src=\"https://example.com/photos/a-800.jpg\" alt=\"Boats\"></p>\
+             <p>Fog &amp; &lt;boats&gt;</p>"
+        );
+    }
+
+    #[test]
+    fn photo_stream_feeds_carry_the_photo_as_media_content() {
+        let (dir, mut config) = photo_site();
+        let root = dir.path();
+        config.root_url = Some("https://example.com".into());
+        let spec = collection_specs(&config).remove(0);
+        let mut index =
+            build_blog_index(&root.join("photos/index.dllu"), Some(root), &config, &spec)
+                .unwrap()
+                .unwrap();
+        index.entries[0].image = Some(photo(
+            "https://example.com/photos/bridge/bridge-800.jpg",
+            "Bridge",
+        ));
+
+        let feed_path = root.join("photos/rss.xml");
+        generate_rss_feed(Some(root), &index, &config, &spec.feed, spec.mode).unwrap();
+        let feed = fs::read_to_string(&feed_path).unwrap();
+        assert_eq!(feed.matches("medium=\"image\"").count(), 1);
+        assert!(feed.contains("url=\"https://example.com/photos/bridge/bridge.jpg\""));
+        assert!(feed.contains("https://example.com/photos/bridge/bridge-800.jpg"));
+        // Posts without a figure keep their whole content
+        assert!(feed.contains("Body of Market."));
+
+        // Outside a photo stream, items carry the whole post and no media
+        fs::remove_file(&feed_path).unwrap();
+        generate_rss_feed(
+            Some(root),
+            &index,
+            &config,
+            &spec.feed,
+            config::BlogMode::Posts,
+        )
+        .unwrap();
+        let feed = fs::read_to_string(&feed_path).unwrap();
+        assert!(!feed.contains("media:content"));
+        assert!(!feed.contains("bridge-800.jpg"));
     }
-    result
 }
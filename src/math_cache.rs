@@ -0,0 +1,141 @@
+//! Rendered equations kept on disk as `math/<hash>.html` in `images.cache_dir`,
+//! so a build only runs the math engine for equations it has not seen before.
+//! The hash covers the TeX, whether it is inline and the `[math]` settings that
+//! change the output, so editing a macro renders everything again.
+//! `dllup-rs warm-math` fills the cache for a whole site ahead of a build.
+
+use crate::config;
+use crate::disk_cache::DiskCache;
+use crate::html_renderer::HtmlRenderer;
+use crate::walk;
+use dllup_rs::parser::Parser;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+pub struct MathCache {
+    cache: DiskCache,
+}
+
+impl MathCache {
+    /// The cache for `config`, or `None` when it renders no math.
+    pub fn new(config: &config::Config) -> Option<Self> {
+        if config.math.engine == config::MathEngineKind::None {
+            return None;
+        }
+        let math = &config.math;
+        let settings = format!(
+            "{:?} {:?} {:?} {:?} {:?}",
+            math.engine, math.prefer_persistent, math.command, math.katex_extensions, math.macros
+        );
+        Some(Self {
+            cache: DiskCache::new(
+                Path::new(&config.images.cache_dir).join("math"),
+                "html",
+                settings,
+            ),
+        })
+    }
+
+    fn settings(&self) -> &str {
+        self.cache.settings()
+    }
+
+    fn key(tex: &str, inline: bool) -> String {
+        format!("{}\n{}", if inline { "inline" } else { "display" }, tex)
+    }
+
+    pub fn get(&self, tex: &str, inline: bool) -> Option<String> {
+        self.cache.get(&Self::key(tex, inline))
+    }
+
+    pub fn put(&self, tex: &str, inline: bool, html: &str) {
+        self.cache.put(&Self::key(tex, inline), html);
+    }
+}
+
+/// Equations sharing the settings of the cache key, rendered with `config`.
+struct WarmJob {
+    config: config::Config,
+    settings: String,
+    equations: Vec<(String, bool)>,
+}
+
+/// `dllup-rs warm-math <dir> [config.toml]`: renders every equation of the
+/// site into the cache at once, spread over all the render threads.
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.is_empty() || args.len() > 2 {
+        return Err("Usage: dllup-rs warm-math <directory> [config.toml]".into());
+    }
+    let dir = Path::new(&args[0]);
+    let explicit_config = match args.get(1) {
+        Some(path) => Some(config::Config::load(Path::new(path))?),
+        None => None,
+    };
+    let site_config = crate::site_config(dir, explicit_config.as_ref())?;
+    let files = walk::collect_dllu_files(dir, &site_config.build)?;
+
+    // Grouped by the settings in the cache key, since macros and engines can
+    // differ between directories
+    let mut jobs: Vec<WarmJob> = Vec::new();
+    let mut seen: HashSet<(String, String, bool)> = HashSet::new();
+    for file in &files {
+        let config = crate::resolve_config(file, explicit_config.as_ref())?;
+        let Some(cache) = MathCache::new(&config) else {
+            continue;
+        };
+        let source = fs::read_to_string(file)
+            .map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+        let mut parser = Parser::with_includes(file);
+        parser.parse(&source);
+        if let Err(e) = crate::plugins::apply(&config.plugins, &mut parser.article, file) {
+            crate::diagnostics::warn(format!("[warm-math] {}: {}", file.display(), e));
+            continue;
+        }
+        let equations: Vec<(String, bool)> = parser
+            .article
+            .equations()
+            .into_iter()
+            .filter(|&(tex, inline)| {
+                seen.insert((cache.settings().to_string(), tex.to_string(), inline))
+            })
+            .map(|(tex, inline)| (tex.to_string(), inline))
+            .collect();
+        match jobs.iter_mut().find(|job| job.settings == cache.settings()) {
+            Some(job) => job.equations.extend(equations),
+            None => jobs.push(WarmJob {
+                config,
+                settings: cache.settings().to_string(),
+                equations,
+            }),
+        }
+    }
+
+    let total: usize = jobs.iter().map(|job| job.equations.len()).sum();
+    let mut failed = 0;
+    for job in &jobs {
+        failed += job
+            .equations
+            .par_iter()
+            .map_init(
+                || HtmlRenderer::with_asset_root(&job.config, dir.to_path_buf()),
+                |renderer, (tex, inline)| renderer.warm_math(tex, *inline),
+            )
+            .filter(|rendered| !rendered)
+            .count();
+    }
+    // Equations already in the cache are only read back
+    println!(
+        "{} equation(s) from {} file(s) are in the math cache",
+        total - failed,
+        files.len()
+    );
+    if failed > 0 {
+        crate::diagnostics::warn(format!(
+            "[warm-math] {} equation(s) failed and will be shown as TeX",
+            failed
+        ));
+    }
+    Ok(())
+}
@@ -1,13 +1,41 @@
 use serde::{Deserialize, Serialize};
-use std::io::{BufRead, BufReader, Write};
-use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub trait MathEngine {
     fn tex_to_html(&mut self, latex: &str, inline: bool) -> Result<String, String>;
+
+    /// Whether formulas are TeX; display math is then wrapped in `aligned`.
+    fn takes_tex(&self) -> bool {
+        true
+    }
 }
 
 pub struct ExternalCmdEngine {
     pub cmd: Vec<String>,
+    pub macros: BTreeMap<String, String>,
+    /// The command is killed if it runs longer than this.
+    pub timeout: Option<Duration>,
+}
+
+impl ExternalCmdEngine {
+    fn command_line(&self, inline: bool) -> Vec<String> {
+        let mut parts = self.cmd.clone();
+        // KaTeX CLI: read TeX from stdin, write HTML to stdout; add display flag if needed
+        if !inline {
+            parts.push("--display-mode".to_string());
+        }
+        for (name, expansion) in &self.macros {
+            parts.push("--macro".to_string());
+            parts.push(format!("{}:{}", name, expansion));
+        }
+        parts
+    }
 }
 
 impl MathEngine for ExternalCmdEngine {
@@ -15,45 +43,210 @@ impl MathEngine for ExternalCmdEngine {
         if self.cmd.is_empty() {
             return Err("no command configured".into());
         }
-        let mut parts = self.cmd.clone();
-        // KaTeX CLI: read TeX from stdin, write HTML to stdout; add display flag if needed
         if !inline {
-            parts.push("--display-mode".to_string());
             dbg!(&latex);
         }
-        let (prog, args) = parts.split_first().unwrap();
-        let mut child = Command::new(prog)
-            .args(args)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .map_err(|e| format!("failed to spawn math command: {}", e))?;
-        {
-            let stdin = child.stdin.as_mut().ok_or("failed to open stdin")?;
-            stdin
-                .write_all(latex.as_bytes())
-                .map_err(|e| format!("failed to write TeX to stdin: {}", e))?;
+        run_with_stdin(&self.command_line(inline), latex, self.timeout)
+    }
+}
+
+/// Renders formulas written in Typst math syntax to inline SVG by running
+/// `typst compile` once per formula; selected with `math.engine = "typst"`.
+pub struct TypstEngine {
+    pub cmd: Vec<String>,
+    pub timeout: Option<Duration>,
+}
+
+impl TypstEngine {
+    fn document(formula: &str, inline: bool) -> String {
+        // Spaces inside `$ ... $` make Typst typeset the formula as a block.
+        let (open, close) = if inline { ("$", "$") } else { ("$ ", " $") };
+        format!(
+            "#set page(width: auto, height: auto, margin: 0pt, fill: none)\n#set text(size: 12pt)\n{}{}{}\n",
+            open,
+            formula.trim(),
+            close
+        )
+    }
+}
+
+impl MathEngine for TypstEngine {
+    fn tex_to_html(&mut self, latex: &str, inline: bool) -> Result<String, String> {
+        if self.cmd.is_empty() {
+            return Err("no typst command configured".into());
         }
-        let out = child
-            .wait_with_output()
-            .map_err(|e| format!("failed waiting for math command: {}", e))?;
-        if out.status.success() {
-            Ok(String::from_utf8_lossy(&out.stdout).to_string())
+        let mut parts = self.cmd.clone();
+        parts.extend(["compile", "--format", "svg", "-", "-"].map(String::from));
+        let out = run_with_stdin(&parts, &Self::document(latex, inline), self.timeout)?;
+        let svg = out
+            .find("<svg")
+            .map(|start| out[start..].trim_end())
+            .ok_or("typst produced no SVG")?;
+        let class = if inline {
+            "typst-math"
         } else {
-            Err(format!(
-                "math command failed: status {} stderr {}",
-                out.status,
-                String::from_utf8_lossy(&out.stderr)
-            ))
+            "typst-math typst-display"
+        };
+        Ok(format!("<span class=\"{}\">{}</span>", class, svg))
+    }
+
+    fn takes_tex(&self) -> bool {
+        false
+    }
+}
+
+/// Runs `parts`, feeding `input` on stdin, and returns stdout on success.
+/// A command that times out is skipped for the rest of the build, so a hung
+/// engine costs one timeout rather than one per equation.
+fn run_with_stdin(
+    parts: &[String],
+    input: &str,
+    timeout: Option<Duration>,
+) -> Result<String, String> {
+    let (prog, args) = parts.split_first().ok_or("no command configured")?;
+    if STALLED_COMMANDS
+        .lock()
+        .expect("stalled command set poisoned")
+        .contains(parts)
+    {
+        return Err(format!("{} timed out earlier in this build", prog));
+    }
+    let mut child = Command::new(prog)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to spawn math command: {}", e))?;
+    {
+        let mut stdin = child.stdin.take().ok_or("failed to open stdin")?;
+        stdin
+            .write_all(input.as_bytes())
+            .map_err(|e| format!("failed to write TeX to stdin: {}", e))?;
+    }
+    let stdout = read_to_end_in_background(child.stdout.take());
+    let stderr = read_to_end_in_background(child.stderr.take());
+    let Some(status) = wait_with_timeout(&mut child, timeout)? else {
+        STALLED_COMMANDS
+            .lock()
+            .expect("stalled command set poisoned")
+            .insert(parts.to_vec());
+        return Err(format!(
+            "math command timed out after {:?}",
+            timeout.unwrap_or_default()
+        ));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    if status.success() {
+        Ok(String::from_utf8_lossy(&stdout).to_string())
+    } else {
+        Err(format!(
+            "math command failed: status {} stderr {}",
+            status,
+            String::from_utf8_lossy(&stderr.join().unwrap_or_default())
+        ))
+    }
+}
+
+fn read_to_end_in_background<R: Read + Send + 'static>(
+    pipe: Option<R>,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Waits for `child`, killing it once `timeout` has passed; `None` when it
+/// was killed.
+fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+) -> Result<Option<ExitStatus>, String> {
+    let wait_error = |e: std::io::Error| format!("failed waiting for math command: {}", e);
+    let Some(timeout) = timeout else {
+        return child.wait().map(Some).map_err(wait_error);
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().map_err(wait_error)? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Tries each engine in order and returns the first non-empty rendering, so a
+/// hung or missing persistent engine falls back to the external command. The
+/// renderer emits escaped TeX when every engine fails.
+pub struct FallbackEngine {
+    pub engines: Vec<Box<dyn MathEngine>>,
+}
+
+impl MathEngine for FallbackEngine {
+    fn tex_to_html(&mut self, latex: &str, inline: bool) -> Result<String, String> {
+        let mut errors = Vec::new();
+        for engine in &mut self.engines {
+            match engine.tex_to_html(latex, inline) {
+                Ok(html) if !html.trim().is_empty() => return Ok(html),
+                Ok(_) => errors.push("empty output".to_string()),
+                Err(e) => errors.push(e),
+            }
         }
+        Err(errors.join("; "))
+    }
+}
+
+/// KaTeX run by the JavaScript engine embedded with the `katex` crate, so
+/// no Node.js is needed; selected with `math.engine = "native"`.
+#[cfg(feature = "native-math")]
+pub struct NativeKatexEngine {
+    pub macros: BTreeMap<String, String>,
+}
+
+#[cfg(feature = "native-math")]
+impl MathEngine for NativeKatexEngine {
+    fn tex_to_html(&mut self, latex: &str, inline: bool) -> Result<String, String> {
+        let mut opts = katex::Opts::builder()
+            .display_mode(!inline)
+            .throw_on_error(true)
+            .build()
+            .map_err(|e| e.to_string())?;
+        for (name, expansion) in &self.macros {
+            opts.add_macro(name.clone(), expansion.clone());
+        }
+        katex::render_with_opts(latex, &opts).map_err(|e| e.to_string())
     }
 }
 
 pub struct PersistentKatexEngine {
-    _child: Child,
+    child: Child,
     stdin: ChildStdin,
-    stdout: BufReader<ChildStdout>,
+    /// Lines from the child's stdout, read on a background thread so a response
+    /// can be awaited with a timeout.
+    responses: Receiver<String>,
+    timeout: Option<Duration>,
+    /// Set once the child missed the timeout and was killed.
+    timed_out: bool,
+}
+
+/// Settings fixed when a persistent KaTeX process starts.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct KatexOptions {
+    pub macros: BTreeMap<String, String>,
+    /// `katex/contrib/<name>` modules (or full module paths) required at startup.
+    pub extensions: Vec<String>,
+    /// Per-equation limit; a child that exceeds it is killed. Not sent to node.
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -67,32 +260,67 @@ struct KatexResp {
 }
 
 impl PersistentKatexEngine {
-    pub fn spawn() -> Result<Self, String> {
-        // Node inline script: loads katex once, reads JSON lines on stdin, writes JSON lines on stdout
+    pub fn spawn(options: &KatexOptions) -> Result<Self, String> {
+        Self::start(Self::command(options)?, options.timeout)
+    }
+
+    /// The node command running the helper for `options`.
+    fn command(options: &KatexOptions) -> Result<Command, String> {
+        // Node inline script: loads katex once, reads JSON lines on stdin, writes JSON lines on stdout.
+        // Options arrive as a JSON argument; macros are copied per call so `\gdef` cannot leak between
+        // equations, and extensions that fail to load are reported on stderr.
         let script = r#"const katex = require('katex');
+const options = JSON.parse(process.argv[1] || '{}');
+const macros = options.macros || {};
+for (const ext of options.extensions || []) {
+  try {
+    require(ext.includes('/') ? ext : 'katex/contrib/' + ext);
+  } catch (e) {
+    process.stderr.write('failed to load KaTeX extension ' + ext + ': ' + e.message + '\n');
+  }
+}
 const rl = require('readline').createInterface({ input: process.stdin, crlfDelay: Infinity });
 rl.on('line', (line) => {
   try {
     const m = JSON.parse(line);
-    const html = katex.renderToString(m.tex, { displayMode: !m.inline, throwOnError: false });
+    const html = katex.renderToString(m.tex, { displayMode: !m.inline, throwOnError: false, macros: { ...macros } });
     process.stdout.write(JSON.stringify({ html }) + '\n');
   } catch (e) {
     process.stdout.write(JSON.stringify({ html: '' }) + '\n');
   }
 });"#;
-        let mut child = Command::new("node")
+        let mut command = Command::new("node");
+        command
             .arg("-e")
             .arg(script)
+            .arg(serde_json::to_string(options).map_err(|e| e.to_string())?);
+        Ok(command)
+    }
+
+    /// Starts `command` as a worker answering [`KatexReq`] lines with [`KatexResp`] lines.
+    fn start(mut command: Command, timeout: Option<Duration>) -> Result<Self, String> {
+        let mut child = command
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn()
             .map_err(|e| format!("failed to spawn node: {}", e))?;
         let stdin = child.stdin.take().ok_or("failed to open node stdin")?;
         let stdout = child.stdout.take().ok_or("failed to open node stdout")?;
+        let (sender, responses) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
         Ok(Self {
-            _child: child,
+            child,
             stdin,
-            stdout: BufReader::new(stdout),
+            responses,
+            timeout,
+            timed_out: false,
         })
     }
 }
@@ -104,12 +332,353 @@ impl MathEngine for PersistentKatexEngine {
         self.stdin
             .write_all(line.as_bytes())
             .map_err(|e| e.to_string())?;
-        let mut out = String::new();
-        self.stdout.read_line(&mut out).map_err(|e| e.to_string())?;
-        if out.is_empty() {
-            return Err("no response from katex child".into());
-        }
+        let out = match self.timeout {
+            Some(timeout) => self.responses.recv_timeout(timeout),
+            None => self
+                .responses
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let out = match out {
+            Ok(out) => out,
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                self.timed_out = true;
+                return Err(format!(
+                    "katex child timed out after {:?}",
+                    self.timeout.unwrap_or_default()
+                ));
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err("no response from katex child".into())
+            }
+        };
         let resp: KatexResp = serde_json::from_str(out.trim_end()).map_err(|e| e.to_string())?;
         Ok(resp.html)
     }
 }
+
+/// Persistent KaTeX children shared by every renderer in the process, so pages
+/// rendered in parallel don't serialize their math through a single node
+/// process. Pools are keyed by [`KatexOptions`] since those are fixed at spawn time.
+pub struct KatexPool {
+    options: KatexOptions,
+    size: usize,
+    /// Starts a worker: [`PersistentKatexEngine::spawn`], or a stand-in in tests.
+    spawn: fn(&KatexOptions) -> Result<PersistentKatexEngine, String>,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<PersistentKatexEngine>,
+    spawned: usize,
+    /// Set once a worker timed out; the pool then refuses further equations
+    /// so the fallback engines take over without waiting again.
+    stalled: bool,
+}
+
+lazy_static! {
+    static ref KATEX_POOLS: Mutex<HashMap<String, Arc<KatexPool>>> = Mutex::new(HashMap::new());
+    /// Commands that timed out, with their arguments.
+    static ref STALLED_COMMANDS: Mutex<HashSet<Vec<String>>> = Mutex::new(HashSet::new());
+}
+
+impl KatexPool {
+    /// Returns the process-wide pool for `options`, spawning its first worker to
+    /// check that node and KaTeX are available. Up to `size` workers are started
+    /// on demand; further requests queue until a worker is free.
+    pub fn shared(options: &KatexOptions, size: usize) -> Result<Arc<Self>, String> {
+        let key = serde_json::to_string(options).map_err(|e| e.to_string())?;
+        let mut pools = KATEX_POOLS.lock().expect("katex pool registry poisoned");
+        if let Some(pool) = pools.get(&key) {
+            return Ok(Arc::clone(pool));
+        }
+        let pool = Arc::new(Self::new(options, size, PersistentKatexEngine::spawn)?);
+        pools.insert(key, Arc::clone(&pool));
+        Ok(pool)
+    }
+
+    fn new(
+        options: &KatexOptions,
+        size: usize,
+        spawn: fn(&KatexOptions) -> Result<PersistentKatexEngine, String>,
+    ) -> Result<Self, String> {
+        let first = spawn(options)?;
+        Ok(Self {
+            options: options.clone(),
+            size: size.max(1),
+            spawn,
+            state: Mutex::new(PoolState {
+                idle: vec![first],
+                spawned: 1,
+                stalled: false,
+            }),
+            available: Condvar::new(),
+        })
+    }
+
+    fn checkout(&self) -> Result<PersistentKatexEngine, String> {
+        let mut state = self.state.lock().expect("katex pool poisoned");
+        loop {
+            if state.stalled {
+                return Err("katex child timed out earlier in this build".into());
+            }
+            if let Some(worker) = state.idle.pop() {
+                return Ok(worker);
+            }
+            if state.spawned < self.size {
+                state.spawned += 1;
+                drop(state);
+                return (self.spawn)(&self.options).inspect_err(|_| self.checkin(None));
+            }
+            state = self.available.wait(state).expect("katex pool poisoned");
+        }
+    }
+
+    /// Returns a worker to the pool; `None` retires a worker that failed so a
+    /// fresh one can be spawned in its place.
+    fn checkin(&self, worker: Option<PersistentKatexEngine>) {
+        let mut state = self.state.lock().expect("katex pool poisoned");
+        match worker {
+            Some(worker) => state.idle.push(worker),
+            None => state.spawned -= 1,
+        }
+        self.available.notify_one();
+    }
+
+    /// Retires a worker that timed out and stops handing out the others.
+    fn stall(&self) {
+        let mut state = self.state.lock().expect("katex pool poisoned");
+        state.spawned -= 1;
+        state.stalled = true;
+        self.available.notify_all();
+    }
+}
+
+/// A renderer's handle on a [`KatexPool`].
+pub struct PooledKatexEngine {
+    pool: Arc<KatexPool>,
+}
+
+impl PooledKatexEngine {
+    pub fn new(pool: Arc<KatexPool>) -> Self {
+        Self { pool }
+    }
+}
+
+impl MathEngine for PooledKatexEngine {
+    fn tex_to_html(&mut self, latex: &str, inline: bool) -> Result<String, String> {
+        let mut worker = self.pool.checkout()?;
+        let result = worker.tex_to_html(latex, inline);
+        if worker.timed_out {
+            self.pool.stall();
+        } else {
+            self.pool.checkin(result.is_ok().then_some(worker));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn external_command_passes_macros_as_katex_flags() {
+        let engine = ExternalCmdEngine {
+            cmd: vec!["katex".into()],
+            macros: BTreeMap::from([("\\R".to_string(), "\\mathbb{R}".to_string())]),
+            timeout: None,
+        };
+        assert_eq!(
+            engine.command_line(false),
+            vec!["katex", "--display-mode", "--macro", "\\R:\\mathbb{R}"]
+        );
+        assert_eq!(
+            engine.command_line(true),
+            vec!["katex", "--macro", "\\R:\\mathbb{R}"]
+        );
+    }
+
+    #[test]
+    fn typst_documents_fit_the_formula() {
+        let inline = TypstEngine::document("x^2", true);
+        assert!(inline.contains("#set page(width: auto, height: auto, margin: 0pt"));
+        assert!(inline.ends_with("$x^2$\n"));
+        assert!(TypstEngine::document(" sum_(i=1)^n i ", false).ends_with("$ sum_(i=1)^n i $\n"));
+    }
+
+    #[cfg(feature = "native-math")]
+    #[test]
+    fn native_engine_renders_with_macros() {
+        let mut engine = NativeKatexEngine {
+            macros: BTreeMap::from([("\\R".to_string(), "\\mathbb{R}".to_string())]),
+        };
+        let inline = engine.tex_to_html("x \\in \\R", true).unwrap();
+        assert!(inline.starts_with("<span class=\"katex\">"), "{}", inline);
+        assert!(inline.contains("mathbb"), "{}", inline);
+        let display = engine.tex_to_html("x^2", false).unwrap();
+        assert!(
+            display.starts_with("<span class=\"katex-display\">"),
+            "{}",
+            display
+        );
+        assert!(engine.tex_to_html("\\frac{", true).is_err());
+    }
+
+    #[test]
+    fn fallback_moves_past_a_hung_engine() {
+        let command = |cmd: &[&str], timeout| ExternalCmdEngine {
+            cmd: cmd.iter().map(|s| s.to_string()).collect(),
+            macros: BTreeMap::new(),
+            timeout,
+        };
+        let mut engine = FallbackEngine {
+            engines: vec![
+                Box::new(command(&["sleep", "5"], Some(Duration::from_millis(100)))),
+                Box::new(command(&["cat"], None)),
+            ],
+        };
+        let start = Instant::now();
+        assert_eq!(engine.tex_to_html("x^2", true).unwrap(), "x^2");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stalled_commands_are_killed_and_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let script = format!("echo $$ > '{}'; exec sleep 30", pid_file.display());
+        let mut engine = ExternalCmdEngine {
+            cmd: vec!["sh".into(), "-c".into(), script],
+            macros: BTreeMap::new(),
+            timeout: Some(Duration::from_millis(300)),
+        };
+        let start = Instant::now();
+        let err = engine.tex_to_html("x", true).unwrap_err();
+        assert!(err.contains("timed out after"), "{}", err);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        assert!(!std::path::Path::new("/proc").join(pid.trim()).exists());
+
+        // Later equations don't wait for the same command again
+        let start = Instant::now();
+        let err = engine.tex_to_html("y", true).unwrap_err();
+        assert!(err.contains("timed out earlier"), "{}", err);
+        assert!(start.elapsed() < Duration::from_millis(300));
+    }
+
+    #[test]
+    fn helper_loads_extensions_and_copies_macros() {
+        // A stand-in katex package reporting what the helper asked of it
+        let dir = tempfile::tempdir().unwrap();
+        let katex = dir.path().join("node_modules/katex");
+        std::fs::create_dir_all(katex.join("contrib")).unwrap();
+        std::fs::write(
+            katex.join("index.js"),
+            "module.exports = { renderToString: (tex, opts) => JSON.stringify(\
+             { tex, display: opts.displayMode, macros: opts.macros, mhchem: !!global.mhchem }) };",
+        )
+        .unwrap();
+        std::fs::write(katex.join("contrib/mhchem.js"), "global.mhchem = true;").unwrap();
+
+        let options = KatexOptions {
+            macros: BTreeMap::from([("\\R".to_string(), "\\mathbb{R}".to_string())]),
+            extensions: vec!["mhchem".into(), "missing".into()],
+            timeout: Some(Duration::from_secs(10)),
+        };
+        let mut command = PersistentKatexEngine::command(&options).unwrap();
+        command.current_dir(dir.path()).stderr(Stdio::null());
+        let Ok(mut engine) = PersistentKatexEngine::start(command, options.timeout) else {
+            eprintln!("skipping KaTeX helper test: node is not installed");
+            return;
+        };
+        let html = engine.tex_to_html("\\ce{H2O}", false).unwrap();
+        let rendered: serde_json::Value = serde_json::from_str(&html).unwrap();
+        assert_eq!(rendered["tex"], "\\ce{H2O}");
+        assert_eq!(rendered["display"], true);
+        assert_eq!(rendered["macros"]["\\R"], "\\mathbb{R}");
+        // A missing extension is reported but doesn't stop the others loading
+        assert_eq!(rendered["mhchem"], true);
+    }
+
+    /// A pool worker running `script` under `sh` in place of node.
+    #[cfg(unix)]
+    fn sh_worker(script: &str, options: &KatexOptions) -> Result<PersistentKatexEngine, String> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        PersistentKatexEngine::start(command, options.timeout)
+    }
+
+    /// Answers every equation with its own process id.
+    #[cfg(unix)]
+    fn pid_worker(options: &KatexOptions) -> Result<PersistentKatexEngine, String> {
+        sh_worker(
+            r#"while read -r line; do echo "{\"html\":\"$$\"}"; done"#,
+            options,
+        )
+    }
+
+    #[cfg(unix)]
+    fn broken_worker(options: &KatexOptions) -> Result<PersistentKatexEngine, String> {
+        sh_worker("while read -r line; do echo oops; done", options)
+    }
+
+    #[cfg(unix)]
+    fn silent_worker(options: &KatexOptions) -> Result<PersistentKatexEngine, String> {
+        sh_worker("cat > /dev/null", options)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pool_shares_at_most_size_workers() {
+        let pool = Arc::new(KatexPool::new(&KatexOptions::default(), 2, pid_worker).unwrap());
+        let renders: Vec<thread::JoinHandle<Vec<String>>> = (0..4)
+            .map(|_| {
+                let mut engine = PooledKatexEngine::new(Arc::clone(&pool));
+                thread::spawn(move || {
+                    (0..20)
+                        .map(|_| engine.tex_to_html("x", true).unwrap())
+                        .collect()
+                })
+            })
+            .collect();
+        let workers: HashSet<String> = renders
+            .into_iter()
+            .flat_map(|render| render.join().unwrap())
+            .collect();
+        assert!((1..=2).contains(&workers.len()), "{:?}", workers);
+        let state = pool.state.lock().unwrap();
+        assert_eq!(state.spawned, workers.len());
+        assert_eq!(state.idle.len(), state.spawned);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pool_retires_failed_workers_and_stalls_after_a_timeout() {
+        let pool = Arc::new(KatexPool::new(&KatexOptions::default(), 1, broken_worker).unwrap());
+        let mut engine = PooledKatexEngine::new(Arc::clone(&pool));
+        assert!(engine.tex_to_html("x", true).is_err());
+        assert_eq!(pool.state.lock().unwrap().spawned, 0);
+        // A new worker takes the failed one's place
+        assert!(engine.tex_to_html("x", true).is_err());
+        assert!(pool.state.lock().unwrap().idle.is_empty());
+
+        let options = KatexOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let pool = Arc::new(KatexPool::new(&options, 2, silent_worker).unwrap());
+        let mut engine = PooledKatexEngine::new(Arc::clone(&pool));
+        let err = engine.tex_to_html("x", true).unwrap_err();
+        assert!(err.contains("timed out after"), "{}", err);
+        let start = Instant::now();
+        let err = engine.tex_to_html("y", true).unwrap_err();
+        assert!(err.contains("timed out earlier"), "{}", err);
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert!(pool.state.lock().unwrap().stalled);
+    }
+}
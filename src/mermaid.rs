@@ -0,0 +1,50 @@
+//! `~~~ lang mermaid` diagrams drawn at build time with the Mermaid CLI
+//! (`mmdc`) for `html.mermaid = "server"`. Each diagram is cached as
+//! `mermaid-<hash>.svg` by a hash of its source, so mmdc only runs for new or
+//! changed diagrams.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// SVG markup of the diagram, ready to put in a page.
+pub fn render_svg(code: &str, cache_dir: &Path) -> Result<String, String> {
+    let hash = blake3::hash(code.as_bytes()).to_hex();
+    // Also the id mmdc scopes the diagram's styles to, so diagrams on one page don't clash
+    let id = format!("mermaid-{}", &hash[..16]);
+    let path = cache_dir.join(format!("{}.svg", id));
+    if !path.exists() {
+        fs::create_dir_all(cache_dir)
+            .map_err(|e| format!("failed to create {}: {}", cache_dir.display(), e))?;
+        let input = cache_dir.join(format!("{}.mmd", id));
+        let partial = cache_dir.join(format!("{}.part.svg", id));
+        fs::write(&input, code)
+            .map_err(|e| format!("failed to write {}: {}", input.display(), e))?;
+        let result = Command::new("mmdc")
+            .arg("-i")
+            .arg(&input)
+            .arg("-o")
+            .arg(&partial)
+            .args(["-b", "transparent", "-I", &id, "-q"])
+            .output();
+        let _ = fs::remove_file(&input);
+        let rendered = match result {
+            Ok(out) if out.status.success() => {
+                fs::rename(&partial, &path).map_err(|e| e.to_string())
+            }
+            Ok(out) => Err(String::from_utf8_lossy(&out.stderr).trim().to_string()),
+            Err(err) => Err(format!("failed to run mmdc: {}", err)),
+        };
+        if rendered.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        rendered?;
+    }
+    let svg = fs::read_to_string(&path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    // An XML declaration may not appear inside HTML
+    Ok(match svg.find("<svg") {
+        Some(start) => svg[start..].trim_end().to_string(),
+        None => svg,
+    })
+}
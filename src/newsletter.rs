@@ -0,0 +1,453 @@
+use crate::config;
+use crate::html_renderer::{escape_html, unescape_html, HtmlRenderer};
+use crate::parser::Parser;
+use regex::{Captures, Regex};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const BODY_STYLE: &str =
+    "margin:0;padding:0;background:#ffffff;color:#111111;font-family:Helvetica,Arial,sans-serif;";
+const CONTAINER_STYLE: &str = "max-width:640px;margin:0 auto;padding:16px;";
+const IMG_STYLE: &str = "display:block;max-width:100%;height:auto;border:0;margin:0 auto;";
+
+// Inline styles applied to bare tags, since most mail clients drop <style> blocks.
+const TAG_STYLES: &[(&str, &str)] = &[
+    ("h1", "font-size:28px;line-height:1.2;margin:16px 0;"),
+    ("h2", "font-size:22px;line-height:1.3;margin:16px 0 8px;"),
+    ("h3", "font-size:18px;line-height:1.3;margin:16px 0 8px;"),
+    ("h4", "font-size:16px;margin:12px 0 8px;"),
+    ("h5", "font-size:16px;margin:12px 0 8px;"),
+    ("h6", "font-size:16px;margin:12px 0 8px;"),
+    ("p", "font-size:16px;line-height:1.6;margin:12px 0;"),
+    ("ul", "font-size:16px;line-height:1.6;margin:12px 0;padding-left:24px;"),
+    ("ol", "font-size:16px;line-height:1.6;margin:12px 0;padding-left:24px;"),
+    ("blockquote", "margin:12px 0;padding:0 12px;border-left:3px solid #c0c0c0;color:#404040;"),
+    ("pre", "font-family:Menlo,Consolas,monospace;font-size:13px;white-space:pre-wrap;padding:12px;background:#f3f2f0;"),
+    ("code", "font-family:Menlo,Consolas,monospace;font-size:14px;"),
+    ("figure", "margin:16px 0;"),
+    ("figcaption", "font-size:14px;line-height:1.4;color:#404040;"),
+    ("table", "border-collapse:collapse;margin:12px 0;"),
+    ("th", "border:1px solid #c0c0c0;padding:4px 8px;"),
+    ("td", "border:1px solid #c0c0c0;padding:4px 8px;"),
+    ("a", "color:#305ca9;"),
+];
+
+/// Entry point for `dllup-rs newsletter <post.dllu> [config.toml]`; prints the
+/// email-safe HTML to stdout.
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.is_empty() || args.len() > 2 {
+        return Err("Usage: dllup-rs newsletter <post.dllu> [config.toml]".into());
+    }
+    let input_path = Path::new(&args[0]);
+    let config_path = match args.get(1) {
+        Some(path) => PathBuf::from(path),
+        None => find_site_config(input_path),
+    };
+    let explicit_config = if config_path.exists() {
+        Some(config::Config::load(&config_path)?)
+    } else {
+        None
+    };
+    let site_root = config_path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let config = crate::resolve_config(input_path, explicit_config.as_ref())?;
+    let html = render_newsletter(input_path, site_root, &config)?;
+    print!("{}", html);
+    crate::image_processor::wait_for_pending_resizes();
+    Ok(())
+}
+
+/// The nearest `dllup.toml` above the post, since a post usually sits a few
+/// directories below the site root; `dllup.toml` beside the post when none
+/// exists, as for a single-file build.
+fn find_site_config(input_path: &Path) -> PathBuf {
+    let fallback = config::default_config_path(input_path);
+    let Some(dir) = fallback.parent() else {
+        return fallback;
+    };
+    dir.ancestors()
+        .map(|dir| dir.join("dllup.toml"))
+        .find(|path| path.exists())
+        .unwrap_or(fallback)
+}
+
+fn render_newsletter(
+    input_path: &Path,
+    site_root: &Path,
+    config: &config::Config,
+) -> Result<String, String> {
+    // Mail clients have no page to resolve links against
+    let Some((scheme, host)) = config.root_url.as_deref().and_then(config::url_origin) else {
+        return Err(
+            "newsletter needs root_url set to an absolute URL such as \"https://example.com\" to make its links and images work in email"
+                .into(),
+        );
+    };
+    let origin = format!("{}://{}", scheme, host);
+    let input = fs::read_to_string(input_path)
+        .map_err(|e| format!("Failed to read {}: {}", input_path.display(), e))?;
+    let mut parser = Parser::with_includes(input_path);
+    parser.parse(&input);
+    if let Some(e) = parser.include_errors.first() {
+        return Err(format!("{}: {}", input_path.display(), e));
+    }
+
+    let asset_root = input_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let permalink = crate::page_permalink(input_path, Some(site_root), config);
+    let mut render_config = config.clone();
+    render_config.html.copy_button = false;
+    // Mail clients don't play <video>, load frames or show inline SVG; a facade is a plain link
+    render_config.images.gif_to_video = false;
+    render_config.images.inline_svg_max_bytes = None;
+    render_config.html.embed_facade = true;
+    // No script draws diagrams in an email, so show their source instead
+    if render_config.html.mermaid == config::Mermaid::Client {
+        render_config.html.mermaid = config::Mermaid::Off;
+    }
+    let mut renderer =
+        HtmlRenderer::with_asset_root(&render_config, asset_root).without_math_engine();
+    renderer.set_page_url(permalink.clone());
+    renderer.set_site_data(crate::data::load_site_data(
+        input_path,
+        Some(site_root),
+        config,
+    )?);
+    let body = renderer.render(&parser.article);
+    crate::diagnostics::emit_image_errors(input_path, &input, renderer.image_errors());
+    let title = parser
+        .article
+        .header
+        .as_ref()
+        .map(|h| h.title.as_str())
+        .unwrap_or("Document");
+
+    let base_url = permalink
+        .as_deref()
+        .map(|url| page_base_url(url, input_path));
+    let mut content = strip_details(&body);
+    content = unfold_details(&content);
+    content = task_marks(&content);
+    if let Some(url_template) = &config.newsletter.math_image_url {
+        content = math_to_images(&content, url_template);
+    }
+    content = cap_images(&content, config.newsletter.image_width);
+    if let (Some(permalink), Some(base)) = (permalink.as_deref(), base_url.as_deref()) {
+        content = absolutize_urls(&content, permalink, base, &origin);
+    }
+    content = inline_styles(&content);
+
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str(&format!("<title>{}</title>\n", escape_html(title)));
+    out.push_str("</head>\n");
+    out.push_str(&format!("<body style=\"{}\">\n", BODY_STYLE));
+    out.push_str(&format!("<div style=\"{}\">\n", CONTAINER_STYLE));
+    out.push_str(&content);
+    if let Some(url) = permalink {
+        out.push_str(&inline_styles(&format!(
+            "<p><a href=\"{}\">View this post on the web</a></p>\n",
+            escape_html(&url)
+        )));
+    }
+    out.push_str("</div>\n</body>\n</html>\n");
+    Ok(out)
+}
+
+/// Relative references in a page resolve against its directory; `index` pages
+/// are published as their directory, so the permalink itself is the directory.
+fn page_base_url(permalink: &str, input_path: &Path) -> String {
+    let is_index = input_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .map(|s| s == "index")
+        .unwrap_or(false);
+    if is_index {
+        format!("{}/", permalink.trim_end_matches('/'))
+    } else {
+        match permalink.rfind('/') {
+            Some(pos) => permalink[..=pos].to_string(),
+            None => format!("{}/", permalink),
+        }
+    }
+}
+
+fn strip_details(html: &str) -> String {
+    lazy_static! {
+        static ref DETAILS: Regex = Regex::new(r"(?s)<details>.*?</details>").unwrap();
+    }
+    DETAILS.replace_all(html, "").into_owned()
+}
+
+/// `+++` details blocks shown open with their summary as a bold first line,
+/// since few mail clients can expand them. The EXIF and download panels are
+/// gone by now, so every `</details>` left closes one of these.
+fn unfold_details(html: &str) -> String {
+    lazy_static! {
+        static ref FOLD: Regex =
+            Regex::new(r#"(?s)<details class="fold"><summary>(.*?)</summary>"#).unwrap();
+    }
+    FOLD.replace_all(html, "<div><p><strong>$1</strong></p>")
+        .replace("</details>", "</div>")
+}
+
+/// Task list checkboxes as ☑ and ☐, since mail clients drop form controls.
+fn task_marks(html: &str) -> String {
+    html.replace("<input type=\"checkbox\" disabled checked/>", "☑")
+        .replace("<input type=\"checkbox\" disabled/>", "☐")
+}
+
+/// Replaces the escaped-TeX math fallback with images from `url_template`,
+/// where `{tex}` is substituted with the percent-encoded TeX source.
+fn math_to_images(html: &str, url_template: &str) -> String {
+    lazy_static! {
+        static ref MATH: Regex = Regex::new(
+            r#"(?s)<span class="math-inline">(.*?)</span>|<div class="math-display">(.*?)</div>"#
+        )
+        .unwrap();
+    }
+    MATH.replace_all(html, |caps: &Captures| {
+        let (escaped, inline) = match caps.get(1) {
+            Some(m) => (m.as_str(), true),
+            None => (caps.get(2).map(|m| m.as_str()).unwrap_or(""), false),
+        };
+        let tex = unescape_html(escaped);
+        let src = url_template.replace("{tex}", &percent_encode(&tex));
+        let style = if inline {
+            "vertical-align:middle;border:0;"
+        } else {
+            IMG_STYLE
+        };
+        format!(
+            "<img src=\"{}\" alt=\"{}\" style=\"{}\"/>",
+            escape_html(&src),
+            escaped,
+            style
+        )
+    })
+    .into_owned()
+}
+
+/// Rewrites responsive `<img>` tags into a single fixed-width source no wider
+/// than `max_width`, since mail clients ignore srcset.
+fn cap_images(html: &str, max_width: u32) -> String {
+    lazy_static! {
+        static ref IMG: Regex = Regex::new(r"<img\s[^>]*>").unwrap();
+        static ref ATTR: Regex = Regex::new(r#"([A-Za-z-]+)="([^"]*)""#).unwrap();
+    }
+    IMG.replace_all(html, |caps: &Captures| {
+        let tag = &caps[0];
+        let attr = |name: &str| {
+            ATTR.captures_iter(tag)
+                .find(|c| &c[1] == name)
+                .map(|c| c[2].to_string())
+        };
+        let src = match attr("src") {
+            Some(src) => src,
+            None => return tag.to_string(),
+        };
+        let srcset: Vec<(String, u32)> = attr("srcset")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|candidate| {
+                let mut parts = candidate.split_whitespace();
+                let url = parts.next()?;
+                let width = parts.next()?.strip_suffix('w')?.parse().ok()?;
+                Some((url.to_string(), width))
+            })
+            .collect();
+        let chosen = srcset
+            .iter()
+            .filter(|(_, w)| *w <= max_width)
+            .max_by_key(|(_, w)| *w)
+            .or_else(|| srcset.iter().min_by_key(|(_, w)| *w))
+            .map(|(url, _)| url.clone())
+            .unwrap_or(src);
+        let width: Option<u32> = attr("width").and_then(|w| w.parse().ok());
+        let height: Option<u32> = attr("height").and_then(|h| h.parse().ok());
+        let mut out = format!(
+            "<img src=\"{}\" alt=\"{}\"",
+            chosen,
+            attr("alt").unwrap_or_default()
+        );
+        if let Some(width) = width {
+            let capped = width.min(max_width).max(1);
+            out.push_str(&format!(" width=\"{}\"", capped));
+            if let Some(height) = height {
+                let scaled = (height as f64 * capped as f64 / width as f64).round() as u32;
+                out.push_str(&format!(" height=\"{}\"", scaled.max(1)));
+            }
+        }
+        if let Some(style) = attr("style") {
+            out.push_str(&format!(" style=\"{}\"/>", style));
+        } else {
+            out.push_str(&format!(" style=\"{}\"/>", IMG_STYLE));
+        }
+        out
+    })
+    .into_owned()
+}
+
+/// Makes fragment, root-relative and relative `href`/`src` values absolute so
+/// links work outside the site; `origin` is the scheme and host of `root_url`.
+fn absolutize_urls(html: &str, permalink: &str, base: &str, origin: &str) -> String {
+    lazy_static! {
+        static ref URL_ATTR: Regex = Regex::new(r#"\b(href|src)="([^"]*)""#).unwrap();
+    }
+    URL_ATTR
+        .replace_all(html, |caps: &Captures| {
+            let value = &caps[2];
+            let resolved = if value.starts_with('#') {
+                format!("{}{}", permalink, value)
+            } else if value.starts_with('/') && !value.starts_with("//") {
+                format!("{}{}", origin, value)
+            } else if value.is_empty()
+                || value.starts_with("//")
+                || value.contains("://")
+                || value.starts_with("mailto:")
+                || value.starts_with("data:")
+            {
+                value.to_string()
+            } else {
+                format!("{}{}", base, value.trim_start_matches("./"))
+            };
+            format!("{}=\"{}\"", &caps[1], resolved)
+        })
+        .into_owned()
+}
+
+fn inline_styles(html: &str) -> String {
+    lazy_static! {
+        static ref OPEN_TAG: Regex = Regex::new(r"<([a-z][a-z0-9]*)(\s[^>]*)?>").unwrap();
+    }
+    OPEN_TAG
+        .replace_all(html, |caps: &Captures| {
+            let name = &caps[1];
+            let attrs = caps.get(2).map(|m| m.as_str()).unwrap_or("");
+            match TAG_STYLES.iter().find(|(tag, _)| *tag == name) {
+                Some((_, style)) if !attrs.contains("style=") => {
+                    let (attrs, close) = match attrs.strip_suffix('/') {
+                        Some(rest) => (rest, "/>"),
+                        None => (attrs, ">"),
+                    };
+                    format!("<{}{} style=\"{}\"{}", name, attrs, style, close)
+                }
+                _ => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+pub fn percent_encode(input: &str) -> String {
+    let mut out = String::new();
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn math_fallback_becomes_image() {
+        let html = math_to_images(
+            "<p>A <span class=\"math-inline\">x &lt; y</span></p>",
+            "https://math.example/?{tex}",
+        );
+        assert!(html.contains("<img src=\"https://math.example/?x%20%3C%20y\" alt=\"x &lt; y\""));
+    }
+
+    #[test]
+    fn details_blocks_are_unfolded() {
+        let html = strip_details(
+            "<details class=\"fold\"><summary>Proof</summary>\n<p>Q.E.D.</p>\n<figure><details><summary>EXIF data</summary></details></figure></details>\n",
+        );
+        let html = unfold_details(&html);
+        assert_eq!(
+            html,
+            "<div><p><strong>Proof</strong></p>\n<p>Q.E.D.</p>\n<figure></figure></div>\n"
+        );
+    }
+
+    #[test]
+    fn images_are_capped_to_configured_width() {
+        let html = cap_images(
+            "<img src=\"a-480.jpg\" alt=\"A\" width=\"1200\" height=\"800\" loading=\"lazy\" srcset=\"a-480.jpg 480w, a-600.jpg 600w, a-1200.jpg 1200w\" sizes=\"100vw\"/>",
+            600,
+        );
+        assert!(html.starts_with("<img src=\"a-600.jpg\" alt=\"A\" width=\"600\" height=\"400\""));
+        assert!(!html.contains("srcset"));
+    }
+
+    #[test]
+    fn relative_urls_become_absolute() {
+        let html = absolutize_urls(
+            "<a href=\"#s1\">x</a><img src=\"pic.png\"/><a href=\"https://a.b/\">y</a><a href=\"/about.html\">z</a>",
+            "https://example.com/blog/post",
+            "https://example.com/blog/post/",
+            "https://example.com",
+        );
+        assert!(html.contains("href=\"https://example.com/blog/post#s1\""));
+        assert!(html.contains("src=\"https://example.com/blog/post/pic.png\""));
+        assert!(html.contains("href=\"https://a.b/\""));
+        assert!(html.contains("href=\"https://example.com/about.html\""));
+    }
+
+    #[test]
+    fn needs_an_absolute_root_url() {
+        let post = Path::new("post.dllu");
+        let mut config = config::Config::default();
+        for root_url in [None, Some("/"), Some("example.com")] {
+            config.root_url = root_url.map(str::to_string);
+            let err = render_newsletter(post, Path::new("."), &config).unwrap_err();
+            assert!(err.starts_with("newsletter needs root_url"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn nested_post_links_from_the_site_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(
+            root.join("dllup.toml"),
+            "root_url = \"https://example.com\"\n",
+        )
+        .unwrap();
+        let post_dir = root.join("blog/post");
+        fs::create_dir_all(&post_dir).unwrap();
+        let post = post_dir.join("index.dllu");
+        fs::write(&post, "Post\n====\n\nSee [the figure](pic.png).\n").unwrap();
+
+        let config_path = find_site_config(&post);
+        assert_eq!(config_path, root.join("dllup.toml"));
+        let config = config::Config::load(&config_path).unwrap();
+        let html = render_newsletter(&post, root, &config).unwrap();
+        assert!(
+            html.contains("href=\"https://example.com/blog/post/pic.png\""),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<a href=\"https://example.com/blog/post\""),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn styles_are_inlined_on_bare_tags() {
+        let html = inline_styles("<p>Hi</p><pre style=\"color: red;\">x</pre>");
+        assert!(html.starts_with("<p style=\""));
+        assert!(html.contains("<pre style=\"color: red;\">"));
+    }
+}
@@ -0,0 +1,1087 @@
+//! Conversion between the dllu AST and the Pandoc JSON AST, so documents can go
+//! through Pandoc filters and writers (`dllup-rs pandoc export`) and Pandoc
+//! input can be rendered with this crate (`dllup-rs pandoc import`).
+
+use crate::ast::*;
+use crate::config;
+use crate::html_renderer::{self, HtmlRenderer};
+use crate::parser::Parser;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const API_VERSION: [u32; 3] = [1, 23, 1];
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    match (args.first().map(String::as_str), args.len()) {
+        (Some("export"), 2) => {
+            let input = Path::new(&args[1]);
+            let source = fs::read_to_string(input)
+                .map_err(|e| format!("Failed to read {}: {}", input.display(), e))?;
+            let mut parser = Parser::with_includes(input);
+            parser.parse(&source);
+            if let Some(e) = parser.include_errors.first() {
+                return Err(format!("{}: {}", input.display(), e));
+            }
+            let json = serde_json::to_string(&to_pandoc(&parser.article))
+                .map_err(|e| format!("Failed to serialize Pandoc JSON: {}", e))?;
+            println!("{}", json);
+            Ok(())
+        }
+        (Some("import"), 2) | (Some("import"), 3) => {
+            let input = Path::new(&args[1]);
+            let explicit_config = match args.get(2) {
+                Some(path) => Some(config::Config::load(Path::new(path))?),
+                None => None,
+            };
+            let config = crate::resolve_config(input, explicit_config.as_ref())?;
+            import_file(input, &config)
+        }
+        _ => Err("Usage: dllup-rs pandoc export <input.dllu>\n       dllup-rs pandoc import <input.json> [config.toml]".into()),
+    }
+}
+
+/// Renders a Pandoc JSON document to `<input>.html` using the page template.
+fn import_file(input_path: &Path, config: &config::Config) -> Result<(), String> {
+    let source = fs::read_to_string(input_path)
+        .map_err(|e| format!("Failed to read {}: {}", input_path.display(), e))?;
+    let doc: Value = serde_json::from_str(&source)
+        .map_err(|e| format!("Failed to parse {}: {}", input_path.display(), e))?;
+    let article = from_pandoc(&doc)?;
+
+    let asset_root = input_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let mut renderer = HtmlRenderer::with_asset_root(config, asset_root);
+    let body = renderer.render(&article);
+    crate::diagnostics::emit_image_errors(input_path, "", renderer.image_errors());
+    let title = article
+        .header
+        .as_ref()
+        .map(|h| h.title.as_str())
+        .unwrap_or("Document");
+    let toc = renderer.table_of_contents_html().unwrap_or_default();
+    let metas = renderer.meta_tags(title);
+    let scripts = renderer.page_scripts();
+    let empty_vars = BTreeMap::new();
+    let word_count = crate::excerpt::word_count(&article);
+    let html = html_renderer::wrap_html_document(
+        config,
+        &html_renderer::TemplateContext {
+            title,
+            date: article
+                .header
+                .as_ref()
+                .and_then(|h| h.date.as_deref())
+                .unwrap_or_default(),
+            body: &body,
+            table_of_contents: &toc,
+            metas: &metas,
+            index: "",
+            scripts: &scripts,
+            vars: article
+                .header
+                .as_ref()
+                .map(|h| &h.vars)
+                .unwrap_or(&empty_vars),
+            meta: article
+                .header
+                .as_ref()
+                .map(|h| &h.meta)
+                .unwrap_or(&empty_vars),
+            data: &Value::Null,
+            featured: article.header.as_ref().is_some_and(|h| h.featured),
+            word_count,
+            reading_minutes: crate::reading_minutes(word_count, &config.html),
+            related: "",
+            posts: &[],
+            images: renderer.page_images(),
+        },
+    )?;
+    let out_path = input_path.with_extension("html");
+    fs::write(&out_path, html)
+        .map_err(|e| format!("Failed to write {}: {}", out_path.display(), e))?;
+    crate::image_processor::wait_for_pending_resizes();
+    Ok(())
+}
+
+fn node(tag: &str, content: Value) -> Value {
+    json!({ "t": tag, "c": content })
+}
+
+fn bare(tag: &str) -> Value {
+    json!({ "t": tag })
+}
+
+fn attr(id: &str, classes: &[&str], kvs: &[(&str, &str)]) -> Value {
+    json!([
+        id,
+        classes,
+        kvs.iter().map(|(k, v)| [k, v]).collect::<Vec<_>>()
+    ])
+}
+
+pub fn to_pandoc(article: &Article) -> Value {
+    let mut meta = Map::new();
+    if let Some(header) = &article.header {
+        meta.insert(
+            "title".into(),
+            node("MetaInlines", Value::Array(text_to_pandoc(&header.title))),
+        );
+        if let Some(date) = &header.date {
+            meta.insert(
+                "date".into(),
+                node("MetaInlines", Value::Array(text_to_pandoc(date))),
+            );
+        }
+        for (key, value) in &header.vars {
+            meta.insert(key.clone(), node("MetaString", json!(value)));
+        }
+        for (key, set) in [("pinned", header.pinned), ("featured", header.featured)] {
+            if set {
+                meta.insert(key.into(), node("MetaBool", json!(true)));
+            }
+        }
+        if let Some(template) = &header.template {
+            meta.insert("template".into(), node("MetaString", json!(template)));
+        }
+        // A `title: ...` field doesn't replace the title line
+        for (key, value) in &header.meta {
+            meta.entry(key.clone())
+                .or_insert_with(|| node("MetaString", json!(value)));
+        }
+    }
+    json!({
+        "pandoc-api-version": API_VERSION,
+        "meta": meta,
+        "blocks": article.body.iter().map(block_to_pandoc).collect::<Vec<_>>(),
+    })
+}
+
+fn block_to_pandoc(block: &Block) -> Value {
+    match block {
+        Block::Raw { format, content } => node("RawBlock", json!([format, content])),
+        // Kept as dllu source, which no Pandoc writer emits
+        Block::Include { path } => node("RawBlock", json!(["dllu", format!("include {}", path)])),
+        Block::CodeBlock {
+            language,
+            code,
+            filename,
+            caption,
+            id,
+            ..
+        } => {
+            let classes: Vec<&str> = language.iter().map(String::as_str).collect();
+            let kvs: Vec<(&str, &str)> = filename
+                .iter()
+                .map(|name| ("filename", name.as_str()))
+                .collect();
+            let code_block = node(
+                "CodeBlock",
+                json!([attr("", &classes, &kvs), code.trim_end_matches('\n')]),
+            );
+            match caption {
+                Some(caption) => node(
+                    "Figure",
+                    json!([
+                        attr(id.as_deref().unwrap_or(""), &[], &[]),
+                        [null, [node("Plain", inlines_to_pandoc(caption))]],
+                        [code_block]
+                    ]),
+                ),
+                None => code_block,
+            }
+        }
+        Block::SectionHeader {
+            level, id, text, ..
+        } => node(
+            "Header",
+            json!([level, attr(id, &[], &[]), text_to_pandoc(text)]),
+        ),
+        Block::BlockQuote(inlines) => node(
+            "BlockQuote",
+            json!([node("Para", inlines_to_pandoc(inlines))]),
+        ),
+        Block::ImageFigure {
+            url,
+            id,
+            alt,
+            decorative,
+            text,
+            credit,
+            no_resize,
+            ..
+        } => {
+            let mut classes = Vec::new();
+            if *decorative {
+                classes.push("decorative");
+            }
+            if *no_resize {
+                classes.push("no-resize");
+            }
+            let image = node(
+                "Image",
+                json!([attr("", &classes, &[]), text_to_pandoc(alt), [url, ""]]),
+            );
+            let credit = credit.as_deref().map(plain_text);
+            let kvs: Vec<(&str, &str)> = credit
+                .iter()
+                .map(|credit| ("credit", credit.as_str()))
+                .collect();
+            node(
+                "Figure",
+                json!([
+                    attr(id.as_deref().unwrap_or(""), &[], &kvs),
+                    [null, [node("Plain", inlines_to_pandoc(text))]],
+                    [node("Plain", json!([image]))]
+                ]),
+            )
+        }
+        Block::Embed {
+            provider,
+            video,
+            id,
+            text,
+            ..
+        } => {
+            let url = provider.watch_url(video);
+            let link = node(
+                "Link",
+                json!([attr("", &[], &[]), [node("Str", json!(url))], [url, ""]]),
+            );
+            let classes = ["embed", provider.name()];
+            node(
+                "Figure",
+                json!([
+                    attr(id.as_deref().unwrap_or(""), &classes, &[]),
+                    [null, [node("Plain", inlines_to_pandoc(text))]],
+                    [node("Plain", json!([link]))]
+                ]),
+            )
+        }
+        Block::DisplayMath { id, content, .. } => {
+            let para = node(
+                "Para",
+                json!([node("Math", json!([bare("DisplayMath"), content]))]),
+            );
+            match id {
+                Some(id) => node("Div", json!([attr(id, &[], &[]), [para]])),
+                None => para,
+            }
+        }
+        Block::Table {
+            header,
+            rows,
+            caption,
+            ..
+        } => {
+            let width =
+                |cells: &Vec<TableCell>| cells.iter().map(|cell| cell.colspan).sum::<usize>();
+            let cols = width(header).max(rows.iter().map(width).max().unwrap_or(0));
+            let colspecs: Vec<Value> = (0..cols)
+                .map(|_| json!([bare("AlignDefault"), bare("ColWidthDefault")]))
+                .collect();
+            let row = |cells: &Vec<TableCell>| {
+                json!([
+                    attr("", &[], &[]),
+                    cells
+                        .iter()
+                        .map(|cell| json!([
+                            attr("", &[], &[]),
+                            bare("AlignDefault"),
+                            cell.rowspan,
+                            cell.colspan,
+                            [node("Plain", inlines_to_pandoc(&cell.content))]
+                        ]))
+                        .collect::<Vec<_>>()
+                ])
+            };
+            let caption_blocks: Vec<Value> = match caption {
+                Some(caption) => vec![node("Plain", inlines_to_pandoc(caption))],
+                None => Vec::new(),
+            };
+            node(
+                "Table",
+                json!([
+                    attr("", &[], &[]),
+                    [null, caption_blocks],
+                    colspecs,
+                    [attr("", &[], &[]), [row(header)]],
+                    [[
+                        attr("", &[], &[]),
+                        0,
+                        [],
+                        rows.iter().map(row).collect::<Vec<_>>()
+                    ]],
+                    [attr("", &[], &[]), []]
+                ]),
+            )
+        }
+        Block::BigButton { text, url } => {
+            let link = node(
+                "Link",
+                json!([attr("", &[], &[]), inlines_to_pandoc(text), [url, ""]]),
+            );
+            node(
+                "Div",
+                json!([
+                    attr("", &["bigbutton"], &[]),
+                    [node("Plain", json!([link]))]
+                ]),
+            )
+        }
+        Block::Details { summary, blocks } => {
+            let summary = node(
+                "Div",
+                json!([
+                    attr("", &["summary"], &[]),
+                    [node("Plain", inlines_to_pandoc(summary))]
+                ]),
+            );
+            let mut inner = vec![summary];
+            inner.extend(blocks.iter().map(block_to_pandoc));
+            node("Div", json!([attr("", &["details"], &[]), inner]))
+        }
+        Block::UnorderedList(items) => node("BulletList", json!(nest_list_items(items, false))),
+        Block::OrderedList(items) => node(
+            "OrderedList",
+            json!([
+                [1, bare("Decimal"), bare("Period")],
+                nest_list_items(items, true)
+            ]),
+        ),
+        Block::Paragraph(inlines) => node("Para", inlines_to_pandoc(inlines)),
+    }
+}
+
+/// dllu lists are flat with a nesting level per item; Pandoc nests sublists
+/// inside the preceding item.
+fn nest_list_items(items: &[ListItem], ordered: bool) -> Vec<Value> {
+    let mut out: Vec<Vec<Value>> = Vec::new();
+    let mut i = 0;
+    let base = items.first().map(|item| item.level).unwrap_or(1);
+    while i < items.len() {
+        let mut j = i + 1;
+        while j < items.len() && items[j].level > base {
+            j += 1;
+        }
+        let mut plain = inlines_to_pandoc(&items[i].text);
+        if let (Some(checked), Value::Array(inlines)) = (items[i].checked, &mut plain) {
+            // Pandoc's task_lists extension marks items with a leading ☐ or ☒
+            let mark = if checked { "☒" } else { "☐" };
+            inlines.splice(0..0, [node("Str", json!(mark)), bare("Space")]);
+        }
+        let mut blocks = vec![node("Plain", plain)];
+        blocks.extend(items[i].blocks.iter().map(block_to_pandoc));
+        if j > i + 1 {
+            let children = nest_list_items(&items[i + 1..j], ordered);
+            blocks.push(if ordered {
+                node(
+                    "OrderedList",
+                    json!([[1, bare("Decimal"), bare("Period")], children]),
+                )
+            } else {
+                node("BulletList", json!(children))
+            });
+        }
+        out.push(blocks);
+        i = j;
+    }
+    out.into_iter().map(Value::Array).collect()
+}
+
+fn text_to_pandoc(text: &str) -> Vec<Value> {
+    let mut out = Vec::new();
+    let mut word = String::new();
+    let mut pending_space: Option<&str> = None;
+    for ch in text.chars() {
+        if ch.is_whitespace() {
+            if !word.is_empty() {
+                out.push(node("Str", json!(word)));
+                word.clear();
+            }
+            if ch == '\n' {
+                pending_space = Some("SoftBreak");
+            } else if pending_space.is_none() {
+                pending_space = Some("Space");
+            }
+        } else {
+            if let Some(space) = pending_space.take() {
+                out.push(bare(space));
+            }
+            word.push(ch);
+        }
+    }
+    if !word.is_empty() {
+        out.push(node("Str", json!(word)));
+    }
+    if let Some(space) = pending_space {
+        out.push(bare(space));
+    }
+    out
+}
+
+fn inlines_to_pandoc(inlines: &[InlineElement]) -> Value {
+    let mut out = Vec::new();
+    for inline in inlines {
+        match inline {
+            InlineElement::Text(text) => out.extend(text_to_pandoc(text)),
+            InlineElement::Code(code) => out.push(node("Code", json!([attr("", &[], &[]), code]))),
+            InlineElement::InlineMath(tex) => {
+                out.push(node("Math", json!([bare("InlineMath"), tex])))
+            }
+            InlineElement::Link { text, url } => out.push(node(
+                "Link",
+                json!([attr("", &[], &[]), inlines_to_pandoc(text), [url, ""]]),
+            )),
+            InlineElement::Emphasis(inner) => out.push(node("Emph", inlines_to_pandoc(inner))),
+            InlineElement::Strong(inner) => out.push(node("Strong", inlines_to_pandoc(inner))),
+            InlineElement::Lang { lang, text } => out.push(node(
+                "Span",
+                json!([
+                    attr("", &[], &[("lang", lang.as_str())]),
+                    inlines_to_pandoc(text)
+                ]),
+            )),
+            InlineElement::Reference(name) => out.push(node(
+                "Link",
+                json!([
+                    attr("", &["refname"], &[]),
+                    [node("Str", json!(name))],
+                    [format!("#{}", name), ""]
+                ]),
+            )),
+            InlineElement::ReferenceAnchor { content, invisible } => {
+                let inner = if *invisible {
+                    json!([])
+                } else {
+                    json!([node("Str", json!(content))])
+                };
+                out.push(node(
+                    "Span",
+                    json!([attr(content, &["refname"], &[]), inner]),
+                ));
+            }
+        }
+    }
+    Value::Array(out)
+}
+
+/// Best-effort conversion of a Pandoc document into the dllu AST. Constructs
+/// without a dllu equivalent are flattened to their text or dropped.
+pub fn from_pandoc(doc: &Value) -> Result<Article, String> {
+    let blocks = doc
+        .get("blocks")
+        .and_then(Value::as_array)
+        .ok_or("Pandoc JSON is missing a \"blocks\" array")?;
+    let mut importer = Importer::default();
+    let mut body = Vec::new();
+    for block in blocks {
+        importer.block(block, &mut body);
+    }
+
+    let meta = doc.get("meta").and_then(Value::as_object);
+    let meta_text = |key: &str| meta.and_then(|m| m.get(key)).map(meta_to_text);
+    let header = meta_text("title").map(|title| {
+        let mut vars = BTreeMap::new();
+        let mut fields = BTreeMap::new();
+        for (key, value) in meta.into_iter().flatten() {
+            if key.starts_with("x-") {
+                vars.insert(key.to_ascii_lowercase(), meta_to_text(value));
+            } else if !["title", "date", "pinned", "featured", "template"].contains(&key.as_str())
+                && key.starts_with(|c: char| c.is_ascii_alphabetic())
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                fields.insert(key.to_ascii_lowercase(), meta_to_text(value));
+            }
+        }
+        ArticleHeader {
+            title,
+            date: meta_text("date"),
+            vars,
+            pinned: meta_text("pinned").is_some_and(|value| value == "true"),
+            featured: meta_text("featured").is_some_and(|value| value == "true"),
+            template: meta_text("template"),
+            meta: fields,
+        }
+    });
+    Ok(Article { header, body })
+}
+
+fn tag(value: &Value) -> &str {
+    value.get("t").and_then(Value::as_str).unwrap_or("")
+}
+
+fn content(value: &Value) -> &Value {
+    value.get("c").unwrap_or(&Value::Null)
+}
+
+fn at(value: &Value, index: usize) -> &Value {
+    value.get(index).unwrap_or(&Value::Null)
+}
+
+fn items(value: &Value) -> &[Value] {
+    value.as_array().map(Vec::as_slice).unwrap_or(&[])
+}
+
+fn meta_to_text(value: &Value) -> String {
+    match tag(value) {
+        "MetaString" => content(value).as_str().unwrap_or("").to_string(),
+        "MetaInlines" => plain_text(&inlines_from_pandoc(content(value))),
+        "MetaBlocks" => items(content(value))
+            .iter()
+            .map(|b| plain_text(&inlines_from_pandoc(block_inlines(b))))
+            .collect::<Vec<_>>()
+            .join(" "),
+        "MetaBool" => content(value).as_bool().unwrap_or(false).to_string(),
+        _ => String::new(),
+    }
+}
+
+fn block_inlines(block: &Value) -> &Value {
+    match tag(block) {
+        "Para" | "Plain" => content(block),
+        _ => &Value::Null,
+    }
+}
+
+/// The id of a Pandoc `Attr` (`[id, classes, key-values]`), if non-empty.
+fn attr_id(attr: &Value) -> Option<String> {
+    at(attr, 0)
+        .as_str()
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+}
+
+/// The provider and video of a figure exported from an `embed` block: classes
+/// `embed` and the provider's name around a link to the video's page.
+fn embed_video(attr: &Value, link: &Value) -> Option<(EmbedProvider, String)> {
+    let classes = attr_classes(attr);
+    if tag(link) != "Link" || !classes.contains(&"embed") {
+        return None;
+    }
+    let provider = classes.into_iter().find_map(EmbedProvider::parse)?;
+    let url = at(at(content(link), 2), 0).as_str()?;
+    let video = url.strip_prefix(&provider.watch_url(""))?;
+    provider
+        .is_video_id(video)
+        .then(|| (provider, video.to_string()))
+}
+
+fn attr_classes(attr: &Value) -> Vec<&str> {
+    items(at(attr, 1))
+        .iter()
+        .filter_map(Value::as_str)
+        .collect()
+}
+
+fn attr_value<'a>(attr: &'a Value, key: &str) -> Option<&'a str> {
+    items(at(attr, 2))
+        .iter()
+        .find(|kv| at(kv, 0).as_str() == Some(key))
+        .and_then(|kv| at(kv, 1).as_str())
+}
+
+#[derive(Default)]
+struct Importer {
+    ids: Parser,
+    figures: usize,
+    equations: usize,
+    tables: usize,
+    listings: usize,
+}
+
+impl Importer {
+    fn block(&mut self, block: &Value, out: &mut Vec<Block>) {
+        let c = content(block);
+        match tag(block) {
+            "Para" | "Plain" => {
+                let inlines = items(c);
+                if let [math] = inlines {
+                    if tag(math) == "Math" && tag(at(content(math), 0)) == "DisplayMath" {
+                        out.push(self.display_math(None, content(math)));
+                        return;
+                    }
+                }
+                let inlines = inlines_from_pandoc(c);
+                if !inlines.is_empty() {
+                    out.push(Block::Paragraph(inlines));
+                }
+            }
+            "LineBlock" => {
+                let mut inlines = Vec::new();
+                for line in items(c) {
+                    if !inlines.is_empty() {
+                        inlines.push(InlineElement::Text("\n".into()));
+                    }
+                    inlines.extend(inlines_from_pandoc(line));
+                }
+                out.push(Block::Paragraph(merge_text(inlines)));
+            }
+            "Header" => {
+                let text = plain_text(&inlines_from_pandoc(at(c, 2)));
+                let id = self.ids.generate_id(&text);
+                out.push(Block::SectionHeader {
+                    level: at(c, 0).as_u64().unwrap_or(1).clamp(1, 6) as usize,
+                    id,
+                    text,
+                    custom_id: false,
+                });
+            }
+            "CodeBlock" => out.push(self.code_block(c, None, None)),
+            "RawBlock" => out.push(Block::Raw {
+                format: at(c, 0).as_str().unwrap_or("html").to_string(),
+                content: at(c, 1).as_str().unwrap_or("").to_string(),
+            }),
+            "BlockQuote" => {
+                let mut inlines = Vec::new();
+                for inner in items(c) {
+                    if !inlines.is_empty() {
+                        inlines.push(InlineElement::Text(" ".into()));
+                    }
+                    inlines.extend(inlines_from_pandoc(block_inlines(inner)));
+                }
+                out.push(Block::BlockQuote(merge_text(inlines)));
+            }
+            "BulletList" => {
+                let mut list = Vec::new();
+                self.flatten_list(c, 1, &mut list);
+                out.push(Block::UnorderedList(list));
+            }
+            "OrderedList" => {
+                let mut list = Vec::new();
+                self.flatten_list(at(c, 1), 1, &mut list);
+                out.push(Block::OrderedList(list));
+            }
+            "DefinitionList" => {
+                for entry in items(c) {
+                    let mut inlines =
+                        vec![InlineElement::Strong(inlines_from_pandoc(at(entry, 0)))];
+                    for definition in items(at(entry, 1)) {
+                        for inner in items(definition) {
+                            inlines.push(InlineElement::Text(" ".into()));
+                            inlines.extend(inlines_from_pandoc(block_inlines(inner)));
+                        }
+                    }
+                    out.push(Block::Paragraph(merge_text(inlines)));
+                }
+            }
+            "Figure" => self.figure(c, out),
+            "Table" => out.push(self.table(c)),
+            "Div" => {
+                let attr = at(c, 0);
+                let inner = items(at(c, 1));
+                if attr_classes(attr).contains(&"bigbutton") {
+                    if let Some(link) = inner
+                        .iter()
+                        .flat_map(|b| items(block_inlines(b)))
+                        .find(|i| tag(i) == "Link")
+                    {
+                        let link = content(link);
+                        out.push(Block::BigButton {
+                            text: inlines_from_pandoc(at(link, 1)),
+                            url: at(at(link, 2), 0).as_str().unwrap_or("").to_string(),
+                        });
+                        return;
+                    }
+                }
+                if attr_classes(attr).contains(&"details") {
+                    let mut summary = Vec::new();
+                    let mut blocks = Vec::new();
+                    for inner in inner {
+                        if summary.is_empty()
+                            && tag(inner) == "Div"
+                            && attr_classes(at(content(inner), 0)).contains(&"summary")
+                        {
+                            for b in items(at(content(inner), 1)) {
+                                summary.extend(inlines_from_pandoc(block_inlines(b)));
+                            }
+                        } else {
+                            self.block(inner, &mut blocks);
+                        }
+                    }
+                    out.push(Block::Details {
+                        summary: merge_text(summary),
+                        blocks,
+                    });
+                    return;
+                }
+                if let [para] = inner {
+                    let math = items(block_inlines(para));
+                    if let [math] = math {
+                        if tag(math) == "Math" && tag(at(content(math), 0)) == "DisplayMath" {
+                            out.push(self.display_math(attr_id(attr), content(math)));
+                            return;
+                        }
+                    }
+                }
+                for inner in inner {
+                    self.block(inner, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn display_math(&mut self, id: Option<String>, math: &Value) -> Block {
+        self.equations += 1;
+        Block::DisplayMath {
+            id,
+            id_number: self.equations - 1,
+            content: at(math, 1).as_str().unwrap_or("").to_string(),
+        }
+    }
+
+    fn code_block(
+        &mut self,
+        c: &Value,
+        caption: Option<Vec<InlineElement>>,
+        id: Option<String>,
+    ) -> Block {
+        let attr = at(c, 0);
+        let id_number = if caption.is_some() {
+            self.listings += 1;
+            self.listings - 1
+        } else {
+            0
+        };
+        let mut code = at(c, 1).as_str().unwrap_or("").to_string();
+        code.push('\n');
+        Block::CodeBlock {
+            language: attr_classes(attr).first().map(|s| s.to_string()),
+            code,
+            line_numbers: None,
+            highlight_lines: Vec::new(),
+            filename: attr_value(attr, "filename").map(str::to_string),
+            caption,
+            id: id.or_else(|| attr_id(attr)),
+            id_number,
+        }
+    }
+
+    fn figure(&mut self, c: &Value, out: &mut Vec<Block>) {
+        let id = attr_id(at(c, 0));
+        let credit = attr_value(at(c, 0), "credit")
+            .filter(|credit| !credit.is_empty())
+            .map(|credit| vec![InlineElement::Text(credit.to_string())]);
+        let caption: Vec<InlineElement> = merge_text(
+            items(at(at(c, 1), 1))
+                .iter()
+                .flat_map(|b| inlines_from_pandoc(block_inlines(b)))
+                .collect(),
+        );
+        let body = items(at(c, 2));
+        if let [inner] = body {
+            if tag(inner) == "CodeBlock" {
+                let block = self.code_block(content(inner), Some(caption), id);
+                out.push(block);
+                return;
+            }
+            if let [image] = items(block_inlines(inner)) {
+                if let Some((provider, video)) = embed_video(at(c, 0), image) {
+                    self.figures += 1;
+                    out.push(Block::Embed {
+                        provider,
+                        video,
+                        id,
+                        id_number: self.figures - 1,
+                        text: caption,
+                    });
+                    return;
+                }
+                if tag(image) == "Image" {
+                    let image = content(image);
+                    self.figures += 1;
+                    out.push(Block::ImageFigure {
+                        url: at(at(image, 2), 0).as_str().unwrap_or("").to_string(),
+                        id,
+                        id_number: self.figures - 1,
+                        alt: plain_text(&inlines_from_pandoc(at(image, 1))),
+                        decorative: attr_classes(at(image, 0)).contains(&"decorative"),
+                        text: caption,
+                        credit,
+                        crop: None,
+                        no_resize: attr_classes(at(image, 0)).contains(&"no-resize"),
+                    });
+                    return;
+                }
+            }
+        }
+        for inner in body {
+            self.block(inner, out);
+        }
+        if !caption.is_empty() {
+            out.push(Block::Paragraph(caption));
+        }
+    }
+
+    fn table(&mut self, c: &Value) -> Block {
+        let span = |value: &Value| value.as_u64().map_or(1, |span| span.max(1) as usize);
+        let row_cells = |row: &Value| -> Vec<TableCell> {
+            items(at(row, 1))
+                .iter()
+                .map(|cell| TableCell {
+                    content: merge_text(
+                        items(at(cell, 4))
+                            .iter()
+                            .flat_map(|b| inlines_from_pandoc(block_inlines(b)))
+                            .collect(),
+                    ),
+                    rowspan: span(at(cell, 2)),
+                    colspan: span(at(cell, 3)),
+                })
+                .collect()
+        };
+        let mut head_rows = items(at(at(c, 3), 1)).iter().map(row_cells);
+        let header = head_rows.next().unwrap_or_default();
+        let mut rows: Vec<_> = head_rows.collect();
+        for body in items(at(c, 4)) {
+            rows.extend(items(at(body, 2)).iter().map(row_cells));
+            rows.extend(items(at(body, 3)).iter().map(row_cells));
+        }
+        rows.extend(items(at(at(c, 5), 1)).iter().map(row_cells));
+        let caption = merge_text(
+            items(at(at(c, 1), 1))
+                .iter()
+                .flat_map(|b| inlines_from_pandoc(block_inlines(b)))
+                .collect(),
+        );
+        let caption = (!caption.is_empty()).then_some(caption);
+        let id_number = if caption.is_some() {
+            self.tables += 1;
+            self.tables - 1
+        } else {
+            0
+        };
+        Block::Table {
+            id_number,
+            header,
+            rows,
+            caption,
+        }
+    }
+
+    /// An item's first paragraph becomes its text and its other blocks are
+    /// kept below it; sublists become the following, deeper items.
+    fn flatten_list(&mut self, list_items: &Value, level: usize, out: &mut Vec<ListItem>) {
+        for item in items(list_items) {
+            let mut text = None;
+            let mut blocks = Vec::new();
+            let mut nested = Vec::new();
+            for block in items(item) {
+                match tag(block) {
+                    "BulletList" => nested.push(content(block)),
+                    "OrderedList" => nested.push(at(content(block), 1)),
+                    "Para" | "Plain" if text.is_none() => {
+                        text = Some(merge_text(inlines_from_pandoc(content(block))))
+                    }
+                    _ => self.block(block, &mut blocks),
+                }
+            }
+            let mut text = text.unwrap_or_default();
+            let checked = take_task_mark(&mut text);
+            out.push(ListItem {
+                level,
+                text,
+                checked,
+                blocks,
+            });
+            for sublist in nested {
+                self.flatten_list(sublist, level + 1, out);
+            }
+        }
+    }
+}
+
+fn inlines_from_pandoc(inlines: &Value) -> Vec<InlineElement> {
+    let mut out = Vec::new();
+    for inline in items(inlines) {
+        let c = content(inline);
+        match tag(inline) {
+            "Str" => out.push(InlineElement::Text(c.as_str().unwrap_or("").to_string())),
+            "Space" | "SoftBreak" | "LineBreak" => out.push(InlineElement::Text(" ".into())),
+            "Emph" | "Underline" => out.push(InlineElement::Emphasis(inlines_from_pandoc(c))),
+            "Strong" => out.push(InlineElement::Strong(inlines_from_pandoc(c))),
+            "Strikeout" | "Superscript" | "Subscript" | "SmallCaps" => {
+                out.extend(inlines_from_pandoc(c))
+            }
+            "Quoted" => {
+                let (open, close) = if tag(at(c, 0)) == "SingleQuote" {
+                    ("\u{2018}", "\u{2019}")
+                } else {
+                    ("\u{201c}", "\u{201d}")
+                };
+                out.push(InlineElement::Text(open.into()));
+                out.extend(inlines_from_pandoc(at(c, 1)));
+                out.push(InlineElement::Text(close.into()));
+            }
+            "Cite" => out.extend(inlines_from_pandoc(at(c, 1))),
+            "Code" => out.push(InlineElement::Code(
+                at(c, 1).as_str().unwrap_or("").to_string(),
+            )),
+            "Math" => out.push(InlineElement::InlineMath(
+                at(c, 1).as_str().unwrap_or("").to_string(),
+            )),
+            "Link" => {
+                let url = at(at(c, 2), 0).as_str().unwrap_or("").to_string();
+                let text = inlines_from_pandoc(at(c, 1));
+                match url.strip_prefix('#') {
+                    Some(name) if attr_classes(at(c, 0)).contains(&"refname") => {
+                        out.push(InlineElement::Reference(name.to_string()))
+                    }
+                    _ => out.push(InlineElement::Link { text, url }),
+                }
+            }
+            "Image" => out.push(InlineElement::Link {
+                text: inlines_from_pandoc(at(c, 1)),
+                url: at(at(c, 2), 0).as_str().unwrap_or("").to_string(),
+            }),
+            "Span" => {
+                let attr = at(c, 0);
+                match attr_id(attr) {
+                    Some(id) if attr_classes(attr).contains(&"refname") => {
+                        out.push(InlineElement::ReferenceAnchor {
+                            invisible: items(at(c, 1)).is_empty(),
+                            content: id,
+                        })
+                    }
+                    _ => match attr_value(attr, "lang") {
+                        Some(lang) => out.push(InlineElement::Lang {
+                            lang: lang.to_string(),
+                            text: inlines_from_pandoc(at(c, 1)),
+                        }),
+                        None => out.extend(inlines_from_pandoc(at(c, 1))),
+                    },
+                }
+            }
+            _ => {}
+        }
+    }
+    merge_text(out)
+}
+
+/// Removes the ☐ or ☒ that starts a Pandoc task list item; `Some(true)` when ticked.
+fn take_task_mark(text: &mut [InlineElement]) -> Option<bool> {
+    let Some(InlineElement::Text(first)) = text.first_mut() else {
+        return None;
+    };
+    let (checked, rest) = if let Some(rest) = first.strip_prefix('☒') {
+        (true, rest)
+    } else {
+        (false, first.strip_prefix('☐')?)
+    };
+    *first = rest.trim_start().to_string();
+    Some(checked)
+}
+
+fn merge_text(inlines: Vec<InlineElement>) -> Vec<InlineElement> {
+    let mut out: Vec<InlineElement> = Vec::new();
+    for inline in inlines {
+        match (out.last_mut(), inline) {
+            (Some(InlineElement::Text(prev)), InlineElement::Text(next)) => prev.push_str(&next),
+            (_, inline) => out.push(inline),
+        }
+    }
+    out
+}
+
+fn plain_text(inlines: &[InlineElement]) -> String {
+    let mut out = String::new();
+    for inline in inlines {
+        match inline {
+            InlineElement::Text(t) | InlineElement::Code(t) | InlineElement::InlineMath(t) => {
+                out.push_str(t)
+            }
+            InlineElement::Link { text, .. } | InlineElement::Lang { text, .. } => {
+                out.push_str(&plain_text(text))
+            }
+            InlineElement::Emphasis(inner) | InlineElement::Strong(inner) => {
+                out.push_str(&plain_text(inner))
+            }
+            InlineElement::Reference(s) => out.push_str(s),
+            InlineElement::ReferenceAnchor { content, .. } => out.push_str(content),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_header_and_blocks_as_pandoc_json() {
+        let mut parser = Parser::default();
+        parser.parse("Doc\n\n2024-01-02\n\n===\n\n# Intro\n\nHello _world_ $x$.\n");
+        let doc = to_pandoc(&parser.article);
+        assert_eq!(doc["pandoc-api-version"], json!([1, 23, 1]));
+        assert_eq!(
+            doc["meta"]["title"]["c"][0],
+            json!({"t": "Str", "c": "Doc"})
+        );
+        assert_eq!(doc["blocks"][0]["t"], "Header");
+        assert_eq!(doc["blocks"][0]["c"][1][0], "intro");
+        let para = &doc["blocks"][1]["c"];
+        assert_eq!(para[2]["t"], "Emph");
+        assert!(para
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|i| i["t"] == "Math" && i["c"][1] == "x"));
+    }
+
+    #[test]
+    fn round_trips_through_pandoc_json() {
+        let source = "Doc\n\nx-repo: a/b\nsubtitle: On kites\n\n===\n\n* [x] one\n** nested\n* [ ] two\n\n| A | B |\n| 1 | 2 |\nCaption\n\n$ e = mc^2 [#eq]\n\n~~~\nlang rust\nfile main.rs : The entry point\nfn main() {}\n~~~\n";
+        let mut parser = Parser::default();
+        parser.parse(source);
+        let article = from_pandoc(&to_pandoc(&parser.article)).unwrap();
+
+        let header = article.header.unwrap();
+        assert_eq!(header.title, "Doc");
+        assert_eq!(header.vars.get("x-repo").map(String::as_str), Some("a/b"));
+        assert_eq!(
+            header.meta.get("subtitle").map(String::as_str),
+            Some("On kites")
+        );
+        match &article.body[0] {
+            Block::UnorderedList(items) => {
+                let levels: Vec<usize> = items.iter().map(|i| i.level).collect();
+                assert_eq!(levels, vec![1, 2, 1]);
+                let checked: Vec<Option<bool>> = items.iter().map(|i| i.checked).collect();
+                assert_eq!(checked, vec![Some(true), None, Some(false)]);
+                assert!(matches!(&items[0].text[..], [InlineElement::Text(t)] if t == "one"));
+            }
+            other => panic!("expected list, got {:?}", other),
+        }
+        match &article.body[1] {
+            Block::Table { header, rows, .. } => {
+                assert_eq!(header.len(), 2);
+                assert_eq!(rows.len(), 1);
+            }
+            other => panic!("expected table, got {:?}", other),
+        }
+        assert!(
+            matches!(&article.body[2], Block::DisplayMath { content, .. } if content.contains("mc^2"))
+        );
+        match &article.body[3] {
+            Block::CodeBlock {
+                language,
+                filename,
+                caption,
+                code,
+                ..
+            } => {
+                assert_eq!(language.as_deref(), Some("rust"));
+                assert_eq!(filename.as_deref(), Some("main.rs"));
+                assert!(caption.is_some());
+                assert_eq!(code, "fn main() {}\n");
+            }
+            other => panic!("expected code block, got {:?}", other),
+        }
+    }
+}
@@ -1,19 +1,41 @@
 use crate::ast::*;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::Lines;
 
 #[derive(Debug, Default)]
 pub struct Parser {
     pub article: Article,
+    /// Files spliced in by `include` lines, in the order they were read.
+    pub included: Vec<PathBuf>,
+    /// `include` lines that named a missing file or a file already being included.
+    pub include_errors: Vec<String>,
     image_figures: Vec<usize>,
     display_equations: Vec<usize>,
     tables: Vec<usize>,
+    listings: Vec<usize>,
 
     section_id_counts: HashMap<String, usize>,
+    /// The parsed file and the files it is including, innermost last; empty
+    /// when `include` lines are kept as they are.
+    include_stack: Vec<PathBuf>,
 }
 
 impl Parser {
+    /// A parser that replaces `include path.dllu` lines with the blocks of
+    /// that file, found relative to the file including it; `source` is the
+    /// file being parsed. Figures, equations and sections of included files
+    /// are numbered along with the rest of the article.
+    pub fn with_includes(source: &Path) -> Self {
+        let source = fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+        Self {
+            include_stack: vec![source],
+            ..Self::default()
+        }
+    }
+
     pub fn parse(&mut self, s: &str) {
         let parts: Vec<&str> = s.splitn(2, "\n===\n").collect();
         if parts.len() > 1 {
@@ -26,11 +48,49 @@ impl Parser {
     }
 
     fn parse_header(&self, s: &str) -> ArticleHeader {
+        lazy_static! {
+            static ref CUSTOM_VAR: Regex = Regex::new(r"(?i)^(x-[a-z0-9_-]+):\s*(.*)$").unwrap();
+            static ref FLAG: Regex =
+                Regex::new(r"(?i)^(pinned|featured):\s*(true|false|yes|no)$").unwrap();
+            static ref TEMPLATE: Regex = Regex::new(r"(?i)^template:\s*([A-Za-z0-9_-]+)$").unwrap();
+            static ref FIELD: Regex = Regex::new(r"^([A-Za-z][A-Za-z0-9_-]*):\s*(.*)$").unwrap();
+        }
         let mut lines = s.lines().filter(|line| !line.trim().is_empty());
         let title = lines.next().unwrap_or_default().to_string();
-        let date = lines.next().map(|line| line.to_string());
+        let mut date = None;
+        let mut vars = BTreeMap::new();
+        let mut pinned = false;
+        let mut featured = false;
+        let mut template = None;
+        let mut meta = BTreeMap::new();
+        for line in lines {
+            if let Some(caps) = CUSTOM_VAR.captures(line.trim()) {
+                vars.insert(caps[1].to_ascii_lowercase(), caps[2].trim().to_string());
+            } else if let Some(caps) = FLAG.captures(line.trim()) {
+                let value = matches!(caps[2].to_ascii_lowercase().as_str(), "true" | "yes");
+                if caps[1].eq_ignore_ascii_case("pinned") {
+                    pinned = value;
+                } else {
+                    featured = value;
+                }
+            } else if let Some(caps) = TEMPLATE.captures(line.trim()) {
+                template = Some(caps[1].to_string());
+            } else if let Some(caps) = FIELD.captures(line.trim()) {
+                meta.insert(caps[1].to_ascii_lowercase(), caps[2].trim().to_string());
+            } else if date.is_none() {
+                date = Some(line.to_string());
+            }
+        }
 
-        ArticleHeader { title, date }
+        ArticleHeader {
+            title,
+            date,
+            vars,
+            pinned,
+            featured,
+            template,
+            meta,
+        }
     }
 
     fn parse_body(&mut self, s: &str) -> Vec<Block> {
@@ -39,17 +99,33 @@ impl Parser {
 
         while lines.peek().is_some() {
             if let Some(block) = self.parse_block(&mut lines) {
+                if let Block::Include { path } = &block {
+                    if let Some(included) = self.include(path) {
+                        blocks.extend(included);
+                        continue;
+                    }
+                }
                 let ind = blocks.len();
                 match &block {
-                    Block::ImageFigure { .. } => {
+                    Block::ImageFigure { .. } | Block::Embed { .. } => {
+                        self.image_figures.push(ind);
+                    }
+                    Block::CodeBlock { language, .. } if is_graphviz(language.as_deref()) => {
                         self.image_figures.push(ind);
                     }
                     Block::DisplayMath { .. } => {
                         self.display_equations.push(ind);
                     }
-                    Block::Table { .. } => {
+                    Block::Table {
+                        caption: Some(_), ..
+                    } => {
                         self.tables.push(ind);
                     }
+                    Block::CodeBlock {
+                        caption: Some(_), ..
+                    } => {
+                        self.listings.push(ind);
+                    }
                     _ => {}
                 }
                 blocks.push(block)
@@ -67,28 +143,38 @@ impl Parser {
                 continue;
             }
 
-            if trimmed == "???" {
+            if is_raw_fence(trimmed) {
                 return Some(Self::parse_raw_block(lines));
+            } else if is_comment(trimmed) {
+                return Some(Self::parse_comment(lines));
             } else if trimmed == "~~~~" {
                 return Some(Self::parse_code_block_nohighlight(lines));
             } else if trimmed == "~~~" {
-                return Some(Self::parse_code_block(lines));
+                return Some(self.parse_code_block(lines));
             } else if trimmed.starts_with('#') {
                 return Some(self.parse_section_header(lines));
             } else if trimmed.starts_with("> ") {
                 return Some(self.parse_blockquote(lines));
             } else if trimmed.starts_with("pic ") {
                 return Some(self.parse_image_figure(lines));
+            } else if let Some(embed) = self.parse_embed(trimmed) {
+                lines.next();
+                return Some(embed);
+            } else if let Some(path) = include_path(trimmed) {
+                lines.next();
+                return Some(Block::Include { path: path.into() });
             } else if trimmed.starts_with("$ ") {
                 return Some(self.parse_display_math(lines));
             } else if trimmed.starts_with("| ") {
                 return Some(self.parse_table(lines));
             } else if trimmed.starts_with(":: ") {
                 return Some(self.parse_big_button(lines));
+            } else if trimmed.starts_with("+++ ") {
+                return Some(self.parse_details(lines));
             } else if Self::is_unordered_list_item(trimmed) {
-                return Some(Self::parse_unordered_list(lines));
+                return Some(self.parse_unordered_list(lines));
             } else if trimmed.starts_with("1. ") {
-                return Some(Self::parse_ordered_list(lines));
+                return Some(self.parse_ordered_list(lines));
             } else {
                 return Some(Self::parse_paragraph(lines));
             }
@@ -114,9 +200,93 @@ impl Parser {
         ORDERED_LIST_REGEX.is_match(s)
     }
 
+    /// `+++ summary` and the blocks up to the matching `+++`, which may hold
+    /// details blocks of their own. Fenced code and raw blocks are skipped
+    /// over, so a `+++` line inside them does not close anything.
+    fn parse_details(&mut self, lines: &mut std::iter::Peekable<Lines>) -> Block {
+        let summary = lines
+            .next()
+            .and_then(|line| line.trim().strip_prefix("+++ "))
+            .unwrap_or_default();
+        let summary = Self::parse_inline_elements(summary.trim());
+
+        let mut content = Vec::new();
+        let mut depth = 0;
+        let mut fence = None;
+        for line in lines.by_ref() {
+            let trimmed = line.trim();
+            match fence {
+                Some(open) if trimmed == open => fence = None,
+                Some(_) => {}
+                None if trimmed == "~~~" || trimmed == "~~~~" => fence = Some(trimmed),
+                None if is_raw_fence(trimmed) => fence = Some("???"),
+                None if trimmed.starts_with("+++ ") => depth += 1,
+                None if trimmed == "+++" => {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                }
+                None => {}
+            }
+            content.push(line);
+        }
+
+        Block::Details {
+            summary,
+            blocks: self.parse_body(&content.join("\n")),
+        }
+    }
+
+    /// The blocks of the file an `include` line names, or `None` when this
+    /// parser keeps `include` lines. A file that cannot be read, or that is
+    /// already being included, adds an error and no blocks.
+    fn include(&mut self, path: &str) -> Option<Vec<Block>> {
+        let including = self.include_stack.last()?;
+        let file = including.parent().unwrap_or(Path::new(".")).join(path);
+        let file = match fs::canonicalize(&file) {
+            Ok(file) => file,
+            Err(e) => {
+                self.include_errors
+                    .push(format!("cannot include {}: {}", file.display(), e));
+                return Some(Vec::new());
+            }
+        };
+        if self.include_stack.contains(&file) {
+            let chain: Vec<String> = self
+                .include_stack
+                .iter()
+                .chain([&file])
+                .map(|file| file.display().to_string())
+                .collect();
+            self.include_errors
+                .push(format!("include cycle: {}", chain.join(" -> ")));
+            return Some(Vec::new());
+        }
+        let source = match fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(e) => {
+                self.include_errors
+                    .push(format!("cannot include {}: {}", file.display(), e));
+                return Some(Vec::new());
+            }
+        };
+        self.included.push(file.clone());
+        self.include_stack.push(file);
+        let blocks = self.parse_body(&source);
+        self.include_stack.pop();
+        Some(blocks)
+    }
+
     fn parse_raw_block(lines: &mut std::iter::Peekable<Lines>) -> Block {
-        // Consume the starting "???"
-        lines.next();
+        // Consume the starting "???", which may name a target backend (`??? latex`)
+        let format = lines
+            .next()
+            .and_then(|line| line.trim().strip_prefix("???"))
+            .map(str::trim)
+            .filter(|format| !format.is_empty())
+            .unwrap_or("html")
+            .to_ascii_lowercase();
 
         let mut content = String::new();
 
@@ -133,21 +303,83 @@ impl Parser {
             }
         }
 
-        Block::Raw(content)
+        Block::Raw { format, content }
+    }
+
+    /// Consecutive `%%` lines, kept as a raw block for no backend so that
+    /// nothing renders them.
+    fn parse_comment(lines: &mut std::iter::Peekable<Lines>) -> Block {
+        let mut content = String::new();
+        while let Some(line) = lines.next_if(|line| is_comment(line.trim())) {
+            let text = &line.trim()["%%".len()..];
+            content.push_str(text.strip_prefix(' ').unwrap_or(text));
+            content.push('\n');
+        }
+        Block::Raw {
+            format: "comment".into(),
+            content,
+        }
     }
 
-    fn parse_code_block(lines: &mut std::iter::Peekable<Lines>) -> Block {
+    fn parse_code_block(&self, lines: &mut std::iter::Peekable<Lines>) -> Block {
         // Consume the starting "~~~"
         lines.next();
 
         let mut language = None;
+        let mut line_numbers = None;
+        let mut highlight_spec = String::new();
+        let mut filename = None;
+        let mut caption = None;
+        let mut id = None;
         let mut code = String::new();
 
-        if let Some(&line) = lines.peek() {
+        // A first `lang ...` line (bare `lang` for no language) opens the header, and
+        // `file ...` and `hl ...` lines are only read right after it, so code that
+        // starts with such a line is never taken for a directive
+        let header = lines
+            .next_if(|line| {
+                let trimmed = line.trim();
+                trimmed == "lang" || trimmed.starts_with("lang ")
+            })
+            .map(|line| &line.trim()["lang".len()..]);
+        if let Some(stripped) = header {
+            // `lang rust:numbers` toggles per-block options after the language name
+            let mut parts = stripped.split(':');
+            let name = parts.next().unwrap_or("").trim();
+            if !name.is_empty() {
+                language = Some(name.to_string());
+            }
+            for flag in parts {
+                match flag.trim() {
+                    "numbers" => line_numbers = Some(true),
+                    "nonumbers" => line_numbers = Some(false),
+                    _ => {}
+                }
+            }
+        }
+        while let Some(&line) = lines.peek().filter(|_| header.is_some()) {
             let trimmed = line.trim();
-            if let Some(stripped) = trimmed.strip_prefix("lang ") {
-                language = Some(stripped.to_string());
+            if let Some(stripped) = trimmed.strip_prefix("file ") {
+                // `file src/main.rs : caption [#label]` makes the block a numbered listing
+                let (name, caption_text) = match stripped.split_once(" : ") {
+                    Some((name, text)) => (name.trim(), Some(text.trim())),
+                    None => (stripped.trim(), None),
+                };
+                if !name.is_empty() {
+                    filename = Some(name.to_string());
+                }
+                if let Some(text) = caption_text {
+                    let (elements, anchor) = Self::parse_caption(text);
+                    caption = Some(elements);
+                    id = anchor;
+                }
+                lines.next();
+            } else if let Some(stripped) = trimmed.strip_prefix("hl ") {
+                highlight_spec.push_str(stripped);
+                highlight_spec.push(',');
                 lines.next();
+            } else {
+                break;
             }
         }
 
@@ -164,7 +396,24 @@ impl Parser {
             }
         }
 
-        Block::CodeBlock { language, code }
+        // Read once the code is, so that ranges stop at its last line
+        let highlight_lines = parse_line_ranges(&highlight_spec, code.lines().count());
+        // Graphs are numbered as figures
+        let id_number = if is_graphviz(language.as_deref()) {
+            self.image_figures.len()
+        } else {
+            self.listings.len()
+        };
+        Block::CodeBlock {
+            language,
+            code,
+            line_numbers,
+            highlight_lines,
+            filename,
+            caption,
+            id,
+            id_number,
+        }
     }
 
     fn parse_code_block_nohighlight(lines: &mut std::iter::Peekable<Lines>) -> Block {
@@ -189,10 +438,17 @@ impl Parser {
         Block::CodeBlock {
             language: None,
             code,
+            line_numbers: None,
+            highlight_lines: Vec::new(),
+            filename: None,
+            caption: None,
+            id: None,
+            id_number: 0,
         }
     }
 
-    fn generate_id(&mut self, text: &str) -> String {
+    /// A section id for `text`, unique among the ids this parser has handed out.
+    pub fn generate_id(&mut self, text: &str) -> String {
         // Generate a URL-friendly ID from the text
         let base_id = text
             .to_lowercase()
@@ -217,11 +473,22 @@ impl Parser {
             let trimmed = line.trim();
             let level = trimmed.chars().take_while(|&c| c == '#').count();
             let text = trimmed[level..].trim();
+            if let Some((text, id)) = split_custom_id(text) {
+                // Counted so a heading whose text makes the same id gets `-2`
+                *self.section_id_counts.entry(id.to_string()).or_insert(0) += 1;
+                return Block::SectionHeader {
+                    level,
+                    id: id.to_string(),
+                    text: text.to_string(),
+                    custom_id: true,
+                };
+            }
             let id = self.generate_id(text);
             Block::SectionHeader {
                 level,
                 id,
                 text: text.to_string(),
+                custom_id: false,
             }
         } else {
             // Should not reach here
@@ -252,29 +519,46 @@ impl Parser {
             if let Some(rest) = trimmed.strip_prefix("pic ") {
                 if let Some((left, caption)) = rest.split_once(" : ") {
                     let left = left.trim();
-                    // left contains: URL and then ALT text
-                    let mut parts = left.split_whitespace();
+                    // left contains: URL, optional `crop=` and `resize=off` hints and then ALT text
+                    let mut parts = left.split_whitespace().peekable();
                     let url = parts.next().unwrap_or("").to_string();
-                    let alt = parts.collect::<Vec<_>>().join(" ");
-
-                    let mut text = Self::parse_inline_elements(caption.trim());
-                    let mut id = None;
-                    for element in &mut text {
-                        if let InlineElement::ReferenceAnchor {
-                            content,
-                            ref mut invisible,
-                        } = element
-                        {
-                            *invisible = true;
-                            id = Some(content);
+                    let mut crop = None;
+                    let mut no_resize = false;
+                    while let Some(part) = parts.peek() {
+                        if let Some(parsed) = part.strip_prefix("crop=").and_then(Crop::parse) {
+                            crop = Some(parsed);
+                        } else if *part == "resize=off" {
+                            no_resize = true;
+                        } else {
+                            break;
                         }
+                        parts.next();
                     }
+                    let alt = parts.collect::<Vec<_>>().join(" ");
+
+                    // `caption :: credit` attaches a source attribution to the figure
+                    let (caption, credit) = match caption.split_once(" :: ") {
+                        Some((caption, credit)) => (caption, Some(credit.trim())),
+                        None => (caption, None),
+                    };
+                    let (text, id) = Self::parse_caption(caption.trim());
+                    let decorative = alt.trim() == "-";
                     return Block::ImageFigure {
                         url: url.trim().to_string(),
-                        id: id.cloned(),
+                        id,
                         id_number: self.image_figures.len(),
-                        alt: alt.trim().to_string(),
+                        alt: if decorative {
+                            String::new()
+                        } else {
+                            alt.trim().to_string()
+                        },
+                        decorative,
                         text,
+                        credit: credit
+                            .filter(|c| !c.is_empty())
+                            .map(Self::parse_inline_elements),
+                        crop,
+                        no_resize,
                     };
                 }
             }
@@ -283,6 +567,44 @@ impl Parser {
         Block::Paragraph(vec![])
     }
 
+    /// `embed <youtube|vimeo> <video id> [: caption]`; anything else starting
+    /// with `embed` is left to be a paragraph.
+    fn parse_embed(&self, line: &str) -> Option<Block> {
+        let rest = line.strip_prefix("embed ")?;
+        let (left, caption) = rest.split_once(" : ").unwrap_or((rest, ""));
+        let mut parts = left.split_whitespace();
+        let provider = EmbedProvider::parse(parts.next()?)?;
+        let video = parts.next()?;
+        if parts.next().is_some() || !provider.is_video_id(video) {
+            return None;
+        }
+        let (text, id) = Self::parse_caption(caption.trim());
+        Some(Block::Embed {
+            provider,
+            video: video.to_string(),
+            id,
+            id_number: self.image_figures.len(),
+            text,
+        })
+    }
+
+    /// Parses a figure-style caption; a `[#label]` anchor inside it becomes the figure id.
+    fn parse_caption(text: &str) -> (Vec<InlineElement>, Option<String>) {
+        let mut elements = Self::parse_inline_elements(text);
+        let mut id = None;
+        for element in &mut elements {
+            if let InlineElement::ReferenceAnchor {
+                content,
+                ref mut invisible,
+            } = element
+            {
+                *invisible = true;
+                id = Some(content.clone());
+            }
+        }
+        (elements, id)
+    }
+
     fn parse_display_math(&self, lines: &mut std::iter::Peekable<Lines>) -> Block {
         let mut content = String::new();
         if let Some(line) = lines.next() {
@@ -297,7 +619,7 @@ impl Parser {
                         break;
                     }
                     // Stop if a new block starts (conservative: allow most content inside math)
-                    if t == "???"
+                    if is_raw_fence(t)
                         || t == "~~~"
                         || t == "~~~~"
                         || t.starts_with("#")
@@ -314,8 +636,9 @@ impl Parser {
                     content.push_str(next_line);
                     lines.next();
                 }
+                let id = extract_equation_label(&mut content);
                 return Block::DisplayMath {
-                    id: None,
+                    id,
                     id_number: self.display_equations.len(),
                     content,
                 };
@@ -325,7 +648,7 @@ impl Parser {
         Block::Paragraph(vec![])
     }
 
-    fn parse_unordered_list(lines: &mut std::iter::Peekable<Lines>) -> Block {
+    fn parse_unordered_list(&mut self, lines: &mut std::iter::Peekable<Lines>) -> Block {
         let mut items = Vec::new();
 
         while let Some(&line) = lines.peek() {
@@ -334,17 +657,26 @@ impl Parser {
             if Self::is_unordered_list_item(trimmed) {
                 let level = trimmed.chars().take_while(|&c| c == '*').count();
                 let content = trimmed[level..].trim();
+                let (checked, content) = Self::task_checkbox(content);
                 items.push(ListItem {
                     level,
                     text: Self::parse_inline_elements(content),
+                    checked,
+                    blocks: Vec::new(),
                 });
                 lines.next();
             } else if trimmed.is_empty() {
-                lines.next(); // end of list block
-                break;
+                lines.next();
+                match items.last_mut() {
+                    Some(last) if Self::indented_lines_follow(lines) => {
+                        last.blocks.extend(self.parse_item_blocks(lines))
+                    }
+                    _ => break, // end of list block
+                }
             } else {
-                // Continuation line for previous list item (multiline <li>)
-                if !items.is_empty() {
+                // Continuation line for previous list item (multiline <li>),
+                // unless blocks below the item have ended it
+                if !is_comment(trimmed) && items.last().is_some_and(|item| item.blocks.is_empty()) {
                     let mut extra = Self::parse_inline_elements(trimmed);
                     if let Some(last) = items.last_mut() {
                         last.text.push(InlineElement::Text(" ".into()));
@@ -360,7 +692,55 @@ impl Parser {
         Block::UnorderedList(items)
     }
 
-    fn parse_ordered_list(lines: &mut std::iter::Peekable<Lines>) -> Block {
+    /// Whether the next non-blank line is indented, continuing the list item
+    /// above with more blocks.
+    fn indented_lines_follow(lines: &std::iter::Peekable<Lines>) -> bool {
+        lines
+            .clone()
+            .find(|line| !line.trim().is_empty())
+            .is_some_and(|line| line.starts_with([' ', '\t']))
+    }
+
+    /// Parses the indented lines (and the blank lines between them) below a
+    /// list item as blocks of their own, with the common indentation removed.
+    fn parse_item_blocks(&mut self, lines: &mut std::iter::Peekable<Lines>) -> Vec<Block> {
+        let mut region = Vec::new();
+        while let Some(&line) = lines.peek() {
+            if !line.trim().is_empty() && !line.starts_with([' ', '\t']) {
+                break;
+            }
+            region.push(line);
+            lines.next();
+        }
+        let indent = region
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+            .min()
+            .unwrap_or(0);
+        let source = region
+            .iter()
+            .map(|line| line.get(indent..).unwrap_or(""))
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.parse_body(&source)
+    }
+
+    /// Splits a leading `[ ] ` or `[x] ` task checkbox off a list item's text.
+    fn task_checkbox(content: &str) -> (Option<bool>, &str) {
+        if let Some(rest) = content.strip_prefix("[ ] ") {
+            (Some(false), rest.trim_start())
+        } else if let Some(rest) = content
+            .strip_prefix("[x] ")
+            .or_else(|| content.strip_prefix("[X] "))
+        {
+            (Some(true), rest.trim_start())
+        } else {
+            (None, content)
+        }
+    }
+
+    fn parse_ordered_list(&mut self, lines: &mut std::iter::Peekable<Lines>) -> Block {
         let mut items = Vec::new();
 
         while let Some(&line) = lines.peek() {
@@ -373,14 +753,21 @@ impl Parser {
                 items.push(ListItem {
                     level: 1,
                     text: Self::parse_inline_elements(content),
+                    checked: None,
+                    blocks: Vec::new(),
                 });
                 lines.next();
             } else if trimmed.is_empty() {
-                lines.next(); // Skip empty line
-                break;
+                lines.next();
+                match items.last_mut() {
+                    Some(last) if Self::indented_lines_follow(lines) => {
+                        last.blocks.extend(self.parse_item_blocks(lines))
+                    }
+                    _ => break, // Skip empty line
+                }
             } else {
                 // Continuation for previous list item
-                if !items.is_empty() {
+                if !is_comment(trimmed) && items.last().is_some_and(|item| item.blocks.is_empty()) {
                     let mut extra = Self::parse_inline_elements(trimmed);
                     if let Some(last) = items.last_mut() {
                         last.text.push(InlineElement::Text(" ".into()));
@@ -407,7 +794,8 @@ impl Parser {
                 break;
             } else {
                 // Stop paragraph if hitting the start of a new block
-                if trimmed == "???"
+                if is_raw_fence(trimmed)
+                    || is_comment(trimmed)
                     || trimmed == "~~~~"
                     || trimmed == "~~~"
                     || trimmed.starts_with('#')
@@ -416,6 +804,8 @@ impl Parser {
                     || trimmed.starts_with("$ ")
                     || trimmed.starts_with("| ")
                     || trimmed.starts_with(":: ")
+                    || trimmed.starts_with("+++ ")
+                    || trimmed == "+++"
                     || Self::is_unordered_list_item(trimmed)
                     || Self::is_ordered_list_item(trimmed)
                 {
@@ -437,6 +827,20 @@ impl Parser {
         let mut buffer = String::new();
         while i < chars.len() {
             let c = chars[i];
+            // `\eqref{label}` cites a labelled display equation, same as `(#label)`
+            if c == '\\' && chars[i..].starts_with(&EQREF_PREFIX) {
+                let start = i + EQREF_PREFIX.len();
+                let j = refname_end(&chars, start);
+                if j > start && j < chars.len() && chars[j] == '}' {
+                    if !buffer.is_empty() {
+                        elements.push(InlineElement::Text(buffer.clone()));
+                        buffer.clear();
+                    }
+                    elements.push(InlineElement::Reference(chars[start..j].iter().collect()));
+                    i = j + 1;
+                    continue;
+                }
+            }
             // escape: treat next char literally
             if c == '\\' {
                 if i + 1 < chars.len() {
@@ -495,6 +899,22 @@ impl Parser {
                 elements.push(InlineElement::InlineMath(math));
                 continue;
             }
+            // language span
+            if c == '{' {
+                if let Some((lang, close)) = Self::language_span(&chars, i) {
+                    if !buffer.is_empty() {
+                        elements.push(InlineElement::Text(buffer.clone()));
+                        buffer.clear();
+                    }
+                    let text: String = chars[i + lang.len() + 2..close].iter().collect();
+                    elements.push(InlineElement::Lang {
+                        lang,
+                        text: Self::parse_inline_elements(&text),
+                    });
+                    i = close + 1;
+                    continue;
+                }
+            }
             // link
             if c == '[' {
                 if !buffer.is_empty() {
@@ -578,10 +998,7 @@ impl Parser {
                 }
             }
             if c == '(' && i + 2 < chars.len() && chars[i + 1] == '#' {
-                let mut j = i + 2;
-                while j < chars.len() && is_valid_refname_char(chars[j]) {
-                    j += 1;
-                }
+                let j = refname_end(&chars, i + 2);
                 if j > i + 2 && j < chars.len() && chars[j] == ')' {
                     if !buffer.is_empty() {
                         elements.push(InlineElement::Text(buffer.clone()));
@@ -652,49 +1069,58 @@ impl Parser {
         elements
     }
 
+    /// The tag of a `{lang|text}` span whose `{` is at `open`, and the index of
+    /// its closing `}`; braces inside the text must be balanced or escaped.
+    fn language_span(chars: &[char], open: usize) -> Option<(String, usize)> {
+        let start = open + 1;
+        let len = chars[start..]
+            .iter()
+            .take_while(|&&c| c.is_ascii_alphanumeric() || c == '-')
+            .count();
+        let lang: String = chars[start..start + len].iter().collect();
+        if chars.get(start + len) != Some(&'|') || !is_language_tag(&lang) {
+            return None;
+        }
+        let mut depth = 0usize;
+        let mut j = start + len + 1;
+        while j < chars.len() {
+            match chars[j] {
+                '\\' => j += 1,
+                '{' => depth += 1,
+                '}' if depth == 0 => return Some((lang, j)),
+                '}' => depth -= 1,
+                _ => {}
+            }
+            j += 1;
+        }
+        None
+    }
+
     fn parse_table(&self, lines: &mut std::iter::Peekable<Lines>) -> Block {
         let mut table_lines: Vec<String> = Vec::new();
         while let Some(&line) = lines.peek() {
             let t = line.trim();
-            if t.starts_with("| ") || is_table_separator_row(t) {
+            // A blank line ends the table, as it ends any block
+            if t.starts_with("| ") || (!t.is_empty() && is_table_separator_row(t)) {
                 table_lines.push(line.to_string());
                 lines.next();
             } else {
                 break;
             }
         }
-        // Next non-empty line is caption
-        let mut caption = Vec::new();
-        while let Some(&line) = lines.peek() {
-            if line.trim().is_empty() {
-                lines.next();
-                continue;
-            } else {
-                caption = Self::parse_inline_elements(line.trim());
-                lines.next();
-                break;
-            }
-        }
-        let mut header: Vec<Vec<InlineElement>> = Vec::new();
-        let mut rows: Vec<Vec<Vec<InlineElement>>> = Vec::new();
-        let mut header_filled = false;
-
-        for row in table_lines.into_iter() {
-            let t = row.trim();
-            if is_table_separator_row(t) {
-                continue;
-            }
-            let cells = parse_table_row_cells(&row)
-                .into_iter()
-                .map(|cell| Self::parse_inline_elements(cell.trim()))
-                .collect::<Vec<_>>();
-            if !header_filled {
-                header = cells;
-                header_filled = true;
-            } else {
-                rows.push(cells);
-            }
-        }
+        // A line right below the last row is the caption; after a blank line
+        // the table has none and is not numbered
+        let caption = lines
+            .next_if(|line| !line.trim().is_empty() && !is_comment(line.trim()))
+            .map(|line| Self::parse_inline_elements(line.trim()));
+        let cells: Vec<Vec<String>> = table_lines
+            .iter()
+            .filter(|row| !is_table_separator_row(row.trim()))
+            .map(|row| parse_table_row_cells(row))
+            .collect();
+        let mut rows = Self::merge_table_cells(&cells).into_iter();
+        let header = rows.next().unwrap_or_default();
+        let rows: Vec<Vec<TableCell>> = rows.collect();
         Block::Table {
             id_number: self.tables.len(),
             header,
@@ -703,6 +1129,46 @@ impl Parser {
         }
     }
 
+    /// The cells of each row, the first being the header. A cell holding just
+    /// `<` widens the cell to its left and one holding just `^` lengthens the
+    /// cell above; a marker with no such cell is kept as text.
+    fn merge_table_cells(rows: &[Vec<String>]) -> Vec<Vec<TableCell>> {
+        let mut out: Vec<Vec<TableCell>> = Vec::new();
+        // For each row and column, the (row, index) of the cell shown there
+        let mut owners: Vec<Vec<(usize, usize)>> = Vec::new();
+        // Top left (row, column) of each cell in `out`
+        let mut origins: Vec<Vec<(usize, usize)>> = Vec::new();
+        for (r, row) in rows.iter().enumerate() {
+            out.push(Vec::new());
+            owners.push(Vec::new());
+            origins.push(Vec::new());
+            for (c, text) in row.iter().enumerate() {
+                let text = text.trim();
+                let owner = match text {
+                    "<" => owners[r].last().copied(),
+                    "^" if r > 0 => owners[r - 1].get(c).copied(),
+                    _ => None,
+                };
+                let owner = match owner {
+                    Some((row, index)) => {
+                        let (top, left) = origins[row][index];
+                        let cell = &mut out[row][index];
+                        cell.colspan = cell.colspan.max(c - left + 1);
+                        cell.rowspan = cell.rowspan.max(r - top + 1);
+                        (row, index)
+                    }
+                    None => {
+                        out[r].push(TableCell::new(Self::parse_inline_elements(text)));
+                        origins[r].push((r, c));
+                        (r, out[r].len() - 1)
+                    }
+                };
+                owners[r].push(owner);
+            }
+        }
+        out
+    }
+
     fn parse_big_button(&self, lines: &mut std::iter::Peekable<Lines>) -> Block {
         if let Some(line) = lines.next() {
             let trimmed = line.trim();
@@ -726,6 +1192,27 @@ fn parse_table_row_cells(row: &str) -> Vec<String> {
         .collect::<Vec<_>>()
 }
 
+/// Parses `3,7-9` into the listed 1-based line numbers, up to `last_line`.
+fn parse_line_ranges(spec: &str, last_line: usize) -> Vec<usize> {
+    let mut lines = Vec::new();
+    for part in spec.split(',') {
+        let part = part.trim();
+        if let Some((start, end)) = part.split_once('-') {
+            if let (Ok(start), Ok(end)) =
+                (start.trim().parse::<usize>(), end.trim().parse::<usize>())
+            {
+                lines.extend(start..=end.min(last_line));
+            }
+        } else if let Ok(line) = part.parse::<usize>() {
+            lines.push(line);
+        }
+    }
+    lines.retain(|line| (1..=last_line).contains(line));
+    lines.sort_unstable();
+    lines.dedup();
+    lines
+}
+
 fn is_table_separator_row(row: &str) -> bool {
     let trimmed = row.trim();
     if trimmed.is_empty() {
@@ -734,6 +1221,61 @@ fn is_table_separator_row(row: &str) -> bool {
     trimmed.chars().all(|c| matches!(c, '|' | '-' | ' ' | '\t'))
 }
 
+/// Opening line of a raw block: `???` or `??? <format>`.
+fn is_raw_fence(trimmed: &str) -> bool {
+    trimmed == "???" || trimmed.starts_with("??? ")
+}
+
+/// A `%% note` line, left out of every output.
+fn is_comment(trimmed: &str) -> bool {
+    trimmed.starts_with("%%")
+}
+
+/// Heading text and id of `Heading {#id}`.
+fn split_custom_id(text: &str) -> Option<(&str, &str)> {
+    let (text, id) = text.strip_suffix('}')?.rsplit_once("{#")?;
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    (valid && text.ends_with(' ')).then(|| (text.trim_end(), id))
+}
+
+/// The path of an `include path.dllu` line.
+fn include_path(trimmed: &str) -> Option<&str> {
+    let path = trimmed.strip_prefix("include ")?.trim();
+    (path.ends_with(".dllu") && !path.contains(char::is_whitespace)).then_some(path)
+}
+
+const EQREF_PREFIX: [char; 7] = ['\\', 'e', 'q', 'r', 'e', 'f', '{'];
+
+/// Removes every `\label{name}` from display math (KaTeX cannot render them) and
+/// returns the first valid name, which becomes the equation id.
+fn extract_equation_label(content: &mut String) -> Option<String> {
+    lazy_static! {
+        static ref LABEL: Regex = Regex::new(r"\\label\{([^}]*)\}").unwrap();
+    }
+    let label = LABEL
+        .captures_iter(content)
+        .map(|caps| caps[1].trim().to_string())
+        .find(|name| is_valid_refname(name));
+    if LABEL.is_match(content) {
+        *content = LABEL.replace_all(content, "").trim().to_string();
+    }
+    label
+}
+
+/// Whether `tag` looks like a BCP 47 language tag such as `fr`, `zh-Hant` or
+/// `de-CH-1996`, as accepted in `{lang|text}` spans.
+pub fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or("");
+    (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags
+            .all(|s| (1..=8).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
 fn is_valid_refname(s: &str) -> bool {
     !s.is_empty() && s.chars().all(is_valid_refname_char)
 }
@@ -742,6 +1284,26 @@ fn is_valid_refname_char(c: char) -> bool {
     c.is_alphanumeric() || matches!(c, '-' | '_')
 }
 
+/// End of the reference name starting at `start`: a label, optionally after
+/// the `page:` slug of another page of the site.
+fn refname_end(chars: &[char], start: usize) -> usize {
+    let label_end = |from: usize| {
+        let mut j = from;
+        while j < chars.len() && is_valid_refname_char(chars[j]) {
+            j += 1;
+        }
+        j
+    };
+    let j = label_end(start);
+    if j > start && chars.get(j) == Some(&':') {
+        let k = label_end(j + 1);
+        if k > j + 1 {
+            return k;
+        }
+    }
+    j
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -756,6 +1318,34 @@ mod tests {
             .join("")
     }
 
+    #[test]
+    fn headings_can_set_their_own_id() {
+        let mut parser = Parser::default();
+        parser.parse("T\n\n===\n\n# Setup {#install}\n\n# Install\n\n# Sets {#a b}\n");
+        let headings: Vec<(&str, &str, bool)> = parser
+            .article
+            .body
+            .iter()
+            .filter_map(|block| match block {
+                Block::SectionHeader {
+                    id,
+                    text,
+                    custom_id,
+                    ..
+                } => Some((id.as_str(), text.as_str(), *custom_id)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            headings,
+            vec![
+                ("install", "Setup", true),
+                ("install-2", "Install", false),
+                ("sets-a-b", "Sets {#a b}", false)
+            ]
+        );
+    }
+
     #[test]
     fn separator_rows_are_ignored() {
         let input = "Table Demo\n\n===\n\n| Colour | Pattern |\n| ------- | -------- |\n| White | Spots |\n";
@@ -774,12 +1364,65 @@ mod tests {
             })
             .expect("expected table");
         assert_eq!(table.0.len(), 2);
-        assert_eq!(cell_text(&table.0[0]), "Colour");
-        assert_eq!(cell_text(&table.0[1]), "Pattern");
+        assert_eq!(cell_text(&table.0[0].content), "Colour");
+        assert_eq!(cell_text(&table.0[1].content), "Pattern");
         assert_eq!(table.1.len(), 1);
         assert_eq!(table.1[0].len(), 2);
-        assert_eq!(cell_text(&table.1[0][0]), "White");
-        assert_eq!(cell_text(&table.1[0][1]), "Spots");
+        assert_eq!(cell_text(&table.1[0][0].content), "White");
+        assert_eq!(cell_text(&table.1[0][1].content), "Spots");
+    }
+
+    #[test]
+    fn merge_markers_span_columns_and_rows() {
+        let input =
+            "T\n\n===\n\n| Model | Speed | < | \\< |\n| A | 1 | 2 | ^ |\n| ^ | 3 | 4 | x |\n";
+        let mut parser = Parser::default();
+        parser.parse(input);
+        let Block::Table { header, rows, .. } = &parser.article.body[0] else {
+            panic!("expected table");
+        };
+        let spans = |row: &[TableCell]| -> Vec<(String, usize, usize)> {
+            row.iter()
+                .map(|cell| (cell_text(&cell.content), cell.colspan, cell.rowspan))
+                .collect()
+        };
+        assert_eq!(
+            spans(header),
+            vec![
+                ("Model".into(), 1, 1),
+                ("Speed".into(), 2, 1),
+                ("<".into(), 1, 2)
+            ]
+        );
+        assert_eq!(
+            spans(&rows[0]),
+            vec![("A".into(), 1, 2), ("1".into(), 1, 1), ("2".into(), 1, 1)]
+        );
+        assert_eq!(
+            spans(&rows[1]),
+            vec![("3".into(), 1, 1), ("4".into(), 1, 1), ("x".into(), 1, 1)]
+        );
+    }
+
+    #[test]
+    fn only_a_line_right_below_a_table_is_its_caption() {
+        let input =
+            "T\n\n===\n\n| a | b |\n| 1 | 2 |\nNumbers.\n\n| c |\n| 3 |\n\nNot a caption.\n";
+        let mut parser = Parser::default();
+        parser.parse(input);
+        let body = &parser.article.body;
+        assert_eq!(body.len(), 3);
+        let Block::Table {
+            caption: Some(caption),
+            ..
+        } = &body[0]
+        else {
+            panic!("expected captioned table");
+        };
+        assert_eq!(cell_text(caption), "Numbers.");
+        assert!(matches!(body[1], Block::Table { caption: None, .. }));
+        assert!(matches!(body[2], Block::Paragraph(_)));
+        assert_eq!(parser.tables, vec![0]);
     }
 
     #[test]
@@ -803,6 +1446,114 @@ mod tests {
         assert!(table.iter().all(|row| row.len() == 2));
     }
 
+    #[test]
+    fn parses_code_block_line_number_flag() {
+        let input = "Doc\n\n===\n\n~~~\nlang rust:numbers\nfn main() {}\n~~~\n";
+        let mut parser = Parser::default();
+        parser.parse(input);
+        match &parser.article.body[0] {
+            Block::CodeBlock {
+                language,
+                line_numbers,
+                ..
+            } => {
+                assert_eq!(language.as_deref(), Some("rust"));
+                assert_eq!(*line_numbers, Some(true));
+            }
+            other => panic!("expected code block, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_code_block_directives_only_after_a_lang_line() {
+        let input = "Doc\n\n===\n\n~~~\nfile foo.bin\nhl 2\n~~~\n\n~~~\nlang\nfile build.rs\nhl 1\nlang sh\n~~~\n";
+        let mut parser = Parser::default();
+        parser.parse(input);
+        let blocks: Vec<_> = parser
+            .article
+            .body
+            .iter()
+            .map(|block| match block {
+                Block::CodeBlock {
+                    language,
+                    code,
+                    highlight_lines,
+                    filename,
+                    ..
+                } => (
+                    language.as_deref(),
+                    code.as_str(),
+                    highlight_lines.clone(),
+                    filename.as_deref(),
+                ),
+                other => panic!("expected code block, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                (None, "file foo.bin\nhl 2\n", vec![], None),
+                (None, "lang sh\n", vec![1], Some("build.rs")),
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_graphs_as_figures() {
+        let input = "Doc\n\n===\n\npic a.jpg A : One\n\n~~~\nlang dot\nfile deps.dot : Crates\ndigraph { a -> b }\n~~~\n\n~~~\nlang rust\nfile main.rs : Entry\nfn main() {}\n~~~\n";
+        let mut parser = Parser::default();
+        parser.parse(input);
+        let numbers: Vec<usize> = parser
+            .article
+            .body
+            .iter()
+            .filter_map(|block| match block {
+                Block::CodeBlock { id_number, .. } => Some(*id_number),
+                _ => None,
+            })
+            .collect();
+        // The graph is the second figure and the Rust block the first listing
+        assert_eq!(numbers, vec![1, 0]);
+    }
+
+    #[test]
+    fn parses_highlight_line_ranges() {
+        assert_eq!(parse_line_ranges("3,7-9", 10), vec![3, 7, 8, 9]);
+        assert_eq!(parse_line_ranges(" 2 , x, 0, 2", 10), vec![2]);
+        assert_eq!(parse_line_ranges("2-4000000000,9", 3), vec![2, 3]);
+    }
+
+    #[test]
+    fn parses_custom_header_vars() {
+        let input =
+            "Doc\nX-Hero-Color: #336699\n2024-01-02\nx-repo:  dllu/dllup-rs \n\n===\n\nBody\n";
+        let mut parser = Parser::default();
+        parser.parse(input);
+        let header = parser.article.header.expect("expected header");
+        assert_eq!(header.date.as_deref(), Some("2024-01-02"));
+        assert_eq!(header.vars.get("x-hero-color").unwrap(), "#336699");
+        assert_eq!(header.vars.get("x-repo").unwrap(), "dllu/dllup-rs");
+        assert!(!header.pinned);
+
+        let mut parser = Parser::default();
+        parser.parse(
+            "Notice\npinned: true\nFeatured: yes\ntemplate: wide\n2024-03-01\n\n===\n\nBody\n",
+        );
+        let header = parser.article.header.expect("expected header");
+        assert_eq!(header.date.as_deref(), Some("2024-03-01"));
+        assert!(header.pinned && header.featured);
+        assert_eq!(header.template.as_deref(), Some("wide"));
+
+        let mut parser = Parser::default();
+        parser.parse(
+            "Notes\n2024-03-01 10:30\nSubtitle: On kites\nhero_image: kite.jpg\n\n===\n\nBody\n",
+        );
+        let header = parser.article.header.expect("expected header");
+        assert_eq!(header.date.as_deref(), Some("2024-03-01 10:30"));
+        assert_eq!(header.meta.get("subtitle").unwrap(), "On kites");
+        assert_eq!(header.meta.get("hero_image").unwrap(), "kite.jpg");
+    }
+
     #[test]
     fn parses_reference_citation() {
         let input = "Doc\n\n===\n\nThis cites (#eade).\n";
@@ -825,6 +1576,23 @@ mod tests {
             .any(|el| { matches!(el, InlineElement::Reference(name) if name == "eade") }));
     }
 
+    #[test]
+    fn references_can_name_another_page() {
+        let mut parser = Parser::default();
+        parser.parse("Doc\n\n===\n\nBy (#part-1:energy) and \\eqref{part-1:mass}, not (#a:).\n");
+        let Block::Paragraph(elements) = &parser.article.body[0] else {
+            panic!("expected paragraph");
+        };
+        let names: Vec<&str> = elements
+            .iter()
+            .filter_map(|el| match el {
+                InlineElement::Reference(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, vec!["part-1:energy", "part-1:mass"]);
+    }
+
     #[test]
     fn parses_reference_anchor() {
         let input = "Doc\n\n===\n\n[#eade]\n";
@@ -852,4 +1620,231 @@ mod tests {
             )
         }));
     }
+
+    #[test]
+    fn parses_figure_credit() {
+        let input =
+            "Doc\n\n===\n\npic a.jpg A cat : A cat [#cat] :: Photo by [Jo](https://jo.example)\n";
+        let mut parser = Parser::default();
+        parser.parse(input);
+        match &parser.article.body[0] {
+            Block::ImageFigure {
+                id, text, credit, ..
+            } => {
+                assert_eq!(id.as_deref(), Some("cat"));
+                assert_eq!(cell_text(text), "A cat ");
+                let credit = credit.as_ref().expect("expected credit");
+                assert_eq!(cell_text(credit), "Photo by ");
+                assert!(
+                    matches!(&credit[1], InlineElement::Link { url, .. } if url == "https://jo.example")
+                );
+            }
+            other => panic!("expected figure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_figure_crop_hint() {
+        let mut parser = Parser::default();
+        parser.parse("Doc\n\n===\n\npic a.jpg crop=16:9@0.25,0.4 A kite : Kite\n");
+        match &parser.article.body[0] {
+            Block::ImageFigure { alt, crop, .. } => {
+                assert_eq!(alt, "A kite");
+                let crop = crop.expect("expected crop");
+                assert_eq!(crop.aspect, Some(16.0 / 9.0));
+                assert_eq!(crop.focus, Some((0.25, 0.4)));
+            }
+            other => panic!("expected figure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_figure_resize_opt_out() {
+        let mut parser = Parser::default();
+        parser.parse("Doc\n\n===\n\npic a.svg resize=off crop=1:1 Plot : Plot\n");
+        match &parser.article.body[0] {
+            Block::ImageFigure {
+                alt,
+                crop,
+                no_resize,
+                ..
+            } => {
+                assert_eq!(alt, "Plot");
+                assert!(crop.is_some());
+                assert!(*no_resize);
+            }
+            other => panic!("expected figure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_embeds_numbered_with_figures() {
+        let mut parser = Parser::default();
+        parser.parse(
+            "Doc\n\n===\n\npic a.jpg A : One\n\nembed youtube dQw4w9WgXcQ : Talk [#talk]\n\nembed vimeo not-a-number\n",
+        );
+        match &parser.article.body[1] {
+            Block::Embed {
+                provider,
+                video,
+                id,
+                id_number,
+                ..
+            } => {
+                assert_eq!(*provider, EmbedProvider::YouTube);
+                assert_eq!(video, "dQw4w9WgXcQ");
+                assert_eq!(id.as_deref(), Some("talk"));
+                assert_eq!(*id_number, 1);
+            }
+            other => panic!("expected embed, got {:?}", other),
+        }
+        assert!(matches!(&parser.article.body[2], Block::Paragraph(_)));
+    }
+
+    #[test]
+    fn parses_language_spans() {
+        let mut parser = Parser::default();
+        parser.parse("Doc\n\n===\n\nSay {fr|_bonjour_ {x}} or {zh-Hant|你好}, not {{x-name}}, {a|b} or {en|open.\n");
+        let Block::Paragraph(elements) = &parser.article.body[0] else {
+            panic!("expected paragraph");
+        };
+        let langs: Vec<(&str, usize)> = elements
+            .iter()
+            .filter_map(|el| match el {
+                InlineElement::Lang { lang, text } => Some((lang.as_str(), text.len())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(langs, vec![("fr", 2), ("zh-Hant", 1)]);
+        assert!(matches!(
+            elements.last(),
+            Some(InlineElement::Text(t)) if t == ", not {{x-name}}, {a|b} or {en|open."
+        ));
+    }
+
+    #[test]
+    fn raw_blocks_take_an_optional_format() {
+        let mut parser = Parser::default();
+        parser.parse("Doc\n\n===\n\n???\n<hr>\n???\n\nText\n??? LaTeX\n\\newpage\n???\n");
+        let raws: Vec<(&str, &str)> = parser
+            .article
+            .body
+            .iter()
+            .filter_map(|block| match block {
+                Block::Raw { format, content } => Some((format.as_str(), content.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(raws, vec![("html", "<hr>\n"), ("latex", "\\newpage\n")]);
+    }
+
+    #[test]
+    fn comment_lines_become_a_block_no_backend_renders() {
+        let mut parser = Parser::default();
+        parser.parse("Doc\n\n===\n\nText\n%% TODO: cite\n%%   the paper\n* Item\n%%\n");
+        let body = &parser.article.body;
+        assert_eq!(body.len(), 4);
+        assert!(matches!(&body[1], Block::Raw { format, content }
+            if format == "comment" && content == "TODO: cite\n  the paper\n"));
+        assert!(matches!(&body[2], Block::UnorderedList(items) if items[0].text.len() == 1));
+        assert!(matches!(&body[3], Block::Raw { content, .. } if content == "\n"));
+    }
+
+    #[test]
+    fn includes_splice_in_the_blocks_of_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let post = dir.path().join("post.dllu");
+        std::fs::create_dir(dir.path().join("parts")).unwrap();
+        std::fs::write(
+            dir.path().join("parts").join("bio.dllu"),
+            "$ x = 1\n\ninclude loop.dllu\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("parts").join("loop.dllu"),
+            "include bio.dllu\n",
+        )
+        .unwrap();
+        let source = "Doc\n\n===\n\n$ y = 2\n\ninclude parts/bio.dllu\n";
+
+        let mut parser = Parser::with_includes(&post);
+        parser.parse(source);
+        let body = &parser.article.body;
+        assert_eq!(body.len(), 2);
+        assert!(matches!(body[1], Block::DisplayMath { id_number: 1, .. }));
+        assert_eq!(parser.included.len(), 2);
+        assert_eq!(parser.include_errors.len(), 1);
+        assert!(parser.include_errors[0].starts_with("include cycle: "));
+
+        // Without a source file the line is kept for `fmt` and the like
+        let mut parser = Parser::default();
+        parser.parse(source);
+        assert!(
+            matches!(&parser.article.body[1], Block::Include { path } if path == "parts/bio.dllu")
+        );
+    }
+
+    #[test]
+    fn indented_blocks_belong_to_the_list_item_above() {
+        let mut parser = Parser::default();
+        parser.parse(
+            "Doc\n\n===\n\n* Install it.\n\n  Then check:\n\n  ~~~~\n  make check\n  ~~~~\n* Done.\n\nAfter.\n",
+        );
+        let body = &parser.article.body;
+        assert_eq!(body.len(), 2);
+        let Block::UnorderedList(items) = &body[0] else {
+            panic!("expected a list, got {:?}", body[0]);
+        };
+        assert_eq!(items.len(), 2);
+        assert!(matches!(
+            items[0].blocks.as_slice(),
+            [Block::Paragraph(_), Block::CodeBlock { .. }]
+        ));
+        assert!(items[1].blocks.is_empty());
+        assert!(matches!(&body[1], Block::Paragraph(_)));
+    }
+
+    #[test]
+    fn equations_are_listed_in_document_order() {
+        let mut parser = Parser::default();
+        parser
+            .parse("Doc\n\n===\n\nLet $x$ be *$y^2$*.\n\n$ e = mc^2\n\n* With $z$\n\n  $ a + b\n");
+        assert_eq!(
+            parser.article.equations(),
+            vec![
+                ("x", true),
+                ("y^2", true),
+                ("e = mc^2", false),
+                ("z", true),
+                ("a + b", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn details_blocks_nest_and_skip_fenced_code() {
+        let mut parser = Parser::default();
+        parser.parse(
+            "Doc\n\n===\n\n+++ The _long_ derivation\n\n$ a = b\n\n+++ Spoiler\nIt was $x$.\n+++\n\n~~~~\n+++\n~~~~\n+++\n\nAfter.\n",
+        );
+        let body = &parser.article.body;
+        assert_eq!(body.len(), 2);
+        let Block::Details { summary, blocks } = &body[0] else {
+            panic!("expected details, got {:?}", body[0]);
+        };
+        assert_eq!(summary.len(), 3);
+        assert!(matches!(
+            blocks.as_slice(),
+            [
+                Block::DisplayMath { .. },
+                Block::Details { .. },
+                Block::CodeBlock { .. }
+            ]
+        ));
+        assert_eq!(
+            parser.article.equations(),
+            vec![("a = b", false), ("x", true)]
+        );
+        assert!(matches!(&body[1], Block::Paragraph(_)));
+    }
 }
@@ -0,0 +1,95 @@
+//! External AST transforms configured as `plugins`, run between parse and render.
+//!
+//! Each plugin receives the article as JSON (the serde form of [`Article`]) on
+//! stdin and must print the transformed article as JSON on stdout.
+
+use crate::ast::Article;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Pipes `article` through every plugin in order, replacing it with the result.
+pub fn apply(plugins: &[String], article: &mut Article, input_path: &Path) -> Result<(), String> {
+    for plugin in plugins {
+        *article = run_plugin(plugin, article, input_path)?;
+    }
+    Ok(())
+}
+
+fn run_plugin(plugin: &str, article: &Article, input_path: &Path) -> Result<Article, String> {
+    let parts =
+        shell_words::split(plugin).map_err(|e| format!("invalid plugin `{}`: {}", plugin, e))?;
+    let (program, args) = parts
+        .split_first()
+        .ok_or_else(|| "empty plugin command".to_string())?;
+    let input = serde_json::to_vec(article).map_err(|e| e.to_string())?;
+    let mut child = Command::new(program)
+        .args(args)
+        .env("DLLUP_INPUT", input_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| format!("failed to run plugin `{}`: {}", plugin, e))?;
+    // Write from a thread so a plugin that streams output before reading all of
+    // its input cannot deadlock against us.
+    let mut stdin = child.stdin.take().ok_or("failed to open plugin stdin")?;
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("failed waiting for plugin `{}`: {}", plugin, e))?;
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(format!(
+            "plugin `{}` failed on {}: {}",
+            plugin,
+            input_path.display(),
+            output.status
+        ));
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| {
+        format!(
+            "plugin `{}` returned an invalid article for {}: {}",
+            plugin,
+            input_path.display(),
+            e
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Block;
+    use crate::parser::Parser;
+
+    #[test]
+    fn identity_plugin_round_trips_the_article() {
+        let mut parser = Parser::default();
+        parser.parse("Title\n\n===\n\n# Intro\n\nSome *text*.\n");
+        let before = format!("{:?}", parser.article);
+        apply(
+            &["cat".to_string()],
+            &mut parser.article,
+            Path::new("x.dllu"),
+        )
+        .unwrap();
+        assert_eq!(format!("{:?}", parser.article), before);
+    }
+
+    #[test]
+    fn plugins_can_rewrite_blocks() {
+        let mut article = Article::default();
+        let plugin = r#"sh -c 'cat >/dev/null; echo "{\"header\":null,\"body\":[{\"Paragraph\":[{\"Text\":\"injected\"}]}]}"'"#;
+        apply(&[plugin.to_string()], &mut article, Path::new("x.dllu")).unwrap();
+        assert!(matches!(article.body.as_slice(), [Block::Paragraph(_)]));
+    }
+
+    #[test]
+    fn failing_plugin_is_an_error() {
+        let mut article = Article::default();
+        let err = apply(&["false".to_string()], &mut article, Path::new("x.dllu")).unwrap_err();
+        assert!(err.starts_with("plugin `false` failed on x.dllu"));
+    }
+}
@@ -0,0 +1,155 @@
+//! Spelling checks for `dllup-rs lint --prose`. The text readers see, without
+//! code, math, references, URLs or `{lang|...}` words, is piped to a checker
+//! command that prints the words it doesn't know, one per line: `hunspell -l`
+//! by default, or `lint.prose_command`.
+
+use crate::ast::{Article, InlineElement};
+use crate::config;
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::lint;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// The words of `article` the checker doesn't know, each pointed at the
+/// first line of `source` that has it.
+pub fn check(
+    input_path: &Path,
+    source: &str,
+    article: &Article,
+    lint: &config::LintConfig,
+) -> Result<Vec<Diagnostic>, String> {
+    let text = prose(article);
+    if text.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let command = match &lint.prose_command {
+        Some(command) => command.clone(),
+        None => format!(
+            "hunspell -l -d {}",
+            lint.prose_dictionary.as_deref().unwrap_or("en_US")
+        ),
+    };
+    let output = unknown_words(&command, &text)?;
+    let accepted: HashSet<String> = lint
+        .prose_words
+        .iter()
+        .map(|word| word.to_lowercase())
+        .collect();
+    let mut reported = HashSet::new();
+    Ok(output
+        .lines()
+        .map(str::trim)
+        .filter(|word| !word.is_empty() && !accepted.contains(&word.to_lowercase()))
+        .filter(|word| reported.insert(*word))
+        .map(|word| {
+            let message = format!("unknown word `{}`", word);
+            Diagnostic::new(input_path, Severity::Warning, "spelling", message).at(source, word)
+        })
+        .collect())
+}
+
+/// The prose of every block, one line per caption, cell, item or paragraph.
+fn prose(article: &Article) -> String {
+    let mut out = String::new();
+    for block in article.blocks() {
+        for inlines in lint::block_inlines(block) {
+            push_prose(inlines, &mut out);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn push_prose(inlines: &[InlineElement], out: &mut String) {
+    for inline in inlines {
+        match inline {
+            InlineElement::Text(text) => out.push_str(text),
+            InlineElement::Link { text, .. }
+            | InlineElement::Emphasis(text)
+            | InlineElement::Strong(text) => push_prose(text, out),
+            // Words of another language would all be unknown
+            InlineElement::Lang { .. }
+            | InlineElement::Code(_)
+            | InlineElement::InlineMath(_)
+            | InlineElement::Reference(_)
+            | InlineElement::ReferenceAnchor { .. } => out.push(' '),
+        }
+    }
+}
+
+fn unknown_words(command: &str, text: &str) -> Result<String, String> {
+    let parts = shell_words::split(command)
+        .map_err(|e| format!("invalid prose command `{}`: {}", command, e))?;
+    let (program, args) = parts
+        .split_first()
+        .ok_or_else(|| "empty prose command".to_string())?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| format!("failed to run prose command `{}`: {}", command, e))?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or("failed to open prose command stdin")?;
+    let input = text.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("failed waiting for prose command `{}`: {}", command, e))?;
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(format!(
+            "prose command `{}` failed: {}",
+            command, output.status
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn leaves_code_math_and_other_languages_out() {
+        let mut parser = Parser::default();
+        parser.parse(
+            "T\n\n===\n\nRun `teh` on $x$ and {fr|bonjour} _all_ [the docs](https://x.org).\n\n* An item\n",
+        );
+        assert_eq!(
+            prose(&parser.article),
+            "Run   on   and   all the docs.\nAn item\n"
+        );
+    }
+
+    #[test]
+    fn reports_each_unknown_word_once() {
+        let mut parser = Parser::default();
+        let source = "T\n\n===\n\nWe recieve it.\n\nThen teh Smith recieve `teh`.\n";
+        parser.parse(source);
+        let lint = config::LintConfig {
+            prose_command: Some(r#"sh -c "grep -ow 'recieve\|teh\|Smith' || true""#.into()),
+            prose_words: vec!["smith".into()],
+            ..Default::default()
+        };
+        let findings = check(Path::new("a.dllu"), source, &parser.article, &lint).unwrap();
+        let found: Vec<(&str, Option<usize>)> = findings
+            .iter()
+            .map(|finding| (finding.message.as_str(), finding.line))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("unknown word `recieve`", Some(5)),
+                ("unknown word `teh`", Some(7))
+            ]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Templates and highlight themes given by URL in the config, so several sites
+//! can share one hosted theme without vendoring it.
+//!
+//! Each is downloaded into `html.remote_cache_dir` when the config is loaded and
+//! the config then points at the local copy. A `#blake3=<hash>` fragment (at
+//! least 12 hex digits of the BLAKE3 hash, as printed by `b3sum`) pins the
+//! content: a matching cached copy is used without asking the origin, and a
+//! download with any other content is an error. Unpinned copies are revalidated
+//! once per build like remote images.
+
+use crate::downloads::{self, FetchPolicy, Fetched, Validators};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const PIN_FRAGMENT: &str = "#blake3=";
+
+lazy_static! {
+    /// Pages render in parallel; one download of each file is enough.
+    static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+pub fn is_url(reference: &str) -> bool {
+    reference.starts_with("https://") || reference.starts_with("http://")
+}
+
+/// The path of a local copy of `reference` if it is a URL; anything else is
+/// returned as it is.
+pub fn localize(reference: &str, cache_dir: &Path, policy: FetchPolicy) -> Result<String, String> {
+    if !is_url(reference) {
+        return Ok(reference.to_string());
+    }
+    let (url, pin) = match reference.split_once(PIN_FRAGMENT) {
+        Some((url, pin)) => (url, Some(parse_pin(reference, pin)?)),
+        None => (reference, None),
+    };
+    let _guard = LOCK.lock().unwrap();
+    let path = cache_path(cache_dir, url);
+    let local = path.to_string_lossy().into_owned();
+
+    let cached = fs::read(&path).ok();
+    let mut validators = None;
+    if let Some(bytes) = &cached {
+        match &pin {
+            // Pinned content cannot change, so a matching copy needs no request
+            Some(pin) if matches_pin(bytes, pin) => return Ok(local),
+            Some(_) => {}
+            None => {
+                validators = Validators::load(&path);
+                if !downloads::should_revalidate(url, validators.is_some()) {
+                    return Ok(local);
+                }
+            }
+        }
+    }
+
+    let validators = validators.filter(|_| !downloads::refreshing());
+    let (bytes, fresh) = match downloads::fetch(url, policy, validators.as_ref()) {
+        Ok(Fetched::Modified(bytes, fresh)) => (bytes, fresh),
+        Ok(Fetched::NotModified) => return Ok(local),
+        Err(e) if cached.is_some() && pin.is_none() => {
+            crate::diagnostics::warn(format!(
+                "could not revalidate {}: {}; using the cached copy",
+                url, e
+            ));
+            return Ok(local);
+        }
+        Err(e) => return Err(format!("failed to fetch {}: {}", url, e)),
+    };
+    if let Some(pin) = &pin {
+        if !matches_pin(&bytes, pin) {
+            return Err(format!(
+                "{} does not match its pinned hash {} (got {})",
+                url,
+                pin,
+                blake3::hash(&bytes).to_hex()
+            ));
+        }
+    }
+    fs::create_dir_all(cache_dir)
+        .map_err(|e| format!("failed to create {}: {}", cache_dir.display(), e))?;
+    fs::write(&path, &bytes).map_err(|e| format!("failed to write {}: {}", local, e))?;
+    fresh
+        .save(&path)
+        .map_err(|e| format!("failed to write validators for {}: {}", local, e))?;
+    Ok(local)
+}
+
+fn parse_pin(reference: &str, pin: &str) -> Result<String, String> {
+    if pin.len() < 12 || pin.len() > 64 || !pin.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!(
+            "invalid pin in {}: expected 12 to 64 hex digits of the BLAKE3 hash",
+            reference
+        ));
+    }
+    Ok(pin.to_ascii_lowercase())
+}
+
+fn matches_pin(bytes: &[u8], pin: &str) -> bool {
+    blake3::hash(bytes).to_hex().starts_with(pin)
+}
+
+/// `<hash of the URL>-<file name>`, keeping the extension that tells a theme
+/// file from a template.
+fn cache_path(cache_dir: &Path, url: &str) -> PathBuf {
+    let name: String = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(url)
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        .collect();
+    let name = if name.is_empty() { "file" } else { &name };
+    let hash = blake3::hash(url.as_bytes()).to_hex();
+    cache_dir.join(format!("{}-{}", &hash[..12], name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_copy_in_the_cache_is_used_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = "https://themes.invalid/base/template.html";
+        let template = b"<html>{{body}}</html>";
+        let path = cache_path(dir.path(), url);
+        assert!(path.to_string_lossy().ends_with("-template.html"));
+        fs::write(&path, template).unwrap();
+
+        let hash = blake3::hash(template).to_hex();
+        let pinned = format!("{}#blake3={}", url, &hash[..16]);
+        let policy = FetchPolicy::new(&crate::config::ImagesConfig::default());
+        let local = localize(&pinned, dir.path(), policy).unwrap();
+        assert_eq!(Path::new(&local), path);
+
+        let short = format!("{}#blake3=abc", url);
+        assert!(localize(&short, dir.path(), policy).is_err());
+        assert_eq!(
+            localize("static/t.html", dir.path(), policy).unwrap(),
+            "static/t.html"
+        );
+    }
+}
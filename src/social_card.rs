@@ -0,0 +1,257 @@
+//! 1200×630 PNG social cards (`[cards]`) used as `og:image` for pages without
+//! a figure fit for one: the title and site name drawn over a background, or a
+//! portrait cover photo letterboxed onto the background. Cards are named after
+//! a hash of everything drawn on them, so unchanged pages reuse their card.
+
+use crate::config::CardsConfig;
+use crate::image_processor;
+use ab_glyph::{point, Font, FontVec, GlyphId, PxScale, ScaleFont};
+use image::imageops::FilterType;
+use image::{ImageFormat, Rgb, RgbImage};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const WIDTH: u32 = 1200;
+pub const HEIGHT: u32 = 630;
+/// Space kept clear around the text, in pixels.
+const MARGIN: f32 = 80.0;
+const TITLE_SIZE: f32 = 64.0;
+const TITLE_LINES: usize = 4;
+const SITE_NAME_SIZE: f32 = 32.0;
+
+/// What a card shows.
+pub enum CardContent<'a> {
+    Title {
+        title: &'a str,
+        site_name: Option<&'a str>,
+    },
+    /// A local image scaled to fit inside the card.
+    Photo(&'a Path),
+}
+
+/// Draws the card into `cache_dir` unless an identical one is already there,
+/// and returns its path.
+pub fn generate(
+    config: &CardsConfig,
+    content: &CardContent,
+    cache_dir: &Path,
+) -> Result<PathBuf, String> {
+    let read = |path: &str| fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e));
+    let background = config.background.as_deref().map(read).transpose()?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(format!("{:?}", config).as_bytes());
+    hasher.update(background.as_deref().unwrap_or_default());
+    let font = match content {
+        CardContent::Title { title, site_name } => {
+            let path = config.font.as_deref().ok_or("cards.font is not set")?;
+            let bytes = read(path)?;
+            hasher.update(&bytes);
+            hasher.update(format!("title {:?} {:?}", title, site_name).as_bytes());
+            Some(
+                FontVec::try_from_vec(bytes)
+                    .map_err(|_| format!("{} is not a TrueType or OpenType font", path))?,
+            )
+        }
+        CardContent::Photo(path) => {
+            let bytes =
+                fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            hasher.update(b"photo");
+            hasher.update(&bytes);
+            None
+        }
+    };
+    let hash = hasher.finalize().to_hex();
+    let path = cache_dir.join(format!("card-{}.png", &hash[..16]));
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let mut card = match background {
+        Some(bytes) => image::load_from_memory(&bytes)
+            .map_err(|e| format!("failed to decode cards.background: {}", e))?
+            .resize_to_fill(WIDTH, HEIGHT, FilterType::Lanczos3)
+            .to_rgb8(),
+        None => RgbImage::from_pixel(WIDTH, HEIGHT, Rgb(color(&config.background_color))),
+    };
+    match (content, font) {
+        (CardContent::Title { title, site_name }, Some(font)) => draw_title(
+            &mut card,
+            &font,
+            title,
+            *site_name,
+            color(&config.text_color),
+        ),
+        (CardContent::Photo(photo), _) => {
+            let photo = image::open(photo)
+                .map_err(|e| format!("failed to decode {}: {}", photo.display(), e))?
+                .resize(WIDTH, HEIGHT, FilterType::Lanczos3)
+                .to_rgb8();
+            let x = (WIDTH - photo.width()) / 2;
+            let y = (HEIGHT - photo.height()) / 2;
+            image::imageops::overlay(&mut card, &photo, x as i64, y as i64);
+        }
+        (CardContent::Title { .. }, None) => unreachable!("title cards load the font"),
+    }
+    fs::create_dir_all(cache_dir)
+        .map_err(|e| format!("failed to create {}: {}", cache_dir.display(), e))?;
+    card.save_with_format(&path, ImageFormat::Png)
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// `#rrggbb`, checked when the config is loaded.
+fn color(hex: &str) -> [u8; 3] {
+    image_processor::parse_hex_color(hex).unwrap_or([0, 0, 0])
+}
+
+/// The title wrapped from the top left, and the site name along the bottom.
+fn draw_title(
+    card: &mut RgbImage,
+    font: &FontVec,
+    title: &str,
+    site_name: Option<&str>,
+    color: [u8; 3],
+) {
+    let max_width = WIDTH as f32 - 2.0 * MARGIN;
+    let line_height = font.as_scaled(PxScale::from(TITLE_SIZE)).height() * 1.15;
+    let mut baseline = MARGIN + font.as_scaled(PxScale::from(TITLE_SIZE)).ascent();
+    for line in wrap(font, TITLE_SIZE, title, max_width, TITLE_LINES) {
+        draw_line(card, font, TITLE_SIZE, MARGIN, baseline, &line, color);
+        baseline += line_height;
+    }
+    if let Some(site_name) = site_name {
+        let descent = font.as_scaled(PxScale::from(SITE_NAME_SIZE)).descent();
+        let baseline = HEIGHT as f32 - MARGIN + descent;
+        if let Some(line) = wrap(font, SITE_NAME_SIZE, site_name, max_width, 1).first() {
+            draw_line(card, font, SITE_NAME_SIZE, MARGIN, baseline, line, color);
+        }
+    }
+}
+
+/// Greedy word wrap into at most `max_lines` lines, ending the last with `…`
+/// when the text does not fit.
+fn wrap(font: &FontVec, size: f32, text: &str, max_width: f32, max_lines: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        if let Some(line) = lines.last_mut() {
+            let joined = format!("{} {}", line, word);
+            if text_width(font, size, &joined) <= max_width {
+                *line = joined;
+                continue;
+            }
+            if lines.len() == max_lines {
+                ellipsize(font, size, lines.last_mut().expect("not empty"), max_width);
+                break;
+            }
+        }
+        lines.push(word.to_string());
+    }
+    // A single word wider than the card is cut short
+    for line in &mut lines {
+        if text_width(font, size, line) > max_width {
+            ellipsize(font, size, line, max_width);
+        }
+    }
+    lines
+}
+
+fn ellipsize(font: &FontVec, size: f32, line: &mut String, max_width: f32) {
+    while !line.is_empty() && text_width(font, size, &format!("{}…", line)) > max_width {
+        line.pop();
+    }
+    let trimmed = line.trim_end().len();
+    line.truncate(trimmed);
+    line.push('…');
+}
+
+fn text_width(font: &FontVec, size: f32, text: &str) -> f32 {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let mut width = 0.0;
+    let mut previous: Option<GlyphId> = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            width += scaled.kern(previous, id);
+        }
+        width += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+fn draw_line(
+    card: &mut RgbImage,
+    font: &FontVec,
+    size: f32,
+    x: f32,
+    baseline: f32,
+    text: &str,
+    color: [u8; 3],
+) {
+    let scale = PxScale::from(size);
+    let scaled = font.as_scaled(scale);
+    let mut caret = x;
+    let mut previous: Option<GlyphId> = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            caret += scaled.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(scale, point(caret, baseline));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let px = bounds.min.x as i64 + gx as i64;
+            let py = bounds.min.y as i64 + gy as i64;
+            if px < 0 || py < 0 || px >= card.width() as i64 || py >= card.height() as i64 {
+                return;
+            }
+            let pixel = card.get_pixel_mut(px as u32, py as u32);
+            for (channel, target) in pixel.0.iter_mut().zip(color) {
+                let blended = *channel as f32 + (target as f32 - *channel as f32) * coverage;
+                *channel = blended.round().clamp(0.0, 255.0) as u8;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn letterboxes_a_portrait_photo_once() {
+        let tmp = tempdir().unwrap();
+        let photo = tmp.path().join("portrait.png");
+        RgbImage::from_pixel(300, 600, Rgb([255, 0, 0]))
+            .save(&photo)
+            .unwrap();
+        let config = CardsConfig {
+            background_color: "#0000ff".into(),
+            ..CardsConfig::default()
+        };
+        let cache = tmp.path().join("cache");
+
+        let path = generate(&config, &CardContent::Photo(&photo), &cache).unwrap();
+        let card = image::open(&path).unwrap().to_rgb8();
+        assert_eq!(card.dimensions(), (WIDTH, HEIGHT));
+        assert_eq!(card.get_pixel(WIDTH / 2, HEIGHT / 2), &Rgb([255, 0, 0]));
+        assert_eq!(card.get_pixel(10, HEIGHT / 2), &Rgb([0, 0, 255]));
+
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let again = generate(&config, &CardContent::Photo(&photo), &cache).unwrap();
+        assert_eq!(again, path);
+        assert_eq!(fs::metadata(&again).unwrap().modified().unwrap(), modified);
+
+        let missing_font = CardContent::Title {
+            title: "Hello",
+            site_name: None,
+        };
+        assert!(generate(&config, &missing_font, &cache).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+//! Finding the `.dllu` sources of a directory build.
+//!
+//! Symlinks are followed, but a directory or file already reached by another
+//! path (a symlink loop, a second link to the same tree, a hardlinked file) is
+//! only visited once. Paths matching `build.ignore` or a `.dllupignore` file
+//! are skipped, as are hidden directories unless `build.include_hidden` is set.
+//!
+//! `.dllupignore` uses gitignore syntax: one pattern per line, `#` comments,
+//! `*`, `?` and `**` wildcards, a trailing `/` to match only directories, a
+//! leading or inner `/` to anchor the pattern to the ignore file's directory
+//! (otherwise it matches a name at any depth) and `!` to re-include a path an
+//! earlier pattern excluded. Patterns apply to the directory holding the file
+//! and everything below it; `build.ignore` patterns are relative to the site root.
+//!
+//! A walk deeper than `build.max_depth` or longer than `build.max_files`
+//! entries stops with an error naming the place it got stuck, which is usually
+//! a mount point or dependency tree that belongs in `.dllupignore`.
+
+use crate::config::BuildConfig;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const IGNORE_FILE: &str = ".dllupignore";
+
+/// Directory levels below the site root searched when `build.max_depth` is unset.
+const DEFAULT_MAX_DEPTH: usize = 32;
+
+/// Directory entries examined when `build.max_files` is unset.
+const DEFAULT_MAX_FILES: usize = 200_000;
+
+/// How often the progress line on a terminal is redrawn.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+struct Rule {
+    /// Directory the pattern is relative to.
+    base: PathBuf,
+    regex: Regex,
+    negated: bool,
+    dir_only: bool,
+}
+
+impl Rule {
+    fn parse(line: &str, base: &Path) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        if pattern.is_empty() {
+            return None;
+        }
+        let prefix = if anchored { "^" } else { "^(?:.*/)?" };
+        let regex = Regex::new(&format!("{}{}$", prefix, glob_to_regex(pattern))).ok()?;
+        Some(Self {
+            base: base.to_path_buf(),
+            regex,
+            negated,
+            dir_only,
+        })
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let Ok(relative) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+        let relative: Vec<_> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect();
+        self.regex.is_match(&relative.join("/"))
+    }
+}
+
+fn glob_to_regex(pattern: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directories at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+/// Rules in effect for a directory: its parent's followed by its own.
+#[derive(Clone, Default)]
+struct Rules(Vec<Rc<Rule>>);
+
+impl Rules {
+    fn extend(&self, lines: &str, base: &Path) -> Self {
+        let mut rules = self.clone();
+        rules.0.extend(
+            lines
+                .lines()
+                .filter_map(|line| Rule::parse(line, base))
+                .map(Rc::new),
+        );
+        rules
+    }
+
+    /// The last matching pattern decides, so `!` lines can re-include paths.
+    fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        self.0
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(path, is_dir))
+            .is_some_and(|rule| !rule.negated)
+    }
+}
+
+/// Identifies a file or directory however it was reached.
+#[cfg(unix)]
+fn file_id(_path: &Path, metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(path: &Path, _metadata: &fs::Metadata) -> Option<PathBuf> {
+    path.canonicalize().ok()
+}
+
+/// A running count of the walk on stderr, shown only on a terminal and only
+/// once the walk has taken long enough to be worth watching.
+struct Progress {
+    enabled: bool,
+    started: Instant,
+    last_drawn: Option<Instant>,
+    directories: usize,
+    entries: usize,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Self {
+            enabled: std::io::stderr().is_terminal(),
+            started: Instant::now(),
+            last_drawn: None,
+            directories: 0,
+            entries: 0,
+        }
+    }
+
+    fn tick(&mut self, pages: usize) {
+        if !self.enabled || self.started.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        if self
+            .last_drawn
+            .is_some_and(|drawn| drawn.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.last_drawn = Some(Instant::now());
+        eprint!(
+            "\rCollecting pages: {} directories, {} entries, {} pages",
+            self.directories, self.entries, pages
+        );
+        let _ = std::io::stderr().flush();
+    }
+
+    /// Clears the progress line so later output starts on a clean line.
+    fn finish(&self) {
+        if self.last_drawn.is_some() {
+            eprint!("\r\x1b[2K");
+        }
+    }
+}
+
+/// The `.dllu` files under `dir`, deepest first, then by path.
+pub fn collect_dllu_files(dir: &Path, build: &BuildConfig) -> Result<Vec<PathBuf>, String> {
+    let mut progress = Progress::new();
+    let result = walk(dir, build, &mut progress);
+    progress.finish();
+    result
+}
+
+fn walk(dir: &Path, build: &BuildConfig, progress: &mut Progress) -> Result<Vec<PathBuf>, String> {
+    let max_depth = build.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+    let max_files = build.max_files.unwrap_or(DEFAULT_MAX_FILES);
+    let root_rules = Rules::default().extend(&build.ignore.join("\n"), dir);
+    let mut stack = vec![(dir.to_path_buf(), 0, root_rules)];
+    let mut seen = HashMap::new();
+    let mut files = Vec::new();
+
+    if let Some(id) = fs::metadata(dir).ok().and_then(|m| file_id(dir, &m)) {
+        seen.insert(id, dir.to_path_buf());
+    }
+
+    while let Some((path, depth, rules)) = stack.pop() {
+        if depth > max_depth {
+            return Err(format!(
+                "Stopped collecting pages at {}: it is more than {} directories below {}. \
+                 Add it to {} or raise build.max_depth",
+                path.display(),
+                max_depth,
+                dir.display(),
+                IGNORE_FILE
+            ));
+        }
+        progress.directories += 1;
+        let rules = match fs::read_to_string(path.join(IGNORE_FILE)) {
+            Ok(lines) => rules.extend(&lines, &path),
+            Err(_) => rules,
+        };
+        let entries = fs::read_dir(&path)
+            .map_err(|e| format!("Failed to read directory {}: {}", path.display(), e))?;
+        let mut entries = entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read entry in {}: {}", path.display(), e))?;
+        // Sorted so the first of several links to one file is always the same
+        entries.sort();
+
+        progress.entries += entries.len();
+        if progress.entries > max_files {
+            return Err(format!(
+                "Stopped collecting pages in {}: more than {} files and directories under {}. \
+                 Add the directories that hold no pages to {} or raise build.max_files",
+                path.display(),
+                max_files,
+                dir.display(),
+                IGNORE_FILE
+            ));
+        }
+
+        let mut subdirs = Vec::new();
+        for entry_path in entries {
+            // Follows symlinks; a dangling one is skipped
+            let Ok(metadata) = fs::metadata(&entry_path) else {
+                continue;
+            };
+            let is_dir = metadata.is_dir();
+            let hidden = entry_path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if (is_dir && hidden && !build.include_hidden) || rules.ignores(&entry_path, is_dir) {
+                continue;
+            }
+            let is_source = metadata.is_file()
+                && entry_path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(|ext| ext.eq_ignore_ascii_case("dllu"))
+                    .unwrap_or(false);
+            if !is_dir && !is_source {
+                continue;
+            }
+            if let Some(id) = file_id(&entry_path, &metadata) {
+                if let Some(first) = seen.get(&id) {
+                    crate::diagnostics::warn(format!(
+                        "skipping {}: same {} as {}",
+                        entry_path.display(),
+                        if is_dir { "directory" } else { "file" },
+                        first.display()
+                    ));
+                    continue;
+                }
+                seen.insert(id, entry_path.clone());
+            }
+            if is_dir {
+                subdirs.push(entry_path);
+            } else {
+                files.push(entry_path);
+            }
+        }
+        stack.extend(
+            subdirs
+                .into_iter()
+                .rev()
+                .map(|sub| (sub, depth + 1, rules.clone())),
+        );
+        progress.tick(files.len());
+    }
+
+    files.sort_by(|a, b| {
+        let depth_a = a.components().count();
+        let depth_b = b.components().count();
+        depth_b.cmp(&depth_a).then_with(|| a.cmp(b))
+    });
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "Title\n===\n\nBody\n").unwrap();
+    }
+
+    fn names(root: &Path, files: &[PathBuf]) -> Vec<String> {
+        let mut names: Vec<String> = files
+            .iter()
+            .map(|f| {
+                f.strip_prefix(root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/")
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn ignore_files_and_hidden_directories_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        touch(&root.join("index.dllu"));
+        touch(&root.join("node_modules/pkg/readme.dllu"));
+        touch(&root.join(".cache/page.dllu"));
+        touch(&root.join("blog/post.dllu"));
+        touch(&root.join("blog/draft-post.dllu"));
+        touch(&root.join("blog/draft-keep.dllu"));
+        touch(&root.join("notes/todo.dllu"));
+        fs::write(root.join(IGNORE_FILE), "# deps\nnode_modules/\n/notes\n").unwrap();
+        fs::write(
+            root.join("blog").join(IGNORE_FILE),
+            "draft-*\n!draft-keep.dllu\n",
+        )
+        .unwrap();
+
+        let build = BuildConfig::default();
+        let files = collect_dllu_files(root, &build).unwrap();
+        assert_eq!(
+            names(root, &files),
+            ["blog/draft-keep.dllu", "blog/post.dllu", "index.dllu"]
+        );
+
+        let build = BuildConfig {
+            ignore: vec!["**/post.dllu".into()],
+            include_hidden: true,
+            ..BuildConfig::default()
+        };
+        let files = collect_dllu_files(root, &build).unwrap();
+        assert_eq!(
+            names(root, &files),
+            [".cache/page.dllu", "blog/draft-keep.dllu", "index.dllu"]
+        );
+    }
+
+    #[test]
+    fn walks_stop_at_the_configured_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        touch(&root.join("a/b/c/deep.dllu"));
+        touch(&root.join("a/one.dllu"));
+        touch(&root.join("a/two.dllu"));
+
+        let build = BuildConfig {
+            max_depth: Some(2),
+            ..BuildConfig::default()
+        };
+        let err = collect_dllu_files(root, &build).unwrap_err();
+        assert!(err.contains("build.max_depth"), "{}", err);
+        assert!(err.contains(&format!("{}", Path::new("a/b/c").display())));
+
+        let build = BuildConfig {
+            max_files: Some(3),
+            ..BuildConfig::default()
+        };
+        let err = collect_dllu_files(root, &build).unwrap_err();
+        assert!(err.contains("build.max_files"), "{}", err);
+
+        let build = BuildConfig {
+            max_depth: Some(3),
+            max_files: Some(6),
+            ..BuildConfig::default()
+        };
+        assert_eq!(collect_dllu_files(root, &build).unwrap().len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loops_are_visited_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        touch(&root.join("a/page.dllu"));
+        std::os::unix::fs::symlink(root.join("a"), root.join("a/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("a"), root.join("b")).unwrap();
+        fs::hard_link(root.join("a/page.dllu"), root.join("copy.dllu")).unwrap();
+
+        // `copy.dllu` sorts before the contents of `a/`
+        let files = collect_dllu_files(root, &BuildConfig::default()).unwrap();
+        assert_eq!(names(root, &files), ["copy.dllu"]);
+    }
+}
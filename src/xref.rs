@@ -0,0 +1,241 @@
+//! Cross-page references: `(#page:label)` and `\eqref{page:label}` point at a
+//! labelled section, figure, embed, equation or listing, or a `[#label]`
+//! anchor, of another page of the site. Pages are named by their slug: the
+//! file stem, or the directory name for an `index.dllu`. Targets are numbered
+//! the way their own page numbers them and linked by absolute URL when
+//! `root_url` is set.
+
+use crate::ast::{is_graphviz, Article, Block, InlineElement};
+use crate::config;
+use crate::html_renderer::next_section_number;
+use crate::lint;
+use crate::walk;
+use dllup_rs::parser::Parser;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    /// Targets of every page of each site root, read once per build.
+    static ref SITES: Mutex<HashMap<PathBuf, Arc<SiteReferences>>> = Mutex::new(HashMap::new());
+}
+
+/// The numbered targets of one page.
+#[derive(Debug)]
+pub struct PageTargets {
+    /// The page's source, which pages referring to it depend on.
+    pub source: PathBuf,
+    pub url: String,
+    pub title: String,
+    /// Label to the text a reference shows, such as `Figure 3` or `(2)`.
+    pub targets: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct SiteReferences {
+    pages: BTreeMap<String, PageTargets>,
+}
+
+impl SiteReferences {
+    /// The page and shown text of a `page:label` reference name, or `None`
+    /// for a name without a page or with no such target.
+    pub fn resolve(&self, name: &str) -> Option<(&PageTargets, &str)> {
+        let (_, label) = name.split_once(':')?;
+        let page = self.page(name)?;
+        let text = page.targets.get(label)?;
+        Some((page, text))
+    }
+
+    /// The page a `page:label` reference name points into.
+    pub fn page(&self, name: &str) -> Option<&PageTargets> {
+        let (slug, _) = name.split_once(':')?;
+        self.pages.get(slug)
+    }
+}
+
+/// Whether `name` is a `page:label` reference to another page.
+pub fn is_cross_page(name: &str) -> bool {
+    name.contains(':')
+}
+
+/// The `page:label` reference names of `article`, in document order.
+pub fn cross_page_references(article: &Article) -> Vec<&str> {
+    let mut names = Vec::new();
+    lint::visit_inlines(article, &mut |element| {
+        if let InlineElement::Reference(name) = element {
+            if is_cross_page(name) {
+                names.push(name.as_str());
+            }
+        }
+    });
+    names
+}
+
+/// The targets of every page below `site_root`, parsed on first use.
+pub fn for_site(site_root: &Path, config: &config::Config) -> Arc<SiteReferences> {
+    // Held while the site is read so concurrent pages wait instead of reading it too
+    let Ok(mut sites) = SITES.lock() else {
+        return Arc::default();
+    };
+    if let Some(site) = sites.get(site_root) {
+        return Arc::clone(site);
+    }
+    let site = Arc::new(read_site(site_root, config));
+    sites.insert(site_root.to_path_buf(), Arc::clone(&site));
+    site
+}
+
+fn read_site(site_root: &Path, config: &config::Config) -> SiteReferences {
+    let mut site = SiteReferences::default();
+    let files = match walk::collect_dllu_files(site_root, &config.build) {
+        Ok(files) => files,
+        Err(e) => {
+            crate::diagnostics::warn(format!("[xref] {}", e));
+            return site;
+        }
+    };
+    let mut sources: HashMap<String, PathBuf> = HashMap::new();
+    for file in files {
+        let Some(slug) = page_slug(&file) else {
+            continue;
+        };
+        if let Some(first) = sources.get(&slug) {
+            crate::diagnostics::warn(format!(
+                "[xref] {} and {} share the slug `{}`; references use the first",
+                first.display(),
+                file.display(),
+                slug
+            ));
+            continue;
+        }
+        let Ok(source) = fs::read_to_string(&file) else {
+            continue;
+        };
+        let mut parser = Parser::with_includes(&file);
+        parser.parse(&source);
+        if let Err(e) = crate::plugins::apply(&config.plugins, &mut parser.article, &file) {
+            crate::diagnostics::warn(format!("[xref] {}: {}", file.display(), e));
+            continue;
+        }
+        let url = crate::page_permalink(&file, Some(site_root), config).unwrap_or_else(|| {
+            let relative = file.with_extension("html");
+            let relative = relative.strip_prefix(site_root).unwrap_or(&relative);
+            format!("/{}", crate::pathbuf_to_url_path(relative))
+        });
+        let title = parser
+            .article
+            .header
+            .as_ref()
+            .map(|header| header.title.clone())
+            .unwrap_or_else(|| slug.clone());
+        site.pages.insert(
+            slug.clone(),
+            PageTargets {
+                source: file.clone(),
+                url,
+                title,
+                targets: numbered_targets(&parser.article),
+            },
+        );
+        sources.insert(slug, file);
+    }
+    site
+}
+
+/// The file stem, or the directory's name for an `index.dllu`.
+fn page_slug(file: &Path) -> Option<String> {
+    let stem = file.file_stem()?.to_str()?;
+    if stem != "index" {
+        return Some(stem.to_string());
+    }
+    let dir = file.parent()?.file_name()?.to_str()?;
+    Some(dir.to_string())
+}
+
+/// Labels of the page mapped to the text a reference to them shows.
+fn numbered_targets(article: &Article) -> BTreeMap<String, String> {
+    let mut targets = BTreeMap::new();
+    let mut section_counters = Vec::new();
+    for block in article.blocks() {
+        let (id, text) = match block {
+            Block::SectionHeader { level, id, .. } => {
+                let level = (*level).clamp(1, 6);
+                let number = next_section_number(&mut section_counters, level);
+                (id.as_str(), format!("Section {}", number))
+            }
+            Block::ImageFigure {
+                id: Some(id),
+                id_number,
+                ..
+            }
+            | Block::Embed {
+                id: Some(id),
+                id_number,
+                ..
+            } => (id.as_str(), format!("Figure {}", id_number + 1)),
+            Block::DisplayMath {
+                id: Some(id),
+                id_number,
+                ..
+            } => (id.as_str(), format!("({})", id_number + 1)),
+            Block::CodeBlock {
+                id: Some(id),
+                id_number,
+                language,
+                ..
+            } if is_graphviz(language.as_deref()) => {
+                (id.as_str(), format!("Figure {}", id_number + 1))
+            }
+            Block::CodeBlock {
+                id: Some(id),
+                id_number,
+                caption: Some(_),
+                ..
+            } => (id.as_str(), format!("Listing {}", id_number + 1)),
+            _ => continue,
+        };
+        targets.insert(id.to_string(), text);
+    }
+    // `[#label]` anchors are cited by name, as on their own page
+    lint::visit_inlines(article, &mut |element| {
+        if let InlineElement::ReferenceAnchor { content, .. } = element {
+            targets
+                .entry(content.clone())
+                .or_insert_with(|| content.clone());
+        }
+    });
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_targets_of_other_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("part-1")).unwrap();
+        std::fs::write(
+            dir.path().join("part-1").join("index.dllu"),
+            "Part one\n\n===\n\n# Setup\n\n## Mass\n\n$ x = 1\n\n$ E = mc^2 \\label{energy}\n\nAs [#kite] said.\n\n~~~\nlang dot\nfile mass.dot : Forces [#forces]\ndigraph { m -> g }\n~~~\n",
+        )
+        .unwrap();
+        let config = config::Config {
+            root_url: Some("https://example.com".into()),
+            ..config::Config::default()
+        };
+        let site = for_site(dir.path(), &config);
+
+        let (page, text) = site.resolve("part-1:energy").unwrap();
+        assert_eq!(text, "(2)");
+        assert_eq!(page.title, "Part one");
+        assert_eq!(page.url, "https://example.com/part-1");
+        let text = |name| site.resolve(name).map(|(_, text)| text);
+        assert_eq!(text("part-1:mass"), Some("Section 1.1"));
+        assert_eq!(text("part-1:kite"), Some("kite"));
+        assert_eq!(text("part-1:forces"), Some("Figure 1"));
+        assert_eq!(text("part-1:energy-2"), None);
+        assert!(site.resolve("part-2:energy").is_none());
+    }
+}